@@ -0,0 +1,105 @@
+//! Compare the memory-mapped and buffered read paths on a synthetic file.
+//!
+//! The file holds a base layer of large files plus a delta of small ones.
+//! Its size defaults to 256 MiB; set `TCOW_BENCH_MB` for multi-GB runs:
+//!
+//! ```sh
+//! TCOW_BENCH_MB=4096 cargo bench --bench read_path
+//! ```
+
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tcow::{sha256_hex, LayerWriter, ReadMode, TcowFile, VPath};
+
+const BIG_FILES: u64 = 4;
+const SMALL_FILES: usize = 2000;
+
+fn bench_size() -> u64 {
+    std::env::var("TCOW_BENCH_MB")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(256)
+        << 20
+}
+
+/// Deterministic, poorly compressible filler.
+struct Filler(u64);
+
+impl Read for Filler {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        for chunk in buf.chunks_mut(8) {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            chunk.copy_from_slice(&self.0.to_le_bytes()[..chunk.len()]);
+        }
+        Ok(buf.len())
+    }
+}
+
+/// Build the synthetic file once per size and reuse it across runs.
+fn synthetic_file(size: u64) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tcow-bench-{}m.tcow", size >> 20));
+    if path.exists() {
+        return path;
+    }
+    let big = size / BIG_FILES;
+    let mut base = LayerWriter::create(&path, Some("bench".into())).unwrap();
+    for i in 0..BIG_FILES {
+        base.add_file(&VPath::new(&format!("/big/{i}.bin")).unwrap(), Filler(i + 1), big).unwrap();
+    }
+    base.commit().unwrap();
+
+    let mut delta = LayerWriter::append(&path).unwrap();
+    for i in 0..SMALL_FILES {
+        delta.add_file(&VPath::new(&format!("/small/{i}.txt")).unwrap(), Filler(i as u64 + 99), 512).unwrap();
+    }
+    delta.commit().unwrap();
+    path
+}
+
+fn open(path: &Path, mode: ReadMode) -> TcowFile {
+    TcowFile::open_with(path, mode).unwrap()
+}
+
+fn read_path(c: &mut Criterion) {
+    let size = bench_size();
+    let path = synthetic_file(size);
+    let modes = [("mmap", ReadMode::Mmap), ("buffered", ReadMode::Buffered)];
+
+    let mut group = c.benchmark_group("read_path");
+    group.sample_size(10).measurement_time(Duration::from_secs(20));
+    group.throughput(Throughput::Bytes(size));
+
+    for (name, mode) in modes {
+        group.bench_function(BenchmarkId::new("open", name), |b| {
+            b.iter(|| open(&path, mode).layers.len())
+        });
+        group.bench_function(BenchmarkId::new("cat_all", name), |b| {
+            b.iter(|| {
+                let tcow = open(&path, mode);
+                (0..BIG_FILES)
+                    .map(|i| {
+                        let mut r = tcow.open_file(&VPath::new(&format!("big/{i}.bin")).unwrap()).unwrap();
+                        io::copy(&mut r, &mut io::sink()).unwrap()
+                    })
+                    .sum::<u64>()
+            })
+        });
+        group.bench_function(BenchmarkId::new("verify", name), |b| {
+            b.iter(|| {
+                let tcow = open(&path, mode);
+                (0..tcow.index.layers.len())
+                    .map(|i| sha256_hex(&tcow.layer_bytes(i).unwrap()))
+                    .collect::<Vec<_>>()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, read_path);
+criterion_main!(benches);
@@ -0,0 +1,494 @@
+//! Zip and newc cpio adapters.
+//!
+//! Both formats use the same metadata mapping as tar import and export:
+//! regular files keep their content and mtime and are stored as mode 0644;
+//! directories are implied by file paths; `.wh.` entries are whiteouts and
+//! opaque markers. Symlinks, devices and other special entries are skipped
+//! on import and every dropped detail is reported back to the caller.
+//!
+//! Imports take two passes, like `scan_tar_archive` and
+//! `import_tar_archive`: a scan that reads only headers, then a pass that
+//! streams the kept files into a `LayerWriter`.
+
+use std::collections::BTreeSet;
+use std::io::{Read, Seek, Write};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Datelike, NaiveDateTime, Timelike};
+
+use crate::{
+    archive_entry_path, now_unix_ts, write_import_entry, ArchiveImport, ImportPass, ImportScan, LayerWriter,
+    StorageMut, TcowFile,
+};
+
+/// Summary of an export: how many files were written and what was lost.
+#[derive(Debug, Clone, Default)]
+pub struct ExportReport {
+    pub files: usize,
+    /// Metadata that the target format could not represent exactly.
+    pub lossy: Vec<String>,
+}
+
+// ── Zip ───────────────────────────────────────────────────────────────────────
+
+/// First pass over a zip archive. Entry names that would escape the
+/// archive root are errors. Zip stores local times without a zone; they
+/// are read as UTC, as `write_union_zip` writes them.
+pub fn scan_zip_archive(r: impl Read + Seek) -> Result<ArchiveImport> {
+    let mut zip = zip::ZipArchive::new(r).context("reading zip central directory")?;
+    let mut scan = ImportScan::default();
+    for i in 0..zip.len() {
+        let file = zip.by_index(i).with_context(|| format!("reading zip entry {i}"))?;
+        let path = archive_entry_path(file.name_raw())?;
+        if path.is_root() || file.is_dir() {
+            continue;
+        }
+        let mtime = match file.last_modified().and_then(zip_timestamp) {
+            Some(t) => t,
+            None => {
+                scan.lossy.push(format!("/{path}: no usable mtime, stored as the import time"));
+                now_unix_ts()
+            }
+        };
+        let mode = file.unix_mode().map_or(0o644, |m| m & 0o7777);
+        let regular = !file.is_symlink();
+        scan.add(i, path, mtime, regular.then(|| (file.size(), mode)));
+    }
+    Ok(scan.finish())
+}
+
+/// Second pass: stream the entries `import` kept from the same zip archive
+/// into `writer`. A file whose content does not match its declared size or
+/// CRC is an error.
+pub fn import_zip_archive<S: StorageMut>(
+    r: impl Read + Seek,
+    import: &ArchiveImport,
+    writer: &mut LayerWriter<S>,
+) -> Result<()> {
+    let mut zip = zip::ZipArchive::new(r).context("reading zip central directory")?;
+    let mut pass = ImportPass::new(import);
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i).with_context(|| format!("reading zip entry {i}"))?;
+        let path = archive_entry_path(file.name_raw())?;
+        let Some(e) = pass.entry(i, &path)? else { continue };
+        write_import_entry(writer, e, &mut file).with_context(|| format!("decompressing {path}"))?;
+        // Reading to the end checks the CRC and catches content longer
+        // than the declared size
+        if file.read(&mut [0u8; 1]).with_context(|| format!("decompressing {path}"))? != 0 {
+            bail!("{path}: content is longer than its declared size");
+        }
+    }
+    pass.finish()?;
+    Ok(())
+}
+
+/// Unix seconds for a zip timestamp, read as UTC.
+fn zip_timestamp(t: zip::DateTime) -> Option<u64> {
+    let t = NaiveDateTime::try_from(t).ok()?;
+    u64::try_from(t.and_utc().timestamp()).ok()
+}
+
+/// Write the union view as of layer `top` as a deflate-compressed zip.
+/// Zip timestamps have two-second resolution and cannot predate 1980; files
+/// outside that range are stamped 1980-01-01 and reported.
+pub fn write_union_zip<S, W: Write + Seek>(tcow: &TcowFile<S>, top: usize, w: W) -> Result<ExportReport> {
+    if top >= tcow.layers.len() {
+        bail!("layer {top} does not exist (file has {} layers)", tcow.layers.len());
+    }
+    let mut visible = tcow.visible_entries(top);
+    visible.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut zip = zip::ZipWriter::new(w);
+    let mut report = ExportReport::default();
+    for (path, entry, _) in &visible {
+        let mtime = match zip_datetime(entry.mtime) {
+            Some(t) => t,
+            None => {
+                report.lossy.push(format!("/{path}: mtime outside zip range, stored as 1980-01-01"));
+                zip::DateTime::default()
+            }
+        };
+        let opts = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .unix_permissions(0o644)
+            .last_modified_time(mtime)
+            .large_file(entry.data.len() as u64 >= u32::MAX as u64);
+        zip.start_file(path.as_str(), opts)?;
+        zip.write_all(&entry.data)?;
+        report.files += 1;
+    }
+    zip.finish()?;
+    Ok(report)
+}
+
+fn zip_datetime(ts: u64) -> Option<zip::DateTime> {
+    let dt = DateTime::from_timestamp(i64::try_from(ts).ok()?, 0)?.naive_utc();
+    zip::DateTime::from_date_and_time(
+        u16::try_from(dt.year()).ok()?,
+        dt.month() as u8,
+        dt.day() as u8,
+        dt.hour() as u8,
+        dt.minute() as u8,
+        dt.second() as u8,
+    )
+    .ok()
+}
+
+// ── cpio (newc) ───────────────────────────────────────────────────────────────
+
+const CPIO_MAGIC: &[u8; 6] = b"070701";
+const CPIO_MAGIC_CRC: &[u8; 6] = b"070702";
+const CPIO_HEADER_SIZE: usize = 110;
+/// Longest entry name accepted, terminating NUL included (Linux `PATH_MAX`).
+const CPIO_MAX_NAME: usize = 4096;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+
+/// One newc header and name, as `read_cpio_header` returns them.
+struct CpioHeader {
+    mode: u32,
+    mtime: u32,
+    file_size: u64,
+    name: String,
+}
+
+impl CpioHeader {
+    fn is_trailer(&self) -> bool {
+        self.name == CPIO_TRAILER
+    }
+}
+
+/// Read the next header and entry name from a newc (`070701`) or
+/// newc-with-CRC (`070702`) stream, leaving `r` at the entry's data.
+fn read_cpio_header(r: &mut impl Read) -> Result<CpioHeader> {
+    let mut hdr = [0u8; CPIO_HEADER_SIZE];
+    r.read_exact(&mut hdr).context("reading cpio header")?;
+    if &hdr[0..6] != CPIO_MAGIC && &hdr[0..6] != CPIO_MAGIC_CRC {
+        bail!("not a newc cpio archive: bad magic {:?}", String::from_utf8_lossy(&hdr[0..6]));
+    }
+    let field = |i: usize| -> Result<u32> {
+        let raw = std::str::from_utf8(&hdr[6 + i * 8..14 + i * 8])?;
+        u32::from_str_radix(raw, 16).map_err(|e| anyhow!("bad cpio header field: {e}"))
+    };
+    let mode = field(1)?;
+    let mtime = field(5)?;
+    let file_size = field(6)? as u64;
+    let name_size = field(11)? as usize;
+    if name_size > CPIO_MAX_NAME {
+        bail!("cpio entry name of {name_size} bytes is longer than {CPIO_MAX_NAME}");
+    }
+
+    let name = read_sized(r, name_size).context("reading cpio entry name")?;
+    skip_padding(r, CPIO_HEADER_SIZE + name_size)?;
+    let name = String::from_utf8(name.into_iter().take_while(|b| *b != 0).collect())
+        .map_err(|_| anyhow!("non-UTF-8 file name in cpio archive"))?;
+    Ok(CpioHeader { mode, mtime, file_size, name })
+}
+
+/// Receives the data of a cpio entry, reading as much of it as it needs.
+type CpioBody<'a> = &'a mut dyn FnMut(&mut dyn Read) -> Result<()>;
+
+/// Move past the data of the entry `h` and its padding, passing the data
+/// to `body` first if given.
+fn finish_cpio_entry(r: &mut impl Read, h: &CpioHeader, body: Option<CpioBody>) -> Result<()> {
+    let mut data = r.take(h.file_size);
+    if let Some(body) = body {
+        body(&mut data)?;
+    }
+    std::io::copy(&mut data, &mut std::io::sink())?;
+    if data.limit() != 0 {
+        bail!("cpio archive ends inside {}", h.name);
+    }
+    skip_padding(r, (h.file_size % 4) as usize)
+}
+
+/// First pass over a newc (`070701`) or newc-with-CRC (`070702`) cpio
+/// stream.
+pub fn scan_cpio_archive(mut r: impl Read) -> Result<ArchiveImport> {
+    let mut scan = ImportScan::default();
+    for position in 0.. {
+        let h = read_cpio_header(&mut r)?;
+        finish_cpio_entry(&mut r, &h, None)?;
+        if h.is_trailer() {
+            break;
+        }
+        let path = archive_entry_path(h.name.as_bytes())?;
+        if path.is_root() || h.mode & S_IFMT == S_IFDIR {
+            continue;
+        }
+        let file = (h.mode & S_IFMT == S_IFREG).then_some((h.file_size, h.mode & 0o7777));
+        scan.add(position, path, h.mtime.into(), file);
+    }
+    Ok(scan.finish())
+}
+
+/// Second pass: stream the entries `import` kept from the same cpio stream
+/// into `writer`.
+pub fn import_cpio_archive<S: StorageMut>(
+    mut r: impl Read,
+    import: &ArchiveImport,
+    writer: &mut LayerWriter<S>,
+) -> Result<()> {
+    let mut pass = ImportPass::new(import);
+    for position in 0.. {
+        let h = read_cpio_header(&mut r)?;
+        if h.is_trailer() {
+            break;
+        }
+        let path = archive_entry_path(h.name.as_bytes())?;
+        match pass.entry(position, &path)? {
+            Some(e) => {
+                let mut write = |data: &mut dyn Read| Ok(write_import_entry(writer, e, data)?);
+                finish_cpio_entry(&mut r, &h, Some(&mut write))?;
+            }
+            None => finish_cpio_entry(&mut r, &h, None)?,
+        }
+    }
+    pass.finish()?;
+    Ok(())
+}
+
+/// Write the union view as of layer `top` as a newc cpio stream. Parent
+/// directories are emitted (mode 0755) before the files inside them, as
+/// initramfs unpackers expect.
+pub fn write_union_cpio<S>(tcow: &TcowFile<S>, top: usize, mut w: impl Write) -> Result<ExportReport> {
+    if top >= tcow.layers.len() {
+        bail!("layer {top} does not exist (file has {} layers)", tcow.layers.len());
+    }
+    let visible = tcow.visible_entries(top);
+    let mut report = ExportReport::default();
+
+    let mut dirs = BTreeSet::new();
+    for (path, _, _) in &visible {
+        for (i, _) in path.match_indices('/') {
+            dirs.insert(&path[..i]);
+        }
+    }
+    let mut items: Vec<(&str, Option<&crate::RawEntry>)> =
+        dirs.into_iter().map(|d| (d, None)).collect();
+    items.extend(visible.iter().map(|(p, e, _)| (p.as_str(), Some(*e))));
+    items.sort_by(|a, b| a.0.cmp(b.0));
+
+    for (ino, (path, entry)) in items.iter().enumerate() {
+        let (mode, nlink, mtime, data) = match entry {
+            Some(e) => (S_IFREG | 0o644, 1, e.mtime, e.data.as_slice()),
+            None => (S_IFDIR | 0o755, 2, 0, &[][..]),
+        };
+        if data.len() > u32::MAX as usize {
+            bail!("/{path} is too large for a newc cpio archive");
+        }
+        let mtime32 = u32::try_from(mtime).unwrap_or_else(|_| {
+            report.lossy.push(format!("/{path}: mtime beyond 2106, stored as 0"));
+            0
+        });
+        write_cpio_entry(&mut w, ino as u32 + 1, mode, nlink, mtime32, path, data)?;
+        if entry.is_some() {
+            report.files += 1;
+        }
+    }
+    write_cpio_entry(&mut w, 0, 0, 1, 0, CPIO_TRAILER, &[])?;
+    Ok(report)
+}
+
+fn write_cpio_entry(
+    w: &mut impl Write,
+    ino: u32,
+    mode: u32,
+    nlink: u32,
+    mtime: u32,
+    name: &str,
+    data: &[u8],
+) -> Result<()> {
+    let name_size = name.len() + 1;
+    let fields = [ino, mode, 0, 0, nlink, mtime, data.len() as u32, 0, 0, 0, 0, name_size as u32, 0];
+    let mut hdr = String::with_capacity(CPIO_HEADER_SIZE);
+    hdr.push_str("070701");
+    for f in fields {
+        hdr.push_str(&format!("{f:08X}"));
+    }
+    w.write_all(hdr.as_bytes())?;
+    w.write_all(name.as_bytes())?;
+    w.write_all(&[0])?;
+    w.write_all(&[0u8; 3][..pad4(CPIO_HEADER_SIZE + name_size)])?;
+    w.write_all(data)?;
+    w.write_all(&[0u8; 3][..pad4(data.len())])?;
+    Ok(())
+}
+
+/// Bytes of padding needed to bring `len` up to a multiple of four.
+fn pad4(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+fn skip_padding(r: &mut impl Read, len: usize) -> Result<()> {
+    let mut pad = [0u8; 3];
+    r.read_exact(&mut pad[..pad4(len)])?;
+    Ok(())
+}
+
+/// Read exactly `len` bytes. The buffer grows as data arrives rather than
+/// being sized from the untrusted header, so a bogus size in a short
+/// archive fails without allocating it.
+fn read_sized(r: &mut impl Read, len: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    r.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        bail!("archive ends {} bytes early", len - buf.len());
+    }
+    Ok(buf)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{scan_tar_archive, import_tar_archive, ImportKind, VPath};
+
+    fn vpath(p: &str) -> VPath {
+        VPath::new(p).unwrap()
+    }
+
+    /// An in-memory `.tcow` file whose base layer holds `files`, each with
+    /// its own mtime.
+    fn tcow_with(files: &[(&str, &[u8], u64)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut w = LayerWriter::create_in(Cursor::new(&mut buf), None).unwrap();
+        for (path, data, mtime) in files {
+            w.set_mtime(*mtime);
+            w.add_file(&vpath(path), *data, data.len() as u64).unwrap();
+        }
+        w.commit().unwrap();
+        buf
+    }
+
+    /// Run both passes of an import into a new layer on top of `base`.
+    fn import(
+        base: Vec<u8>,
+        scan: impl Fn() -> Result<ArchiveImport>,
+        write: impl Fn(&ArchiveImport, &mut LayerWriter<Cursor<&mut Vec<u8>>>) -> Result<()>,
+    ) -> (TcowFile<Cursor<Vec<u8>>>, ArchiveImport) {
+        let plan = scan().unwrap();
+        let mut buf = base;
+        let mut w = LayerWriter::append_to(Cursor::new(&mut buf)).unwrap();
+        write(&plan, &mut w).unwrap();
+        w.commit().unwrap();
+        (TcowFile::from_storage(Cursor::new(buf)).unwrap(), plan)
+    }
+
+    fn tar_of(entries: &[(&str, &[u8], u64, tar::EntryType, u32)]) -> Vec<u8> {
+        let mut b = tar::Builder::new(Vec::new());
+        for (name, data, mtime, kind, mode) in entries {
+            let mut hdr = tar::Header::new_gnu();
+            hdr.set_path(name).unwrap();
+            hdr.set_entry_type(*kind);
+            hdr.set_size(data.len() as u64);
+            hdr.set_mtime(*mtime);
+            hdr.set_mode(*mode);
+            if kind.is_symlink() {
+                hdr.set_link_name("target").unwrap();
+            }
+            hdr.set_cksum();
+            b.append(&hdr, *data).unwrap();
+        }
+        b.into_inner().unwrap()
+    }
+
+    #[test]
+    fn tar_import_keeps_mtimes_and_docker_markers() {
+        use tar::EntryType::{Regular, Symlink};
+        let base = tcow_with(&[("etc/old.conf", b"old", 1), ("gone.txt", b"x", 1), ("keep.txt", b"k", 1)]);
+        let tar = tar_of(&[
+            ("etc/.wh..wh..opq", b"", 10, Regular, 0o644),
+            ("etc/new.conf", b"new", 11, Regular, 0o644),
+            ("./.wh.gone.txt", b"", 12, Regular, 0o644),
+            ("dup.txt", b"first", 13, Regular, 0o644),
+            ("dup.txt", b"second", 14, Regular, 0o755),
+            ("link", b"", 15, Symlink, 0o777),
+        ]);
+        let (tcow, plan) = import(
+            base,
+            || Ok(scan_tar_archive(&tar[..])?),
+            |plan, w| Ok(import_tar_archive(&tar[..], plan, w)?),
+        );
+
+        assert_eq!((plan.files(), plan.whiteouts(), plan.bytes()), (2, 2, 9));
+        assert_eq!(plan.skipped, ["link"]);
+        assert_eq!(plan.lossy, ["/dup.txt: mode 0755 stored as 0644"]);
+        assert_eq!(plan.entries[0].kind, ImportKind::Opaque);
+
+        assert!(tcow.lookup(&vpath("etc/old.conf")).is_none());
+        assert!(tcow.lookup(&vpath("gone.txt")).is_none());
+        assert!(tcow.lookup(&vpath("keep.txt")).is_some());
+        let (new, _) = tcow.lookup(&vpath("etc/new.conf")).unwrap();
+        assert_eq!((&new.data[..], new.mtime), (&b"new"[..], 11));
+        let (dup, _) = tcow.lookup(&vpath("dup.txt")).unwrap();
+        assert_eq!((&dup.data[..], dup.mtime), (&b"second"[..], 14));
+        assert_eq!(tcow.layer(1).unwrap().len(), 4, "one entry per path");
+        tcow.verify_layer(1).unwrap();
+    }
+
+    #[test]
+    fn an_archive_that_changes_between_passes_is_refused() {
+        let first = tar_of(&[("a", b"1", 1, tar::EntryType::Regular, 0o644)]);
+        let second = tar_of(&[("b", b"1", 1, tar::EntryType::Regular, 0o644)]);
+        let plan = scan_tar_archive(&first[..]).unwrap();
+        let mut buf = tcow_with(&[("x", b"x", 1)]);
+        let mut w = LayerWriter::append_to(Cursor::new(&mut buf)).unwrap();
+        let err = import_tar_archive(&second[..], &plan, &mut w).unwrap_err();
+        assert!(err.to_string().contains("changed while it was being imported"), "{err}");
+    }
+
+    #[test]
+    fn zip_round_trip_keeps_content_and_mtime() {
+        // Zip times have two-second resolution
+        let files: &[(&str, &[u8], u64)] = &[("a.txt", b"alpha", 1_600_000_000), ("d/b.bin", &[7; 3000], 1_700_000_002)];
+        let src = TcowFile::from_storage(Cursor::new(tcow_with(files))).unwrap();
+        let mut zip = Cursor::new(Vec::new());
+        write_union_zip(&src, 0, &mut zip).unwrap();
+        let zip = zip.into_inner();
+
+        let (tcow, plan) = import(
+            tcow_with(&[("other", b"o", 1)]),
+            || scan_zip_archive(Cursor::new(&zip)),
+            |plan, w| import_zip_archive(Cursor::new(&zip), plan, w),
+        );
+        assert!(plan.lossy.is_empty() && plan.skipped.is_empty(), "{plan:?}");
+        for (path, data, mtime) in files {
+            let (e, layer) = tcow.lookup(&vpath(path)).unwrap();
+            assert_eq!((&e.data[..], e.mtime, layer), (*data, *mtime, 1), "/{path}");
+        }
+    }
+
+    #[test]
+    fn cpio_round_trip_keeps_content_and_mtime() {
+        let files: &[(&str, &[u8], u64)] = &[("a.txt", b"alpha", 1_600_000_001), ("d/e/b.bin", b"", 1_700_000_000)];
+        let src = TcowFile::from_storage(Cursor::new(tcow_with(files))).unwrap();
+        let mut cpio = Vec::new();
+        write_union_cpio(&src, 0, &mut cpio).unwrap();
+
+        let (tcow, plan) = import(
+            tcow_with(&[("other", b"o", 1)]),
+            || scan_cpio_archive(&cpio[..]),
+            |plan, w| import_cpio_archive(&cpio[..], plan, w),
+        );
+        assert_eq!(plan.files(), 2);
+        for (path, data, mtime) in files {
+            let (e, _) = tcow.lookup(&vpath(path)).unwrap();
+            assert_eq!((&e.data[..], e.mtime), (*data, *mtime), "/{path}");
+        }
+    }
+
+    #[test]
+    fn truncated_cpio_is_an_error() {
+        let src = TcowFile::from_storage(Cursor::new(tcow_with(&[("a.txt", b"alpha", 1)]))).unwrap();
+        let mut cpio = Vec::new();
+        write_union_cpio(&src, 0, &mut cpio).unwrap();
+        // Cut inside the body of a.txt
+        let cut = cpio.windows(5).position(|w| w == b"alpha").unwrap() + 2;
+        assert!(scan_cpio_archive(&cpio[..cut]).is_err());
+    }
+}
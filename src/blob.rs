@@ -0,0 +1,226 @@
+//! Shared, read-only byte buffers for layer data.
+//!
+//! `TcowFile::open` loads the layer region of a `.tcow` file once, either by
+//! memory-mapping it or, when mapping is unavailable, with a single buffered
+//! read. Every parsed entry then borrows its content as a `Blob` slice of that
+//! region instead of owning a copy.
+
+use std::fmt;
+use std::io::SeekFrom;
+use std::ops::{Deref, Range};
+use std::sync::{Arc, OnceLock};
+
+use memmap2::Mmap;
+
+use crate::delta;
+use crate::error::{IoContext, Result, TcowError};
+use crate::storage::Storage;
+
+/// How `TcowFile::open_with` loads layer data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadMode {
+    /// Memory-map the file, falling back to a buffered read if mapping fails
+    /// or the storage is not a file.
+    #[default]
+    Auto,
+    /// Memory-map the file and fail if that is not possible.
+    Mmap,
+    /// Read the layer region into memory with ordinary reads.
+    Buffered,
+}
+
+impl ReadMode {
+    /// `Buffered` when `TCOW_NO_MMAP` is set to anything other than `0`,
+    /// otherwise `Auto`.
+    pub fn from_env() -> Self {
+        match std::env::var_os("TCOW_NO_MMAP") {
+            Some(v) if v != "0" => ReadMode::Buffered,
+            _ => ReadMode::Auto,
+        }
+    }
+}
+
+enum Backing {
+    Mapped(Mmap),
+    Heap(Vec<u8>),
+    /// Content rebuilt from a delta on first access.
+    Patched { base: Blob, patch: Blob, size: u64, content: OnceLock<Vec<u8>> },
+}
+
+impl Backing {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Backing::Mapped(m) => m,
+            Backing::Heap(v) => v,
+            Backing::Patched { base, patch, size, content } => {
+                content.get_or_init(|| delta::apply(base, patch, *size))
+            }
+        }
+    }
+}
+
+/// A cheaply clonable slice of a shared buffer. Derefs to `[u8]`.
+#[derive(Clone)]
+pub struct Blob {
+    buf: Arc<Backing>,
+    start: usize,
+    len: usize,
+}
+
+impl Blob {
+    /// Load bytes `0..len` of `f`. Only the layer region is loaded: the
+    /// trailer and footer after it are rewritten in place by appends, while
+    /// the layers themselves are never modified once written.
+    pub fn load<S: Storage + ?Sized>(f: &mut S, len: u64, mode: ReadMode) -> Result<Blob> {
+        Self::load_range(f, 0..len, mode)
+    }
+
+    /// Load bytes `range` of `f`, e.g. layers appended after the region
+    /// loaded by `load`.
+    pub fn load_range<S: Storage + ?Sized>(f: &mut S, range: Range<u64>, mode: ReadMode) -> Result<Blob> {
+        let len = usize::try_from(range.end.saturating_sub(range.start))
+            .map_err(|_| TcowError::Invalid("file too large for this platform".into()))?;
+        if len == 0 {
+            return Ok(Blob::from(Vec::new()));
+        }
+        let backing = match mode {
+            ReadMode::Buffered => Backing::Heap(read_range(f, range.start, len)?),
+            ReadMode::Mmap => Backing::Mapped(map_range(f, range.start, len)?),
+            ReadMode::Auto => match map_range(f, range.start, len) {
+                Ok(m) => Backing::Mapped(m),
+                Err(_) => Backing::Heap(read_range(f, range.start, len)?),
+            },
+        };
+        Ok(Blob { buf: Arc::new(backing), start: 0, len })
+    }
+
+    /// Content rebuilt from `base` and a delta `patch` the first time it is
+    /// read. The patch must already have passed `delta::validate`.
+    pub(crate) fn patched(base: Blob, patch: Blob, size: u64) -> Blob {
+        let content = OnceLock::new();
+        Blob { buf: Arc::new(Backing::Patched { base, patch, size, content }), start: 0, len: size as usize }
+    }
+
+    /// Length in bytes. Unlike the slice's `len`, this never forces a
+    /// delta to be rebuilt.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// True when the bytes come from a memory mapping.
+    pub fn is_mapped(&self) -> bool {
+        matches!(*self.buf, Backing::Mapped(_))
+    }
+
+    /// A sub-slice sharing the same buffer. Fails if `range` is out of bounds.
+    pub fn slice(&self, range: Range<u64>) -> Result<Blob> {
+        if range.start > range.end || range.end > self.len as u64 {
+            return Err(TcowError::corrupt(format!(
+                "byte range {}..{} is outside the {}-byte buffer",
+                range.start, range.end, self.len
+            )));
+        }
+        Ok(Blob {
+            buf: Arc::clone(&self.buf),
+            start: self.start + range.start as usize,
+            len: (range.end - range.start) as usize,
+        })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf.bytes()[self.start..self.start + self.len]
+    }
+}
+
+fn map_range<S: Storage + ?Sized>(f: &S, offset: u64, len: usize) -> Result<Mmap> {
+    match f.map(offset, len) {
+        Some(mapped) => mapped.io_context(|| "memory-mapping file".into()),
+        None => Err(TcowError::Invalid("this storage cannot be memory-mapped".into())),
+    }
+}
+
+fn read_range<S: Storage + ?Sized>(f: &mut S, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    f.seek(SeekFrom::Start(offset))?;
+    f.read_exact(&mut buf).io_context(|| "reading layer data".into())?;
+    Ok(buf)
+}
+
+/// The layer region of a file as loaded so far: the bytes read when it was
+/// opened, then one piece for each refresh that found layers appended after
+/// them. Loaded pieces are never reloaded, so picking up a new layer costs
+/// only that layer's bytes.
+pub(crate) struct LoadedRegion {
+    /// `(file offset, bytes)`, oldest first. A later piece may start inside
+    /// an earlier one, where the old trailer was overwritten.
+    pieces: Vec<(u64, Blob)>,
+}
+
+impl LoadedRegion {
+    pub fn new(first: Blob) -> Self {
+        LoadedRegion { pieces: vec![(0, first)] }
+    }
+
+    pub fn push(&mut self, offset: u64, piece: Blob) {
+        self.pieces.push((offset, piece));
+    }
+
+    /// Bytes `range` of the file, from the newest piece that holds all of it.
+    pub fn slice(&self, range: Range<u64>) -> Result<Blob> {
+        let piece = self.pieces.iter().rev().find(|(offset, piece)| {
+            range.start >= *offset && range.end <= offset + piece.len() as u64
+        });
+        match piece {
+            Some((offset, piece)) => piece.slice(range.start - offset..range.end - offset),
+            None => Err(TcowError::corrupt(format!(
+                "byte range {}..{} is outside the loaded layer data",
+                range.start, range.end
+            ))),
+        }
+    }
+
+    /// True when the region opened with the file is memory-mapped.
+    pub fn is_mapped(&self) -> bool {
+        self.pieces[0].1.is_mapped()
+    }
+}
+
+impl Deref for Blob {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for Blob {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl From<Vec<u8>> for Blob {
+    fn from(v: Vec<u8>) -> Self {
+        let len = v.len();
+        Blob { buf: Arc::new(Backing::Heap(v)), start: 0, len }
+    }
+}
+
+impl Default for Blob {
+    fn default() -> Self {
+        Blob::from(Vec::new())
+    }
+}
+
+impl fmt::Debug for Blob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Blob")
+            .field("len", &self.len)
+            .field("mapped", &self.is_mapped())
+            .finish()
+    }
+}
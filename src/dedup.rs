@@ -0,0 +1,224 @@
+//! Content-addressed deduplication of file bodies.
+//!
+//! In dedup mode a file whose content already exists in an earlier layer is
+//! stored as a *reference entry*: an empty regular file whose PAX extended
+//! header names the SHA-256 of the content and where the stored copy lives.
+//! Readers resolve references while parsing, so everything above the parser
+//! (union view, `cat`, `export`, `compact`) sees the materialized content.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use crate::error::{Result, TcowError};
+use crate::{RawEntry, VPath};
+
+/// PAX key holding `sha256:<hex>` of the referenced content.
+pub const REF_DIGEST_KEY: &str = "TCOW.ref.digest";
+/// PAX key holding the index of the layer that stores the content.
+pub const REF_LAYER_KEY: &str = "TCOW.ref.layer";
+/// PAX key holding the canonical path of the stored entry in that layer.
+pub const REF_PATH_KEY: &str = "TCOW.ref.path";
+/// PAX key holding the content length in bytes.
+pub const REF_SIZE_KEY: &str = "TCOW.ref.size";
+
+/// Files smaller than this are always stored inline: a reference costs two
+/// tar headers of its own.
+pub const DEDUP_MIN_SIZE: usize = 1024;
+
+/// Where a deduplicated file's content is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobRef {
+    /// Hex SHA-256 of the content (without the `sha256:` prefix).
+    pub digest: String,
+    pub layer: usize,
+    /// Canonical path of the stored entry inside `layer`.
+    pub path: VPath,
+    pub size: u64,
+}
+
+/// Content digest → stored copy, for every inline file in a set of layers.
+/// The lowest layer wins, so references always point at the oldest copy.
+/// Only files in layers without recorded digests are hashed.
+pub fn content_index(layers: &[HashMap<VPath, RawEntry>]) -> HashMap<String, BlobRef> {
+    let mut index = HashMap::new();
+    for (layer, entries) in layers.iter().enumerate() {
+        for (path, entry) in entries {
+            if entry.is_whiteout || entry.is_dir || entry.reference.is_some() {
+                continue;
+            }
+            if entry.data.len() < DEDUP_MIN_SIZE {
+                continue;
+            }
+            let digest = entry.sha256();
+            index.entry(digest.clone()).or_insert_with(|| BlobRef {
+                digest,
+                layer,
+                path: path.clone(),
+                size: entry.data.len() as u64,
+            });
+        }
+    }
+    index
+}
+
+/// Append a reference entry for `path` to a layer being built.
+pub fn append_ref_entry<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    blob: &BlobRef,
+    mtime: u64,
+) -> std::io::Result<()> {
+    let digest = format!("sha256:{}", blob.digest);
+    let layer = blob.layer.to_string();
+    let size = blob.size.to_string();
+    builder.append_pax_extensions([
+        (REF_DIGEST_KEY, digest.as_bytes()),
+        (REF_LAYER_KEY, layer.as_bytes()),
+        (REF_PATH_KEY, blob.path.as_bytes()),
+        (REF_SIZE_KEY, size.as_bytes()),
+    ])?;
+    let mut hdr = tar::Header::new_ustar();
+    hdr.set_path(path)?;
+    hdr.set_size(0);
+    hdr.set_mtime(mtime);
+    hdr.set_mode(0o644);
+    hdr.set_cksum();
+    builder.append(&hdr, std::io::empty())?;
+    Ok(())
+}
+
+/// The reference carried by a tar entry's PAX header, if it is one.
+pub(crate) fn read_ref_entry<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Option<BlobRef>> {
+    let Some(exts) = entry.pax_extensions()? else { return Ok(None) };
+    let (mut digest, mut layer, mut path, mut size) = (None, None, None, None);
+    for ext in exts {
+        let ext = ext?;
+        let value = || ext.value().map_err(|_| TcowError::corrupt(format!("non-UTF-8 value for {:?}", ext.key())));
+        match ext.key() {
+            Ok(REF_DIGEST_KEY) => digest = Some(value()?.to_string()),
+            Ok(REF_LAYER_KEY) => layer = Some(parse_field(REF_LAYER_KEY, value()?)?),
+            Ok(REF_PATH_KEY) => path = Some(VPath::from_entry_name(value()?.as_bytes())?),
+            Ok(REF_SIZE_KEY) => size = Some(parse_field(REF_SIZE_KEY, value()?)?),
+            _ => {}
+        }
+    }
+    let Some(digest) = digest else { return Ok(None) };
+    let (Some(layer), Some(path), Some(size)) = (layer, path, size) else {
+        return Err(TcowError::corrupt(format!("incomplete reference entry for {digest}")));
+    };
+    let digest = digest
+        .strip_prefix("sha256:")
+        .ok_or_else(|| TcowError::corrupt(format!("unsupported reference digest {digest}")))?
+        .to_string();
+    Ok(Some(BlobRef { digest, layer, path, size }))
+}
+
+/// Parse the numeric value of PAX field `key`.
+pub(crate) fn parse_field<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| TcowError::corrupt(format!("invalid {key} value {value:?}")))
+}
+
+/// Point each reference entry of the layer being opened at the content it
+/// names in `lower`, the layers beneath it. The target must have the size
+/// the reference records and, when its layer records per-file digests, the
+/// same digest. Targets in layers without recorded digests are not hashed
+/// here; `verify` catches those mismatches.
+pub(crate) fn resolve_refs(
+    entries: &mut HashMap<VPath, RawEntry>,
+    lower: &[HashMap<VPath, RawEntry>],
+) -> Result<()> {
+    for (path, entry) in entries.iter_mut() {
+        let Some(blob) = &entry.reference else { continue };
+        let target = lower
+            .get(blob.layer)
+            .and_then(|l| l.get(&blob.path))
+            .filter(|t| !t.is_whiteout && !t.is_dir)
+            .ok_or_else(|| {
+                TcowError::corrupt(format!(
+                    "/{path} references /{} in layer {}, which does not exist",
+                    blob.path, blob.layer
+                ))
+            })?;
+        if target.data.len() as u64 != blob.size {
+            return Err(TcowError::corrupt(format!(
+                "/{path} references {} bytes but /{} in layer {} has {}",
+                blob.size,
+                blob.path,
+                blob.layer,
+                target.data.len()
+            )));
+        }
+        if let Some(digest) = target.digest.as_ref().filter(|d| **d != blob.digest) {
+            return Err(TcowError::corrupt(format!(
+                "/{path} references sha256:{} but /{} in layer {} has sha256:{digest}",
+                blob.digest, blob.path, blob.layer
+            )));
+        }
+        entry.data = target.data.clone();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{parse_tar_layer, sha256_hex, AppendOptions, TcowFile};
+
+    fn vpath(p: &str) -> VPath {
+        VPath::new(p).unwrap()
+    }
+
+    fn blob(byte: u8) -> Vec<u8> {
+        vec![byte; DEDUP_MIN_SIZE * 16]
+    }
+
+    #[test]
+    fn references_round_trip_through_read_export_and_compact() {
+        let base = [(vpath("a.bin"), blob(b'a')), (vpath("small"), b"tiny".to_vec())];
+        let mut tcow = TcowFile::create_in(Cursor::new(Vec::new()), &base, &[], None).unwrap();
+        let copies = [(vpath("copy/a.bin"), blob(b'a')), (vpath("copy/small"), b"tiny".to_vec())];
+        let report = tcow.append_with(&copies, &[], AppendOptions { dedup: true, delta: false }).unwrap();
+        assert_eq!(report.deduped, 1, "files under DEDUP_MIN_SIZE stay inline");
+
+        let tcow = TcowFile::from_storage(tcow.into_storage()).unwrap();
+        assert!(!tcow.layer_is_self_contained(1));
+        let (entry, layer) = tcow.lookup(&vpath("copy/a.bin")).unwrap();
+        assert_eq!((layer, &entry.data[..]), (1, &blob(b'a')[..]));
+        let reference = entry.reference.as_ref().unwrap();
+        assert_eq!((reference.layer, reference.path.as_str()), (0, "a.bin"));
+        assert!(tcow.layer_bytes(1).unwrap().len() < blob(b'a').len(), "the content is stored once");
+
+        // Exporting the layer on its own writes the content inline
+        let mut layer = Vec::new();
+        tcow.write_layer_tar(1, &mut layer).unwrap();
+        let exported = parse_tar_layer(&layer).unwrap();
+        assert_eq!(&exported[&vpath("copy/a.bin")].data[..], &blob(b'a')[..]);
+        assert!(exported[&vpath("copy/a.bin")].reference.is_none());
+
+        // So does compacting the union view into a single layer
+        let mut files: Vec<_> = tcow.union_view().into_iter().map(|(p, e)| (p, e.data.to_vec())).collect();
+        files.sort();
+        let compacted = TcowFile::create_in(Cursor::new(Vec::new()), &files, &[], None).unwrap();
+        assert!(compacted.layer_is_self_contained(0));
+        assert_eq!(&compacted.lookup(&vpath("copy/a.bin")).unwrap().0.data[..], &blob(b'a')[..]);
+        assert_eq!(compacted.union_view().len(), 4);
+    }
+
+    #[test]
+    fn a_reference_to_other_content_of_the_same_size_is_refused() {
+        let base = [(vpath("a.bin"), blob(b'a')), (vpath("b.bin"), blob(b'b'))];
+        let mut tcow = TcowFile::create_in(Cursor::new(Vec::new()), &base, &[], None).unwrap();
+        // Claims a.bin's content but points at b.bin
+        let size = blob(b'b').len() as u64;
+        let wrong = BlobRef { digest: sha256_hex(&blob(b'a')), layer: 0, path: vpath("b.bin"), size };
+        let mut builder = tar::Builder::new(Vec::new());
+        append_ref_entry(&mut builder, "c.bin", &wrong, 0).unwrap();
+        // The layer is written, but reading it back fails
+        tcow.append_raw(&builder.into_inner().unwrap()).unwrap_err();
+
+        let err = TcowFile::from_storage(tcow.into_storage()).err().unwrap();
+        assert!(err.to_string().contains("references sha256:"), "{err}");
+    }
+}
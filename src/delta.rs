@@ -0,0 +1,405 @@
+//! Chunked binary deltas for large files that change slightly.
+//!
+//! In delta mode a new version of a large file is stored as a *delta entry*:
+//! a patch against the version visible below it. Both versions are split
+//! with content-defined chunking, so an append or a local edit only changes
+//! the chunks around it; every chunk that also occurs in the base becomes a
+//! copy instruction and the rest is stored literally.
+//!
+//! The patch is a sequence of operations, each starting with a tag byte:
+//!
+//! | Tag | Operands | Meaning |
+//! |---|---|---|
+//! | `0x01` | offset: u64 LE, len: u64 LE | copy `len` bytes of the base from `offset` |
+//! | `0x02` | len: u64 LE, then `len` bytes | insert literal bytes |
+//!
+//! Readers reconstruct the content on first access. Chains of deltas are
+//! capped at `MAX_DELTA_DEPTH` so a read never replays more than that many
+//! patches.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use crate::dedup::parse_field;
+use crate::error::{Result, TcowError};
+use crate::{Blob, RawEntry, VPath};
+
+/// PAX key holding the index of the layer with the base version.
+pub const DELTA_BASE_LAYER_KEY: &str = "TCOW.delta.base.layer";
+/// PAX key holding the canonical path of the base version in that layer.
+pub const DELTA_BASE_PATH_KEY: &str = "TCOW.delta.base.path";
+/// PAX key holding the reconstructed size in bytes.
+pub const DELTA_SIZE_KEY: &str = "TCOW.delta.size";
+/// PAX key holding `sha256:<hex>` of the reconstructed content.
+pub const DELTA_DIGEST_KEY: &str = "TCOW.delta.digest";
+/// PAX key holding the chain depth: 1 for a delta against an inline file.
+pub const DELTA_DEPTH_KEY: &str = "TCOW.delta.depth";
+
+/// Longest delta chain a writer creates or a reader accepts.
+pub const MAX_DELTA_DEPTH: u32 = 8;
+/// Files smaller than this are always stored inline.
+pub const DELTA_MIN_SIZE: usize = 64 * 1024;
+
+const OP_COPY: u8 = 0x01;
+const OP_DATA: u8 = 0x02;
+
+const MIN_CHUNK: usize = 2 * 1024;
+const MAX_CHUNK: usize = 64 * 1024;
+/// 13 bits → 8 KiB average chunks.
+const CHUNK_MASK: u64 = (1 << 13) - 1;
+
+/// The base a delta entry is a patch against, and what it reconstructs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaRef {
+    pub base_layer: usize,
+    /// Canonical path of the base entry inside `base_layer`.
+    pub base_path: VPath,
+    pub size: u64,
+    /// Hex SHA-256 of the reconstructed content (without the `sha256:` prefix).
+    pub digest: String,
+    pub depth: u32,
+}
+
+// ── Chunking ──────────────────────────────────────────────────────────────────
+
+/// Gear hash table: 256 pseudo-random words from splitmix64.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Split `data` into content-defined chunks, returned as `(start, end)`.
+fn chunks(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut out = Vec::with_capacity(data.len() / 8192 + 1);
+    let (mut start, mut hash) = (0, 0u64);
+    for (i, byte) in data.iter().enumerate() {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let len = i + 1 - start;
+        if (len >= MIN_CHUNK && hash & CHUNK_MASK == 0) || len >= MAX_CHUNK {
+            out.push((start, i + 1));
+            start = i + 1;
+            hash = 0;
+        }
+    }
+    if start < data.len() {
+        out.push((start, data.len()));
+    }
+    out
+}
+
+// ── Encoding ──────────────────────────────────────────────────────────────────
+
+/// Encode `target` as a patch against `base`.
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut known: HashMap<&[u8], usize> = HashMap::new();
+    for (start, end) in chunks(base) {
+        known.entry(&base[start..end]).or_insert(start);
+    }
+
+    let mut patch = Vec::new();
+    let mut copy: Option<(usize, usize)> = None;
+    let mut literal: Option<(usize, usize)> = None;
+    for (start, end) in chunks(target) {
+        match known.get(&target[start..end]) {
+            Some(&offset) => {
+                flush_literal(&mut patch, target, literal.take());
+                copy = match copy {
+                    Some((o, l)) if o + l == offset => Some((o, l + end - start)),
+                    prev => {
+                        flush_copy(&mut patch, prev);
+                        Some((offset, end - start))
+                    }
+                };
+            }
+            None => {
+                flush_copy(&mut patch, copy.take());
+                literal = Some(literal.map_or((start, end), |(s, _)| (s, end)));
+            }
+        }
+    }
+    flush_copy(&mut patch, copy);
+    flush_literal(&mut patch, target, literal);
+    patch
+}
+
+fn flush_copy(patch: &mut Vec<u8>, op: Option<(usize, usize)>) {
+    if let Some((offset, len)) = op {
+        patch.push(OP_COPY);
+        patch.extend_from_slice(&(offset as u64).to_le_bytes());
+        patch.extend_from_slice(&(len as u64).to_le_bytes());
+    }
+}
+
+fn flush_literal(patch: &mut Vec<u8>, target: &[u8], op: Option<(usize, usize)>) {
+    if let Some((start, end)) = op {
+        patch.push(OP_DATA);
+        patch.extend_from_slice(&((end - start) as u64).to_le_bytes());
+        patch.extend_from_slice(&target[start..end]);
+    }
+}
+
+// ── Decoding ──────────────────────────────────────────────────────────────────
+
+enum Op<'a> {
+    Copy(u64, u64),
+    Data(&'a [u8]),
+}
+
+fn ops(patch: &[u8]) -> impl Iterator<Item = Result<Op<'_>>> {
+    let mut rest = patch;
+    std::iter::from_fn(move || {
+        let (&tag, tail) = rest.split_first()?;
+        let word = |b: &[u8], at: usize| -> Option<u64> {
+            Some(u64::from_le_bytes(b.get(at..at + 8)?.try_into().ok()?))
+        };
+        let op = match tag {
+            OP_COPY => match (word(tail, 0), word(tail, 8)) {
+                (Some(offset), Some(len)) => {
+                    rest = &tail[16..];
+                    Ok(Op::Copy(offset, len))
+                }
+                _ => Err(TcowError::corrupt("truncated copy operation in delta")),
+            },
+            OP_DATA => match word(tail, 0)
+                .and_then(|len| usize::try_from(len).ok()?.checked_add(8))
+                .and_then(|end| tail.get(8..end))
+            {
+                Some(bytes) => {
+                    rest = &tail[8 + bytes.len()..];
+                    Ok(Op::Data(bytes))
+                }
+                None => Err(TcowError::corrupt("truncated literal in delta")),
+            },
+            other => Err(TcowError::corrupt(format!("unknown delta operation 0x{other:02x}"))),
+        };
+        if op.is_err() {
+            rest = &[];
+        }
+        Some(op)
+    })
+}
+
+/// Check that `patch` is well formed and rebuilds exactly `size` bytes from a
+/// base of `base_len` bytes, so `apply` cannot fail later.
+pub fn validate(patch: &[u8], base_len: u64, size: u64) -> Result<()> {
+    let mut total = 0u64;
+    for op in ops(patch) {
+        total += match op? {
+            Op::Copy(offset, len) => {
+                if offset.checked_add(len).is_none_or(|end| end > base_len) {
+                    return Err(TcowError::corrupt(format!(
+                        "delta copies {offset}+{len} from a {base_len}-byte base"
+                    )));
+                }
+                len
+            }
+            Op::Data(bytes) => bytes.len() as u64,
+        };
+    }
+    if total != size {
+        return Err(TcowError::corrupt(format!("delta rebuilds {total} bytes, expected {size}")));
+    }
+    Ok(())
+}
+
+/// Rebuild content from `base` and a patch already checked by `validate`.
+pub fn apply(base: &[u8], patch: &[u8], size: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(size as usize);
+    for op in ops(patch).map_while(Result::ok) {
+        match op {
+            Op::Copy(offset, len) => {
+                out.extend_from_slice(&base[offset as usize..(offset + len) as usize])
+            }
+            Op::Data(bytes) => out.extend_from_slice(bytes),
+        }
+    }
+    out
+}
+
+// ── Tar entries ───────────────────────────────────────────────────────────────
+
+/// Append a delta entry for `path` whose body is `patch`.
+pub fn append_delta_entry<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    delta: &DeltaRef,
+    patch: &[u8],
+    mtime: u64,
+) -> std::io::Result<()> {
+    let layer = delta.base_layer.to_string();
+    let size = delta.size.to_string();
+    let digest = format!("sha256:{}", delta.digest);
+    let depth = delta.depth.to_string();
+    builder.append_pax_extensions([
+        (DELTA_BASE_LAYER_KEY, layer.as_bytes()),
+        (DELTA_BASE_PATH_KEY, delta.base_path.as_bytes()),
+        (DELTA_SIZE_KEY, size.as_bytes()),
+        (DELTA_DIGEST_KEY, digest.as_bytes()),
+        (DELTA_DEPTH_KEY, depth.as_bytes()),
+    ])?;
+    let mut hdr = tar::Header::new_ustar();
+    hdr.set_path(path)?;
+    hdr.set_size(patch.len() as u64);
+    hdr.set_mtime(mtime);
+    hdr.set_mode(0o644);
+    hdr.set_cksum();
+    builder.append(&hdr, patch)?;
+    Ok(())
+}
+
+/// The delta described by a tar entry's PAX header, if it is one.
+pub(crate) fn read_delta_entry<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Option<DeltaRef>> {
+    let Some(exts) = entry.pax_extensions()? else { return Ok(None) };
+    let (mut layer, mut path, mut size, mut digest, mut depth) = (None, None, None, None, None);
+    for ext in exts {
+        let ext = ext?;
+        let value = || ext.value().map_err(|_| TcowError::corrupt(format!("non-UTF-8 value for {:?}", ext.key())));
+        match ext.key() {
+            Ok(DELTA_BASE_LAYER_KEY) => layer = Some(parse_field(DELTA_BASE_LAYER_KEY, value()?)?),
+            Ok(DELTA_BASE_PATH_KEY) => path = Some(VPath::from_entry_name(value()?.as_bytes())?),
+            Ok(DELTA_SIZE_KEY) => size = Some(parse_field(DELTA_SIZE_KEY, value()?)?),
+            Ok(DELTA_DIGEST_KEY) => digest = Some(value()?.to_string()),
+            Ok(DELTA_DEPTH_KEY) => depth = Some(parse_field(DELTA_DEPTH_KEY, value()?)?),
+            _ => {}
+        }
+    }
+    let Some(base_layer) = layer else { return Ok(None) };
+    let (Some(base_path), Some(size), Some(digest), Some(depth)) = (path, size, digest, depth) else {
+        return Err(TcowError::corrupt(format!("incomplete delta entry against layer {base_layer}")));
+    };
+    let digest = digest
+        .strip_prefix("sha256:")
+        .ok_or_else(|| TcowError::corrupt(format!("unsupported delta digest {digest}")))?
+        .to_string();
+    Ok(Some(DeltaRef { base_layer, base_path, size, digest, depth }))
+}
+
+/// Chain depth of `entry`: 0 unless it is itself a delta.
+pub fn depth_of(entry: &RawEntry) -> u32 {
+    entry.delta.as_ref().map_or(0, |d| d.depth)
+}
+
+/// Swap each delta entry's patch for a lazily rebuilt view of its content,
+/// based on the entry it names in `lower`, the layers beneath it.
+pub(crate) fn resolve_deltas(
+    entries: &mut HashMap<VPath, RawEntry>,
+    lower: &[HashMap<VPath, RawEntry>],
+) -> Result<()> {
+    for (path, entry) in entries.iter_mut() {
+        let Some(delta) = &entry.delta else { continue };
+        let base = lower
+            .get(delta.base_layer)
+            .and_then(|l| l.get(&delta.base_path))
+            .filter(|b| !b.is_whiteout && !b.is_dir)
+            .ok_or_else(|| {
+                TcowError::corrupt(format!(
+                    "/{path} is a delta against /{} in layer {}, which does not exist",
+                    delta.base_path, delta.base_layer
+                ))
+            })?;
+        let depth = depth_of(base) + 1;
+        if depth > MAX_DELTA_DEPTH || depth != delta.depth {
+            return Err(TcowError::corrupt(format!(
+                "/{path}: delta chain depth {depth} is invalid (limit {MAX_DELTA_DEPTH})"
+            )));
+        }
+        validate(&entry.data, base.data.len() as u64, delta.size)
+            .map_err(|e| TcowError::corrupt(format!("invalid delta for /{path}: {e}")))?;
+        entry.data = Blob::patched(base.data.clone(), entry.data.clone(), delta.size);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic incompressible bytes.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn roundtrip(base: &[u8], target: &[u8]) -> Vec<u8> {
+        let patch = encode(base, target);
+        validate(&patch, base.len() as u64, target.len() as u64).unwrap();
+        assert_eq!(apply(base, &patch, target.len() as u64), target);
+        patch
+    }
+
+    #[test]
+    fn small_edits_become_small_patches() {
+        let base = noise(256 * 1024, 1);
+
+        let mut appended = base.clone();
+        appended.extend_from_slice(b"one more line\n");
+        assert!(roundtrip(&base, &appended).len() < 32 * 1024);
+
+        let mut edited = base.clone();
+        edited[100_000..100_010].copy_from_slice(b"0123456789");
+        assert!(roundtrip(&base, &edited).len() < 32 * 1024);
+
+        let mut cut = base.clone();
+        cut.drain(50_000..60_000);
+        assert!(roundtrip(&base, &cut).len() < 32 * 1024);
+    }
+
+    #[test]
+    fn unrelated_and_empty_content_roundtrips() {
+        roundtrip(&noise(100_000, 2), &noise(90_000, 3));
+        roundtrip(&noise(10_000, 4), &[]);
+        roundtrip(&[], &noise(10_000, 5));
+        assert!(encode(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn validate_rejects_copies_outside_the_base() {
+        let mut patch = vec![OP_COPY];
+        patch.extend_from_slice(&90u64.to_le_bytes());
+        patch.extend_from_slice(&20u64.to_le_bytes());
+        assert!(validate(&patch, 100, 20).is_err());
+        assert!(validate(&patch, 110, 20).is_ok());
+
+        let mut overflow = vec![OP_COPY];
+        overflow.extend_from_slice(&u64::MAX.to_le_bytes());
+        overflow.extend_from_slice(&2u64.to_le_bytes());
+        assert!(validate(&overflow, 100, 2).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_malformed_patches() {
+        // Wrong reconstructed size
+        let mut literal = vec![OP_DATA];
+        literal.extend_from_slice(&3u64.to_le_bytes());
+        literal.extend_from_slice(b"abc");
+        assert!(validate(&literal, 0, 3).is_ok());
+        assert!(validate(&literal, 0, 4).is_err());
+
+        // Literal longer than what follows, and lengths that overflow
+        assert!(validate(&literal[..10], 0, 3).is_err());
+        let mut huge = vec![OP_DATA];
+        huge.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(validate(&huge, 0, 0).is_err());
+
+        // Truncated copy and unknown tag
+        assert!(validate(&[OP_COPY, 0, 0, 0], 10, 0).is_err());
+        assert!(validate(&[0x7f], 10, 0).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+//! The library's error type.
+//!
+//! Core operations on a `.tcow` file — opening, reading, appending, writing
+//! layers — fail with a `TcowError`, so callers can tell a file that is not
+//! a `.tcow` at all from one with a damaged trailer, a missing path or a
+//! plain I/O failure without matching on messages. The archive, OCI, verify
+//! and fsck modules build on these and report through `anyhow`; a
+//! `TcowError` stays reachable from those with `downcast_ref`.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::{PathError, VPath};
+
+/// `Result` with `TcowError` as the default error.
+pub type Result<T, E = TcowError> = std::result::Result<T, E>;
+
+/// Why a `.tcow` operation failed.
+#[derive(Debug)]
+pub enum TcowError {
+    /// An I/O operation failed; `context` says which. The `io::Error` is
+    /// the error's `source`.
+    Io { context: String, source: io::Error },
+    /// The file does not start with the `TCOW` magic bytes. The path is
+    /// empty for storage that was not opened from a path.
+    BadMagic(PathBuf),
+    /// The header names a format version this build cannot read.
+    UnsupportedVersion(u16),
+    /// The header sets required flags for features this build does not
+    /// support.
+    UnsupportedFlags(u16),
+    /// The file is too short for a footer, the footer magic is wrong, or the
+    /// footer points at a trailer outside the file.
+    TruncatedFooter(String),
+    /// The CBOR trailer cannot be decoded or encoded.
+    Cbor(String),
+    /// A layer's bytes no longer hash to the digest in its record.
+    DigestMismatch { layer: usize, expected: String, actual: String },
+    /// An entry name or path argument is not a safe canonical path.
+    UnsafePath { layer: Option<usize>, reason: PathError },
+    /// No visible file at `path`, or none in `layer` when one was named.
+    NotFound { path: VPath, layer: Option<usize> },
+    /// A layer index past the last layer.
+    NoSuchLayer { index: usize, count: usize },
+    /// A layer's tar stream, or a reference or delta entry in it, is malformed.
+    CorruptLayer { layer: Option<usize>, message: String },
+    /// The operation does not make sense for this file or these arguments.
+    Invalid(String),
+}
+
+impl TcowError {
+    pub(crate) fn corrupt(message: impl Into<String>) -> Self {
+        TcowError::CorruptLayer { layer: None, message: message.into() }
+    }
+
+    /// Attach the index of the layer being loaded to errors about its
+    /// contents.
+    pub(crate) fn in_layer(self, idx: usize) -> Self {
+        match self {
+            TcowError::CorruptLayer { layer: None, message } => TcowError::CorruptLayer { layer: Some(idx), message },
+            TcowError::UnsafePath { layer: None, reason } => TcowError::UnsafePath { layer: Some(idx), reason },
+            other => other,
+        }
+    }
+}
+
+fn layer_prefix(f: &mut fmt::Formatter<'_>, layer: &Option<usize>) -> fmt::Result {
+    match layer {
+        Some(i) => write!(f, "layer {i}: "),
+        None => Ok(()),
+    }
+}
+
+impl fmt::Display for TcowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcowError::Io { context, .. } => f.write_str(context),
+            TcowError::BadMagic(path) if path.as_os_str().is_empty() => {
+                f.write_str("not a .tcow file: bad magic bytes")
+            }
+            TcowError::BadMagic(path) => write!(f, "{path:?} is not a .tcow file: bad magic bytes"),
+            TcowError::UnsupportedVersion(v) => write!(f, "unsupported TCOW version {v}"),
+            TcowError::UnsupportedFlags(flags) => write!(
+                f,
+                "file needs features this build does not support: {}",
+                crate::flag_names(*flags).join(", ")
+            ),
+            TcowError::TruncatedFooter(why) => write!(f, "{why} — file may be truncated or corrupt"),
+            TcowError::Cbor(e) => write!(f, "invalid CBOR trailer: {e}"),
+            TcowError::DigestMismatch { layer, expected, actual } => {
+                write!(f, "layer {layer} digest mismatch: expected {expected}, found {actual}")
+            }
+            TcowError::UnsafePath { layer, reason } => {
+                layer_prefix(f, layer)?;
+                write!(f, "{reason}")
+            }
+            TcowError::NotFound { path, layer: None } => write!(f, "/{path} not found in virtual filesystem"),
+            TcowError::NotFound { path, layer: Some(i) } => write!(f, "/{path} not found in layer {i}"),
+            TcowError::NoSuchLayer { index, count } => {
+                write!(f, "layer {index} does not exist (file has {count} layers)")
+            }
+            TcowError::CorruptLayer { layer, message } => {
+                layer_prefix(f, layer)?;
+                write!(f, "{message}")
+            }
+            TcowError::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for TcowError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TcowError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TcowError {
+    fn from(source: io::Error) -> Self {
+        TcowError::Io { context: "I/O error".into(), source }
+    }
+}
+
+impl From<PathError> for TcowError {
+    fn from(reason: PathError) -> Self {
+        TcowError::UnsafePath { layer: None, reason }
+    }
+}
+
+/// `with_context` for I/O results inside the crate.
+pub(crate) trait IoContext<T> {
+    fn io_context(self, context: impl FnOnce() -> String) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn io_context(self, context: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|source| TcowError::Io { context: context(), source })
+    }
+}
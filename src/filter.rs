@@ -0,0 +1,152 @@
+//! Glob and regex filters on virtual paths, for `ls` and `WalkOptions`.
+//!
+//! A glob without a `/` matches the last path component, so `*.json` finds
+//! JSON files at any depth; one with a `/` matches the whole path from the
+//! root. `*` and `?` stop at `/`, `**` crosses it, and `[...]` is a
+//! character class (`[!...]` negates). A regex is searched for anywhere in
+//! the path written with its leading `/`.
+
+use regex::Regex;
+
+use crate::error::{Result, TcowError};
+use crate::VPath;
+
+/// A compiled path pattern.
+#[derive(Debug, Clone)]
+pub enum PathFilter {
+    Glob { pattern: String, regex: Regex, whole_path: bool },
+    Regex(Regex),
+}
+
+impl PathFilter {
+    pub fn glob(pattern: &str) -> Result<Self> {
+        let trimmed = pattern.trim_start_matches('/');
+        let whole_path = pattern.contains('/');
+        let regex = Regex::new(&glob_to_regex(trimmed))
+            .map_err(|e| TcowError::Invalid(format!("invalid glob {pattern:?}: {e}")))?;
+        Ok(PathFilter::Glob { pattern: pattern.to_string(), regex, whole_path })
+    }
+
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Regex::new(pattern)
+            .map(PathFilter::Regex)
+            .map_err(|e| TcowError::Invalid(format!("invalid regex {pattern:?}: {e}")))
+    }
+
+    pub fn matches(&self, path: &VPath) -> bool {
+        match self {
+            PathFilter::Glob { regex, whole_path: true, .. } => regex.is_match(path),
+            PathFilter::Glob { regex, .. } => path.file_name().is_some_and(|name| regex.is_match(name)),
+            PathFilter::Regex(regex) => regex.is_match(&format!("/{path}")),
+        }
+    }
+}
+
+/// Translate a glob into an anchored regex. An unclosed `[` is literal.
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut out = String::from("^");
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                i += 1;
+                if chars.get(i + 1) == Some(&'/') {
+                    // `**/` also matches no directories at all
+                    i += 1;
+                    out.push_str("(?:.*/)?");
+                } else {
+                    out.push_str(".*");
+                }
+            }
+            '*' => out.push_str("[^/]*"),
+            '?' => out.push_str("[^/]"),
+            '[' => {
+                let negate = chars.get(i + 1) == Some(&'!');
+                let start = i + 1 + usize::from(negate);
+                // A `]` right after the `[` or `[!` is part of the class
+                match chars.get(start + 1..).and_then(|rest| rest.iter().position(|&c| c == ']')) {
+                    Some(n) => {
+                        let end = start + 1 + n;
+                        out.push('[');
+                        if negate {
+                            out.push('^');
+                        }
+                        for &c in &chars[start..end] {
+                            if matches!(c, '\\' | '[' | ']' | '&' | '~' | '^') {
+                                out.push('\\');
+                            }
+                            out.push(c);
+                        }
+                        out.push(']');
+                        i = end;
+                    }
+                    None => out.push_str("\\["),
+                }
+            }
+            c => out.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+        i += 1;
+    }
+    out.push('$');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, path: &str) -> bool {
+        PathFilter::glob(pattern).unwrap().matches(&VPath::new(path).unwrap())
+    }
+
+    #[test]
+    fn translates_wildcards() {
+        assert_eq!(glob_to_regex("*.json"), "^[^/]*\\.json$");
+        assert_eq!(glob_to_regex("a?c"), "^a[^/]c$");
+        assert_eq!(glob_to_regex("**/x"), "^(?:.*/)?x$");
+        assert_eq!(glob_to_regex("a/**"), "^a/.*$");
+    }
+
+    #[test]
+    fn escapes_regex_metacharacters() {
+        assert_eq!(glob_to_regex("a.b+(c)"), "^a\\.b\\+\\(c\\)$");
+        assert!(glob("f(1).txt", "dir/f(1).txt"));
+        assert!(!glob("f.txt", "fxtxt"));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert_eq!(glob_to_regex("[abc]"), "^[abc]$");
+        assert_eq!(glob_to_regex("[!abc]"), "^[^abc]$");
+        assert_eq!(glob_to_regex("[]a]"), "^[\\]a]$");
+        assert_eq!(glob_to_regex("[!]a]"), "^[^\\]a]$");
+        assert_eq!(glob_to_regex("[^a]"), "^[\\^a]$");
+        assert!(glob("log[0-9].txt", "log7.txt"));
+        assert!(!glob("log[!0-9].txt", "log7.txt"));
+    }
+
+    #[test]
+    fn unclosed_bracket_is_literal() {
+        assert_eq!(glob_to_regex("a["), "^a\\[$");
+        assert_eq!(glob_to_regex("[]"), "^\\[\\]$");
+        assert!(glob("a[b", "a[b"));
+    }
+
+    #[test]
+    fn slash_selects_whole_path_matching() {
+        assert!(glob("*.json", "deep/down/x.json"));
+        assert!(!glob("/*.json", "deep/x.json"));
+        assert!(glob("/*.json", "x.json"));
+        assert!(glob("deep/**/*.json", "deep/x.json"));
+        assert!(glob("deep/**/*.json", "deep/a/b/x.json"));
+        assert!(!glob("deep/*.json", "deep/a/x.json"));
+    }
+
+    #[test]
+    fn regex_sees_the_leading_slash() {
+        let f = PathFilter::regex("^/logs/.*\\.log$").unwrap();
+        assert!(f.matches(&VPath::new("logs/a/b.log").unwrap()));
+        assert!(!f.matches(&VPath::new("x/logs/b.log").unwrap()));
+    }
+}
@@ -0,0 +1,358 @@
+//! Entry search behind `tcow find`.
+//!
+//! A `Query` is a set of predicates (name, size, mtime, layer, kind and
+//! content hash) that must all hold. `find` applies it to the union view,
+//! directories included, or to every entry stored in every layer, hidden
+//! copies and whiteouts included. Content hashes are only computed for
+//! entries that pass every other predicate.
+
+use chrono::{DateTime, NaiveDate};
+
+use crate::error::{Result, TcowError};
+use crate::filter::PathFilter;
+use crate::vfs::{Vfs, WalkOptions};
+use crate::{RawEntry, TcowFile, VPath};
+
+/// What an entry is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Whiteout,
+}
+
+/// An inclusive range; an unset end is open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bounds<T> {
+    pub min: Option<T>,
+    pub max: Option<T>,
+}
+
+impl<T> Default for Bounds<T> {
+    fn default() -> Self {
+        Bounds { min: None, max: None }
+    }
+}
+
+impl<T: PartialOrd + Copy> Bounds<T> {
+    /// Parse `A..B`, `A..`, `..B` or a single value `A` (meaning `A..A`),
+    /// reading each end with `value`.
+    pub fn parse(s: &str, value: impl Fn(&str) -> Result<T>) -> Result<Self> {
+        let end = |part: &str| match part.trim() {
+            "" => Ok(None),
+            v => value(v).map(Some),
+        };
+        let bounds = match s.split_once("..") {
+            Some((lo, hi)) => Bounds { min: end(lo)?, max: end(hi)? },
+            None => {
+                let v = value(s.trim())?;
+                Bounds { min: Some(v), max: Some(v) }
+            }
+        };
+        if let (Some(lo), Some(hi)) = (bounds.min, bounds.max) {
+            if lo > hi {
+                return Err(TcowError::Invalid(format!("empty range {s:?}: the start is after the end")));
+            }
+        }
+        Ok(bounds)
+    }
+
+    pub fn contains(&self, v: T) -> bool {
+        self.min.is_none_or(|lo| v >= lo) && self.max.is_none_or(|hi| v <= hi)
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.min.is_none() && self.max.is_none()
+    }
+}
+
+/// A byte count, optionally with a binary suffix: `512`, `4k`, `1.5M`, `2G`.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let invalid = || TcowError::Invalid(format!("invalid size {s:?}: expected a number with an optional k, M or G suffix"));
+    let (num, unit) = match s.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let scale: u64 = match unit.to_ascii_lowercase().trim_end_matches("ib").trim_end_matches('b') {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    if let Ok(n) = num.parse::<u64>() {
+        return n.checked_mul(scale).ok_or_else(invalid);
+    }
+    let n: f64 = num.parse().map_err(|_| invalid())?;
+    if !n.is_finite() || n < 0.0 {
+        return Err(invalid());
+    }
+    Ok((n * scale as f64) as u64)
+}
+
+/// A point in time as Unix seconds: RFC 3339 (`2026-03-01T12:00:00Z`), a
+/// UTC date (`2026-03-01`, meaning midnight) or plain Unix seconds.
+pub fn parse_time(s: &str) -> Result<u64> {
+    let ts = if let Ok(n) = s.parse::<u64>() {
+        Some(n as i64)
+    } else if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        Some(dt.timestamp())
+    } else {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .ok()
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .map(|dt| dt.and_utc().timestamp())
+    };
+    ts.and_then(|t| u64::try_from(t).ok()).ok_or_else(|| {
+        TcowError::Invalid(format!("invalid time {s:?}: expected RFC 3339, YYYY-MM-DD or Unix seconds"))
+    })
+}
+
+/// Predicates an entry must all satisfy. The default matches everything.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pub name: Option<PathFilter>,
+    pub size: Bounds<u64>,
+    /// Implied directories have no mtime or layer and never match a range
+    /// on either.
+    pub mtime: Bounds<u64>,
+    pub layer: Bounds<usize>,
+    pub kind: Option<EntryKind>,
+    /// Case-insensitive prefix of the hex SHA-256 of a file's content.
+    pub sha256: Option<String>,
+}
+
+/// One entry that matched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Found {
+    pub path: VPath,
+    pub kind: EntryKind,
+    pub size: u64,
+    /// Unix seconds; 0 for implied directories.
+    pub mtime: u64,
+    /// Layer the entry is stored in; `None` for implied directories.
+    pub layer: Option<usize>,
+    /// False for an entry that a higher layer hides or replaces, or a
+    /// whiteout a higher layer has undone. Only `all_layers` searches
+    /// return those.
+    pub visible: bool,
+}
+
+impl Query {
+    /// Every predicate except the content hash.
+    fn admits(&self, f: &Found) -> bool {
+        let implied = f.layer.is_none();
+        self.kind.is_none_or(|k| k == f.kind)
+            && self.size.contains(f.size)
+            && (self.mtime.is_unbounded() || !implied && self.mtime.contains(f.mtime))
+            && (self.layer.is_unbounded() || f.layer.is_some_and(|l| self.layer.contains(l)))
+    }
+
+    fn hash_matches(&self, entry: Option<&RawEntry>) -> bool {
+        let Some(prefix) = &self.sha256 else { return true };
+        entry.is_some_and(|e| e.sha256().starts_with(&prefix.to_ascii_lowercase()))
+    }
+}
+
+/// Entries at or beneath `root` matching `query`. The union view is
+/// searched in path order and fails if `root` is not in it; `all_layers`
+/// searches layer by layer, bottom first, then in path order.
+pub fn find<S>(tcow: &TcowFile<S>, root: &VPath, query: &Query, all_layers: bool) -> Result<Vec<Found>> {
+    let mut out = Vec::new();
+    if all_layers {
+        for (layer_idx, entries) in tcow.layers.iter().enumerate() {
+            let mut paths: Vec<&VPath> = entries
+                .keys()
+                .filter(|p| p.is_within(root) && query.name.as_ref().is_none_or(|f| f.matches(p)))
+                .collect();
+            paths.sort();
+            for path in paths {
+                let entry = &entries[path];
+                let kind = if entry.is_whiteout {
+                    EntryKind::Whiteout
+                } else if entry.is_dir {
+                    EntryKind::Dir
+                } else {
+                    EntryKind::File
+                };
+                let visible = match kind {
+                    EntryKind::File => tcow.visible_layer(path) == Some(layer_idx),
+                    EntryKind::Dir => tcow.view().exists(path),
+                    // Still in effect unless a higher layer brought the file back
+                    EntryKind::Whiteout => tcow.visible_layer(path).is_none(),
+                };
+                let found = Found {
+                    path: path.clone(),
+                    kind,
+                    size: entry.data.len() as u64,
+                    mtime: entry.mtime,
+                    layer: Some(layer_idx),
+                    visible,
+                };
+                let file = (kind == EntryKind::File).then_some(entry);
+                if query.admits(&found) && query.hash_matches(file) {
+                    out.push(found);
+                }
+            }
+        }
+        return Ok(out);
+    }
+
+    let opts = WalkOptions { dirs: true, filter: query.name.clone(), ..WalkOptions::default() };
+    for entry in tcow.view().walk_with(root, &opts)? {
+        let meta = entry.metadata;
+        let kind = if meta.is_dir { EntryKind::Dir } else { EntryKind::File };
+        let found = Found { path: entry.path, kind, size: meta.size, mtime: meta.mtime, layer: meta.layer, visible: true };
+        if !query.admits(&found) {
+            continue;
+        }
+        let file = match kind {
+            EntryKind::File => tcow.lookup(&found.path).map(|(e, _)| e),
+            _ => None,
+        };
+        if query.hash_matches(file) {
+            out.push(found);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::sha256_hex;
+
+    fn vpath(p: &str) -> VPath {
+        VPath::new(p).unwrap()
+    }
+
+    #[test]
+    fn bounds_parse_open_closed_and_single_values() {
+        let parse = |s| Bounds::parse(s, |v| v.parse::<u64>().map_err(|_| TcowError::Invalid(v.into())));
+        let bounds = |min, max| Bounds { min, max };
+        assert_eq!(parse("1..5").unwrap(), bounds(Some(1), Some(5)));
+        assert_eq!(parse(" 2 .. 4 ").unwrap(), bounds(Some(2), Some(4)));
+        assert_eq!(parse("3..").unwrap(), bounds(Some(3), None));
+        assert_eq!(parse("..5").unwrap(), bounds(None, Some(5)));
+        assert_eq!(parse("7").unwrap(), bounds(Some(7), Some(7)));
+        assert!(parse("..").unwrap().is_unbounded());
+        for bad in ["5..1", "x", "1..x", ""] {
+            assert!(parse(bad).is_err(), "{bad:?}");
+        }
+
+        let range = parse("2..4").unwrap();
+        assert_eq!([1, 2, 4, 5].map(|v| range.contains(v)), [false, true, true, false]);
+        assert!(parse("..").unwrap().contains(u64::MAX));
+    }
+
+    #[test]
+    fn sizes_take_binary_suffixes_and_fractions() {
+        let cases = [
+            ("512", 512),
+            ("4k", 4 << 10),
+            ("4K", 4 << 10),
+            ("1KiB", 1 << 10),
+            ("3kb", 3 << 10),
+            ("1.5M", 3 << 19),
+            ("10MB", 10 << 20),
+            ("2G", 2 << 30),
+            ("0.5k", 512),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(parse_size(s).unwrap(), expected, "{s:?}");
+        }
+        for bad in ["", "k", "1.5X", "-1", "1e400", "99999999999G", "1 M"] {
+            assert!(parse_size(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn times_are_rfc3339_dates_or_unix_seconds() {
+        const MARCH_1: u64 = 1_772_323_200;
+        assert_eq!(parse_time("1700000000").unwrap(), 1_700_000_000);
+        assert_eq!(parse_time("2026-03-01").unwrap(), MARCH_1);
+        assert_eq!(parse_time("2026-03-01T12:00:00Z").unwrap(), MARCH_1 + 12 * 3600);
+        assert_eq!(parse_time("2026-03-01T12:00:00+02:00").unwrap(), MARCH_1 + 10 * 3600);
+        for bad in ["yesterday", "2026-13-01", "1969-12-31", "-5"] {
+            assert!(parse_time(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    /// `a` rewritten in layer 1, `d/x` whited out in layer 1 and brought
+    /// back in layer 2, `gone` whited out for good.
+    fn layered() -> TcowFile<Cursor<Vec<u8>>> {
+        let files = |f: &[(&str, &str)]| -> Vec<_> {
+            f.iter().map(|(p, d)| (vpath(p), d.as_bytes().to_vec())).collect()
+        };
+        let base = files(&[("a", "one"), ("d/x", "x"), ("gone", "g")]);
+        let mut tcow = TcowFile::create_in(Cursor::new(Vec::new()), &base, &[], None).unwrap();
+        tcow.append(&files(&[("a", "two")]), &[vpath("d/x"), vpath("gone")]).unwrap();
+        tcow.append(&files(&[("d/x", "x2")]), &[]).unwrap();
+        tcow
+    }
+
+    fn found(tcow: &TcowFile<Cursor<Vec<u8>>>, query: &Query, all_layers: bool) -> Vec<String> {
+        find(tcow, &VPath::root(), query, all_layers)
+            .unwrap()
+            .iter()
+            .map(|f| {
+                let layer = f.layer.map_or("-".to_string(), |l| l.to_string());
+                let hidden = if f.visible { "" } else { " (hidden)" };
+                format!("{layer} {:?} /{}{hidden}", f.kind, f.path)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn all_layers_reports_hidden_copies_and_whiteouts() {
+        let tcow = layered();
+        let all = found(&tcow, &Query::default(), true);
+        assert_eq!(
+            all,
+            [
+                "0 File /a (hidden)",
+                "0 File /d/x (hidden)",
+                "0 File /gone (hidden)",
+                "1 File /a",
+                "1 Whiteout /d/x (hidden)",
+                "1 Whiteout /gone",
+                "2 File /d/x",
+            ]
+        );
+        // The union view has the implied directory and no whiteouts
+        assert_eq!(found(&tcow, &Query::default(), false), ["1 File /a", "- Dir /d", "2 File /d/x"]);
+
+        let layer = Bounds::parse("1", |v| parse_size(v).map(|n| n as usize)).unwrap();
+        let layer_1 = Query { layer, ..Query::default() };
+        assert_eq!(found(&tcow, &layer_1, false), ["1 File /a"]);
+        assert_eq!(found(&tcow, &layer_1, true), ["1 File /a", "1 Whiteout /d/x (hidden)", "1 Whiteout /gone"]);
+        // Implied directories have no mtime, so any mtime range leaves them out
+        let since = Query { mtime: Bounds { min: Some(0), max: None }, ..Query::default() };
+        assert_eq!(found(&tcow, &since, false), ["1 File /a", "2 File /d/x"]);
+
+        let name = Some(PathFilter::glob("x").unwrap());
+        let whiteouts = Query { kind: Some(EntryKind::Whiteout), name, ..Query::default() };
+        assert_eq!(found(&tcow, &whiteouts, true), ["1 Whiteout /d/x (hidden)"]);
+        let sized = Query { size: Bounds::parse("2..", parse_size).unwrap(), ..Query::default() };
+        assert_eq!(found(&tcow, &sized, true), ["0 File /a (hidden)", "1 File /a", "2 File /d/x"]);
+
+        assert!(find(&tcow, &vpath("gone"), &Query::default(), false).is_err());
+        assert_eq!(find(&tcow, &vpath("d"), &Query::default(), true).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn hash_prefixes_match_file_content_in_any_case() {
+        let tcow = layered();
+        let digest = sha256_hex(b"one");
+        let by_hash = |prefix: &str| Query { sha256: Some(prefix.to_string()), ..Query::default() };
+        assert_eq!(found(&tcow, &by_hash(&digest[..8].to_ascii_uppercase()), true), ["0 File /a (hidden)"]);
+        assert_eq!(found(&tcow, &by_hash(&digest), false), Vec::<String>::new());
+        let two = sha256_hex(b"two");
+        assert_eq!(found(&tcow, &by_hash(&two[..6]), false), ["1 File /a"]);
+        // An empty prefix matches every file, but never a directory or whiteout
+        assert_eq!(found(&tcow, &by_hash(""), true).len(), 5);
+        assert_eq!(found(&tcow, &by_hash(""), false), ["1 File /a", "2 File /d/x"]);
+    }
+}
@@ -0,0 +1,450 @@
+//! Structural consistency checks behind `tcow fsck`.
+//!
+//! `verify` only proves that each layer still hashes to its digest; it takes
+//! the trailer's offsets and sizes on trust. `fsck` checks the container
+//! itself: header against index, footer against file length, layer extents
+//! against each other, and every layer's tar framing. When the file loads,
+//! it then looks for entries that are legal but suspicious.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    from_whiteout_tar_path, opaque_whiteout_dir, Blob, LayerKind, ReadMode, TcowFile, TcowIndex, UnionTree,
+    VPath, FileHeader, FLAGS_KNOWN, FLAG_HAS_BASE, FLAG_HAS_ENTRIES, FOOTER_SIZE, HEADER_SIZE, MAGIC, MAGIC_TAIL,
+};
+
+const BLOCK: u64 = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The file is damaged or violates the format.
+    Error,
+    /// The file is readable but contains something no tcow writer produces.
+    Warning,
+}
+
+/// One problem found by `fsck`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub layer: Option<usize>,
+    /// Virtual path, with a leading `/`, when the problem is about an entry.
+    pub path: Option<String>,
+    pub message: String,
+}
+
+/// Everything `fsck` found, in the order it was found.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FsckReport {
+    pub layers: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl FsckReport {
+    pub fn errors(&self) -> usize {
+        self.findings.iter().filter(|f| f.severity == Severity::Error).count()
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.findings.iter().filter(|f| f.severity == Severity::Warning).count()
+    }
+
+    fn error(&mut self, layer: Option<usize>, message: impl Into<String>) {
+        self.push(Severity::Error, layer, None, message.into());
+    }
+
+    fn warning(&mut self, layer: Option<usize>, message: impl Into<String>) {
+        self.push(Severity::Warning, layer, None, message.into());
+    }
+
+    fn push(&mut self, severity: Severity, layer: Option<usize>, path: Option<String>, message: String) {
+        self.findings.push(Finding { severity, layer, path, message });
+    }
+}
+
+/// Check the `.tcow` file at `path`. Only failing to open the file at all
+/// is an `Err`; everything else is reported as a finding.
+pub fn fsck(path: &Path) -> Result<FsckReport> {
+    let mut f = File::open(path).with_context(|| format!("cannot open {:?}", path))?;
+    let mut report = FsckReport::default();
+    let Some((header, index, trailer_offset)) = check_container(&mut f, &mut report)? else {
+        return Ok(report);
+    };
+    report.layers = index.layers.len();
+
+    let extents_ok = check_extents(&index, trailer_offset, &mut report);
+    let region = Blob::load(&mut f, trailer_offset, ReadMode::Auto)?;
+    let mut counts = Vec::with_capacity(index.layers.len());
+    for (i, rec) in index.layers.iter().enumerate() {
+        let Ok(layer) = region.slice(rec.offset..rec.offset.saturating_add(rec.size)) else {
+            counts.push(None);
+            continue;
+        };
+        counts.push(check_tar(i, &layer, &mut report));
+    }
+    let tars_ok = counts.iter().all(Option::is_some);
+
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if index.version != version {
+        report.error(None, format!("header says format version {version} but the index says {}", index.version));
+    }
+    let flags = u16::from_le_bytes([header[6], header[7]]);
+    // FLAG_HAS_BASE describes the base layer; v2's FLAG_HAS_ENTRIES covers every layer
+    let base_populated = counts.first().copied().flatten().map(|n| n > 0);
+    let any_populated = (version >= 2 && tars_ok).then(|| counts.iter().any(|n| n.is_some_and(|n| n > 0)));
+    let checks = [
+        (base_populated, FLAG_HAS_BASE, "FLAG_HAS_BASE", Some(0), "the base layer is"),
+        (any_populated, FLAG_HAS_ENTRIES, "FLAG_HAS_ENTRIES", None, "the layers are"),
+    ];
+    for (populated, flag, name, layer, what) in checks {
+        let Some(populated) = populated else { continue };
+        let set = flags & flag != 0;
+        if set != populated {
+            let (said, is) = if set { ("set", "empty") } else { ("clear", "not empty") };
+            report.error(layer, format!("header {name} is {said} but {what} {is}"));
+        }
+    }
+    if flags & !FLAGS_KNOWN != 0 {
+        report.warning(None, format!("unknown optional header flags {:#06x}", flags & !FLAGS_KNOWN));
+    }
+    if header[8..16].iter().any(|&b| b != 0) {
+        report.warning(None, "reserved header bytes 8..16 are not zero");
+    }
+
+    // Semantic checks need the layers parsed and stacked
+    if extents_ok && tars_ok {
+        match TcowFile::open_with(path, ReadMode::Auto) {
+            Ok(tcow) => check_whiteouts(&tcow, &mut report),
+            Err(e) => report.error(None, format!("layers cannot be loaded: {e:#}")),
+        }
+    }
+    Ok(report)
+}
+
+/// Header magic and version, footer, and trailer. Returns the raw header,
+/// the decoded index and the trailer offset, or `None` once a problem makes
+/// the rest of the file impossible to locate.
+fn check_container(f: &mut File, report: &mut FsckReport) -> Result<Option<([u8; 16], TcowIndex, u64)>> {
+    let file_len = f.seek(SeekFrom::End(0))?;
+    if file_len < HEADER_SIZE + FOOTER_SIZE {
+        report.error(None, format!("file is {file_len} bytes, too small for a header and footer"));
+        return Ok(None);
+    }
+    let mut header = [0u8; 16];
+    f.seek(SeekFrom::Start(0))?;
+    f.read_exact(&mut header)?;
+    if &header[0..4] != MAGIC {
+        report.error(None, "bad header magic: not a .tcow file");
+        return Ok(None);
+    }
+    if let Err(e) = FileHeader::parse(&header, Path::new("")) {
+        report.error(None, e.to_string());
+        return Ok(None);
+    }
+
+    let mut footer = [0u8; 16];
+    f.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+    f.read_exact(&mut footer)?;
+    if &footer[12..16] != MAGIC_TAIL {
+        report.error(None, "bad footer magic: file is truncated or has bytes after the footer");
+        return Ok(None);
+    }
+    let trailer_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+    let trailer_len = u32::from_le_bytes(footer[8..12].try_into().unwrap()) as u64;
+    let footer_start = file_len - FOOTER_SIZE;
+    if trailer_offset < HEADER_SIZE || trailer_offset.saturating_add(trailer_len) > footer_start {
+        report.error(None, format!("trailer at offset {trailer_offset} ({trailer_len} bytes) is outside the file"));
+        return Ok(None);
+    }
+    if trailer_offset + trailer_len != footer_start {
+        let gap = footer_start - trailer_offset - trailer_len;
+        report.error(None, format!("{gap} bytes between the trailer and the footer"));
+    }
+
+    let mut cbor = vec![0u8; trailer_len as usize];
+    f.seek(SeekFrom::Start(trailer_offset))?;
+    f.read_exact(&mut cbor)?;
+    match ciborium::from_reader::<TcowIndex, _>(Cursor::new(&cbor)) {
+        Ok(index) => Ok(Some((header, index, trailer_offset))),
+        Err(e) => {
+            report.error(None, format!("invalid CBOR trailer: {e}"));
+            Ok(None)
+        }
+    }
+}
+
+/// Layers must tile the file from the header to the trailer in index order.
+/// Returns false when any layer lies outside that range.
+fn check_extents(index: &TcowIndex, trailer_offset: u64, report: &mut FsckReport) -> bool {
+    let mut ok = true;
+    let mut expected = HEADER_SIZE;
+    for (i, rec) in index.layers.iter().enumerate() {
+        let end = rec.offset.saturating_add(rec.size);
+        if rec.offset < expected {
+            let what = if i == 0 { "the header".to_string() } else { format!("layer {}", i - 1) };
+            report.error(Some(i), format!("overlaps {what} by {} bytes", expected - rec.offset));
+        } else if rec.offset > expected {
+            report.error(Some(i), format!("{} unused bytes before this layer", rec.offset - expected));
+        }
+        if end > trailer_offset {
+            report.error(Some(i), format!("extends {} bytes into the trailer", end - trailer_offset));
+            ok = false;
+        }
+        let want = LayerKind::for_index(i);
+        if rec.kind != want {
+            report.warning(Some(i), format!("kind is {}, expected {want}", rec.kind));
+        }
+        expected = expected.max(end);
+    }
+    if expected < trailer_offset {
+        let slack = trailer_offset - expected;
+        report.warning(None, format!("{slack} unused bytes between the last layer and the trailer"));
+    }
+    ok
+}
+
+/// Walk one layer's tar stream: every header must parse, the stream must
+/// end with two zero blocks, and paths must be safe and appear once.
+/// Returns the number of entries, or `None` if the stream is malformed.
+fn check_tar(layer_idx: usize, layer: &[u8], report: &mut FsckReport) -> Option<usize> {
+    let i = Some(layer_idx);
+    let len = layer.len() as u64;
+    if !len.is_multiple_of(BLOCK) {
+        report.error(i, format!("size {len} is not a multiple of {BLOCK} bytes"));
+    }
+
+    let mut archive = tar::Archive::new(Cursor::new(layer));
+    let mut seen: HashMap<VPath, usize> = HashMap::new();
+    let (mut count, mut end) = (0usize, 0u64);
+    let entries = match archive.entries_with_seek() {
+        Ok(entries) => entries,
+        Err(e) => {
+            report.error(i, format!("malformed tar: {e}"));
+            return None;
+        }
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                report.error(i, format!("malformed tar after {count} entries: {e}"));
+                return None;
+            }
+        };
+        count += 1;
+        end = entry.raw_file_position() + entry.size().div_ceil(BLOCK) * BLOCK;
+        let raw = entry.path_bytes();
+        let path = match VPath::from_entry_name(&raw) {
+            Ok(path) => path,
+            Err(e) => {
+                let shown = String::from_utf8_lossy(&raw).into_owned();
+                report.push(Severity::Error, i, Some(shown), format!("unsafe path: {e}"));
+                continue;
+            }
+        };
+        if path.is_root() || opaque_whiteout_dir(&path).is_some() || entry.header().entry_type().is_dir() {
+            continue;
+        }
+        *seen.entry(from_whiteout_tar_path(&path).unwrap_or(path)).or_default() += 1;
+    }
+
+    let mut dups: Vec<(VPath, usize)> = seen.into_iter().filter(|(_, n)| *n > 1).collect();
+    dups.sort();
+    for (p, n) in dups {
+        report.push(Severity::Warning, i, Some(format!("/{p}")), format!("appears {n} times in this layer"));
+    }
+
+    let rest = &layer[(end.min(len)) as usize..];
+    if rest.len() < 2 * BLOCK as usize || rest[..2 * BLOCK as usize].iter().any(|&b| b != 0) {
+        report.error(i, "tar stream does not end with two zero blocks");
+    } else if let Some(pos) = rest.iter().skip(2 * BLOCK as usize).position(|&b| b != 0) {
+        let extra = rest.len() - 2 * BLOCK as usize - pos;
+        report.warning(i, format!("{extra} bytes of data after the end-of-archive blocks"));
+    }
+    Some(count)
+}
+
+/// Whiteouts that hide nothing in the layers beneath them.
+fn check_whiteouts(tcow: &TcowFile, report: &mut FsckReport) {
+    let mut tree = UnionTree::default();
+    for (i, layer) in tcow.layers.iter().enumerate() {
+        let mut idle: Vec<&VPath> = layer
+            .iter()
+            .filter(|(p, e)| e.is_whiteout && !tree.contains(p))
+            .map(|(p, _)| p)
+            .collect();
+        idle.sort();
+        for p in idle {
+            report.push(Severity::Warning, Some(i), Some(format!("/{p}")), "whiteout hides nothing".into());
+        }
+        tree.apply_layer(i, layer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_tar_layer, encode_cbor, write_trailer_footer, LayerRecord, FORMAT_VERSION};
+
+    const FLAGS: u16 = FLAG_HAS_BASE | FLAG_HAS_ENTRIES;
+
+    fn layer(files: &[&str], whiteouts: &[&str]) -> Vec<u8> {
+        let files: Vec<_> = files.iter().map(|p| (VPath::new(p).unwrap(), b"data".to_vec())).collect();
+        let whiteouts: Vec<_> = whiteouts.iter().map(|p| VPath::new(p).unwrap()).collect();
+        build_tar_layer(&files, &whiteouts).unwrap()
+    }
+
+    /// A layer written by something other than tcow, with entry names
+    /// stored exactly as given.
+    fn raw_layer(names: &[&[u8]]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for name in names {
+            let mut hdr = tar::Header::new_old();
+            hdr.as_old_mut().name[..name.len()].copy_from_slice(name);
+            hdr.set_size(4);
+            hdr.set_mode(0o644);
+            hdr.set_cksum();
+            builder.append(&hdr, &b"data"[..]).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// A `.tcow` file holding `region` after the header, with layers at the
+    /// given `(offset, size)` extents and the trailer right after `region`.
+    fn tcow(flags: u16, region: &[u8], extents: &[(u64, u64)]) -> Vec<u8> {
+        let layers = extents
+            .iter()
+            .enumerate()
+            .map(|(i, &(offset, size))| LayerRecord {
+                offset,
+                size,
+                kind: LayerKind::for_index(i),
+                digest: None,
+                created_at: "2026-01-01T00:00:00Z".into(),
+                file_digests: Default::default(),
+                extensions: Default::default(),
+            })
+            .collect();
+        let index = TcowIndex {
+            version: FORMAT_VERSION,
+            layers,
+            last_modified: "2026-01-01T00:00:00Z".into(),
+            label: None,
+            extensions: Default::default(),
+        };
+        let mut file = FileHeader { version: FORMAT_VERSION, flags, reserved: [0; 8] }.to_bytes().to_vec();
+        file.extend_from_slice(region);
+        let cbor = encode_cbor(&index).unwrap();
+        file.extend_from_slice(&cbor);
+        write_trailer_footer(&mut file, HEADER_SIZE + region.len() as u64, cbor.len() as u32).unwrap();
+        file
+    }
+
+    /// Layers stored back to back, each where the index says.
+    fn stacked(flags: u16, layers: &[&[u8]]) -> Vec<u8> {
+        let mut offset = HEADER_SIZE;
+        let extents: Vec<_> = layers
+            .iter()
+            .map(|l| {
+                offset += l.len() as u64;
+                (offset - l.len() as u64, l.len() as u64)
+            })
+            .collect();
+        tcow(flags, &layers.concat(), &extents)
+    }
+
+    /// `(severity, layer, path, message)` of a finding.
+    type Row = (Severity, Option<usize>, Option<String>, String);
+
+    /// Every finding for `file`.
+    fn check(file: &[u8]) -> Vec<Row> {
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(tmp.path(), file).unwrap();
+        let report = fsck(tmp.path()).unwrap();
+        report.findings.into_iter().map(|f| (f.severity, f.layer, f.path, f.message)).collect()
+    }
+
+    fn finding(severity: Severity, layer: Option<usize>, path: Option<&str>, message: &str) -> Row {
+        (severity, layer, path.map(String::from), message.to_string())
+    }
+
+    #[test]
+    fn a_well_formed_file_has_no_findings() {
+        let (l0, l1) = (layer(&["a.txt"], &[]), layer(&["b.txt"], &["a.txt"]));
+        assert_eq!(check(&stacked(FLAGS, &[&l0, &l1])), vec![]);
+    }
+
+    #[test]
+    fn gaps_and_overlaps_between_layers() {
+        let (l0, l1) = (layer(&["a.txt"], &[]), layer(&["b.txt"], &[]));
+        let (n0, n1) = (l0.len() as u64, l1.len() as u64);
+
+        let gap = tcow(FLAGS, &[&l0[..], &[0; 512], &l1].concat(), &[(HEADER_SIZE, n0), (HEADER_SIZE + n0 + 512, n1)]);
+        assert_eq!(check(&gap), [finding(Severity::Error, Some(1), None, "512 unused bytes before this layer")]);
+
+        // Layer 0 claims the first block of layer 1
+        let overlap = tcow(FLAGS, &[&l0[..], &l1].concat(), &[(HEADER_SIZE, n0 + 512), (HEADER_SIZE + n0, n1)]);
+        let findings = check(&overlap);
+        let want = finding(Severity::Error, Some(1), None, "overlaps layer 0 by 512 bytes");
+        assert!(findings.contains(&want), "{findings:?}");
+    }
+
+    #[test]
+    fn slack_before_the_trailer_is_a_warning() {
+        let l0 = layer(&["a.txt"], &[]);
+        let file = tcow(FLAGS, &[&l0[..], &[0; 1024]].concat(), &[(HEADER_SIZE, l0.len() as u64)]);
+        let want = "1024 unused bytes between the last layer and the trailer";
+        assert_eq!(check(&file), [finding(Severity::Warning, None, None, want)]);
+    }
+
+    #[test]
+    fn a_layer_without_end_of_archive_blocks() {
+        let l0 = layer(&["a.txt"], &[]);
+        let truncated = &l0[..l0.len() - 1024];
+        let want = "tar stream does not end with two zero blocks";
+        assert_eq!(check(&stacked(FLAGS, &[truncated])), [finding(Severity::Error, Some(0), None, want)]);
+    }
+
+    #[test]
+    fn duplicate_and_unsafe_paths() {
+        let dup = raw_layer(&[b"a.txt", b"./a.txt"]);
+        let want = finding(Severity::Warning, Some(0), Some("/a.txt"), "appears 2 times in this layer");
+        assert_eq!(check(&stacked(FLAGS, &[&dup])), [want]);
+
+        let findings = check(&stacked(FLAGS, &[&raw_layer(&[b"ok.txt", b"../escape.sh"])]));
+        let unsafe_path = findings.iter().find(|f| f.2.as_deref() == Some("../escape.sh")).expect("reported");
+        assert_eq!((unsafe_path.0, unsafe_path.1), (Severity::Error, Some(0)));
+        assert!(unsafe_path.3.starts_with("unsafe path: "), "{}", unsafe_path.3);
+    }
+
+    #[test]
+    fn a_whiteout_that_hides_nothing() {
+        let (l0, l1) = (layer(&["a.txt"], &[]), layer(&[], &["a.txt", "never.txt"]));
+        let want = finding(Severity::Warning, Some(1), Some("/never.txt"), "whiteout hides nothing");
+        assert_eq!(check(&stacked(FLAGS, &[&l0, &l1])), [want]);
+    }
+
+    #[test]
+    fn flags_that_disagree_with_the_layers() {
+        let (empty, full) = (layer(&[], &[]), layer(&["a.txt"], &[]));
+        let base = "header FLAG_HAS_BASE is clear but the base layer is not empty";
+        let entries = "header FLAG_HAS_ENTRIES is clear but the layers are not empty";
+        assert_eq!(
+            check(&stacked(0, &[&full])),
+            [finding(Severity::Error, Some(0), None, base), finding(Severity::Error, None, None, entries)]
+        );
+        assert_eq!(
+            check(&stacked(FLAGS, &[&empty, &full])),
+            [finding(Severity::Error, Some(0), None, "header FLAG_HAS_BASE is set but the base layer is empty")]
+        );
+        // HAS_ENTRIES without HAS_BASE is right when only a later layer holds entries
+        assert_eq!(check(&stacked(FLAG_HAS_ENTRIES, &[&empty, &full])), vec![]);
+    }
+}
@@ -0,0 +1,176 @@
+//! Content search behind `tcow grep`.
+//!
+//! Files are searched line by line with a byte regex, so content that is
+//! not UTF-8 can still match, several files at a time. By default only the
+//! visible copy of each file is searched; `history` searches every version
+//! stored in every layer, so a match in an overwritten or deleted file is
+//! still found and reported with its layer.
+
+use rayon::prelude::*;
+use regex::bytes::Regex;
+
+use crate::error::Result;
+use crate::filter::PathFilter;
+use crate::vfs::{Vfs, WalkOptions};
+use crate::{Blob, TcowFile, VPath};
+
+/// How many leading bytes are checked for a NUL when deciding whether a
+/// file is binary.
+const BINARY_PROBE: usize = 8192;
+
+/// Options for `grep`.
+#[derive(Debug, Clone, Default)]
+pub struct GrepOptions {
+    /// Lines to show before each matching line.
+    pub before: usize,
+    /// Lines to show after each matching line.
+    pub after: usize,
+    /// Only search files whose path matches.
+    pub filter: Option<PathFilter>,
+    /// Search files that look binary too. They are skipped by default.
+    pub binary: bool,
+    /// Search every stored version in every layer, not just the visible one.
+    pub history: bool,
+}
+
+/// One line of output: a match or context around one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    /// 1-based line number.
+    pub number: usize,
+    /// The line without its terminator.
+    pub text: Vec<u8>,
+    pub is_match: bool,
+}
+
+/// The matches in one stored copy of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMatches {
+    pub path: VPath,
+    pub layer: usize,
+    /// Matching lines and their context, in order. Line numbers jump where
+    /// two groups of context are not adjacent.
+    pub lines: Vec<Line>,
+}
+
+impl FileMatches {
+    pub fn match_count(&self) -> usize {
+        self.lines.iter().filter(|l| l.is_match).count()
+    }
+}
+
+/// Search the files at or beneath `root` for `regex`. Results come in path
+/// order; with `history`, layer by layer from the base up, then in path
+/// order. Files without a match are left out.
+pub fn grep<S: Sync>(tcow: &TcowFile<S>, root: &VPath, regex: &Regex, opts: &GrepOptions) -> Result<Vec<FileMatches>> {
+    let files: Vec<(VPath, usize, Blob)> = if opts.history {
+        let mut files = Vec::new();
+        for (layer_idx, entries) in tcow.layers.iter().enumerate() {
+            let mut stored: Vec<_> = entries
+                .iter()
+                .filter(|(p, e)| !e.is_dir && !e.is_whiteout && p.is_within(root))
+                .filter(|(p, _)| opts.filter.as_ref().is_none_or(|f| f.matches(p)))
+                .map(|(p, e)| (p.clone(), layer_idx, e.data.clone()))
+                .collect();
+            stored.sort_by(|a, b| a.0.cmp(&b.0));
+            files.extend(stored);
+        }
+        files
+    } else {
+        let view = tcow.view();
+        let walk = WalkOptions { filter: opts.filter.clone(), ..WalkOptions::default() };
+        view.walk_with(root, &walk)?
+            .into_iter()
+            .map(|e| {
+                let layer = e.metadata.layer.expect("files in a union view have a layer");
+                let data = view.read(&e.path)?;
+                Ok((e.path, layer, data))
+            })
+            .collect::<Result<_>>()?
+    };
+
+    Ok(files
+        .into_par_iter()
+        .filter_map(|(path, layer, data)| {
+            if !opts.binary && is_binary(&data) {
+                return None;
+            }
+            let lines = search(&data, regex, opts.before, opts.after);
+            (!lines.is_empty()).then_some(FileMatches { path, layer, lines })
+        })
+        .collect())
+}
+
+/// A NUL byte near the start marks a file as binary, as in `grep` and git.
+fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(BINARY_PROBE)].contains(&0)
+}
+
+fn search(data: &[u8], regex: &Regex, before: usize, after: usize) -> Vec<Line> {
+    let mut lines: Vec<&[u8]> = data.split(|&b| b == b'\n').collect();
+    if data.ends_with(b"\n") {
+        lines.pop();
+    }
+    let hits: Vec<usize> = (0..lines.len()).filter(|&i| regex.is_match(lines[i])).collect();
+
+    let mut out = Vec::new();
+    // Index of the first line not yet emitted, so context is never repeated
+    let mut next = 0;
+    for (n, &hit) in hits.iter().enumerate() {
+        let start = hit.saturating_sub(before).max(next);
+        let end = (hit + after).min(lines.len() - 1);
+        // Stop this group's trailing context where the next group begins
+        let end = hits.get(n + 1).map_or(end, |&h| end.min(h - 1));
+        for (i, text) in lines.iter().enumerate().take(end.max(hit) + 1).skip(start) {
+            out.push(Line { number: i + 1, text: text.to_vec(), is_match: i == hit });
+        }
+        next = end.max(hit) + 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `(line number, is_match)` for each emitted line.
+    fn lines(data: &str, pattern: &str, before: usize, after: usize) -> Vec<(usize, bool)> {
+        let regex = Regex::new(pattern).unwrap();
+        search(data.as_bytes(), &regex, before, after).iter().map(|l| (l.number, l.is_match)).collect()
+    }
+
+    const TEXT: &str = "a\nb\nhit1\nc\nd\ne\nf\nhit2\ng\n";
+
+    #[test]
+    fn matches_without_context() {
+        assert_eq!(lines(TEXT, "hit", 0, 0), [(3, true), (8, true)]);
+        assert_eq!(lines(TEXT, "nothing", 2, 2), []);
+    }
+
+    #[test]
+    fn context_around_separate_groups() {
+        assert_eq!(
+            lines(TEXT, "hit", 1, 1),
+            [(2, false), (3, true), (4, false), (7, false), (8, true), (9, false)]
+        );
+    }
+
+    #[test]
+    fn overlapping_context_is_not_repeated() {
+        // Trailing context of the first hit and leading context of the
+        // second cover lines 4-6 between them exactly once
+        assert_eq!(
+            lines(TEXT, "hit", 3, 3),
+            [(1, false), (2, false), (3, true), (4, false), (5, false), (6, false), (7, false), (8, true), (9, false)]
+        );
+        assert_eq!(lines("x\nx\nx\n", "x", 1, 1), [(1, true), (2, true), (3, true)]);
+    }
+
+    #[test]
+    fn context_is_clamped_to_the_file() {
+        assert_eq!(lines("hit\nb", "hit", 5, 5), [(1, true), (2, false)]);
+        // A trailing newline does not add an empty last line
+        assert_eq!(lines("a\nhit\n", "hit", 0, 5), [(2, true)]);
+        assert_eq!(lines("a\nhit\n\n", "hit", 0, 5), [(2, true), (3, false)]);
+    }
+}
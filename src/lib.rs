@@ -1,24 +1,189 @@
-use std::collections::{HashMap, HashSet};
-use std::fs::{File, OpenOptions};
-use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
-use anyhow::{anyhow, bail, Context, Result};
 use chrono::{TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+use error::IoContext;
+
+pub mod archive;
+pub mod blob;
+pub mod dedup;
+pub mod delta;
+pub mod error;
+pub mod filter;
+pub mod find;
+pub mod fsck;
+pub mod grep;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+pub mod oci;
+pub mod storage;
+pub mod tree;
+pub mod verify;
+pub mod vfs;
+pub mod vpath;
+pub mod writer;
+
+pub use blob::{Blob, ReadMode};
+use blob::LoadedRegion;
+pub use dedup::BlobRef;
+pub use delta::DeltaRef;
+pub use error::{Result, TcowError};
+pub use filter::PathFilter;
+pub use storage::{FileStorage, Region, Storage, StorageMut};
+pub use tree::UnionTree;
+pub use vfs::{HostDir, TcowView, Vfs, WalkOptions};
+pub use vpath::{PathError, VPath};
+pub use writer::LayerWriter;
+
 // ── File-format constants ─────────────────────────────────────────────────────
 
 pub const MAGIC: &[u8; 4] = b"TCOW";
 pub const MAGIC_TAIL: &[u8; 4] = b"W0CT";
-pub const FORMAT_VERSION: u16 = 1;
+/// Version new files are written as.
+pub const FORMAT_VERSION: u16 = 2;
+/// Oldest version this build still reads. Appends keep a file's version;
+/// `upgrade_format` moves it to `FORMAT_VERSION`.
+pub const MIN_FORMAT_VERSION: u16 = 1;
 pub const HEADER_SIZE: u64 = 16;
 pub const FOOTER_SIZE: u64 = 16;
+
+// Header flags. Bits 0-7 are optional capabilities: a reader that does not
+// know one can still read the file. Bits 8-15 are required: a reader must
+// refuse a file with one it does not support.
+
+/// Optional. The base layer holds entries.
 pub const FLAG_HAS_BASE: u16 = 0x0001;
+/// Optional. The trailer carries an index of every entry's location.
+pub const FLAG_ENTRY_INDEX: u16 = 0x0002;
+/// Optional. A signature covers the trailer.
+pub const FLAG_SIGNED: u16 = 0x0004;
+/// Optional, v2 only. Some layer holds entries.
+pub const FLAG_HAS_ENTRIES: u16 = 0x0008;
+/// Required. Layer data is compressed.
+pub const FLAG_COMPRESSED: u16 = 0x0100;
+/// Required. Layer data is encrypted.
+pub const FLAG_ENCRYPTED: u16 = 0x0200;
+/// Bits a reader must understand to read the file.
+pub const FLAGS_REQUIRED: u16 = 0xff00;
+/// Every flag defined so far.
+pub const FLAGS_KNOWN: u16 =
+    FLAG_HAS_BASE | FLAG_ENTRY_INDEX | FLAG_SIGNED | FLAG_HAS_ENTRIES | FLAG_COMPRESSED | FLAG_ENCRYPTED;
+/// Required flags this build can read. None yet.
+pub const SUPPORTED_REQUIRED_FLAGS: u16 = 0;
+/// Flags appends keep up to date. Other optional flags describe data an
+/// append would invalidate (a signature, an entry index), so appends clear
+/// them.
+pub const FLAGS_MAINTAINED: u16 = FLAG_HAS_BASE | FLAG_HAS_ENTRIES;
+
+const FLAG_NAMES: [(u16, &str); 6] = [
+    (FLAG_HAS_BASE, "has_base"),
+    (FLAG_ENTRY_INDEX, "entry_index"),
+    (FLAG_SIGNED, "signed"),
+    (FLAG_HAS_ENTRIES, "has_entries"),
+    (FLAG_COMPRESSED, "compressed"),
+    (FLAG_ENCRYPTED, "encrypted"),
+];
+
+/// Names of the bits set in `flags`, with unnamed bits in hex.
+pub fn flag_names(flags: u16) -> Vec<String> {
+    (0..16)
+        .map(|bit| 1u16 << bit)
+        .filter(|b| flags & b != 0)
+        .map(|b| match FLAG_NAMES.iter().find(|(f, _)| *f == b) {
+            Some((_, name)) => name.to_string(),
+            None => format!("{b:#06x}"),
+        })
+        .collect()
+}
+
+// ── File header ───────────────────────────────────────────────────────────────
+
+/// The fixed 16-byte header at the start of every `.tcow` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHeader {
+    pub version: u16,
+    pub flags: u16,
+    /// Bytes 8..16: zero when written, never interpreted.
+    pub reserved: [u8; 8],
+}
+
+impl FileHeader {
+    /// Header for a new file in the current format, whose only layer is
+    /// the base.
+    pub fn new(has_base: bool) -> Self {
+        let flags = if has_base { FLAG_HAS_BASE | FLAG_HAS_ENTRIES } else { 0 };
+        FileHeader { version: FORMAT_VERSION, flags, reserved: [0; 8] }
+    }
+
+    /// Check the magic, version and required flags of a raw header. `path`
+    /// is only used in errors.
+    pub fn parse(bytes: &[u8; 16], path: &Path) -> Result<Self> {
+        if &bytes[0..4] != MAGIC {
+            return Err(TcowError::BadMagic(path.to_path_buf()));
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(TcowError::UnsupportedVersion(version));
+        }
+        let header = FileHeader {
+            version,
+            flags: u16::from_le_bytes([bytes[6], bytes[7]]),
+            reserved: bytes[8..16].try_into().unwrap(),
+        };
+        match header.unsupported_flags() {
+            0 => Ok(header),
+            flags => Err(TcowError::UnsupportedFlags(flags)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut hdr = [0u8; 16];
+        hdr[0..4].copy_from_slice(MAGIC);
+        hdr[4..6].copy_from_slice(&self.version.to_le_bytes());
+        hdr[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        hdr[8..16].copy_from_slice(&self.reserved);
+        hdr
+    }
+
+    pub fn has_base(&self) -> bool {
+        self.flags & FLAG_HAS_BASE != 0
+    }
+
+    pub fn has_entries(&self) -> bool {
+        self.flags & FLAG_HAS_ENTRIES != 0
+    }
+
+    /// Required flags this build cannot read.
+    pub fn unsupported_flags(&self) -> u16 {
+        self.flags & FLAGS_REQUIRED & !SUPPORTED_REQUIRED_FLAGS
+    }
+
+    /// Flags once a layer has been appended: `FLAG_HAS_ENTRIES` is set if
+    /// the new layer holds entries, `FLAG_HAS_BASE` is kept (the base never
+    /// changes), other optional flags are cleared, and required ones are
+    /// kept. v1 headers are left as they are.
+    pub fn flags_after_append(&self, layer_has_entries: bool) -> u16 {
+        if self.version < 2 {
+            return self.flags;
+        }
+        let has_entries = if layer_has_entries { FLAG_HAS_ENTRIES } else { 0 };
+        (self.flags & (FLAGS_REQUIRED | FLAGS_MAINTAINED)) | has_entries
+    }
+}
 
 // ── CBOR index structures ─────────────────────────────────────────────────────
 
+/// Named fields added by later writers. Readers keep the ones they do not
+/// understand unchanged and otherwise ignore them. Always empty in v1.
+pub type Extensions = BTreeMap<String, ciborium::Value>;
+
 /// Top-level CBOR document stored in the trailer.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TcowIndex {
@@ -27,16 +192,52 @@ pub struct TcowIndex {
     pub layers: Vec<LayerRecord>,
     pub last_modified: String,
     pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extensions: Extensions,
+}
+
+/// Role of a layer in the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LayerKind {
+    /// Layer 0, the initial filesystem state.
+    Base,
+    /// Every layer above the base.
+    Delta,
+}
+
+impl LayerKind {
+    /// The kind a layer at `idx` should have.
+    pub fn for_index(idx: usize) -> Self {
+        if idx == 0 {
+            LayerKind::Base
+        } else {
+            LayerKind::Delta
+        }
+    }
+}
+
+impl fmt::Display for LayerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            LayerKind::Base => "Base",
+            LayerKind::Delta => "Delta",
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LayerRecord {
     pub offset: u64,
     pub size: u64,
-    /// "Base" or "Delta"
-    pub kind: String,
+    pub kind: LayerKind,
     pub digest: Option<String>,
     pub created_at: String,
+    /// Hex SHA-256 of every regular file in the layer, keyed by canonical
+    /// path. Empty for layers written before per-file digests existed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub file_digests: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extensions: Extensions,
 }
 
 // ── In-memory layer entry ─────────────────────────────────────────────────────
@@ -45,17 +246,53 @@ pub struct LayerRecord {
 /// All paths are stored without a leading `/`.
 #[derive(Debug, Clone)]
 pub struct RawEntry {
-    pub data: Vec<u8>,
+    /// Content, borrowed from the file's loaded layer region.
+    pub data: Blob,
     pub mtime: u64,
     /// True when this entry is a whiteout marker (deletion).
     pub is_whiteout: bool,
     pub is_dir: bool,
+    /// True when this directory carries an opaque whiteout (`.wh..wh..opq`):
+    /// everything beneath it in lower layers is hidden.
+    pub is_opaque: bool,
+    /// Offset of the entry's content from the start of its layer's tar stream.
+    pub data_offset: u64,
+    /// Set when the entry is a dedup reference; `data` then holds the content
+    /// of the stored copy it points at.
+    pub reference: Option<BlobRef>,
+    /// Set when the entry is a delta against a lower version; `data` then
+    /// rebuilds the full content on first access.
+    pub delta: Option<DeltaRef>,
+    /// Hex SHA-256 of the content as recorded when the layer was written.
+    pub digest: Option<String>,
+}
+
+impl RawEntry {
+    fn marker(mtime: u64) -> Self {
+        RawEntry {
+            data: Blob::default(),
+            mtime,
+            is_whiteout: false,
+            is_dir: false,
+            is_opaque: false,
+            data_offset: 0,
+            reference: None,
+            delta: None,
+            digest: None,
+        }
+    }
+
+    /// Hex SHA-256 of the content: the recorded digest when there is one,
+    /// otherwise computed from the content.
+    pub fn sha256(&self) -> String {
+        self.digest.clone().unwrap_or_else(|| sha256_hex(&self.data))
+    }
 }
 
 /// An entry resolved through the full union view.
 #[derive(Debug, Clone)]
 pub struct ResolvedEntry {
-    pub data: Vec<u8>,
+    pub data: Blob,
     pub mtime: u64,
     pub layer_idx: usize,
     pub size: u64,
@@ -63,181 +300,304 @@ pub struct ResolvedEntry {
 
 // ── TcowFile ──────────────────────────────────────────────────────────────────
 
-/// An open .tcow file with all layers parsed into memory.
-pub struct TcowFile {
-    pub path: std::path::PathBuf,
+/// An open .tcow file with all layers parsed into memory. `S` is the
+/// storage it was read from and appends go to; see `storage`.
+pub struct TcowFile<S = FileStorage> {
+    pub header: FileHeader,
     pub index: TcowIndex,
     /// Entries for each layer, keyed by canonical path (no leading `/`).
     /// Whiteout entries are stored under the *real* (non-`.wh.`) path with
     /// `is_whiteout = true`.
-    pub layers: Vec<HashMap<String, RawEntry>>,
+    pub layers: Vec<HashMap<VPath, RawEntry>>,
+    /// Everything before the trailer: header plus layer tar streams.
+    region: LoadedRegion,
+    /// Union view of all layers, kept in step with `layers`.
+    tree: UnionTree,
+    storage: S,
 }
 
 impl TcowFile {
     // ── Open ──────────────────────────────────────────────────────────────────
 
-    /// Open and parse an existing `.tcow` file.
+    /// Open and parse an existing `.tcow` file. Layer data is memory-mapped
+    /// when possible (see `ReadMode::from_env`).
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let path = path.as_ref().to_path_buf();
-        let mut f = File::open(&path)
-            .with_context(|| format!("cannot open {:?}", path))?;
+        Self::open_with(path, ReadMode::from_env())
+    }
 
-        // Validate header
-        let mut hdr = [0u8; 16];
-        f.read_exact(&mut hdr).context("reading TCOW file header")?;
-        if &hdr[0..4] != MAGIC {
-            bail!("{:?} is not a .tcow file: bad magic bytes", path);
-        }
-        let version = u16::from_le_bytes([hdr[4], hdr[5]]);
-        if version != 1 {
-            bail!("unsupported TCOW version {}", version);
-        }
+    /// Open and parse an existing `.tcow` file, loading layer data as `mode`
+    /// says. Entries borrow their content from the loaded region.
+    pub fn open_with(path: impl AsRef<Path>, mode: ReadMode) -> Result<Self> {
+        let path = path.as_ref();
+        Self::load(FileStorage::open(path)?, path, mode)
+    }
 
-        // Read footer (last 16 bytes)
-        let file_len = f.seek(SeekFrom::End(0))?;
-        if file_len < HEADER_SIZE + FOOTER_SIZE {
-            bail!("file too small to be a valid .tcow");
-        }
-        f.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
-        let mut footer = [0u8; 16];
-        f.read_exact(&mut footer)?;
-        if &footer[12..16] != MAGIC_TAIL {
-            bail!("bad footer magic — file may be truncated or corrupt");
-        }
-        let trailer_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
-        let trailer_len = u32::from_le_bytes(footer[8..12].try_into().unwrap());
+    // ── Create ────────────────────────────────────────────────────────────────
+
+    /// Create a brand-new `.tcow` file with a single Base layer.
+    pub fn create(
+        path: impl AsRef<Path>,
+        entries: &[(VPath, Vec<u8>)],
+        whiteouts: &[VPath],
+        label: Option<String>,
+    ) -> Result<Self> {
+        Self::create_in(FileStorage::create(path)?, entries, whiteouts, label)
+    }
+
+    /// Create a brand-new `.tcow` file whose Base layer is `layer_bytes`, an
+    /// already-built tar stream that is stored unchanged.
+    pub fn create_raw(
+        path: impl AsRef<Path>,
+        layer_bytes: &[u8],
+        has_content: bool,
+        label: Option<String>,
+    ) -> Result<Self> {
+        Self::create_raw_in(FileStorage::create(path)?, layer_bytes, has_content, label)
+    }
+
+    /// `create_raw` for a layer read from `layer`, such as a temporary file,
+    /// rather than held in memory. `layer` is read twice: once to check and
+    /// hash it, then from the start to copy it. Nothing is created at `path`
+    /// if the first pass fails.
+    pub fn create_raw_from(
+        path: impl AsRef<Path>,
+        mut layer: impl Read + Seek,
+        label: Option<String>,
+    ) -> Result<Self> {
+        let new = writer::scan_raw_layer(&mut layer)?;
+        layer.rewind()?;
+        let mut storage = FileStorage::create(path)?;
+        writer::write_base(&mut storage, writer::new_index(label), &mut layer, new)?;
+        Self::from_storage(storage)
+    }
+
+}
+
+impl<S: Storage> TcowFile<S> {
+    /// Parse a `.tcow` file held in `storage`, e.g. an already open `File`
+    /// or a `Cursor<Vec<u8>>`.
+    pub fn from_storage(storage: S) -> Result<Self> {
+        Self::from_storage_with(storage, ReadMode::from_env())
+    }
 
-        // Parse CBOR trailer
-        f.seek(SeekFrom::Start(trailer_offset))?;
-        let mut cbor_bytes = vec![0u8; trailer_len as usize];
-        f.read_exact(&mut cbor_bytes).context("reading CBOR trailer")?;
-        let index: TcowIndex = ciborium::from_reader(Cursor::new(&cbor_bytes))
-            .map_err(|e| anyhow!("invalid CBOR trailer: {e}"))?;
+    /// `from_storage`, loading layer data as `mode` says. Storage other than
+    /// a file is always read into memory.
+    pub fn from_storage_with(storage: S, mode: ReadMode) -> Result<Self> {
+        Self::load(storage, Path::new(""), mode)
+    }
+
+    /// `name` is what errors call the file.
+    fn load(mut storage: S, name: &Path, mode: ReadMode) -> Result<Self> {
+        let header = read_header(&mut storage, name)?;
+        let (index, trailer_offset) = read_index(&mut storage, name)?;
+        let region = LoadedRegion::new(Blob::load(&mut storage, trailer_offset, mode)?);
 
         // Parse each layer's tar stream
         let mut layers = Vec::with_capacity(index.layers.len());
         for record in &index.layers {
-            f.seek(SeekFrom::Start(record.offset))?;
-            let mut layer_bytes = vec![0u8; record.size as usize];
-            f.read_exact(&mut layer_bytes)?;
-            let entries = parse_tar_layer(&layer_bytes)
-                .with_context(|| format!("parsing layer at offset {}", record.offset))?;
+            let entries = load_layer(&region, record, &layers)?;
             layers.push(entries);
         }
 
-        Ok(TcowFile { path, index, layers })
+        let tree = UnionTree::build(&layers);
+        Ok(TcowFile { header, index, layers, region, tree, storage })
     }
 
-    // ── Create ────────────────────────────────────────────────────────────────
+    /// Pick up layers appended to the storage since it was opened or last
+    /// refreshed, e.g. by a `LayerWriter`. Only the new layers are parsed and
+    /// stacked onto the cached union view. Returns how many were added.
+    pub fn refresh(&mut self) -> Result<usize> {
+        let (index, trailer_offset) = read_index(&mut self.storage, Path::new(""))?;
+        let known = self.layers.len();
+        if index.layers.len() < known
+            || index.layers[..known].iter().zip(&self.index.layers).any(|(a, b)| a.offset != b.offset)
+        {
+            return Err(TcowError::Invalid(
+                "the file was rewritten since it was opened; reopen it instead".into(),
+            ));
+        }
+        if index.layers.len() > known {
+            // New layers start where the last known one ended; only the bytes
+            // from there to the new trailer are loaded
+            let start = self.index.layers.last().map_or(HEADER_SIZE, |r| r.offset + r.size);
+            let mode = if self.region.is_mapped() { ReadMode::Auto } else { ReadMode::Buffered };
+            let piece = Blob::load_range(&mut self.storage, start..trailer_offset, mode)?;
+            self.region.push(start, piece);
+        }
+        for record in &index.layers[known..] {
+            let entries = load_layer(&self.region, record, &self.layers)?;
+            self.tree.apply_layer(self.layers.len(), &entries);
+            self.layers.push(entries);
+        }
+        self.header = read_header(&mut self.storage, Path::new(""))?;
+        self.index = index;
+        Ok(self.layers.len() - known)
+    }
 
-    /// Create a brand-new `.tcow` file with a single Base layer.
-    pub fn create(
-        path: impl AsRef<Path>,
-        entries: &[(String, Vec<u8>)],
-        whiteouts: &[String],
+    /// The storage the file lives in.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+}
+
+impl<S: StorageMut> TcowFile<S> {
+    /// Write a brand-new `.tcow` file with a single Base layer into
+    /// `storage`, replacing anything it held.
+    pub fn create_in(
+        storage: S,
+        entries: &[(VPath, Vec<u8>)],
+        whiteouts: &[VPath],
+        label: Option<String>,
+    ) -> Result<Self> {
+        let layer_bytes = build_tar_layer(entries, whiteouts)?;
+        let has_content = !entries.is_empty() || !whiteouts.is_empty();
+        Self::create_raw_in(storage, &layer_bytes, has_content, label)
+    }
+
+    /// `create_raw` into `storage`, replacing anything it held.
+    pub fn create_raw_in(
+        mut storage: S,
+        layer_bytes: &[u8],
+        has_content: bool,
         label: Option<String>,
     ) -> Result<Self> {
-        let path = path.as_ref().to_path_buf();
-        let mut f = File::create(&path)
-            .with_context(|| format!("cannot create {:?}", path))?;
+        let f = &mut storage;
+        f.set_len(0)?;
+        f.seek(SeekFrom::Start(0))?;
 
         let now = now_rfc3339();
-        let has_content = !entries.is_empty() || !whiteouts.is_empty();
-        write_file_header(&mut f, has_content)?;
+        write_file_header(f, has_content)?;
 
-        // Build & write base tar layer
-        let layer_bytes = build_tar_layer(entries, whiteouts)?;
-        let digest = sha256_hex(&layer_bytes);
+        // Write base tar layer
+        let digest = sha256_hex(layer_bytes);
         let layer_offset = HEADER_SIZE;
         let layer_size = layer_bytes.len() as u64;
-        f.write_all(&layer_bytes)?;
+        f.write_all(layer_bytes)?;
 
         let index = TcowIndex {
-            version: 1,
+            version: FORMAT_VERSION,
             layers: vec![LayerRecord {
                 offset: layer_offset,
                 size: layer_size,
-                kind: "Base".into(),
+                kind: LayerKind::Base,
                 digest: Some(digest),
                 created_at: now.clone(),
+                file_digests: layer_file_digests(layer_bytes)?,
+                extensions: Extensions::new(),
             }],
             last_modified: now,
             label,
+            extensions: Extensions::new(),
         };
 
         let trailer_offset = layer_offset + layer_size;
         let cbor_bytes = encode_cbor(&index)?;
         let trailer_len = cbor_bytes.len() as u32;
         f.write_all(&cbor_bytes)?;
-        write_trailer_footer(&mut f, trailer_offset, trailer_len)?;
+        write_trailer_footer(f, trailer_offset, trailer_len)?;
         f.flush()?;
 
-        let layer_entries = parse_tar_layer(&layer_bytes)?;
-        Ok(TcowFile { path, index, layers: vec![layer_entries] })
+        Self::from_storage(storage)
     }
 
-    // ── Append delta ──────────────────────────────────────────────────────────
-
-    /// Append a new Delta layer to an existing `.tcow` file.
-    /// Truncates the old trailer+footer, writes new tar + trailer + footer.
-    pub fn append_delta(
-        path: impl AsRef<Path>,
-        entries: &[(String, Vec<u8>)],
-        whiteouts: &[String],
-    ) -> Result<Self> {
-        let path = path.as_ref().to_path_buf();
-
-        // Parse current state to get the index
-        let existing = TcowFile::open(&path)?;
-
-        // Locate the old trailer offset from the footer
-        let mut f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&path)
-            .context("opening file for writing delta")?;
-
-        f.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
-        let mut footer_buf = [0u8; 16];
-        f.read_exact(&mut footer_buf)?;
-        let old_trailer_offset = u64::from_le_bytes(footer_buf[0..8].try_into().unwrap());
-
-        // Truncate at old trailer, overwrite from there
-        f.set_len(old_trailer_offset)?;
-        f.seek(SeekFrom::Start(old_trailer_offset))?;
+    /// Like `append`, but file bodies may be stored compactly as `opts`
+    /// allows: as references to identical content already in the file, or
+    /// as deltas against the version currently visible at the same path.
+    pub fn append_with(
+        &mut self,
+        entries: &[(VPath, Vec<u8>)],
+        whiteouts: &[VPath],
+        opts: AppendOptions,
+    ) -> Result<AppendReport> {
+        let known = if opts.dedup { self.content_index() } else { HashMap::new() };
+        let encode = |path: &VPath, data: &[u8]| {
+            if data.len() >= dedup::DEDUP_MIN_SIZE {
+                if let Some(blob) = known.get(&sha256_hex(data)) {
+                    return Some(Body::Ref(blob.clone()));
+                }
+            }
+            opts.delta
+                .then(|| self.encode_delta(path, data))
+                .flatten()
+                .map(|(delta, patch)| Body::Delta(delta, patch))
+        };
+        let (layer_bytes, report) = build_layer(entries, whiteouts, &encode)?;
+        self.append_raw(&layer_bytes)?;
+        Ok(report)
+    }
 
-        // Build and write new delta tar stream
+    /// Append a new Delta layer through this handle. The file is not
+    /// re-parsed: only the new layer is read back and stacked onto the
+    /// cached union view.
+    pub fn append(&mut self, entries: &[(VPath, Vec<u8>)], whiteouts: &[VPath]) -> Result<()> {
         let layer_bytes = build_tar_layer(entries, whiteouts)?;
-        let digest = sha256_hex(&layer_bytes);
-        let delta_offset = old_trailer_offset;
-        let delta_size = layer_bytes.len() as u64;
-        f.write_all(&layer_bytes)?;
+        self.append_raw(&layer_bytes)
+    }
 
-        // Build updated index
-        let now = now_rfc3339();
-        let mut index = existing.index.clone();
-        index.layers.push(LayerRecord {
-            offset: delta_offset,
-            size: delta_size,
-            kind: "Delta".into(),
-            digest: Some(digest),
-            created_at: now.clone(),
-        });
-        index.last_modified = now;
+    /// Append `layer_bytes`, an already-built tar stream, as a new Delta
+    /// layer stored unchanged.
+    pub fn append_raw(&mut self, layer_bytes: &[u8]) -> Result<()> {
+        write_delta_layer(&mut self.storage, layer_bytes)?;
+        self.refresh()?;
+        Ok(())
+    }
 
-        // Write new CBOR trailer + footer
-        let new_trailer_offset = delta_offset + delta_size;
-        let cbor_bytes = encode_cbor(&index)?;
-        let new_trailer_len = cbor_bytes.len() as u32;
-        f.write_all(&cbor_bytes)?;
-        write_trailer_footer(&mut f, new_trailer_offset, new_trailer_len)?;
-        f.flush()?;
+    /// `append_raw` for a layer read from `layer` rather than held in
+    /// memory. As with `create_raw_from`, it is read twice, and the file is
+    /// only written once the first pass has succeeded.
+    pub fn append_raw_from(&mut self, mut layer: impl Read + Seek) -> Result<()> {
+        let new = writer::scan_raw_layer(&mut layer)?;
+        layer.rewind()?;
+        let (index, trailer_offset) = read_index(&mut self.storage, Path::new(""))?;
+        commit_layer(&mut self.storage, index, trailer_offset, &mut layer, new)?;
+        self.refresh()?;
+        Ok(())
+    }
+}
 
-        let new_layer_entries = parse_tar_layer(&layer_bytes)?;
-        let mut all_layers = existing.layers;
-        all_layers.push(new_layer_entries);
+impl<S> TcowFile<S> {
+    /// Encode `data` as a delta against the version of `vpath` visible now.
+    /// `None` when the file is small, has no visible base, would exceed the
+    /// chain limit, or does not shrink to under half its size.
+    pub fn encode_delta(&self, vpath: &VPath, data: &[u8]) -> Option<(DeltaRef, Vec<u8>)> {
+        if data.len() < delta::DELTA_MIN_SIZE {
+            return None;
+        }
+        let (base, base_layer) = self.lookup(vpath)?;
+        let depth = delta::depth_of(base) + 1;
+        if depth > delta::MAX_DELTA_DEPTH {
+            return None;
+        }
+        let patch = delta::encode(&base.data, data);
+        if patch.len() > data.len() / 2 {
+            return None;
+        }
+        let delta = DeltaRef {
+            base_layer,
+            base_path: vpath.clone(),
+            size: data.len() as u64,
+            digest: sha256_hex(data),
+            depth,
+        };
+        Some((delta, patch))
+    }
+
+    /// Content digest → stored copy for every inline file of at least
+    /// `dedup::DEDUP_MIN_SIZE` bytes.
+    pub fn content_index(&self) -> HashMap<String, BlobRef> {
+        dedup::content_index(&self.layers)
+    }
 
-        Ok(TcowFile { path, index, layers: all_layers })
+    /// False when layer `layer_idx` holds dedup references or deltas, whose
+    /// content lives partly in lower layers.
+    pub fn layer_is_self_contained(&self, layer_idx: usize) -> bool {
+        self.layers
+            .get(layer_idx)
+            .is_none_or(|l| l.values().all(|e| e.reference.is_none() && e.delta.is_none()))
     }
 
     // ── Union view ────────────────────────────────────────────────────────────
@@ -245,117 +605,692 @@ impl TcowFile {
     /// Compute the union view: the set of currently visible files.
     /// Iterates layers from highest (most recent) to lowest; whiteouts shadow
     /// same-named entries in lower layers.
-    pub fn union_view(&self) -> HashMap<String, ResolvedEntry> {
-        let mut result: HashMap<String, ResolvedEntry> = HashMap::new();
-        let mut deleted: HashSet<String> = HashSet::new();
-
-        for (layer_idx, layer_entries) in self.layers.iter().enumerate().rev() {
-            for (path, entry) in layer_entries {
-                if entry.is_whiteout {
-                    deleted.insert(path.clone());
-                } else if !deleted.contains(path) && !result.contains_key(path) && !entry.is_dir {
-                    result.insert(
-                        path.clone(),
-                        ResolvedEntry {
-                            data: entry.data.clone(),
-                            mtime: entry.mtime,
-                            layer_idx,
-                            size: entry.data.len() as u64,
-                        },
-                    );
-                }
-            }
+    pub fn union_view(&self) -> HashMap<VPath, ResolvedEntry> {
+        match self.layers.len() {
+            0 => HashMap::new(),
+            n => self.union_view_at(n - 1),
         }
-        result
+    }
+
+    /// Compute the union view as it was when layer `top` was the most recent
+    /// one, ignoring every layer above it.
+    pub fn union_view_at(&self, top: usize) -> HashMap<VPath, ResolvedEntry> {
+        self.visible_entries(top)
+            .into_iter()
+            .map(|(path, entry, layer_idx)| {
+                (
+                    path.clone(),
+                    ResolvedEntry {
+                        data: entry.data.clone(),
+                        mtime: entry.mtime,
+                        layer_idx,
+                        size: entry.data.len() as u64,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// The union view through `Vfs`, backed by the cached tree.
+    pub fn view(&self) -> TcowView<'_, S> {
+        TcowView::new(self, Cow::Borrowed(&self.tree), None)
+    }
+
+    /// `view` as it was when layer `top` was the most recent one.
+    pub fn view_at(&self, top: usize) -> Result<TcowView<'_, S>> {
+        self.layer(top)?;
+        if top + 1 == self.layers.len() {
+            return Ok(self.view());
+        }
+        Ok(TcowView::new(self, Cow::Owned(UnionTree::build(&self.layers[..=top])), None))
+    }
+
+    /// Just the files stored in layer `layer_idx`, without the layers below
+    /// it or its whiteouts.
+    pub fn layer_view(&self, layer_idx: usize) -> Result<TcowView<'_, S>> {
+        let mut tree = UnionTree::default();
+        tree.apply_layer(layer_idx, self.layer(layer_idx)?);
+        Ok(TcowView::new(self, Cow::Owned(tree), Some(layer_idx)))
+    }
+
+    /// Borrowing core of `union_view_at`: the winning entry for each visible
+    /// path together with the layer it came from. The view of the top layer
+    /// comes from the cached tree; older views are built on demand.
+    fn visible_entries(&self, top: usize) -> Vec<(&VPath, &RawEntry, usize)> {
+        let end = (top + 1).min(self.layers.len());
+        let files = if end == self.layers.len() {
+            self.tree.files()
+        } else {
+            UnionTree::build(&self.layers[..end]).files()
+        };
+        files.into_iter().map(|(path, idx)| self.entry_in(idx, &path)).collect()
+    }
+
+    /// Visible files whose path starts with `prefix` (a plain string prefix
+    /// of the canonical path, leading `/` optional), sorted by path, without
+    /// touching the rest of the view.
+    pub fn visible_with_prefix(&self, prefix: &str) -> Vec<(&VPath, &RawEntry, usize)> {
+        let mut files: Vec<_> = self
+            .tree
+            .files_with_prefix(prefix.trim_start_matches('/'))
+            .into_iter()
+            .map(|(path, idx)| self.entry_in(idx, &path))
+            .collect();
+        files.sort_by(|a, b| a.0.cmp(b.0));
+        files
+    }
+
+    /// Layer of the visible copy of `vpath`, if any.
+    pub fn visible_layer(&self, vpath: &VPath) -> Option<usize> {
+        self.tree.get(vpath)
+    }
+
+    fn entry_in(&self, layer_idx: usize, path: &str) -> (&VPath, &RawEntry, usize) {
+        let (key, entry) = self.layers[layer_idx]
+            .get_key_value(path)
+            .expect("union tree out of step with layers");
+        (key, entry, layer_idx)
     }
 
     /// Resolve a single virtual path through the union view.
-    pub fn resolve(&self, vpath: &str) -> Option<(ResolvedEntry, usize)> {
-        let canonical = normalize_path(vpath);
-        let view = self.union_view();
-        view.get(&canonical).map(|e| (e.clone(), e.layer_idx))
+    pub fn resolve(&self, vpath: &VPath) -> Option<(ResolvedEntry, usize)> {
+        let (entry, layer_idx) = self.lookup(vpath)?;
+        let resolved = ResolvedEntry {
+            data: entry.data.clone(),
+            mtime: entry.mtime,
+            layer_idx,
+            size: entry.data.len() as u64,
+        };
+        Some((resolved, layer_idx))
+    }
+
+    /// Find the visible entry for `vpath` in the cached union view.
+    pub fn lookup(&self, vpath: &VPath) -> Option<(&RawEntry, usize)> {
+        let layer_idx = self.tree.get(vpath)?;
+        Some((&self.layers[layer_idx][vpath], layer_idx))
+    }
+
+    // ── Streaming reads ───────────────────────────────────────────────────────
+
+    /// Open a visible file for streaming. The handle reads from the loaded
+    /// layer region, so nothing is copied up front.
+    pub fn open_file(&self, vpath: &VPath) -> Result<FileReader> {
+        let (entry, _) = self
+            .lookup(vpath)
+            .ok_or_else(|| TcowError::NotFound { path: vpath.clone(), layer: None })?;
+        Ok(FileReader::new(entry.data.clone()))
+    }
+
+    /// Open a file as stored in one specific layer, ignoring the layers above.
+    pub fn open_layer_file(&self, layer_idx: usize, vpath: &VPath) -> Result<FileReader> {
+        let layer = self.layer(layer_idx)?;
+        let entry = layer
+            .get(vpath)
+            .ok_or_else(|| TcowError::NotFound { path: vpath.clone(), layer: Some(layer_idx) })?;
+        if entry.is_whiteout {
+            return Err(TcowError::Invalid(format!(
+                "/{vpath} is a whiteout (deletion marker) in layer {layer_idx}"
+            )));
+        }
+        if entry.is_dir {
+            return Err(TcowError::Invalid(format!("/{vpath} is a directory in layer {layer_idx}")));
+        }
+        Ok(FileReader::new(entry.data.clone()))
+    }
+
+    /// The stored tar bytes of a layer, borrowed from the loaded region.
+    pub fn layer_bytes(&self, layer_idx: usize) -> Result<Blob> {
+        let rec = self.index.layers.get(layer_idx).ok_or(TcowError::NoSuchLayer {
+            index: layer_idx,
+            count: self.index.layers.len(),
+        })?;
+        self.region.slice(rec.offset..rec.offset + rec.size)
+    }
+
+    /// Hash layer `layer_idx` and compare it with the digest in its record.
+    /// Layers without a recorded digest pass.
+    pub fn verify_layer(&self, layer_idx: usize) -> Result<()> {
+        let bytes = self.layer_bytes(layer_idx)?;
+        let Some(expected) = &self.index.layers[layer_idx].digest else { return Ok(()) };
+        let actual = sha256_hex(&bytes);
+        if actual != *expected {
+            return Err(TcowError::DigestMismatch { layer: layer_idx, expected: expected.clone(), actual });
+        }
+        Ok(())
+    }
+
+    /// True when layer data is served from a memory mapping rather than a
+    /// buffered read.
+    pub fn is_mapped(&self) -> bool {
+        self.region.is_mapped()
     }
 
     /// Count of visible files in the union view.
     pub fn visible_count(&self) -> usize {
-        self.union_view().len()
+        self.tree.len()
+    }
+
+    // ── Export ────────────────────────────────────────────────────────────────
+
+    /// Write the union view as of layer `top` as a plain tar stream, one entry
+    /// at a time (the archive is never assembled in memory).
+    pub fn write_union_tar(&self, top: usize, w: impl Write) -> Result<usize> {
+        self.layer(top)?;
+        let mut visible = self.visible_entries(top);
+        visible.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut builder = tar::Builder::new(w);
+        for (path, entry, _) in &visible {
+            append_tar_file(&mut builder, path, &entry.data, entry.mtime)?;
+        }
+        builder.into_inner()?;
+        Ok(visible.len())
+    }
+
+    /// Write the entries of a single layer, whiteouts and opaque markers
+    /// included, as a tar stream. Dedup references are written inline.
+    pub fn write_layer_tar(&self, layer_idx: usize, w: impl Write) -> Result<usize> {
+        let layer = self.layer(layer_idx)?;
+        let mut paths: Vec<&VPath> = layer.keys().collect();
+        paths.sort();
+
+        let mut builder = tar::Builder::new(w);
+        let mut count = 0;
+        for path in paths {
+            let entry = &layer[path];
+            if entry.is_opaque {
+                let marker = match path.as_str() {
+                    "" => OPAQUE_WHITEOUT.to_string(),
+                    dir => format!("{dir}/{OPAQUE_WHITEOUT}"),
+                };
+                append_tar_file(&mut builder, &marker, &[], entry.mtime)?;
+            }
+            if entry.is_dir {
+                continue;
+            }
+            if entry.is_whiteout {
+                append_tar_file(&mut builder, &to_whiteout_tar_path(path), &[], entry.mtime)?;
+            } else {
+                append_tar_file(&mut builder, path, &entry.data, entry.mtime)?;
+            }
+            count += 1;
+        }
+        builder.into_inner()?;
+        Ok(count)
+    }
+
+    /// Entries of layer `layer_idx`, or `NoSuchLayer`.
+    pub fn layer(&self, layer_idx: usize) -> Result<&HashMap<VPath, RawEntry>> {
+        self.layers
+            .get(layer_idx)
+            .ok_or(TcowError::NoSuchLayer { index: layer_idx, count: self.layers.len() })
+    }
+
+    /// Copy the stored tar bytes of a layer to `w` unchanged.
+    pub fn copy_raw_layer(&self, layer_idx: usize, w: &mut impl Write) -> Result<u64> {
+        let bytes = self.layer_bytes(layer_idx)?;
+        w.write_all(&bytes)?;
+        Ok(bytes.len() as u64)
     }
 }
 
-// ── Path helpers ──────────────────────────────────────────────────────────────
+/// Parse the layer `record` describes out of `region`, resolve its dedup
+/// references and deltas against `lower`, and attach its recorded digests.
+fn load_layer(
+    region: &LoadedRegion,
+    record: &LayerRecord,
+    lower: &[HashMap<VPath, RawEntry>],
+) -> Result<HashMap<VPath, RawEntry>> {
+    let idx = lower.len();
+    let layer = region.slice(record.offset..record.offset + record.size).map_err(|_| {
+        TcowError::CorruptLayer {
+            layer: Some(idx),
+            message: format!("{} bytes at offset {} run past the layer data", record.size, record.offset),
+        }
+    })?;
+    let mut entries = parse_layer(&layer).map_err(|e| e.in_layer(idx))?;
+    dedup::resolve_refs(&mut entries, lower).map_err(|e| e.in_layer(idx))?;
+    delta::resolve_deltas(&mut entries, lower).map_err(|e| e.in_layer(idx))?;
+    for (path, entry) in entries.iter_mut() {
+        entry.digest = record
+            .file_digests
+            .get(path.as_str())
+            .or(entry.reference.as_ref().map(|r| &r.digest))
+            .or(entry.delta.as_ref().map(|d| &d.digest))
+            .cloned();
+    }
+    Ok(entries)
+}
 
-/// Strip leading `/` and ensure consistent internal representation.
-pub fn normalize_path(p: &str) -> String {
-    p.trim_start_matches('/').to_string()
+/// Hex SHA-256 of every regular file in `entries`. References and deltas
+/// use the digest they carry, so they need not be resolved first.
+pub fn file_digests(entries: &HashMap<VPath, RawEntry>) -> BTreeMap<String, String> {
+    entries
+        .iter()
+        .filter(|(_, e)| !e.is_whiteout && !e.is_dir)
+        .map(|(path, e)| {
+            let digest = match (&e.reference, &e.delta) {
+                (Some(blob), _) => blob.digest.clone(),
+                (_, Some(delta)) => delta.digest.clone(),
+                _ => sha256_hex(&e.data),
+            };
+            (path.to_string(), digest)
+        })
+        .collect()
 }
 
-/// `"data/records.db"` → `"data/.wh.records.db"` (tar entry name for whiteout)
-pub fn to_whiteout_tar_path(canonical: &str) -> String {
-    if let Some(pos) = canonical.rfind('/') {
-        format!("{}/.wh.{}", &canonical[..pos], &canonical[pos + 1..])
-    } else {
-        format!(".wh.{}", canonical)
+/// `file_digests` of a layer's tar stream.
+fn layer_file_digests(layer_bytes: &[u8]) -> Result<BTreeMap<String, String>> {
+    Ok(file_digests(&parse_tar_layer(layer_bytes)?))
+}
+
+/// Write `layer_bytes` as a new Delta layer at the end of the `.tcow` file
+/// in `f` (see `commit_layer`). Returns the updated index.
+fn write_delta_layer<S: StorageMut>(f: &mut S, layer_bytes: &[u8]) -> Result<TcowIndex> {
+    // Parse before touching the file so a bad stream leaves it unchanged
+    let entries = parse_tar_layer(layer_bytes)?;
+    let (index, trailer_offset) = read_index(f, Path::new(""))?;
+    let layer = NewLayer {
+        size: layer_bytes.len() as u64,
+        digest: sha256_hex(layer_bytes),
+        file_digests: file_digests(&entries),
+        has_entries: !entries.is_empty(),
+    };
+    commit_layer(f, index, trailer_offset, &mut Cursor::new(layer_bytes), layer)
+}
+
+/// A finished layer tar stream waiting to be written by `commit_layer`.
+pub(crate) struct NewLayer {
+    pub size: u64,
+    pub digest: String,
+    pub file_digests: BTreeMap<String, String>,
+    pub has_entries: bool,
+}
+
+/// Append `layer.size` bytes read from `data` as a new Delta layer right
+/// after the last layer of the `.tcow` file in `f`, then write the updated
+/// trailer and footer. `index` and `trailer_offset` are what `read_index`
+/// returned for `f`. Returns the updated index.
+///
+/// The file stays readable while the layer is copied: a copy of the old
+/// trailer and footer is first written past where the new layer will end,
+/// so the footer at the end of the file always points at a complete index.
+/// Only the final trailer write, a few kilobytes, replaces it. If the copy
+/// is interrupted, the file keeps its old layers with unused bytes before
+/// the trailer, which the next append overwrites.
+pub(crate) fn commit_layer<S: StorageMut>(
+    f: &mut S,
+    mut index: TcowIndex,
+    trailer_offset: u64,
+    data: &mut dyn Read,
+    layer: NewLayer,
+) -> Result<TcowIndex> {
+    let old_end = f.seek(SeekFrom::End(0))?;
+    let layer_start = index.layers.last().map_or(HEADER_SIZE, |l| l.offset + l.size).min(trailer_offset);
+    let layer_end = layer_start + layer.size;
+
+    // Move the old trailer out of the new layer's way
+    let mut old_trailer = Vec::with_capacity((old_end - trailer_offset) as usize);
+    f.seek(SeekFrom::Start(trailer_offset))?;
+    Read::by_ref(f)
+        .take(old_end - FOOTER_SIZE - trailer_offset)
+        .read_to_end(&mut old_trailer)
+        .io_context(|| "reading CBOR trailer".into())?;
+    let moved_to = layer_end.max(old_end);
+    let moved_len = old_trailer.len() as u32;
+    write_trailer_footer(&mut old_trailer, moved_to, moved_len)?;
+    f.seek(SeekFrom::Start(moved_to))?;
+    f.write_all(&old_trailer)?;
+    f.flush()?;
+
+    f.seek(SeekFrom::Start(layer_start))?;
+    let copied = std::io::copy(&mut data.take(layer.size), f).io_context(|| "writing layer data".into())?;
+    if copied != layer.size {
+        return Err(TcowError::Invalid(format!(
+            "layer data ended after {copied} of {} bytes",
+            layer.size
+        )));
     }
+
+    let now = now_rfc3339();
+    index.layers.push(LayerRecord {
+        offset: layer_start,
+        size: layer.size,
+        kind: LayerKind::Delta,
+        digest: Some(layer.digest),
+        created_at: now.clone(),
+        file_digests: layer.file_digests,
+        extensions: Extensions::new(),
+    });
+    index.last_modified = now;
+
+    // Trailer and footer in one write, then drop whatever is left past them
+    let mut trailer = encode_cbor(&index)?;
+    let trailer_len = trailer.len() as u32;
+    write_trailer_footer(&mut trailer, layer_end, trailer_len)?;
+    f.seek(SeekFrom::Start(layer_end))?;
+    f.write_all(&trailer)?;
+    f.set_len(layer_end + trailer.len() as u64)?;
+    update_flags_after_append(f, layer.has_entries)?;
+    f.flush()?;
+    Ok(index)
 }
 
-/// `"data/.wh.records.db"` → `Some("data/records.db")`, or `None` if not a whiteout.
-pub fn from_whiteout_tar_path(path: &str) -> Option<String> {
-    let filename = path.split('/').next_back()?;
-    if filename.starts_with(".wh.") && !filename.starts_with(".wh..wh.") {
-        let real_name = &filename[4..];
-        if let Some(slash_pos) = path.rfind('/') {
-            Some(format!("{}/{}", &path[..slash_pos], real_name))
-        } else {
-            Some(real_name.to_string())
+/// Rewrite the header flags once a layer has been appended, as
+/// `FileHeader::flags_after_append` says.
+pub(crate) fn update_flags_after_append<S: StorageMut>(f: &mut S, layer_has_entries: bool) -> Result<()> {
+    let header = read_header(f, Path::new(""))?;
+    let flags = header.flags_after_append(layer_has_entries);
+    if flags != header.flags {
+        f.seek(SeekFrom::Start(6))?;
+        f.write_all(&flags.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Rewrite the `.tcow` file in `f` in the current format version. Layer
+/// data is left untouched: only the trailer and the header's version and
+/// `FLAG_HAS_ENTRIES` are rewritten. Returns the version the file had; a file already in the
+/// current format is not modified.
+pub fn upgrade_format<S: StorageMut>(f: &mut S) -> Result<u16> {
+    let header = read_header(f, Path::new(""))?;
+    let (mut index, trailer_offset) = read_index(f, Path::new(""))?;
+    if header.version == FORMAT_VERSION && index.version == FORMAT_VERSION {
+        return Ok(FORMAT_VERSION);
+    }
+    // v2 adds FLAG_HAS_ENTRIES, which covers every layer rather than just the base
+    let mut has_entries = false;
+    for rec in &index.layers {
+        f.seek(SeekFrom::Start(rec.offset))?;
+        if layer_has_entries(BufReader::new(Read::by_ref(f).take(rec.size)))? {
+            has_entries = true;
+            break;
         }
-    } else {
-        None
     }
+    let has_entries = if has_entries { FLAG_HAS_ENTRIES } else { 0 };
+    let upgraded =
+        FileHeader { version: FORMAT_VERSION, flags: (header.flags & !FLAG_HAS_ENTRIES) | has_entries, ..header };
+
+    index.version = FORMAT_VERSION;
+    index.last_modified = now_rfc3339();
+    // Trailer first: a v2 trailer under a v1 header still opens
+    write_trailer(f, trailer_offset, &index)?;
+    f.seek(SeekFrom::Start(0))?;
+    f.write_all(&upgraded.to_bytes())?;
+    f.flush()?;
+    Ok(header.version)
+}
+
+/// Whether the tar stream in `r` holds an entry `parse_layer` would keep.
+/// Bodies are read past, never held in memory.
+fn layer_has_entries(r: impl Read) -> Result<bool> {
+    let mut archive = tar::Archive::new(r);
+    for entry in archive.entries().io_context(|| "reading layer data".into())? {
+        let entry = entry.io_context(|| "reading tar entry".into())?;
+        let path = VPath::from_entry_name(&entry.path_bytes())?;
+        let kind = entry.header().entry_type();
+        if !path.is_root() && (kind.is_file() || kind.is_dir()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Read and check the header of an open `.tcow` file. `path` is only used
+/// in errors.
+pub fn read_header<S: Read + Seek + ?Sized>(f: &mut S, path: &Path) -> Result<FileHeader> {
+    f.seek(SeekFrom::Start(0))?;
+    let mut hdr = [0u8; 16];
+    match f.read_exact(&mut hdr) {
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Err(TcowError::BadMagic(path.to_path_buf()));
+        }
+        r => r.io_context(|| "reading TCOW file header".into())?,
+    }
+    FileHeader::parse(&hdr, path)
+}
+
+/// Validate the header and footer of an open `.tcow` file and decode its
+/// CBOR trailer. Returns the index and the trailer's byte offset, which is
+/// where the next layer will be written. `path` is only used in errors.
+pub fn read_index<S: Read + Seek + ?Sized>(f: &mut S, path: &Path) -> Result<(TcowIndex, u64)> {
+    f.seek(SeekFrom::Start(0))?;
+
+    read_header(f, path)?;
+
+    // Read footer (last 16 bytes)
+    let file_len = f.seek(SeekFrom::End(0))?;
+    if file_len < HEADER_SIZE + FOOTER_SIZE {
+        return Err(TcowError::TruncatedFooter("file too small to hold a footer".into()));
+    }
+    f.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+    let mut footer = [0u8; 16];
+    f.read_exact(&mut footer)?;
+    if &footer[12..16] != MAGIC_TAIL {
+        return Err(TcowError::TruncatedFooter("bad footer magic".into()));
+    }
+    let trailer_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+    let trailer_len = u32::from_le_bytes(footer[8..12].try_into().unwrap());
+    if trailer_offset < HEADER_SIZE || trailer_offset.saturating_add(trailer_len as u64) > file_len - FOOTER_SIZE {
+        return Err(TcowError::TruncatedFooter(format!(
+            "trailer at offset {trailer_offset} ({trailer_len} bytes) lies outside the file"
+        )));
+    }
+
+    // Parse CBOR trailer
+    f.seek(SeekFrom::Start(trailer_offset))?;
+    let mut cbor_bytes = vec![0u8; trailer_len as usize];
+    f.read_exact(&mut cbor_bytes).io_context(|| "reading CBOR trailer".into())?;
+    let index: TcowIndex =
+        ciborium::from_reader(Cursor::new(&cbor_bytes)).map_err(|e| TcowError::Cbor(e.to_string()))?;
+
+    Ok((index, trailer_offset))
+}
+
+// ── FileReader ────────────────────────────────────────────────────────────────
+
+/// A `Read + Seek` view of one file's bytes inside a `.tcow` file. Reads
+/// come straight from the file's loaded layer region (usually a memory
+/// mapping), so opening a handle copies nothing.
+pub struct FileReader {
+    data: Blob,
+    pos: u64,
+}
+
+impl FileReader {
+    fn new(data: Blob) -> Self {
+        FileReader { data, pos: 0 }
+    }
+
+    /// Size of the file's content in bytes.
+    pub fn len(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let rest = self.data.get(self.pos as usize..).unwrap_or_default();
+        let n = buf.len().min(rest.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for FileReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.len().checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = target.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before start of file")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+// ── Path helpers ──────────────────────────────────────────────────────────────
+
+/// Basename of a Docker opaque-whiteout marker entry.
+pub const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// `"data/records.db"` → `"data/.wh.records.db"` (tar entry name for whiteout)
+pub fn to_whiteout_tar_path(path: &VPath) -> String {
+    match path.rsplit_once('/') {
+        Some((dir, name)) => format!("{dir}/.wh.{name}"),
+        None => format!(".wh.{path}"),
+    }
+}
+
+/// `"data/.wh.records.db"` → `Some("data/records.db")`, or `None` if not a
+/// whiteout. A bare `.wh.` prefix with nothing usable after it is not one.
+pub fn from_whiteout_tar_path(path: &VPath) -> Option<VPath> {
+    let filename = path.file_name()?;
+    let real_name = filename.strip_prefix(".wh.")?;
+    if filename.starts_with(".wh..wh.") || matches!(real_name, "" | "." | "..") {
+        return None;
+    }
+    let dir_len = path.len() - filename.len();
+    Some(VPath::from_canonical(format!("{}{real_name}", &path[..dir_len])))
+}
+
+/// `"etc/.wh..wh..opq"` → `Some("etc")`, or `None` if not an opaque marker.
+/// A marker at the root (`".wh..wh..opq"`) yields the root.
+pub fn opaque_whiteout_dir(path: &VPath) -> Option<VPath> {
+    (path.file_name()? == OPAQUE_WHITEOUT).then(|| path.parent()).flatten()
 }
 
 // ── Tar helpers ───────────────────────────────────────────────────────────────
 
 /// Parse a raw ustar tar byte stream into a map of canonical_path → RawEntry.
-pub fn parse_tar_layer(data: &[u8]) -> Result<HashMap<String, RawEntry>> {
-    let mut entries: HashMap<String, RawEntry> = HashMap::new();
-    let cursor = Cursor::new(data);
-    let mut archive = tar::Archive::new(cursor);
+/// Layers written by other tools are accepted as well: `./` prefixes are
+/// stripped, opaque whiteouts mark their directory, and entries that are
+/// neither regular files nor directories (links, devices) are skipped.
+/// Entry names that are absolute, escape the root or are not UTF-8 are
+/// errors (see `VPath::from_entry_name`).
+pub fn parse_tar_layer(data: &[u8]) -> Result<HashMap<VPath, RawEntry>> {
+    parse_layer(&Blob::from(data.to_vec()))
+}
 
-    for entry_res in archive.entries()? {
-        let mut entry = entry_res.context("reading tar entry")?;
-        let raw_path = entry.path()?.to_string_lossy().to_string();
-        let path = raw_path.trim_start_matches('/').to_string();
+/// Like `parse_tar_layer`, but file contents are slices of `layer` rather
+/// than copies, and file bodies are seeked over instead of read.
+pub fn parse_layer(layer: &Blob) -> Result<HashMap<VPath, RawEntry>> {
+    // Reads come from memory, so an I/O error here means a malformed stream
+    parse_entries(layer).map_err(|e| match e {
+        TcowError::Io { context, source } => TcowError::corrupt(format!("{context}: {source}")),
+        other => other,
+    })
+}
 
-        let mtime = entry.header().mtime().unwrap_or(0);
-        let is_dir = entry.header().entry_type().is_dir();
+fn parse_entries(layer: &Blob) -> Result<HashMap<VPath, RawEntry>> {
+    let mut entries: HashMap<VPath, RawEntry> = HashMap::new();
+    let cursor = Cursor::new(layer.as_slice());
+    let mut archive = tar::Archive::new(cursor);
 
-        let mut data = Vec::new();
-        entry.read_to_end(&mut data)?;
+    for entry_res in archive.entries_with_seek()? {
+        let mut entry = entry_res.io_context(|| "reading tar entry".into())?;
+        let path = VPath::from_entry_name(&entry.path_bytes())?;
+        if path.is_root() {
+            continue;
+        }
 
-        if let Some(real_path) = from_whiteout_tar_path(&path) {
+        let mtime = entry.header().mtime().unwrap_or(0);
+        let entry_type = entry.header().entry_type();
+        let is_dir = entry_type.is_dir();
+
+        if let Some(dir) = opaque_whiteout_dir(&path) {
+            let marker = entries
+                .entry(dir)
+                .or_insert(RawEntry { is_dir: true, ..RawEntry::marker(mtime) });
+            marker.is_opaque = true;
+        } else if let Some(real_path) = from_whiteout_tar_path(&path) {
             // Whiteout: store under the real path with is_whiteout=true
             entries.insert(
                 real_path,
-                RawEntry { data: Vec::new(), mtime, is_whiteout: true, is_dir: false },
+                RawEntry { is_whiteout: true, ..RawEntry::marker(mtime) },
             );
-        } else {
-            entries.insert(path, RawEntry { data, mtime, is_whiteout: false, is_dir });
+        } else if is_dir {
+            let is_opaque = entries.get(&path).is_some_and(|e| e.is_opaque);
+            entries.insert(path, RawEntry { is_dir, is_opaque, ..RawEntry::marker(mtime) });
+        } else if entry_type.is_file() {
+            let data_offset = entry.raw_file_position();
+            if let Some(reference) = dedup::read_ref_entry(&mut entry)? {
+                let reference = Some(reference);
+                entries.insert(path, RawEntry { data_offset, reference, ..RawEntry::marker(mtime) });
+                continue;
+            }
+            let delta = delta::read_delta_entry(&mut entry)?;
+            let data = layer
+                .slice(data_offset..data_offset + entry.size())
+                .map_err(|_| TcowError::corrupt(format!("content of /{path} is truncated")))?;
+            entries.insert(path, RawEntry { data, data_offset, delta, ..RawEntry::marker(mtime) });
         }
     }
     Ok(entries)
 }
 
 /// Serialise a set of file entries + whiteout paths into a ustar tar byte stream.
-pub fn build_tar_layer(entries: &[(String, Vec<u8>)], whiteouts: &[String]) -> Result<Vec<u8>> {
+pub fn build_tar_layer(entries: &[(VPath, Vec<u8>)], whiteouts: &[VPath]) -> Result<Vec<u8>> {
+    build_layer(entries, whiteouts, &|_, _| None).map(|(buf, _)| buf)
+}
+
+/// How `TcowFile::append_with` may store file bodies.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AppendOptions {
+    /// Store content that already exists in the file as a reference.
+    pub dedup: bool,
+    /// Store large files as deltas against their visible lower version.
+    pub delta: bool,
+}
+
+/// How many files `TcowFile::append_with` stored compactly.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AppendReport {
+    pub deduped: usize,
+    pub deltas: usize,
+}
+
+/// A file body stored as something other than its full content.
+enum Body {
+    Ref(BlobRef),
+    Delta(DeltaRef, Vec<u8>),
+}
+
+fn build_layer(
+    entries: &[(VPath, Vec<u8>)],
+    whiteouts: &[VPath],
+    encode: &dyn Fn(&VPath, &[u8]) -> Option<Body>,
+) -> Result<(Vec<u8>, AppendReport)> {
     let mut buf = Vec::new();
+    let mut report = AppendReport::default();
     {
         let mut builder = tar::Builder::new(&mut buf);
         let ts = now_unix_ts();
 
-        for (vpath, data) in entries {
-            let path = normalize_path(vpath);
+        for (path, data) in entries {
+            path.check_writable()?;
+            match encode(path, data) {
+                Some(Body::Ref(blob)) => {
+                    dedup::append_ref_entry(&mut builder, path, &blob, ts)?;
+                    report.deduped += 1;
+                    continue;
+                }
+                Some(Body::Delta(delta, patch)) => {
+                    delta::append_delta_entry(&mut builder, path, &delta, &patch, ts)?;
+                    report.deltas += 1;
+                    continue;
+                }
+                None => {}
+            }
             let mut hdr = tar::Header::new_ustar();
-            hdr.set_path(&path)?;
+            hdr.set_path(path.as_str())?;
             hdr.set_size(data.len() as u64);
             hdr.set_mtime(ts);
             hdr.set_mode(0o644);
@@ -363,9 +1298,9 @@ pub fn build_tar_layer(entries: &[(String, Vec<u8>)], whiteouts: &[String]) -> R
             builder.append(&hdr, Cursor::new(data))?;
         }
 
-        for canonical in whiteouts {
-            let canonical = normalize_path(canonical);
-            let wh_path = to_whiteout_tar_path(&canonical);
+        for path in whiteouts {
+            path.check_writable()?;
+            let wh_path = to_whiteout_tar_path(path);
             let mut hdr = tar::Header::new_ustar();
             hdr.set_path(&wh_path)?;
             hdr.set_size(0);
@@ -377,28 +1312,369 @@ pub fn build_tar_layer(entries: &[(String, Vec<u8>)], whiteouts: &[String]) -> R
 
         builder.finish()?;
     }
-    Ok(buf)
+    Ok((buf, report))
+}
+
+// ── Archive import ────────────────────────────────────────────────────────────
+
+/// What importing an external archive (tar, zip, cpio) puts in a layer,
+/// found by a first pass over the archive that reads no file bodies. A
+/// second pass (`import_tar_archive` and its zip and cpio counterparts)
+/// streams the bodies into a `LayerWriter`, so an archive of any size is
+/// imported without holding its files in memory.
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveImport {
+    /// The last entry for each path, in archive order. Earlier entries for
+    /// the same path are replaced, as `tar -x` would.
+    pub entries: Vec<ImportEntry>,
+    /// Entries that cannot be represented in a layer (links, devices, …).
+    pub skipped: Vec<String>,
+    /// Metadata that was dropped on the way in, one note per affected path.
+    pub lossy: Vec<String>,
+}
+
+/// One entry an import writes into the layer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportEntry {
+    /// Index of the entry in the archive, counting every entry.
+    pub position: usize,
+    /// Canonical path: the file, the path a whiteout deletes, or the
+    /// directory an opaque marker covers.
+    pub path: VPath,
+    pub mtime: u64,
+    pub kind: ImportKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportKind {
+    /// A regular file of `size` bytes.
+    File { size: u64 },
+    /// A Docker-style `.wh.` entry deleting the path from lower layers.
+    Whiteout,
+    /// A `.wh..wh..opq` marker hiding the directory's lower-layer contents.
+    Opaque,
+}
+
+impl ArchiveImport {
+    pub fn files(&self) -> usize {
+        self.entries.iter().filter(|e| matches!(e.kind, ImportKind::File { .. })).count()
+    }
+
+    /// Whiteouts and opaque markers.
+    pub fn whiteouts(&self) -> usize {
+        self.entries.len() - self.files()
+    }
+
+    /// Total size of the files.
+    pub fn bytes(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|e| match e.kind {
+                ImportKind::File { size } => size,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// The entry kept for archive entry `position`, if it is the last one
+    /// for its path.
+    pub(crate) fn at(&self, position: usize) -> Option<&ImportEntry> {
+        self.entries
+            .binary_search_by_key(&position, |e| e.position)
+            .ok()
+            .map(|i| &self.entries[i])
+    }
+}
+
+/// Collects an `ArchiveImport` during the first pass.
+#[derive(Default)]
+pub(crate) struct ImportScan {
+    /// By the tar name the entry is written under, so a file and a
+    /// whiteout for the same path replace each other.
+    kept: HashMap<String, ImportEntry>,
+    pub skipped: Vec<String>,
+    pub lossy: Vec<String>,
+}
+
+impl ImportScan {
+    /// Record archive entry `position` named `path`. Directories are
+    /// implied by file paths and ignored; `.wh.` names become whiteouts or
+    /// opaque markers; other special files are skipped and reported.
+    pub fn add(&mut self, position: usize, path: VPath, mtime: u64, file: Option<(u64, u32)>) {
+        let (path, kind) = if let Some(dir) = opaque_whiteout_dir(&path) {
+            (dir, ImportKind::Opaque)
+        } else if let Some(real_path) = from_whiteout_tar_path(&path) {
+            (real_path, ImportKind::Whiteout)
+        } else if let (Some((size, mode)), Ok(())) = (file, path.check_writable()) {
+            if let Some(note) = mode_loss(&path, mode) {
+                self.lossy.push(note);
+            }
+            (path, ImportKind::File { size })
+        } else {
+            self.skipped.push(path.to_string());
+            return;
+        };
+        let key = match kind {
+            ImportKind::Opaque => format!("{path}/{OPAQUE_WHITEOUT}"),
+            _ => path.to_string(),
+        };
+        self.kept.insert(key, ImportEntry { position, path, mtime, kind });
+    }
+
+    pub fn finish(self) -> ArchiveImport {
+        let mut entries: Vec<ImportEntry> = self.kept.into_values().collect();
+        entries.sort_by_key(|e| e.position);
+        ArchiveImport { entries, skipped: self.skipped, lossy: self.lossy }
+    }
+}
+
+/// Second-pass bookkeeping: checks that each entry the first pass kept is
+/// met again, at the same position, and written exactly once.
+pub(crate) struct ImportPass<'a> {
+    import: &'a ArchiveImport,
+    written: usize,
+}
+
+impl<'a> ImportPass<'a> {
+    pub fn new(import: &'a ArchiveImport) -> Self {
+        ImportPass { import, written: 0 }
+    }
+
+    /// The entry to write for archive entry `position`, found under `path`
+    /// (whiteouts and opaque markers by their tar name), or `None` if it
+    /// was replaced or skipped.
+    pub fn entry(&mut self, position: usize, path: &VPath) -> Result<Option<&'a ImportEntry>> {
+        let Some(e) = self.import.at(position) else { return Ok(None) };
+        let expected = match e.kind {
+            ImportKind::File { .. } => e.path.clone(),
+            ImportKind::Whiteout => VPath::from_canonical(to_whiteout_tar_path(&e.path)),
+            ImportKind::Opaque => e.path.join(OPAQUE_WHITEOUT)?,
+        };
+        if *path != expected {
+            return Err(archive_changed());
+        }
+        self.written += 1;
+        Ok(Some(e))
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if self.written != self.import.entries.len() {
+            return Err(archive_changed());
+        }
+        Ok(())
+    }
+}
+
+fn archive_changed() -> TcowError {
+    TcowError::Invalid("the archive changed while it was being imported".into())
+}
+
+/// Write `e` through `writer`, streaming a file's body from `body`.
+pub(crate) fn write_import_entry<S: StorageMut>(
+    writer: &mut LayerWriter<S>,
+    e: &ImportEntry,
+    body: impl Read,
+) -> Result<()> {
+    writer.set_mtime(e.mtime);
+    match e.kind {
+        ImportKind::File { size } => writer.add_file(&e.path, body, size),
+        ImportKind::Whiteout => writer.add_whiteout(&e.path),
+        ImportKind::Opaque => writer.add_opaque(&e.path),
+    }
+}
+
+/// Open an archive on disk, transparently decompressing gzip or zstd based on
+/// the stream's magic bytes.
+pub fn open_archive_reader(path: impl AsRef<Path>) -> Result<Box<dyn Read>> {
+    let path = path.as_ref();
+    let f = File::open(path).io_context(|| format!("cannot open {:?}", path))?;
+    decompressing_reader(BufReader::new(f))
+}
+
+/// Wrap `r` in a gzip or zstd decoder when its first bytes carry that format's
+/// magic; otherwise return it unchanged.
+pub fn decompressing_reader<R: BufRead + 'static>(mut r: R) -> Result<Box<dyn Read>> {
+    let magic = r.fill_buf()?;
+    let reader: Box<dyn Read> = if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::GzDecoder::new(r))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(zstd::stream::read::Decoder::with_buffer(r)?)
+    } else {
+        Box::new(r)
+    };
+    Ok(reader)
+}
+
+/// First pass over a tar stream produced by other tooling. Leading `/` and
+/// `./` are stripped, names that escape the root or are not UTF-8 are
+/// errors, `.wh.` entries become whiteouts and opaque markers, and a later
+/// entry for the same path replaces an earlier one, as `tar -x` would.
+/// Files keep their mtime; modes other than 0644 are reported as lossy.
+pub fn scan_tar_archive(r: impl Read) -> Result<ArchiveImport> {
+    let mut scan = ImportScan::default();
+    let mut archive = tar::Archive::new(r);
+    for (position, entry_res) in archive.entries()?.enumerate() {
+        let entry = entry_res.io_context(|| "reading tar entry".into())?;
+        let path = archive_entry_path(&entry.path_bytes())?;
+        let header = entry.header();
+        if path.is_root() || header.entry_type().is_dir() {
+            continue;
+        }
+        let mtime = header.mtime().unwrap_or_else(|_| {
+            scan.lossy.push(format!("/{path}: unreadable mtime stored as 0"));
+            0
+        });
+        let file = header
+            .entry_type()
+            .is_file()
+            .then(|| (entry.size(), header.mode().unwrap_or(0o644) & 0o7777));
+        scan.add(position, path, mtime, file);
+    }
+    Ok(scan.finish())
+}
+
+/// Second pass: stream the entries `import` kept from the same tar stream
+/// into `writer`. Fails if the stream no longer matches the first pass.
+pub fn import_tar_archive<S: StorageMut>(
+    r: impl Read,
+    import: &ArchiveImport,
+    writer: &mut LayerWriter<S>,
+) -> Result<()> {
+    let mut pass = ImportPass::new(import);
+    let mut archive = tar::Archive::new(r);
+    for (position, entry_res) in archive.entries()?.enumerate() {
+        let entry = entry_res.io_context(|| "reading tar entry".into())?;
+        let path = archive_entry_path(&entry.path_bytes())?;
+        if let Some(e) = pass.entry(position, &path)? {
+            write_import_entry(writer, e, entry)?;
+        }
+    }
+    pass.finish()
+}
+
+/// Layers store every file as mode 0644; describe the loss for any other mode.
+pub(crate) fn mode_loss(path: &str, mode: u32) -> Option<String> {
+    (mode != 0o644).then(|| format!("/{path}: mode {mode:04o} stored as 0644"))
+}
+
+/// Canonical path for an entry name taken from a foreign archive. Leading
+/// `/` is stripped, as `tar -x` does; anything else `VPath::from_entry_name`
+/// rejects is an error naming the entry.
+pub(crate) fn archive_entry_path(raw: &[u8]) -> Result<VPath> {
+    let start = raw.iter().take_while(|&&b| b == b'/').count();
+    Ok(VPath::from_entry_name(&raw[start..])?)
+}
+
+// ── Archive output ────────────────────────────────────────────────────────────
+
+/// Compression applied to an exported archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Pick a compression from an output file name (`.gz`/`.tgz`, `.zst`/`.tzst`).
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz" | "tgz") => Compression::Gzip,
+            Some("zst" | "tzst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// A writer that optionally compresses everything written through it.
+/// `finish` must be called to flush the compressed stream's trailer.
+pub enum ArchiveWriter<W: Write> {
+    Plain(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(w: W, compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::None => ArchiveWriter::Plain(w),
+            Compression::Gzip => {
+                ArchiveWriter::Gzip(flate2::write::GzEncoder::new(w, flate2::Compression::default()))
+            }
+            Compression::Zstd => ArchiveWriter::Zstd(zstd::stream::write::Encoder::new(w, 0)?),
+        })
+    }
+
+    pub fn finish(self) -> Result<W> {
+        let mut w = match self {
+            ArchiveWriter::Plain(w) => w,
+            ArchiveWriter::Gzip(enc) => enc.finish()?,
+            ArchiveWriter::Zstd(enc) => enc.finish()?,
+        };
+        w.flush()?;
+        Ok(w)
+    }
+}
+
+impl<W: Write> Write for ArchiveWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ArchiveWriter::Plain(w) => w.write(buf),
+            ArchiveWriter::Gzip(w) => w.write(buf),
+            ArchiveWriter::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ArchiveWriter::Plain(w) => w.flush(),
+            ArchiveWriter::Gzip(w) => w.flush(),
+            ArchiveWriter::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+/// Append one regular-file entry with the given mtime to a tar builder.
+fn append_tar_file<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+    mtime: u64,
+) -> Result<()> {
+    let mut hdr = tar::Header::new_ustar();
+    hdr.set_path(path)?;
+    hdr.set_size(data.len() as u64);
+    hdr.set_mtime(mtime);
+    hdr.set_mode(0o644);
+    hdr.set_cksum();
+    builder.append(&hdr, data)?;
+    Ok(())
 }
 
 // ── CBOR helpers ──────────────────────────────────────────────────────────────
 
 pub fn encode_cbor(index: &TcowIndex) -> Result<Vec<u8>> {
     let mut buf = Vec::new();
-    ciborium::into_writer(index, &mut buf)
-        .map_err(|e| anyhow!("CBOR encode error: {e}"))?;
+    ciborium::into_writer(index, &mut buf).map_err(|e| TcowError::Cbor(format!("encode error: {e}")))?;
     Ok(buf)
 }
 
 // ── Binary format helpers ─────────────────────────────────────────────────────
 
 pub fn write_file_header(w: &mut impl Write, has_base: bool) -> Result<()> {
-    let mut hdr = [0u8; 16];
-    hdr[0..4].copy_from_slice(MAGIC);
-    hdr[4..6].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
-    let flags: u16 = if has_base { FLAG_HAS_BASE } else { 0 };
-    hdr[6..8].copy_from_slice(&flags.to_le_bytes());
-    // bytes 8..16 are reserved zeros
-    w.write_all(&hdr)?;
+    w.write_all(&FileHeader::new(has_base).to_bytes())?;
+    Ok(())
+}
+
+/// Cut `f` back to `offset` and write `index` there as the trailer,
+/// followed by the footer.
+pub fn write_trailer<S: StorageMut>(f: &mut S, offset: u64, index: &TcowIndex) -> Result<()> {
+    f.set_len(offset)?;
+    f.seek(SeekFrom::Start(offset))?;
+    let cbor_bytes = encode_cbor(index)?;
+    f.write_all(&cbor_bytes)?;
+    write_trailer_footer(f, offset, cbor_bytes.len() as u32)?;
+    f.flush()?;
     Ok(())
 }
 
@@ -419,6 +1695,35 @@ pub fn sha256_hex(data: &[u8]) -> String {
     hex::encode(hasher.finalize())
 }
 
+/// Pass-through writer that computes the SHA-256 of everything written.
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        HashingWriter { inner, hasher: Sha256::new() }
+    }
+
+    /// Return the inner writer and the hex digest of the bytes written.
+    pub fn finish(self) -> (W, String) {
+        (self.inner, hex::encode(self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 // ── Timestamp utilities ───────────────────────────────────────────────────────
 
 pub fn now_rfc3339() -> String {
@@ -445,3 +1750,160 @@ pub fn format_bytes(n: u64) -> String {
         format!("{:.1} MiB", n as f64 / (1024.0 * 1024.0))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(names: &[&str]) -> Vec<(VPath, Vec<u8>)> {
+        names.iter().map(|n| (VPath::new(n).unwrap(), n.as_bytes().to_vec())).collect()
+    }
+
+    fn content(tcow: &TcowFile<impl Storage>, path: &str) -> Vec<u8> {
+        tcow.lookup(&VPath::new(path).unwrap()).unwrap().0.data.to_vec()
+    }
+
+    /// A `.tcow` file as v1 wrote it: no per-file digests, no extensions
+    /// and no `FLAG_HAS_ENTRIES`, with an empty base when `base` is empty.
+    fn v1_file(base: &[&str]) -> Vec<u8> {
+        let layer = build_tar_layer(&files(base), &[]).unwrap();
+        let index = TcowIndex {
+            version: 1,
+            layers: vec![LayerRecord {
+                offset: HEADER_SIZE,
+                size: layer.len() as u64,
+                kind: LayerKind::Base,
+                digest: Some(sha256_hex(&layer)),
+                created_at: "2024-01-01T00:00:00Z".into(),
+                file_digests: BTreeMap::new(),
+                extensions: Extensions::new(),
+            }],
+            last_modified: "2024-01-01T00:00:00Z".into(),
+            label: Some("old".into()),
+            extensions: Extensions::new(),
+        };
+        let flags = if base.is_empty() { 0 } else { FLAG_HAS_BASE };
+        let mut buf = FileHeader { version: 1, flags, reserved: [0; 8] }.to_bytes().to_vec();
+        buf.extend_from_slice(&layer);
+        let cbor = encode_cbor(&index).unwrap();
+        buf.extend_from_slice(&cbor);
+        write_trailer_footer(&mut buf, HEADER_SIZE + layer.len() as u64, cbor.len() as u32).unwrap();
+        buf
+    }
+
+    #[test]
+    fn a_v1_file_opens_appends_and_upgrades() {
+        let mut tcow = TcowFile::from_storage(Cursor::new(v1_file(&["a.txt"]))).unwrap();
+        assert_eq!((tcow.header.version, tcow.index.version), (1, 1));
+        assert_eq!(content(&tcow, "a.txt"), b"a.txt");
+
+        // Appending keeps the file in v1, flags included
+        tcow.append(&files(&["b.txt"]), &[]).unwrap();
+        assert_eq!((tcow.header.version, tcow.header.flags), (1, FLAG_HAS_BASE));
+        let layers: Vec<_> = (0..2).map(|i| tcow.layer_bytes(i).unwrap().to_vec()).collect();
+
+        let mut storage = tcow.into_storage();
+        assert_eq!(upgrade_format(&mut storage).unwrap(), 1);
+        let tcow = TcowFile::from_storage(storage).unwrap();
+        assert_eq!((tcow.header.version, tcow.index.version), (FORMAT_VERSION, FORMAT_VERSION));
+        assert_eq!(tcow.header.flags, FLAG_HAS_BASE | FLAG_HAS_ENTRIES);
+        assert_eq!(tcow.index.label.as_deref(), Some("old"));
+        for (i, bytes) in layers.iter().enumerate() {
+            assert_eq!(&tcow.layer_bytes(i).unwrap()[..], &bytes[..], "layer {i} is left untouched");
+        }
+        assert_eq!(content(&tcow, "a.txt"), b"a.txt");
+        assert_eq!(content(&tcow, "b.txt"), b"b.txt");
+
+        // A second upgrade has nothing to do
+        let mut storage = tcow.into_storage();
+        let before = storage.get_ref().clone();
+        assert_eq!(upgrade_format(&mut storage).unwrap(), FORMAT_VERSION);
+        assert_eq!(storage.into_inner(), before);
+    }
+
+    #[test]
+    fn upgrade_sets_has_entries_only_when_some_layer_holds_entries() {
+        let mut empty = Cursor::new(v1_file(&[]));
+        upgrade_format(&mut empty).unwrap();
+        assert_eq!(TcowFile::from_storage(empty).unwrap().header.flags, 0);
+
+        let mut tcow = TcowFile::from_storage(Cursor::new(v1_file(&[]))).unwrap();
+        tcow.append(&files(&["late.txt"]), &[]).unwrap();
+        let mut storage = tcow.into_storage();
+        upgrade_format(&mut storage).unwrap();
+        let tcow = TcowFile::from_storage(storage).unwrap();
+        assert_eq!(tcow.header.flags, FLAG_HAS_ENTRIES);
+        assert_eq!(content(&tcow, "late.txt"), b"late.txt");
+    }
+
+    #[test]
+    fn unknown_extension_keys_are_decoded_and_kept() {
+        let tcow = TcowFile::create_in(Cursor::new(Vec::new()), &files(&["a.txt"]), &[], None).unwrap();
+        let (mut index, mut storage) = (tcow.index.clone(), tcow.into_storage());
+        let value = ciborium::Value::Map(vec![(
+            ciborium::Value::Text("level".into()),
+            ciborium::Value::Integer(3.into()),
+        )]);
+        index.extensions.insert("x-future".into(), value.clone());
+        index.layers[0].extensions.insert("x-layer".into(), ciborium::Value::Bytes(vec![1, 2, 3]));
+        let trailer_offset = HEADER_SIZE + index.layers[0].size;
+        write_trailer(&mut storage, trailer_offset, &index).unwrap();
+
+        let mut tcow = TcowFile::from_storage(storage).unwrap();
+        assert_eq!(tcow.index.extensions.get("x-future"), Some(&value));
+        assert_eq!(content(&tcow, "a.txt"), b"a.txt");
+
+        // Writers that do not know the keys carry them over
+        tcow.append(&files(&["b.txt"]), &[]).unwrap();
+        let tcow = TcowFile::from_storage(tcow.into_storage()).unwrap();
+        assert_eq!(tcow.index.extensions.get("x-future"), Some(&value));
+        assert_eq!(tcow.index.layers[0].extensions.get("x-layer"), Some(&ciborium::Value::Bytes(vec![1, 2, 3])));
+        assert!(tcow.index.layers[1].extensions.is_empty());
+    }
+
+    fn with_flags(mut file: Vec<u8>, flags: u16) -> Cursor<Vec<u8>> {
+        file[6..8].copy_from_slice(&flags.to_le_bytes());
+        Cursor::new(file)
+    }
+
+    #[test]
+    fn unknown_required_flags_are_refused_and_optional_ones_accepted() {
+        let file = TcowFile::create_in(Cursor::new(Vec::new()), &files(&["a.txt"]), &[], None).unwrap();
+        let (flags, file) = (file.header.flags, file.into_storage().into_inner());
+
+        for required in [0x0200, FLAG_COMPRESSED, 0x8000] {
+            let err = TcowFile::from_storage(with_flags(file.clone(), flags | required)).err().unwrap();
+            assert!(matches!(err, TcowError::UnsupportedFlags(f) if f == required), "{required:#06x}: {err}");
+        }
+
+        let mut tcow = TcowFile::from_storage(with_flags(file, flags | 0x0040)).unwrap();
+        assert_eq!(content(&tcow, "a.txt"), b"a.txt");
+        // An append cannot vouch for an optional flag it does not know, so drops it
+        tcow.append(&files(&["b.txt"]), &[]).unwrap();
+        assert_eq!(tcow.header.flags, FLAG_HAS_BASE | FLAG_HAS_ENTRIES);
+    }
+
+    #[test]
+    fn has_base_and_has_entries_follow_the_layers() {
+        let flags = |tcow: &TcowFile<Cursor<Vec<u8>>>| tcow.header.flags & FLAGS_MAINTAINED;
+
+        let mut tcow = TcowFile::create_in(Cursor::new(Vec::new()), &[], &[], None).unwrap();
+        assert_eq!(flags(&tcow), 0);
+        tcow.append(&[], &[]).unwrap();
+        assert_eq!(flags(&tcow), 0, "an empty layer adds no entries");
+        tcow.append(&[], &[VPath::new("gone").unwrap()]).unwrap();
+        assert_eq!(flags(&tcow), FLAG_HAS_ENTRIES, "a whiteout is an entry");
+        tcow.append(&[], &[]).unwrap();
+        assert_eq!(flags(&tcow), FLAG_HAS_ENTRIES, "HAS_ENTRIES is never cleared");
+
+        let mut tcow = TcowFile::create_in(Cursor::new(Vec::new()), &files(&["a.txt"]), &[], None).unwrap();
+        assert_eq!(flags(&tcow), FLAG_HAS_BASE | FLAG_HAS_ENTRIES);
+        tcow.append(&[], &[VPath::new("a.txt").unwrap()]).unwrap();
+        assert_eq!(flags(&tcow), FLAG_HAS_BASE | FLAG_HAS_ENTRIES, "HAS_BASE is about the base alone");
+
+        // Upgrading a current file leaves the flags alone
+        let mut storage = tcow.into_storage();
+        upgrade_format(&mut storage).unwrap();
+        assert_eq!(read_header(&mut storage, Path::new("")).unwrap().flags, FLAG_HAS_BASE | FLAG_HAS_ENTRIES);
+    }
+}
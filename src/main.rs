@@ -1,14 +1,21 @@
+use std::collections::HashMap;
 use std::fs;
-use std::io::{self, Read, Write};
-use std::path::PathBuf;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use anyhow::{bail, Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
+use tcow::find::{self, Bounds, EntryKind, Query};
+use tcow::grep::{self, GrepOptions};
+use tcow::oci::OciExportOptions;
+use tcow::vfs::{self, Change};
 use tcow::{
-    encode_cbor, format_bytes, normalize_path, now_rfc3339,
-    sha256_hex, unix_ts_to_rfc3339, write_trailer_footer,
-    TcowFile, TcowIndex,
+    format_bytes, now_rfc3339, read_header, read_index,
+    unix_ts_to_rfc3339, upgrade_format, write_trailer,
+    AppendOptions, ArchiveWriter, Blob, BlobRef, Compression, DeltaRef, FileStorage, HashingWriter, HostDir, LayerWriter,
+    PathError, PathFilter, RawEntry, ReadMode, TcowError, TcowFile, TcowIndex, VPath, Vfs, WalkOptions, FORMAT_VERSION,
 };
 
 // ── CLI definition ────────────────────────────────────────────────────────────
@@ -35,7 +42,7 @@ enum Commands {
     #[command(name = "ls")]
     List {
         file: PathBuf,
-        /// Only list entries under this virtual directory prefix
+        /// Only list this virtual directory (or file) and what lies beneath it
         path: Option<String>,
         /// Restrict listing to this layer index only (0-based)
         #[arg(short, long, value_name = "N")]
@@ -49,6 +56,18 @@ enum Commands {
         /// Include whiteout (deletion marker) entries
         #[arg(long)]
         show_whiteouts: bool,
+        /// Descend at most N levels below PATH, listing directories too
+        #[arg(long, value_name = "N")]
+        depth: Option<usize>,
+        /// List only directories
+        #[arg(short = 'd', long)]
+        dirs_only: bool,
+        /// Only list names matching this glob (whole paths if it contains `/`)
+        #[arg(short, long, value_name = "PATTERN", conflicts_with = "regex")]
+        glob: Option<String>,
+        /// Only list paths matching this regex
+        #[arg(short, long, value_name = "PATTERN")]
+        regex: Option<String>,
     },
 
     /// Print the contents of a file from the virtual filesystem to stdout
@@ -69,6 +88,73 @@ enum Commands {
         json: bool,
     },
 
+    /// Find entries by name, size, mtime, layer, type or content hash
+    Find {
+        file: PathBuf,
+        /// Only search this virtual directory (or file) [default: /]
+        path: Option<String>,
+        /// Search every entry stored in every layer, including hidden ones and whiteouts
+        #[arg(short = 'a', long)]
+        all_layers: bool,
+        /// Name glob (matched against whole paths if it contains `/`)
+        #[arg(long, value_name = "GLOB")]
+        name: Option<String>,
+        /// Size range in bytes, e.g. `1M..`, `..4k`, `100..200` or `0`
+        #[arg(long, value_name = "RANGE")]
+        size: Option<String>,
+        /// Mtime range of RFC 3339 times, YYYY-MM-DD dates or Unix seconds
+        #[arg(long, value_name = "RANGE")]
+        mtime: Option<String>,
+        /// Layer range, e.g. `5..9`, `3..` or `2`
+        #[arg(long, value_name = "RANGE")]
+        layer: Option<String>,
+        /// Entry type
+        #[arg(long = "type", value_enum, value_name = "TYPE")]
+        kind: Option<FindType>,
+        /// Files whose content SHA-256 starts with this hex prefix
+        #[arg(long, value_name = "HEX")]
+        sha256: Option<String>,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Search file contents by regex, in the union view or across every stored version
+    Grep {
+        file: PathBuf,
+        /// Regular expression to search for
+        pattern: String,
+        /// Only search this virtual directory (or file) [default: /]
+        path: Option<String>,
+        /// Search every version stored in every layer and show which layer matched
+        #[arg(long)]
+        history: bool,
+        /// Only search files whose names match this glob (whole paths if it contains `/`)
+        #[arg(short, long, value_name = "GLOB")]
+        glob: Option<String>,
+        /// Match case-insensitively
+        #[arg(short, long)]
+        ignore_case: bool,
+        /// Lines of context after each match
+        #[arg(short = 'A', long, value_name = "N")]
+        after_context: Option<usize>,
+        /// Lines of context before each match
+        #[arg(short = 'B', long, value_name = "N")]
+        before_context: Option<usize>,
+        /// Lines of context before and after each match
+        #[arg(short = 'C', long, value_name = "N")]
+        context: Option<usize>,
+        /// Search binary files as text instead of skipping them
+        #[arg(short = 'a', long)]
+        text: bool,
+        /// Print only the paths of files with matches
+        #[arg(short = 'l', long, conflicts_with = "count")]
+        files_with_matches: bool,
+        /// Print only the number of matching lines in each file
+        #[arg(short, long)]
+        count: bool,
+    },
+
     /// Add or replace a file in a new delta layer (creates .tcow if absent)
     Insert {
         file: PathBuf,
@@ -76,6 +162,12 @@ enum Commands {
         vpath: String,
         /// Source file to read from (default: stdin)
         source: Option<PathBuf>,
+        /// Store a reference instead of a copy if the content already exists
+        #[arg(long)]
+        dedup: bool,
+        /// Store a binary delta against the current version if it is much smaller
+        #[arg(long)]
+        delta: bool,
         /// Do not modify the file — only show what would happen
         #[arg(long)]
         dry_run: bool,
@@ -94,13 +186,13 @@ enum Commands {
         file: PathBuf,
         /// Host directory to write into (created if absent)
         outdir: PathBuf,
-        /// Only extract entries under this virtual path prefix
+        /// Only extract this file, or this directory and everything under it
         #[arg(short = 'p', long, value_name = "VPATH")]
         vpath: Option<String>,
         /// Extract from a specific layer only (bypasses union view)
         #[arg(short, long, value_name = "N")]
         layer: Option<usize>,
-        /// Strip this virtual prefix before writing to OUTDIR
+        /// Strip this virtual directory before writing to OUTDIR
         #[arg(long, value_name = "PREFIX")]
         strip_prefix: Option<String>,
         #[arg(long)]
@@ -128,12 +220,36 @@ enum Commands {
         dry_run: bool,
     },
 
+    /// Rewrite a file from an older format version in the current one (layer data is untouched)
+    Upgrade {
+        file: PathBuf,
+        #[arg(long)]
+        dry_run: bool,
+    },
+
     /// Check integrity of all layer digests stored in the CBOR trailer
     Verify {
         file: PathBuf,
-        /// Compute and write digests for layers that currently have none
+        /// Also check every file against its recorded digest and name the damaged paths
+        #[arg(long)]
+        deep: bool,
+        /// Compute and write digests for layers and files that currently have none
         #[arg(long)]
         fix_missing: bool,
+        /// Output a JSON report with per-layer status
+        #[arg(long)]
+        json: bool,
+        /// Number of layers to hash at once [default: one per CPU]
+        #[arg(short, long, value_name = "N")]
+        jobs: Option<usize>,
+    },
+
+    /// Check the file's structure: header, layer extents, tar framing and entry paths
+    Fsck {
+        file: PathBuf,
+        /// Output the findings as JSON
+        #[arg(long)]
+        json: bool,
     },
 
     /// List all layers with byte offsets and sizes
@@ -143,28 +259,343 @@ enum Commands {
         #[arg(long)]
         json: bool,
     },
+
+    /// Compare two sources, each a .tcow file or a host directory
+    Diff {
+        /// Old side: a .tcow file or a host directory
+        old: PathBuf,
+        /// New side: a .tcow file or a host directory
+        new: PathBuf,
+        /// Read OLD as its union view was at this layer index
+        #[arg(long, value_name = "N")]
+        old_at: Option<usize>,
+        /// Read NEW as its union view was at this layer index
+        #[arg(long, value_name = "N")]
+        new_at: Option<usize>,
+        /// Only compare this virtual directory on both sides
+        #[arg(short = 'p', long, value_name = "VPATH")]
+        vpath: Option<String>,
+        /// Hash every file on both sides instead of trusting size and mtime
+        #[arg(long)]
+        checksum: bool,
+    },
+
+    /// Capture changes from a host directory as a single new delta layer
+    Sync {
+        file: PathBuf,
+        /// Host directory to compare against the union view
+        dir: PathBuf,
+        /// Virtual directory that DIR maps onto [default: /]
+        #[arg(short = 'p', long, value_name = "VPATH")]
+        vpath: Option<String>,
+        /// Hash every file on both sides instead of trusting size and mtime
+        #[arg(long)]
+        checksum: bool,
+        /// Store references instead of copies for content that already exists
+        #[arg(long)]
+        dedup: bool,
+        /// Store changed large files as binary deltas against their current version
+        #[arg(long)]
+        delta: bool,
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Append a tar archive (plain, gzip or zstd) as a new layer (creates .tcow if absent)
+    #[command(name = "import-tar")]
+    ImportTar {
+        file: PathBuf,
+        /// Tar archive to import (.tar, .tar.gz, .tar.zst)
+        archive: PathBuf,
+        /// Label to record when creating a new .tcow file
+        #[arg(long)]
+        label: Option<String>,
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Append a zip archive as a new layer (creates .tcow if absent)
+    #[command(name = "import-zip")]
+    ImportZip {
+        file: PathBuf,
+        /// Zip archive to import
+        archive: PathBuf,
+        /// Label to record when creating a new .tcow file
+        #[arg(long)]
+        label: Option<String>,
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Append a newc cpio archive (plain, gzip or zstd) as a new layer (creates .tcow if absent)
+    #[command(name = "import-cpio")]
+    ImportCpio {
+        file: PathBuf,
+        /// cpio archive to import (.cpio, .cpio.gz, .cpio.zst)
+        archive: PathBuf,
+        /// Label to record when creating a new .tcow file
+        #[arg(long)]
+        label: Option<String>,
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Write the union view (or a single layer) to a standalone tar, zip or cpio archive
+    Export {
+        file: PathBuf,
+        /// Output archive; .gz or .zst selects compression [default: stdout]
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+        /// Archive format [default: from the output name, else tar]
+        #[arg(long, value_enum)]
+        format: Option<ExportFormat>,
+        /// Export the union view as it was at this layer index
+        #[arg(long, value_name = "N", conflicts_with = "layer")]
+        at: Option<usize>,
+        /// Export only the entries of this layer, including whiteouts
+        #[arg(short, long, value_name = "N")]
+        layer: Option<usize>,
+        /// With --layer: copy the stored tar bytes unchanged
+        #[arg(long, requires = "layer")]
+        raw: bool,
+        /// With --raw: copy a layer even if it refers to content in lower layers
+        #[arg(long, requires = "raw")]
+        force: bool,
+    },
+
+    /// Write an OCI image layout directory with one image layer per tcow layer
+    #[command(name = "export-oci")]
+    ExportOci {
+        file: PathBuf,
+        /// Output directory for the image layout (created if absent)
+        dir: PathBuf,
+        /// Reference name recorded in index.json
+        #[arg(long, default_value = "latest")]
+        tag: String,
+        /// Image operating system
+        #[arg(long, default_value = "linux")]
+        os: String,
+        /// Image architecture
+        #[arg(long, default_value = "amd64")]
+        arch: String,
+    },
+
+    /// Import an OCI image layout or `docker save` tarball, one tcow layer per image layer
+    #[command(name = "import-oci")]
+    ImportOci {
+        file: PathBuf,
+        /// OCI layout directory, or a tar produced by `docker save`
+        source: PathBuf,
+        /// Image to import when the source holds several (reference name or tag)
+        #[arg(long)]
+        tag: Option<String>,
+        /// Label to record when creating a new .tcow file
+        #[arg(long)]
+        label: Option<String>,
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum FindType {
+    #[value(alias = "f")]
+    File,
+    #[value(alias = "d")]
+    Dir,
+    #[value(alias = "w")]
+    Whiteout,
+}
+
+impl From<FindType> for EntryKind {
+    fn from(t: FindType) -> Self {
+        match t {
+            FindType::File => EntryKind::File,
+            FindType::Dir => EntryKind::Dir,
+            FindType::Whiteout => EntryKind::Whiteout,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ExportFormat {
+    Tar,
+    Zip,
+    Cpio,
+}
+
+impl ExportFormat {
+    fn from_path(path: &Path) -> Self {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_lowercase()).unwrap_or_default();
+        if name.ends_with(".zip") {
+            ExportFormat::Zip
+        } else if name.contains(".cpio") {
+            ExportFormat::Cpio
+        } else {
+            ExportFormat::Tar
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ImportFormat {
+    Tar,
+    Zip,
+    Cpio,
+}
+
+impl ImportFormat {
+    fn name(self) -> &'static str {
+        match self {
+            ImportFormat::Tar => "tar",
+            ImportFormat::Zip => "zip",
+            ImportFormat::Cpio => "cpio",
+        }
+    }
 }
 
 // ── Entry point ───────────────────────────────────────────────────────────────
 
 fn main() {
     if let Err(e) = run() {
-        eprintln!("error: {e:#}");
-        std::process::exit(1);
+        // An `ExitError` without a message only sets the status
+        if !e.to_string().is_empty() {
+            eprintln!("error: {e:#}");
+        }
+        std::process::exit(exit_code(&e));
+    }
+}
+
+/// Exit code for `grep` when nothing matched, as in `grep` itself.
+const EXIT_NO_MATCH: i32 = 1;
+/// Exit code for a file that is not a valid `.tcow` or cannot be parsed.
+/// Not `2`, which clap uses for usage errors.
+const EXIT_INVALID: i32 = 10;
+/// Exit code for a path or layer that does not exist.
+const EXIT_NOT_FOUND: i32 = 3;
+/// Exit code for a failed integrity check.
+const EXIT_INTEGRITY: i32 = 4;
+/// Exit code for `verify` when some digests are missing and nothing failed.
+const EXIT_MISSING_DIGEST: i32 = 5;
+/// Exit code for a file written in a format version this build cannot read.
+const EXIT_UNSUPPORTED_VERSION: i32 = 6;
+/// Exit code for a truncated file: no valid footer, or a trailer outside it.
+const EXIT_TRUNCATED: i32 = 7;
+/// Exit code for a CBOR trailer that cannot be decoded.
+const EXIT_BAD_TRAILER: i32 = 8;
+/// Exit code for an unsafe path, given on the command line or stored in a file.
+const EXIT_UNSAFE_PATH: i32 = 9;
+/// Exit code for a failed read or write, on the `.tcow` file or anything else.
+const EXIT_IO: i32 = 11;
+/// Exit code for input that makes no sense for the file or the arguments,
+/// and for any error no other code describes. Not `1`, which `grep` uses
+/// for "no match".
+const EXIT_INVALID_INPUT: i32 = 12;
+
+/// The exit code for `e`: an explicit `ExitError` code, else one chosen by
+/// the first `TcowError`, `PathError` or `io::Error` behind it, else
+/// `EXIT_INVALID_INPUT`.
+fn exit_code(e: &anyhow::Error) -> i32 {
+    if let Some(e) = e.downcast_ref::<ExitError>() {
+        return e.code;
+    }
+    for cause in e.chain() {
+        if cause.is::<PathError>() {
+            return EXIT_UNSAFE_PATH;
+        }
+        if cause.is::<io::Error>() {
+            return EXIT_IO;
+        }
+        let Some(e) = cause.downcast_ref::<TcowError>() else { continue };
+        return match e {
+            TcowError::BadMagic(_) | TcowError::CorruptLayer { .. } => EXIT_INVALID,
+            TcowError::NotFound { .. } | TcowError::NoSuchLayer { .. } => EXIT_NOT_FOUND,
+            TcowError::DigestMismatch { .. } => EXIT_INTEGRITY,
+            TcowError::UnsupportedVersion(_) | TcowError::UnsupportedFlags(_) => EXIT_UNSUPPORTED_VERSION,
+            TcowError::TruncatedFooter(_) => EXIT_TRUNCATED,
+            TcowError::Cbor(_) => EXIT_BAD_TRAILER,
+            TcowError::UnsafePath { .. } => EXIT_UNSAFE_PATH,
+            TcowError::Io { .. } => EXIT_IO,
+            TcowError::Invalid(_) => EXIT_INVALID_INPUT,
+        };
     }
+    EXIT_INVALID_INPUT
+}
+
+/// An error that ends the process with a specific exit code instead of the
+/// one `exit_code` would choose.
+#[derive(Debug)]
+struct ExitError {
+    code: i32,
+    message: String,
 }
 
+impl ExitError {
+    fn new(code: i32, message: impl std::fmt::Display) -> Self {
+        ExitError { code, message: format!("{message:#}") }
+    }
+}
+
+impl std::fmt::Display for ExitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ExitError {}
+
 fn run() -> Result<()> {
     let cli = Cli::parse();
     match cli.command {
         Commands::Info { file } => cmd_info(file),
-        Commands::List { file, path, layer, all_layers, long, show_whiteouts } => {
-            cmd_list(file, path, layer, all_layers, long, show_whiteouts)
+        Commands::List { file, path, layer, all_layers, long, show_whiteouts, depth, dirs_only, glob, regex } => {
+            let filter = match (glob, regex) {
+                (Some(glob), _) => Some(PathFilter::glob(&glob)?),
+                (None, Some(regex)) => Some(PathFilter::regex(&regex)?),
+                (None, None) => None,
+            };
+            let walk = WalkOptions { max_depth: depth, dirs: depth.is_some() || dirs_only, filter };
+            cmd_list(file, path, layer, all_layers, long, show_whiteouts, ListOptions { walk, dirs_only })
         }
         Commands::Cat { file, vpath, layer } => cmd_cat(file, vpath, layer),
         Commands::Stat { file, vpath, json } => cmd_stat(file, vpath, json),
-        Commands::Insert { file, vpath, source, dry_run } => {
-            cmd_insert(file, vpath, source, dry_run)
+        Commands::Find { file, path, all_layers, name, size, mtime, layer, kind, sha256, json } => {
+            let query = Query {
+                name: name.as_deref().map(PathFilter::glob).transpose()?,
+                size: size.as_deref().map(|r| Bounds::parse(r, find::parse_size)).transpose()?.unwrap_or_default(),
+                mtime: mtime.as_deref().map(|r| Bounds::parse(r, find::parse_time)).transpose()?.unwrap_or_default(),
+                layer: layer.as_deref().map(|r| Bounds::parse(r, parse_layer_index)).transpose()?.unwrap_or_default(),
+                kind: kind.map(EntryKind::from),
+                sha256,
+            };
+            cmd_find(file, path, all_layers, query, json)
+        }
+        Commands::Grep {
+            file, pattern, path, history, glob, ignore_case,
+            after_context, before_context, context, text, files_with_matches, count,
+        } => {
+            let regex = regex::bytes::RegexBuilder::new(&pattern)
+                .case_insensitive(ignore_case)
+                .build()
+                .with_context(|| format!("invalid regex {pattern:?}"))?;
+            let opts = GrepOptions {
+                before: before_context.or(context).unwrap_or(0),
+                after: after_context.or(context).unwrap_or(0),
+                filter: glob.as_deref().map(PathFilter::glob).transpose()?,
+                binary: text,
+                history,
+            };
+            let output = if files_with_matches {
+                GrepOutput::Files
+            } else if count {
+                GrepOutput::Count
+            } else {
+                GrepOutput::Lines
+            };
+            cmd_grep(file, path, &regex, &opts, output)
+        }
+        Commands::Insert { file, vpath, source, dedup, delta, dry_run } => {
+            cmd_insert(file, vpath, source, dedup, delta, dry_run)
         }
         Commands::Delete { file, vpath, dry_run } => cmd_delete(file, vpath, dry_run),
         Commands::Extract { file, vpath, outdir, layer, strip_prefix, dry_run } => {
@@ -174,8 +605,36 @@ fn run() -> Result<()> {
         Commands::Compact { file, output, in_place, dry_run } => {
             cmd_compact(file, output, in_place, dry_run)
         }
-        Commands::Verify { file, fix_missing } => cmd_verify(file, fix_missing),
+        Commands::Upgrade { file, dry_run } => cmd_upgrade(file, dry_run),
+        Commands::Verify { file, deep, fix_missing, json, jobs } => {
+            cmd_verify(file, deep, fix_missing, json, jobs)
+        }
+        Commands::Fsck { file, json } => cmd_fsck(file, json),
         Commands::Layers { file, json } => cmd_layers(file, json),
+        Commands::Diff { old, new, old_at, new_at, vpath, checksum } => {
+            cmd_diff(old, new, old_at, new_at, vpath, checksum)
+        }
+        Commands::Sync { file, dir, vpath, checksum, dedup, delta, dry_run } => {
+            cmd_sync(file, dir, vpath, checksum, AppendOptions { dedup, delta }, dry_run)
+        }
+        Commands::ImportTar { file, archive, label, dry_run } => {
+            cmd_import(file, archive, ImportFormat::Tar, label, dry_run)
+        }
+        Commands::ImportZip { file, archive, label, dry_run } => {
+            cmd_import(file, archive, ImportFormat::Zip, label, dry_run)
+        }
+        Commands::ImportCpio { file, archive, label, dry_run } => {
+            cmd_import(file, archive, ImportFormat::Cpio, label, dry_run)
+        }
+        Commands::Export { file, output, format, at, layer, raw, force } => {
+            cmd_export(file, output, format, at, layer, raw, force)
+        }
+        Commands::ExportOci { file, dir, tag, os, arch } => {
+            cmd_export_oci(file, dir, tag, os, arch)
+        }
+        Commands::ImportOci { file, source, tag, label, dry_run } => {
+            cmd_import_oci(file, source, tag, label, dry_run)
+        }
     }
 }
 
@@ -188,6 +647,8 @@ fn cmd_info(path: PathBuf) -> Result<()> {
     println!("File:          {}", path.display());
     println!("Size:          {} bytes", meta.len());
     println!("Format:        TCOW v{}", tcow.index.version);
+    let flags = tcow::flag_names(tcow.header.flags);
+    println!("Flags:         {}", if flags.is_empty() { "(none)".to_string() } else { flags.join(", ") });
     println!("Last modified: {}", tcow.index.last_modified);
     if let Some(label) = &tcow.index.label {
         println!("Label:         {label}");
@@ -221,31 +682,42 @@ fn cmd_info(path: PathBuf) -> Result<()> {
 
 // ── list ──────────────────────────────────────────────────────────────────────
 
+/// What `ls` lists beyond the choice of view.
+struct ListOptions {
+    walk: WalkOptions,
+    dirs_only: bool,
+}
+
+impl ListOptions {
+    /// Whether a raw layer entry at `p` beneath `root` is listed.
+    fn admits(&self, root: &VPath, p: &VPath, entry: &RawEntry) -> bool {
+        let kind_listed = if entry.is_dir { self.walk.dirs } else { !self.dirs_only };
+        kind_listed && self.walk.admits(root, p)
+    }
+}
+
 fn cmd_list(
     path: PathBuf,
-    prefix: Option<String>,
+    dir: Option<String>,
     layer: Option<usize>,
     all_layers: bool,
     long: bool,
     show_whiteouts: bool,
+    opts: ListOptions,
 ) -> Result<()> {
     let tcow = TcowFile::open(&path)?;
-    let prefix_canon = prefix.as_deref().map(normalize_path).unwrap_or_default();
+    let root = dir.as_deref().map(VPath::new).transpose()?.unwrap_or_default();
 
     if all_layers {
         // Show every entry from every layer including shadowed/whiteouts
         for (layer_idx, layer_entries) in tcow.layers.iter().enumerate() {
             let layer_kind = &tcow.index.layers[layer_idx].kind;
-            let union = tcow.union_view();
-            let mut paths: Vec<&String> = layer_entries.keys().collect();
+            let mut paths: Vec<&VPath> = layer_entries.keys().collect();
             paths.sort();
             for p in paths {
                 let entry = &layer_entries[p];
-                if !prefix_canon.is_empty() && !p.starts_with(&prefix_canon) {
-                    continue;
-                }
-                if entry.is_dir { continue; }
-                let visible_in_union = union.contains_key(p.as_str());
+                if !opts.admits(&root, p, entry) { continue; }
+                let visible_in_union = entry.is_dir || tcow.visible_layer(p) == Some(layer_idx);
                 let tag = if entry.is_whiteout {
                     "[DEL]"
                 } else if !visible_in_union {
@@ -254,15 +726,15 @@ fn cmd_list(
                     "       "
                 };
                 if !show_whiteouts && entry.is_whiteout { continue; }
+                let slash = if entry.is_dir { "/" } else { "" };
                 if long {
                     println!(
-                        "  {tag}  {:>10}  {:<18}  layer {layer_idx} ({layer_kind})  /{}",
+                        "  {tag}  {:>10}  {:<18}  layer {layer_idx} ({layer_kind})  /{p}{slash}",
                         format_bytes(entry.data.len() as u64),
                         unix_ts_to_rfc3339(entry.mtime),
-                        p
                     );
                 } else {
-                    println!("  {tag}  /{p}  (layer {layer_idx} — {layer_kind})");
+                    println!("  {tag}  /{p}{slash}  (layer {layer_idx} — {layer_kind})");
                 }
             }
         }
@@ -271,54 +743,44 @@ fn cmd_list(
 
     if let Some(layer_idx) = layer {
         // Specific layer only, no union logic
-        if layer_idx >= tcow.layers.len() {
-            bail!("layer {layer_idx} does not exist (file has {} layers)", tcow.layers.len());
-        }
-        let layer_entries = &tcow.layers[layer_idx];
-        let mut paths: Vec<&String> = layer_entries.keys().collect();
+        let layer_entries = tcow.layer(layer_idx)?;
+        let mut paths: Vec<&VPath> = layer_entries.keys().collect();
         paths.sort();
         for p in paths {
             let entry = &layer_entries[p];
-            if !prefix_canon.is_empty() && !p.starts_with(&prefix_canon) {
-                continue;
-            }
-            if entry.is_dir { continue; }
+            if !opts.admits(&root, p, entry) { continue; }
             if !show_whiteouts && entry.is_whiteout { continue; }
+            let slash = if entry.is_dir { "/" } else { "" };
             if long {
                 let tag = if entry.is_whiteout { "[DEL]" } else { "     " };
                 println!(
-                    "{tag}  {:>10}  {:<18}  /{}",
+                    "{tag}  {:>10}  {:<18}  /{p}{slash}",
                     format_bytes(entry.data.len() as u64),
                     unix_ts_to_rfc3339(entry.mtime),
-                    p
                 );
             } else {
                 let tag = if entry.is_whiteout { "[DEL] " } else { "" };
-                println!("{tag}/{p}");
+                println!("{tag}/{p}{slash}");
             }
         }
         return Ok(());
     }
 
     // Default: union view
-    let view = tcow.union_view();
-    let mut paths: Vec<(&String, _)> = view.iter().collect();
-    paths.sort_by_key(|(p, _)| p.as_str());
-
-    for (p, entry) in paths {
-        if !prefix_canon.is_empty() && !p.starts_with(&prefix_canon) {
+    for entry in tcow.view().walk_with(&root, &opts.walk)? {
+        let (p, meta) = (&entry.path, &entry.metadata);
+        if opts.dirs_only && !meta.is_dir {
             continue;
         }
+        let slash = if meta.is_dir { "/" } else { "" };
         if long {
-            println!(
-                "  {:>10}  {:<18}  layer {:>2}  /{}",
-                format_bytes(entry.size),
-                unix_ts_to_rfc3339(entry.mtime),
-                entry.layer_idx,
-                p
-            );
+            // Implied directories have no size, mtime or layer of their own
+            let size = if meta.is_dir { "-".to_string() } else { format_bytes(meta.size) };
+            let mtime = if meta.mtime == 0 && meta.is_dir { "-".to_string() } else { unix_ts_to_rfc3339(meta.mtime) };
+            let layer = meta.layer.map_or(String::new(), |l| format!("layer {l:>2}"));
+            println!("  {size:>10}  {mtime:<18}  {layer:<8}  /{p}{slash}");
         } else {
-            println!("/{p}");
+            println!("/{p}{slash}");
         }
     }
     Ok(())
@@ -328,28 +790,15 @@ fn cmd_list(
 
 fn cmd_cat(path: PathBuf, vpath: String, layer: Option<usize>) -> Result<()> {
     let tcow = TcowFile::open(&path)?;
-    let canonical = normalize_path(&vpath);
+    let vpath = VPath::new(&vpath)?;
 
-    if let Some(layer_idx) = layer {
-        if layer_idx >= tcow.layers.len() {
-            bail!("layer {layer_idx} does not exist");
-        }
-        let entry = tcow.layers[layer_idx].get(&canonical)
-            .ok_or_else(|| anyhow::anyhow!("/{canonical} not found in layer {layer_idx}"))?;
-        if entry.is_whiteout {
-            bail!("/{canonical} is a whiteout (deletion marker) in layer {layer_idx}");
-        }
-        io::stdout().write_all(&entry.data)?;
-        io::stdout().write_all(b"\n")?;
-    } else {
-        match tcow.resolve(&vpath) {
-            None => bail!("/{canonical} not found in virtual filesystem"),
-            Some((entry, _)) => {
-                io::stdout().write_all(&entry.data)?;
-                io::stdout().write_all(b"\n")?;
-            }
-        }
-    }
+    let mut reader = match layer {
+        Some(layer_idx) => tcow.open_layer_file(layer_idx, &vpath)?,
+        None => tcow.open_file(&vpath)?,
+    };
+    let mut out = io::stdout().lock();
+    io::copy(&mut reader, &mut out)?;
+    out.write_all(b"\n")?;
     Ok(())
 }
 
@@ -357,38 +806,40 @@ fn cmd_cat(path: PathBuf, vpath: String, layer: Option<usize>) -> Result<()> {
 
 fn cmd_stat(path: PathBuf, vpath: String, json: bool) -> Result<()> {
     let tcow = TcowFile::open(&path)?;
-    let canonical = normalize_path(&vpath);
-    let view = tcow.union_view();
+    let canonical = VPath::new(&vpath)?;
+    let found = tcow.lookup(&canonical);
 
     if json {
-        match view.get(&canonical) {
+        match found {
             None => {
                 // Check if it's a whiteout
                 let whiteout = tcow.layers.iter().rev().any(|l| {
-                    l.get(&canonical).map_or(false, |e| e.is_whiteout)
+                    l.get(&canonical).is_some_and(|e| e.is_whiteout)
                 });
                 if whiteout {
                     println!(r#"{{"path":"/{canonical}","size":0,"mtime":null,"layer":null,"whiteout":true}}"#);
                 } else {
-                    bail!("/{canonical} not found");
+                    return Err(TcowError::NotFound { path: canonical, layer: None }.into());
                 }
             }
-            Some(entry) => {
+            Some((entry, layer_idx)) => {
                 let mtime = unix_ts_to_rfc3339(entry.mtime);
                 println!(
-                    r#"{{"path":"/{canonical}","size":{},"mtime":"{mtime}","layer":{},"whiteout":false}}"#,
-                    entry.size, entry.layer_idx
+                    r#"{{"path":"/{canonical}","size":{},"mtime":"{mtime}","layer":{layer_idx},"sha256":"{}","whiteout":false}}"#,
+                    entry.data.len(),
+                    entry.sha256()
                 );
             }
         }
     } else {
-        match view.get(&canonical) {
-            None => bail!("/{canonical} not found in virtual filesystem"),
-            Some(entry) => {
+        match found {
+            None => return Err(TcowError::NotFound { path: canonical, layer: None }.into()),
+            Some((entry, layer_idx)) => {
                 println!("Path:     /{canonical}");
-                println!("Size:     {} bytes", entry.size);
+                println!("Size:     {} bytes", entry.data.len());
                 println!("Mtime:    {}", unix_ts_to_rfc3339(entry.mtime));
-                println!("Layer:    {} ({})", entry.layer_idx, tcow.index.layers[entry.layer_idx].kind);
+                println!("Layer:    {layer_idx} ({})", tcow.index.layers[layer_idx].kind);
+                println!("SHA-256:  {}", entry.sha256());
                 println!("Whiteout: false");
             }
         }
@@ -396,27 +847,145 @@ fn cmd_stat(path: PathBuf, vpath: String, json: bool) -> Result<()> {
     Ok(())
 }
 
-// ── insert ────────────────────────────────────────────────────────────────────
+// ── find ──────────────────────────────────────────────────────────────────────
+
+fn parse_layer_index(s: &str) -> tcow::Result<usize> {
+    s.parse().map_err(|_| TcowError::Invalid(format!("invalid layer index {s:?}")))
+}
+
+fn cmd_find(path: PathBuf, dir: Option<String>, all_layers: bool, query: Query, json: bool) -> Result<()> {
+    if query.kind == Some(EntryKind::Whiteout) && !all_layers {
+        bail!("the union view has no whiteouts; add --all-layers to search for them");
+    }
+    let tcow = TcowFile::open(&path)?;
+    let root = dir.as_deref().map(VPath::new).transpose()?.unwrap_or_default();
+    let found = find::find(&tcow, &root, &query, all_layers)?;
+
+    if json {
+        let docs: Vec<_> = found
+            .iter()
+            .map(|f| {
+                let is_file = f.kind == EntryKind::File;
+                let sha256 = f.layer.filter(|_| is_file).map(|l| tcow.layers[l][&f.path].sha256());
+                let kind = match f.kind {
+                    EntryKind::File => "file",
+                    EntryKind::Dir => "dir",
+                    EntryKind::Whiteout => "whiteout",
+                };
+                serde_json::json!({
+                    "path": format!("/{}", f.path),
+                    "type": kind,
+                    "size": f.size,
+                    "mtime": (f.mtime != 0 || is_file).then(|| unix_ts_to_rfc3339(f.mtime)),
+                    "layer": f.layer,
+                    "sha256": sha256,
+                    "whiteout": f.kind == EntryKind::Whiteout,
+                    "visible": f.visible,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&docs)?);
+        return Ok(());
+    }
 
-fn cmd_insert(path: PathBuf, vpath: String, source: Option<PathBuf>, dry_run: bool) -> Result<()> {
-    let content = match source {
-        Some(ref src) => {
-            fs::read(src).with_context(|| format!("reading source file {:?}", src))?
+    for f in &found {
+        let is_dir = f.kind == EntryKind::Dir;
+        let size = if is_dir { "-".to_string() } else { format_bytes(f.size) };
+        let mtime = if f.mtime == 0 && is_dir { "-".to_string() } else { unix_ts_to_rfc3339(f.mtime) };
+        let layer = f.layer.map_or(String::new(), |l| format!("layer {l:>2}"));
+        let slash = if is_dir { "/" } else { "" };
+        if all_layers {
+            let tag = match f.kind {
+                EntryKind::Whiteout => "[DEL]",
+                _ if !f.visible => "[hidden]",
+                _ => "",
+            };
+            println!("  {tag:<8}  {size:>10}  {mtime:<20}  {layer:<8}  /{}{slash}", f.path);
+        } else {
+            println!("  {size:>10}  {mtime:<20}  {layer:<8}  /{}{slash}", f.path);
         }
-        None => {
-            let mut buf = Vec::new();
-            io::stdin().read_to_end(&mut buf).context("reading stdin")?;
-            buf
+    }
+    Ok(())
+}
+
+// ── grep ──────────────────────────────────────────────────────────────────────
+
+/// What `grep` prints for each file with matches.
+enum GrepOutput {
+    Lines,
+    Files,
+    Count,
+}
+
+fn cmd_grep(
+    path: PathBuf,
+    dir: Option<String>,
+    regex: &regex::bytes::Regex,
+    opts: &GrepOptions,
+    output: GrepOutput,
+) -> Result<()> {
+    let tcow = TcowFile::open(&path)?;
+    let root = dir.as_deref().map(VPath::new).transpose()?.unwrap_or_default();
+    let results = grep::grep(&tcow, &root, regex, opts)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut first_group = true;
+    for file in &results {
+        let name = if opts.history { format!("[layer {}] /{}", file.layer, file.path) } else { format!("/{}", file.path) };
+        match output {
+            GrepOutput::Files => writeln!(out, "{name}")?,
+            GrepOutput::Count => writeln!(out, "{name}:{}", file.match_count())?,
+            GrepOutput::Lines => {
+                let mut prev: Option<usize> = None;
+                for line in &file.lines {
+                    // `--` between groups of context that are not adjacent, as grep does
+                    let new_group = prev.is_none_or(|p| line.number != p + 1);
+                    if new_group && (opts.before > 0 || opts.after > 0) && !first_group {
+                        writeln!(out, "--")?;
+                    }
+                    first_group = false;
+                    prev = Some(line.number);
+                    let sep = if line.is_match { ':' } else { '-' };
+                    writeln!(out, "{name}{sep}{}{sep}{}", line.number, String::from_utf8_lossy(&line.text))?;
+                }
+            }
         }
-    };
+    }
+    if results.is_empty() {
+        return Err(ExitError::new(EXIT_NO_MATCH, "").into());
+    }
+    Ok(())
+}
+
+// ── insert ────────────────────────────────────────────────────────────────────
 
-    let size = content.len();
-    let canonical = normalize_path(&vpath);
+fn cmd_insert(
+    path: PathBuf,
+    vpath: String,
+    source: Option<PathBuf>,
+    dedup: bool,
+    delta: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let canonical = VPath::new(&vpath)?;
+    let existed = path.exists();
+
+    // Plain stdin is streamed straight into the layer. When its size or
+    // content is needed first it is spooled to an anonymous temporary file
+    // rather than buffered in memory.
+    let mut src = match source {
+        Some(ref src) => fs::File::open(src).with_context(|| format!("reading source file {:?}", src))?,
+        None if dry_run || (existed && (dedup || delta)) => spool_stdin().context("reading stdin")?,
+        None => return insert_stdin(path, canonical, existed),
+    };
+    let size = src.metadata()?.len();
 
     if dry_run {
         if path.exists() {
-            let tcow = TcowFile::open(&path)?;
-            let n = tcow.index.layers.len();
+            let mut f = fs::File::open(&path)?;
+            let (index, _) = tcow::read_index(&mut f, &path)?;
+            let n = index.layers.len();
             println!("[DRY RUN] Would insert /{canonical} ({size} bytes) as new delta layer {n}");
         } else {
             println!("[DRY RUN] Would create {:?} with /{canonical} ({size} bytes) as base layer 0", path);
@@ -424,28 +993,104 @@ fn cmd_insert(path: PathBuf, vpath: String, source: Option<PathBuf>, dry_run: bo
         return Ok(());
     }
 
-    let entries = vec![(canonical.clone(), content)];
+    let mut stored = Stored::Inline;
+    if existed && (dedup || delta) {
+        let tcow = TcowFile::open(&path)?;
+        if dedup {
+            let mut hasher = HashingWriter::new(io::sink());
+            io::copy(&mut src, &mut hasher)?;
+            src.rewind()?;
+            if let Some(blob) = tcow.content_index().get(&hasher.finish().1) {
+                stored = Stored::Ref(blob.clone());
+            }
+        }
+        if delta && matches!(stored, Stored::Inline) {
+            let content = Blob::load(&mut src, size, ReadMode::Auto)?;
+            if let Some((d, patch)) = tcow.encode_delta(&canonical, &content) {
+                stored = Stored::Delta(d, patch);
+            }
+        }
+    }
 
-    if path.exists() {
-        let tcow = TcowFile::append_delta(&path, &entries, &[])?;
-        let n = tcow.index.layers.len();
-        println!("Inserted /{canonical} ({size} bytes) into new delta layer {}", n - 1);
+    let mut writer = if existed {
+        LayerWriter::append(&path)?
+    } else {
+        LayerWriter::create(&path, None)?
+    };
+    match &stored {
+        Stored::Inline => writer.add_file(&canonical, io::BufReader::new(src), size)?,
+        Stored::Ref(blob) => writer.add_reference(&canonical, blob)?,
+        Stored::Delta(d, patch) => writer.add_delta(&canonical, d, patch)?,
+    }
+    let n = writer.layer_index();
+    writer.commit()?;
+
+    match stored {
+        Stored::Ref(blob) => println!(
+            "Inserted /{canonical} ({size} bytes) into new delta layer {n} as a reference to /{} in layer {}",
+            blob.path, blob.layer
+        ),
+        Stored::Delta(d, patch) => println!(
+            "Inserted /{canonical} ({size} bytes) into new delta layer {n} as a {} delta against layer {}",
+            format_bytes(patch.len() as u64),
+            d.base_layer
+        ),
+        Stored::Inline if existed => {
+            println!("Inserted /{canonical} ({size} bytes) into new delta layer {n}")
+        }
+        Stored::Inline => {
+            println!("Created {:?} — inserted /{canonical} ({size} bytes) into base layer 0", path)
+        }
+    }
+    Ok(())
+}
+
+/// `insert` of stdin, streamed into the layer as it is read.
+fn insert_stdin(path: PathBuf, canonical: VPath, existed: bool) -> Result<()> {
+    let mut writer = if existed {
+        LayerWriter::append(&path)?
+    } else {
+        LayerWriter::create(&path, None)?
+    };
+    let size = writer.add_stream(&canonical, io::stdin().lock()).context("reading stdin")?;
+    let n = writer.layer_index();
+    writer.commit()?;
+    if existed {
+        println!("Inserted /{canonical} ({size} bytes) into new delta layer {n}");
     } else {
-        let _tcow = TcowFile::create(&path, &entries, &[], None)?;
         println!("Created {:?} — inserted /{canonical} ({size} bytes) into base layer 0", path);
     }
     Ok(())
 }
 
+/// How `insert` stores the new file body.
+enum Stored {
+    Inline,
+    Ref(BlobRef),
+    Delta(DeltaRef, Vec<u8>),
+}
+
+/// Copy stdin into an unnamed temporary file and rewind it. The file has no
+/// path, so nothing else can open or replace it, and it is gone once closed.
+fn spool_stdin() -> Result<fs::File> {
+    let mut f = tempfile::tempfile()?;
+    let mut w = io::BufWriter::new(&mut f);
+    io::copy(&mut io::stdin().lock(), &mut w)?;
+    w.flush()?;
+    drop(w);
+    f.rewind()?;
+    Ok(f)
+}
+
 // ── delete ────────────────────────────────────────────────────────────────────
 
 fn cmd_delete(path: PathBuf, vpath: String, dry_run: bool) -> Result<()> {
-    let canonical = normalize_path(&vpath);
-    let tcow = TcowFile::open(&path)?;
-    let view = tcow.union_view();
+    let canonical = VPath::new(&vpath)?;
+    let mut tcow = TcowFile::open(&path)?;
 
-    if !view.contains_key(&canonical) {
-        bail!("/{canonical} does not exist in the virtual filesystem (nothing to delete)");
+    if tcow.visible_layer(&canonical).is_none() {
+        return Err(anyhow::Error::new(TcowError::NotFound { path: canonical, layer: None })
+            .context("nothing to delete"));
     }
 
     let wh_tar_path = tcow::to_whiteout_tar_path(&canonical);
@@ -456,8 +1101,8 @@ fn cmd_delete(path: PathBuf, vpath: String, dry_run: bool) -> Result<()> {
         return Ok(());
     }
 
-    let updated = TcowFile::append_delta(&path, &[], &[canonical.clone()])?;
-    let n = updated.index.layers.len();
+    tcow.append(&[], std::slice::from_ref(&canonical))?;
+    let n = tcow.index.layers.len();
     println!("Wrote whiteout for /{canonical} in new delta layer {}", n - 1);
     Ok(())
 }
@@ -473,31 +1118,18 @@ fn cmd_extract(
     dry_run: bool,
 ) -> Result<()> {
     let tcow = TcowFile::open(&path)?;
-    let prefix_canon = vpath.as_deref().map(normalize_path).unwrap_or_default();
-    let strip = strip_prefix.as_deref().map(normalize_path).unwrap_or_default();
+    let under = vpath.as_deref().map(VPath::new).transpose()?.unwrap_or_default();
+    let strip = strip_prefix.as_deref().map(VPath::new).transpose()?.unwrap_or_default();
 
-    // Collect entries to extract
-    let to_extract: Vec<(String, Vec<u8>)> = if let Some(layer_idx) = layer {
-        if layer_idx >= tcow.layers.len() {
-            bail!("layer {layer_idx} does not exist");
-        }
-        tcow.layers[layer_idx]
-            .iter()
-            .filter(|(_p, e)| !e.is_dir && !e.is_whiteout)
-            .filter(|(p, _)| prefix_canon.is_empty() || p.starts_with(&prefix_canon))
-            .map(|(p, e)| (p.clone(), e.data.clone()))
-            .collect()
-    } else {
-        tcow.union_view()
-            .into_iter()
-            .filter(|(p, _)| prefix_canon.is_empty() || p.starts_with(&prefix_canon))
-            .map(|(p, e)| (p, e.data))
-            .collect()
+    let view = match layer {
+        Some(layer_idx) => tcow.layer_view(layer_idx)?,
+        None => tcow.view(),
     };
+    let to_extract = if view.exists(&under) { view.walk(&under)? } else { Vec::new() };
 
     if dry_run {
-        for (p, data) in &to_extract {
-            println!("[DRY RUN] Would extract /{p} ({} bytes)", data.len());
+        for entry in &to_extract {
+            println!("[DRY RUN] Would extract /{} ({} bytes)", entry.path, entry.metadata.size);
         }
         return Ok(());
     }
@@ -508,18 +1140,18 @@ fn cmd_extract(
     }
 
     let mut count = 0usize;
-    for (p, data) in &to_extract {
-        let rel = if !strip.is_empty() && p.starts_with(&strip) {
-            p[strip.len()..].trim_start_matches('/')
-        } else {
-            p.as_str()
-        };
-
-        let dest = outdir.join(rel);
+    let out = HostDir::new(&outdir);
+    for entry in &to_extract {
+        let p = &entry.path;
+        let rel = p.relative_to(&strip).unwrap_or_else(|| p.clone());
+        if rel.is_root() {
+            bail!("/{p} would be extracted onto the output directory itself; use a shorter --strip-prefix");
+        }
+        let dest = out.host_path(&rel);
         if let Some(parent) = dest.parent() {
             fs::create_dir_all(parent)?;
         }
-        fs::write(&dest, data)
+        fs::write(&dest, view.read(p)?)
             .with_context(|| format!("writing {:?}", dest))?;
         count += 1;
     }
@@ -532,9 +1164,10 @@ fn cmd_extract(
 
 fn cmd_snapshot(path: PathBuf, label: Option<String>) -> Result<()> {
     // Append an empty delta layer (just the end-of-archive two zero blocks)
-    let updated = TcowFile::append_delta(&path, &[], &[])?;
-    let n = updated.index.layers.len();
-    let rec = &updated.index.layers[n - 1];
+    let mut tcow = TcowFile::open(&path)?;
+    tcow.append(&[], &[])?;
+    let n = tcow.index.layers.len();
+    let rec = &tcow.index.layers[n - 1];
     if let Some(lbl) = &label {
         // We can't easily update the label after the fact without re-reading.
         // Just report that it was created.
@@ -559,10 +1192,10 @@ fn cmd_compact(
 
     // Collect all visible files
     let view = tcow.union_view();
-    let entries: Vec<(String, Vec<u8>)> = {
+    let entries: Vec<(VPath, Vec<u8>)> = {
         let mut v: Vec<_> = view.into_iter().collect();
         v.sort_by(|a, b| a.0.cmp(&b.0));
-        v.into_iter().map(|(p, e)| (p, e.data)).collect()
+        v.into_iter().map(|(p, e)| (p, e.data.to_vec())).collect()
     };
 
     if dry_run {
@@ -593,7 +1226,7 @@ fn cmd_compact(
 
     let new_size = fs::metadata(&dest)?.len();
     let saved = orig_size.saturating_sub(new_size);
-    let pct = if orig_size > 0 { 100 * saved / orig_size } else { 0 };
+    let pct = (100 * saved).checked_div(orig_size).unwrap_or(0);
 
     println!("Compacted {:?} → {:?}", path, dest);
     println!("  Before: {n_layers} layer(s), {orig_size} bytes");
@@ -601,94 +1234,331 @@ fn cmd_compact(
     Ok(())
 }
 
-// ── verify ────────────────────────────────────────────────────────────────────
-
-fn cmd_verify(path: PathBuf, fix_missing: bool) -> Result<()> {
-    use std::fs::OpenOptions;
-    use std::io::{Seek, SeekFrom};
+// ── upgrade ───────────────────────────────────────────────────────────────────
 
-    let tcow = TcowFile::open(&path)?;
-    let n = tcow.index.layers.len();
-    println!("Verifying {} ({n} layers)…\n", path.display());
+fn cmd_upgrade(path: PathBuf, dry_run: bool) -> Result<()> {
+    let mut f = FileStorage::open(&path)?;
+    let header = read_header(&mut f, &path)?;
+    let (index, _) = read_index(&mut f, &path)?;
+    if header.version == FORMAT_VERSION && index.version == FORMAT_VERSION {
+        println!("{:?} is already TCOW v{FORMAT_VERSION}", path);
+        return Ok(());
+    }
+    if dry_run {
+        println!(
+            "[DRY RUN] Would upgrade {:?} from TCOW v{} to v{FORMAT_VERSION} ({} layer(s) unchanged)",
+            path,
+            header.version,
+            index.layers.len()
+        );
+        return Ok(());
+    }
+    let old = upgrade_format(&mut f)?;
+    println!("Upgraded {:?} from TCOW v{old} to v{FORMAT_VERSION}", path);
+    Ok(())
+}
 
-    let mut f = std::fs::File::open(&path)?;
-    let mut errors = 0usize;
-    let mut missing = Vec::new();
+// ── verify ────────────────────────────────────────────────────────────────────
 
-    for (i, rec) in tcow.index.layers.iter().enumerate() {
-        f.seek(SeekFrom::Start(rec.offset))?;
-        let mut raw = vec![0u8; rec.size as usize];
-        f.read_exact(&mut raw)?;
-        let computed = sha256_hex(&raw);
+fn cmd_verify(
+    path: PathBuf,
+    deep: bool,
+    fix_missing: bool,
+    json: bool,
+    jobs: Option<usize>,
+) -> Result<()> {
+    use rayon::prelude::*;
+    use std::collections::BTreeMap;
+    use std::fs::OpenOptions;
+    use std::io::IsTerminal;
+    use tcow::verify::{self, EntryKind, EntryStatus, LayerStatus};
+
+    let mut f = fs::File::open(&path).with_context(|| format!("cannot open {:?}", path))?;
+    let (index, trailer_offset) = read_index(&mut f, &path)?;
+    drop(f);
+    let n = index.layers.len();
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs.unwrap_or(0)).build()?;
+    if !json {
+        println!("Verifying {} ({n} layers)…\n", path.display());
+    }
 
-        match &rec.digest {
-            None => {
-                println!("  Layer {i:>2}  [{:>5}]  (no digest stored)  -  SKIPPED", rec.kind);
-                missing.push(i);
+    // Stream every layer through SHA-256 straight from disk
+    let total: u64 = index.layers.iter().map(|r| r.size).sum();
+    let progress = Progress::new(total, !json && io::stderr().is_terminal());
+    let layers = pool.install(|| verify::check_layers(&path, &index, trailer_offset, &|b| progress.add(b)));
+    progress.finish();
+
+    for check in &layers {
+        let (i, kind) = (check.index, &check.kind);
+        let computed = check.computed.as_deref().unwrap_or_default();
+        match check.status {
+            _ if json => {}
+            LayerStatus::Ok => println!("  Layer {i:>2}  [{kind:>5}]  {}…  ✓", &computed[..16]),
+            LayerStatus::Missing => {
+                println!("  Layer {i:>2}  [{kind:>5}]  (no digest stored)  -  SKIPPED")
             }
-            Some(stored) => {
-                if *stored == computed {
-                    println!("  Layer {i:>2}  [{:>5}]  {}…  ✓", rec.kind, &computed[..16]);
-                } else {
-                    println!(
-                        "  Layer {i:>2}  [{:>5}]  {}…  ✗  MISMATCH",
-                        rec.kind,
-                        &computed[..16]
-                    );
-                    eprintln!("             stored:   {stored}");
-                    eprintln!("             computed: {computed}");
-                    errors += 1;
-                }
+            LayerStatus::Mismatch => {
+                println!("  Layer {i:>2}  [{kind:>5}]  {}…  ✗  MISMATCH", &computed[..16]);
+                eprintln!("             stored:   {}", check.stored.as_deref().unwrap_or_default());
+                eprintln!("             computed: {computed}");
+            }
+            LayerStatus::Unreadable => {
+                println!("  Layer {i:>2}  [{kind:>5}]  ✗  UNREADABLE");
+                eprintln!("             {}", check.error.as_deref().unwrap_or_default());
             }
         }
     }
 
-    if fix_missing && !missing.is_empty() {
-        // Re-open for read to compute digests, then rewrite trailer
-        f.seek(SeekFrom::Start(0))?;
-        let mut new_layers = tcow.index.layers.clone();
-        for i in &missing {
-            let rec = &new_layers[*i];
-            f.seek(SeekFrom::Start(rec.offset))?;
-            let mut raw = vec![0u8; rec.size as usize];
-            f.read_exact(&mut raw)?;
-            new_layers[*i].digest = Some(sha256_hex(&raw));
+    // The layers must also parse, and dedup references and deltas must still
+    // rebuild the content whose digest they were written with. With --deep,
+    // inline files must hash to the digest recorded for them, which narrows
+    // a damaged layer down to the paths that are actually bad.
+    // Both are read from disk a layer at a time, so memory stays bounded
+    // however large the file is.
+    let checked = pool.install(|| verify::check_entries_on_disk(&path, &index, deep));
+    let structure_error = checked.as_ref().err().map(|e| format!("{e:#}"));
+    let entries = checked.ok();
+    if let (Some(e), false) = (&structure_error, json) {
+        println!("  Structure  ✗  {e}");
+    }
+    if let (Some(report), false) = (&entries, json) {
+        for fail in &report.failures {
+            let (i, p) = (fail.layer, &fail.path);
+            let label = match (fail.kind, fail.status, &fail.origin) {
+                (EntryKind::Reference, _, Some(o)) => format!("{p} → {} (layer {})  ✗  MISMATCH", o.path, o.layer),
+                (EntryKind::Delta, _, Some(o)) => format!("{p} Δ {} (layer {})  ✗  MISMATCH", o.path, o.layer),
+                (_, EntryStatus::Missing, _) => format!("{p}  ✗  MISSING"),
+                _ => format!("{p}  ✗  DAMAGED"),
+            };
+            println!("  Layer {i:>2}  {label}");
+            if let Some(computed) = &fail.computed {
+                eprintln!("             stored:   {}", fail.stored);
+                eprintln!("             computed: {computed}");
+            }
         }
-        let new_index = TcowIndex {
-            version: tcow.index.version,
-            layers: new_layers,
-            last_modified: now_rfc3339(),
-            label: tcow.index.label.clone(),
-        };
-        // Find where to write the new trailer
-        let last_rec = new_index.layers.last().unwrap();
-        let trailer_offset = last_rec.offset + last_rec.size;
-        let cbor = encode_cbor(&new_index)?;
-        let trailer_len = cbor.len() as u32;
+        let ref_failures = report.failures.iter().filter(|f| f.kind != EntryKind::File).count();
+        if report.references > 0 && ref_failures == 0 {
+            println!("  {} dedup reference(s) and delta(s)  ✓", report.references);
+        }
+        if report.files > 0 && ref_failures == report.failures.len() {
+            println!("  {} file(s)  ✓", report.files);
+        }
+        if report.unrecorded > 0 {
+            println!("  {} file(s) without a recorded digest  -  SKIPPED", report.unrecorded);
+        }
+    }
 
+    let mut missing: Vec<usize> = layers
+        .iter()
+        .filter(|c| c.status == LayerStatus::Missing)
+        .map(|c| c.index)
+        .collect();
+    // Layers written before per-file digests were recorded
+    let mut unhashed: Vec<(usize, BTreeMap<String, String>)> = match &entries {
+        Some(_) => pool.install(|| {
+            (0..n)
+                .into_par_iter()
+                .filter(|&i| index.layers[i].file_digests.is_empty())
+                .map(|i| verify::file_digests_on_disk(&path, &index.layers[i]).map(|digests| (i, digests)))
+                .collect::<Result<Vec<_>>>()
+                .map(|all| all.into_iter().filter(|(_, digests)| !digests.is_empty()).collect())
+        })?,
+        None => Vec::new(),
+    };
+    if fix_missing && (!missing.is_empty() || !unhashed.is_empty()) {
+        // Compute the missing digests, then rewrite trailer
+        let mut new_layers = index.layers.clone();
+        for check in layers.iter().filter(|c| c.status == LayerStatus::Missing) {
+            new_layers[check.index].digest = check.computed.clone();
+        }
+        for (i, digests) in &unhashed {
+            new_layers[*i].file_digests = digests.clone();
+        }
+        let new_index = TcowIndex { layers: new_layers, last_modified: now_rfc3339(), ..index.clone() };
         let mut fw = OpenOptions::new().write(true).open(&path)?;
-        fw.seek(SeekFrom::Start(trailer_offset))?;
-        fw.set_len(trailer_offset)?;
-        fw.seek(SeekFrom::Start(trailer_offset))?;
-        fw.write_all(&cbor)?;
-        write_trailer_footer(&mut fw, trailer_offset, trailer_len)?;
-        fw.flush()?;
-        println!("\nFixed {count} missing digest(s).", count = missing.len());
+        write_trailer(&mut fw, trailer_offset, &new_index)?;
+        if !json {
+            println!();
+            if !missing.is_empty() {
+                println!("Fixed {count} missing digest(s).", count = missing.len());
+            }
+            if !unhashed.is_empty() {
+                println!("Recorded file digests for {} layer(s).", unhashed.len());
+            }
+        }
+        missing.clear();
+        unhashed.clear();
     }
 
-    println!();
-    if errors == 0 {
-        if n == 0 {
+    let unrecorded = if unhashed.is_empty() { 0 } else { entries.as_ref().map_or(0, |r| r.unrecorded) };
+    let outcome = verify_outcome(&layers, entries.as_ref(), structure_error.is_some(), missing.len(), unrecorded);
+    if json {
+        let report = verify_report(&path, outcome.as_ref(), &layers, structure_error.as_deref(), entries.as_ref());
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!();
+    }
+    match outcome {
+        Some((code, _, message)) => Err(ExitError::new(code, message).into()),
+        None if json => Ok(()),
+        None if n == 0 => {
             println!("No layers in file.");
-        } else {
+            Ok(())
+        }
+        None => {
             println!("All layers verified. File is intact.");
+            Ok(())
+        }
+    }
+}
+
+/// Shows how much of a verify run has been hashed, on stderr.
+struct Progress {
+    total: u64,
+    enabled: bool,
+    done: AtomicU64,
+    shown: AtomicU64,
+}
+
+impl Progress {
+    fn new(total: u64, enabled: bool) -> Self {
+        Progress { total, enabled, done: AtomicU64::new(0), shown: AtomicU64::new(0) }
+    }
+
+    /// Record `bytes` more hashed; redraws whenever the percentage moves.
+    fn add(&self, bytes: u64) {
+        if !self.enabled {
+            return;
+        }
+        let done = self.done.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let pct = done * 100 / self.total.max(1);
+        if self.shown.fetch_max(pct, Ordering::Relaxed) < pct {
+            eprint!("\r  Hashing {pct:>3}%  ({} of {})\x1b[K", format_bytes(done), format_bytes(self.total));
+        }
+    }
+
+    fn finish(&self) {
+        if self.enabled && self.shown.load(Ordering::Relaxed) > 0 {
+            eprint!("\r\x1b[K");
         }
-        Ok(())
+    }
+}
+
+
+/// Exit code, JSON `status` and message for a `verify` run, or `None` when
+/// everything checked out. Structural errors outrank mismatches, which
+/// outrank missing digests.
+fn verify_outcome(
+    layers: &[tcow::verify::LayerCheck],
+    entries: Option<&tcow::verify::EntryReport>,
+    structure_error: bool,
+    missing: usize,
+    unrecorded: usize,
+) -> Option<(i32, &'static str, String)> {
+    use tcow::verify::{EntryKind, LayerStatus};
+
+    let count = |status| layers.iter().filter(|c| c.status == status).count();
+    let (mismatched, unreadable) = (count(LayerStatus::Mismatch), count(LayerStatus::Unreadable));
+    let failures = entries.map_or(&[][..], |r| &r.failures[..]);
+    let ref_errors = failures.iter().filter(|f| f.kind != EntryKind::File).count();
+    let damaged = failures.len() - ref_errors;
+
+    if unreadable > 0 || structure_error {
+        let what = match unreadable {
+            0 => "the file's layers could not be parsed".to_string(),
+            u => format!("{u} layer(s) could not be read"),
+        };
+        Some((EXIT_INVALID, "structural", format!("structural error: {what}")))
+    } else if mismatched + ref_errors + damaged > 0 {
+        let failed: Vec<String> = [
+            (mismatched, "layer(s)"),
+            (ref_errors, "reference(s) or delta(s)"),
+            (damaged, "file(s)"),
+        ]
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, what)| format!("{count} {what}"))
+        .collect();
+        Some((EXIT_INTEGRITY, "mismatch", format!("{} failed integrity check", failed.join(" and "))))
+    } else if missing > 0 || unrecorded > 0 {
+        let what = match (missing, unrecorded) {
+            (0, f) => format!("{f} file(s) have no recorded digest"),
+            (l, 0) => format!("{l} layer(s) have no stored digest"),
+            (l, f) => format!("{l} layer(s) and {f} file(s) have no stored digest"),
+        };
+        Some((EXIT_MISSING_DIGEST, "missing", format!("{what}; run with --fix-missing to record them")))
     } else {
-        bail!("{errors} layer(s) failed integrity check");
+        None
     }
 }
 
+
+/// The `verify --json` report.
+fn verify_report(
+    path: &Path,
+    outcome: Option<&(i32, &str, String)>,
+    layers: &[tcow::verify::LayerCheck],
+    structure_error: Option<&str>,
+    entries: Option<&tcow::verify::EntryReport>,
+) -> serde_json::Value {
+    let (code, status) = outcome.map_or((0, "ok"), |(code, status, _)| (*code, *status));
+    serde_json::json!({
+        "file": path.display().to_string(),
+        "status": status,
+        "exit_code": code,
+        "layers": layers,
+        "structure_error": structure_error,
+        "entries": entries,
+    })
+}
+
+// ── fsck ──────────────────────────────────────────────────────────────────────
+
+fn cmd_fsck(path: PathBuf, json: bool) -> Result<()> {
+    use tcow::fsck::{self, Severity};
+
+    let report = fsck::fsck(&path)?;
+    let (errors, warnings) = (report.errors(), report.warnings());
+    if json {
+        let doc = serde_json::json!({
+            "file": path.display().to_string(),
+            "layers": report.layers,
+            "errors": errors,
+            "warnings": warnings,
+            "findings": report.findings,
+        });
+        println!("{}", serde_json::to_string_pretty(&doc)?);
+    } else {
+        println!("Checking {} ({} layers)…\n", path.display(), report.layers);
+        for finding in &report.findings {
+            let mark = match finding.severity {
+                Severity::Error => "✗",
+                Severity::Warning => "!",
+            };
+            let place = match (finding.layer, &finding.path) {
+                (Some(i), Some(p)) => format!("Layer {i:>2}  {p}: "),
+                (Some(i), None) => format!("Layer {i:>2}: "),
+                (None, Some(p)) => format!("{p}: "),
+                (None, None) => String::new(),
+            };
+            println!("  {mark}  {place}{}", finding.message);
+        }
+        if report.findings.is_empty() {
+            println!("  No problems found.");
+        }
+        println!();
+    }
+    if errors > 0 {
+        return Err(ExitError::new(EXIT_INVALID, format!("{errors} error(s), {warnings} warning(s)")).into());
+    }
+    if !json && warnings > 0 {
+        println!("{warnings} warning(s), no errors.");
+    } else if !json {
+        println!("File structure is consistent.");
+    }
+    Ok(())
+}
+
 // ── layers ────────────────────────────────────────────────────────────────────
 
 fn cmd_layers(path: PathBuf, json: bool) -> Result<()> {
@@ -711,8 +1581,8 @@ fn cmd_layers(path: PathBuf, json: bool) -> Result<()> {
         println!("]");
     } else {
         println!(
-            "  {:<3}  {:<6}  {:<12}  {:<10}  {:<64}  {}",
-            "#", "Kind", "Offset", "Size", "Digest (SHA-256)", "Created"
+            "  {:<3}  {:<6}  {:<12}  {:<10}  {:<64}  Created",
+            "#", "Kind", "Offset", "Size", "Digest (SHA-256)"
         );
         println!(
             "  {}  {}  {}  {}  {}  {}",
@@ -733,3 +1603,617 @@ fn cmd_layers(path: PathBuf, json: bool) -> Result<()> {
     }
     Ok(())
 }
+
+// ── diff ──────────────────────────────────────────────────────────────────────
+
+/// One side of `diff`: an open `.tcow` file and the layer to view it at, or
+/// a host directory.
+enum Source {
+    Tcow(Box<TcowFile>, Option<usize>),
+    Dir(HostDir),
+}
+
+impl Source {
+    fn open(path: &Path, at: Option<usize>, flag: &str) -> Result<Self> {
+        if path.is_dir() {
+            if at.is_some() {
+                bail!("{flag} only applies to .tcow files, and {:?} is a directory", path);
+            }
+            return Ok(Source::Dir(HostDir::new(path)));
+        }
+        Ok(Source::Tcow(Box::new(TcowFile::open(path)?), at))
+    }
+
+    fn vfs(&self) -> Result<Box<dyn Vfs + '_>> {
+        Ok(match self {
+            Source::Tcow(tcow, Some(top)) => Box::new(tcow.view_at(*top)?),
+            Source::Tcow(tcow, None) => Box::new(tcow.view()),
+            Source::Dir(dir) => Box::new(dir.clone()),
+        })
+    }
+}
+
+fn cmd_diff(
+    old_path: PathBuf,
+    new_path: PathBuf,
+    old_at: Option<usize>,
+    new_at: Option<usize>,
+    vpath: Option<String>,
+    checksum: bool,
+) -> Result<()> {
+    let root = vpath.as_deref().map(VPath::new).transpose()?.unwrap_or_default();
+    let old = Source::open(&old_path, old_at, "--old-at")?;
+    let new = Source::open(&new_path, new_at, "--new-at")?;
+
+    let changes = vfs::diff(&*old.vfs()?, &root, &*new.vfs()?, &root, checksum)?;
+    for source in [&old, &new] {
+        if let Source::Dir(dir) = source {
+            warn_skipped(dir);
+        }
+    }
+    if changes.is_empty() {
+        println!("No differences");
+        return Ok(());
+    }
+    for change in &changes {
+        println!("  {}  /{}", change.tag(), root.join(change.path())?);
+    }
+    let count = |tag| changes.iter().filter(|c| c.tag() == tag).count();
+    println!("{} added, {} modified, {} deleted", count('A'), count('M'), count('D'));
+    Ok(())
+}
+
+/// Warn about host entries left out because their names are not UTF-8.
+fn warn_skipped(dir: &HostDir) {
+    for p in dir.skipped() {
+        eprintln!("warning: skipping {:?} (file name is not UTF-8)", p);
+    }
+}
+
+// ── sync ──────────────────────────────────────────────────────────────────────
+
+fn cmd_sync(
+    path: PathBuf,
+    dir: PathBuf,
+    vpath: Option<String>,
+    checksum: bool,
+    opts: AppendOptions,
+    dry_run: bool,
+) -> Result<()> {
+    if !dir.is_dir() {
+        bail!("{:?} is not a directory", dir);
+    }
+    let prefix = vpath.as_deref().map(VPath::new).transpose()?.unwrap_or_default();
+
+    let tcow = if path.exists() { Some(TcowFile::open(&path)?) } else { None };
+    let host = HostDir::new(&dir);
+    let changes = match &tcow {
+        Some(tcow) => vfs::diff(&tcow.view(), &prefix, &host, &VPath::root(), checksum)?,
+        None => host.walk(&VPath::root())?.into_iter().map(|e| Change::Added(e.path)).collect(),
+    };
+    warn_skipped(&host);
+
+    let mut files: Vec<(VPath, &VPath)> = Vec::new();
+    let mut whiteouts: Vec<VPath> = Vec::new();
+    let (mut added, mut modified) = (0usize, 0usize);
+
+    // Additions and modifications first, then deletions, each in path order
+    for change in changes.iter().filter(|c| !matches!(c, Change::Deleted(_))) {
+        let canonical = prefix.join(change.path())?;
+        println!("  {}  /{canonical}", change.tag());
+        match change {
+            Change::Added(_) => added += 1,
+            _ => modified += 1,
+        }
+        files.push((canonical, change.path()));
+    }
+    for change in changes.iter().filter(|c| matches!(c, Change::Deleted(_))) {
+        let canonical = prefix.join(change.path())?;
+        println!("  D  /{canonical}");
+        whiteouts.push(canonical);
+    }
+
+    let summary = format!("{added} added, {modified} modified, {} deleted", whiteouts.len());
+    if added + modified + whiteouts.len() == 0 {
+        println!("No changes between {} and the union view", dir.display());
+        return Ok(());
+    }
+    if dry_run {
+        let n = tcow.as_ref().map_or(0, |t| t.index.layers.len());
+        println!("[DRY RUN] Would write layer {n}: {summary}");
+        return Ok(());
+    }
+
+    // Each file keeps its host mtime, so the next sync can skip files whose
+    // size and mtime still match without reading them. The mtime is taken
+    // before the content: a write in between then shows up as a newer mtime.
+    // A file modified in the second this sync started could change again
+    // without its mtime moving, so it is recorded a second early and the
+    // next sync compares its content instead.
+    let started = tcow::now_unix_ts();
+    let known = match &tcow {
+        Some(tcow) if opts.dedup => tcow.content_index(),
+        _ => HashMap::new(),
+    };
+    let mut writer = match tcow {
+        Some(_) => LayerWriter::append(&path)?,
+        None => LayerWriter::create(&path, None)?,
+    };
+    let (mut deduped, mut deltas) = (0usize, 0usize);
+    for (canonical, rel) in &files {
+        let host_path = host.host_path(rel);
+        let mut f = fs::File::open(&host_path).with_context(|| format!("reading {:?}", host_path))?;
+        let meta = f.metadata().with_context(|| format!("reading metadata of {:?}", host_path))?;
+        let (size, mtime) = (meta.len(), vfs::mtime_secs(&meta));
+        writer.set_mtime(if mtime >= started { mtime.saturating_sub(1) } else { mtime });
+
+        // Bodies are streamed from the host file. Only a file that may be
+        // stored as a delta against its current version is read into memory
+        let delta_base = match &tcow {
+            Some(tcow) => opts.delta && size >= tcow::delta::DELTA_MIN_SIZE as u64 && tcow.lookup(canonical).is_some(),
+            None => false,
+        };
+        let content = if delta_base {
+            let mut buf = Vec::new();
+            f.read_to_end(&mut buf).with_context(|| format!("reading {:?}", host_path))?;
+            Some(buf)
+        } else {
+            None
+        };
+        let digest = match &content {
+            _ if known.is_empty() || size < tcow::dedup::DEDUP_MIN_SIZE as u64 => None,
+            Some(buf) => Some(tcow::sha256_hex(buf)),
+            None => {
+                let mut hasher = HashingWriter::new(io::sink());
+                io::copy(&mut f, &mut hasher).with_context(|| format!("reading {:?}", host_path))?;
+                f.rewind()?;
+                Some(hasher.finish().1)
+            }
+        };
+        let blob = digest.and_then(|d| known.get(&d));
+        let delta = match (&tcow, &content) {
+            (Some(tcow), Some(buf)) if blob.is_none() => tcow.encode_delta(canonical, buf),
+            _ => None,
+        };
+
+        if let Some(blob) = blob {
+            writer.add_reference(canonical, blob)?;
+            deduped += 1;
+        } else if let Some((d, patch)) = delta {
+            writer.add_delta(canonical, &d, &patch)?;
+            deltas += 1;
+        } else if let Some(buf) = content {
+            writer.add_file(canonical, &buf[..], buf.len() as u64)?;
+        } else {
+            writer.add_file(canonical, io::BufReader::new(f), size)?;
+        }
+    }
+    writer.set_mtime(tcow::now_unix_ts());
+    for wh in &whiteouts {
+        writer.add_whiteout(wh)?;
+    }
+    let n = writer.layer_index();
+    writer.commit()?;
+
+    if n == 0 {
+        println!("Created {:?} from {} as base layer 0: {summary}", path, dir.display());
+        return Ok(());
+    }
+    println!("Synced {} into new delta layer {n}: {summary}", dir.display());
+    if deduped > 0 {
+        println!("  {deduped} file(s) stored as references to existing content");
+    }
+    if deltas > 0 {
+        println!("  {deltas} file(s) stored as deltas against their previous version");
+    }
+    Ok(())
+}
+
+// ── import-tar / import-zip / import-cpio ─────────────────────────────────────
+
+fn cmd_import(
+    path: PathBuf,
+    archive: PathBuf,
+    format: ImportFormat,
+    label: Option<String>,
+    dry_run: bool,
+) -> Result<()> {
+    let existed = path.exists();
+    if existed && label.is_some() {
+        bail!("--label is only recorded when creating a new .tcow file, and {:?} already exists", path);
+    }
+    let open_zip = || -> Result<_> {
+        let f = fs::File::open(&archive).with_context(|| format!("cannot open {:?}", archive))?;
+        Ok(io::BufReader::new(f))
+    };
+    let context = || format!("reading {} archive {:?}", format.name(), archive);
+
+    // A first pass reads only headers; the files are streamed in by a second
+    let import = match format {
+        ImportFormat::Tar => tcow::scan_tar_archive(tcow::open_archive_reader(&archive)?).with_context(context)?,
+        ImportFormat::Zip => tcow::archive::scan_zip_archive(open_zip()?).with_context(context)?,
+        ImportFormat::Cpio => tcow::archive::scan_cpio_archive(tcow::open_archive_reader(&archive)?)
+            .with_context(context)?,
+    };
+
+    for p in &import.skipped {
+        eprintln!("warning: skipping /{p} (cannot be represented in a layer)");
+    }
+    for note in &import.lossy {
+        eprintln!("warning: {note}");
+    }
+    let summary = format!(
+        "{} file(s) ({}), {} whiteout(s)",
+        import.files(),
+        format_bytes(import.bytes()),
+        import.whiteouts()
+    );
+
+    if dry_run {
+        if existed {
+            let mut f = fs::File::open(&path)?;
+            let (index, _) = tcow::read_index(&mut f, &path)?;
+            println!("[DRY RUN] Would import {summary} as new delta layer {}", index.layers.len());
+        } else {
+            println!("[DRY RUN] Would create {:?} with {summary} as base layer 0", path);
+        }
+        return Ok(());
+    }
+
+    let mut writer = if existed {
+        LayerWriter::append(&path)?
+    } else {
+        LayerWriter::create(&path, label)?
+    };
+    let n = writer.layer_index();
+    match format {
+        ImportFormat::Tar => tcow::import_tar_archive(tcow::open_archive_reader(&archive)?, &import, &mut writer)
+            .with_context(context)?,
+        ImportFormat::Zip => tcow::archive::import_zip_archive(open_zip()?, &import, &mut writer)
+            .with_context(context)?,
+        ImportFormat::Cpio => {
+            tcow::archive::import_cpio_archive(tcow::open_archive_reader(&archive)?, &import, &mut writer)
+                .with_context(context)?
+        }
+    }
+    writer.commit()?;
+
+    if existed {
+        println!("Imported {} into new delta layer {n}: {summary}", archive.display());
+    } else {
+        println!("Created {:?} from {} as base layer 0: {summary}", path, archive.display());
+    }
+    Ok(())
+}
+
+// ── export ────────────────────────────────────────────────────────────────────
+
+fn cmd_export(
+    path: PathBuf,
+    output: Option<PathBuf>,
+    format: Option<ExportFormat>,
+    at: Option<usize>,
+    layer: Option<usize>,
+    raw: bool,
+    force: bool,
+) -> Result<()> {
+    let tcow = TcowFile::open(&path)?;
+    let n_layers = tcow.layers.len();
+    if n_layers == 0 {
+        bail!("{:?} has no layers to export", path);
+    }
+    if let Some(layer_idx) = layer.filter(|&i| raw && !tcow.layer_is_self_contained(i)) {
+        // Reference and delta entries only make sense next to their lower layers
+        if !force {
+            bail!(
+                "layer {layer_idx} holds dedup references or deltas, which a raw copy stores as \
+                 placeholders without their content; export it without --raw, or add --force"
+            );
+        }
+        eprintln!("warning: layer {layer_idx} refers to content in lower layers; the raw copy is not self-contained");
+    }
+    let format = format
+        .or_else(|| output.as_deref().map(ExportFormat::from_path))
+        .unwrap_or(ExportFormat::Tar);
+    let top = at.unwrap_or(n_layers - 1);
+
+    if format != ExportFormat::Tar && layer.is_some() {
+        bail!("--layer is only supported for tar exports (zip and cpio cannot express whiteouts)");
+    }
+    if format == ExportFormat::Zip {
+        let out = output.ok_or_else(|| {
+            anyhow::anyhow!("zip export needs --output (zip cannot be streamed to stdout)")
+        })?;
+        let f = fs::File::create(&out).with_context(|| format!("cannot create {:?}", out))?;
+        let report = tcow::archive::write_union_zip(&tcow, top, io::BufWriter::new(f))?;
+        for note in &report.lossy {
+            eprintln!("warning: {note}");
+        }
+        println!("Exported union view at layer {top} ({} files) to {}", report.files, out.display());
+        return Ok(());
+    }
+
+    let (sink, compression): (Box<dyn Write>, _) = match &output {
+        Some(out) => {
+            let f = fs::File::create(out).with_context(|| format!("cannot create {:?}", out))?;
+            (Box::new(io::BufWriter::new(f)), Compression::from_path(out))
+        }
+        None => (Box::new(io::stdout().lock()), Compression::None),
+    };
+    let mut w = ArchiveWriter::new(sink, compression)?;
+
+    let what = match layer {
+        Some(layer_idx) if raw => {
+            let bytes = tcow.copy_raw_layer(layer_idx, &mut w)?;
+            format!("layer {layer_idx} ({} raw)", format_bytes(bytes))
+        }
+        Some(layer_idx) => {
+            let count = tcow.write_layer_tar(layer_idx, &mut w)?;
+            format!("layer {layer_idx} ({count} entries)")
+        }
+        None if format == ExportFormat::Cpio => {
+            let report = tcow::archive::write_union_cpio(&tcow, top, &mut w)?;
+            for note in &report.lossy {
+                eprintln!("warning: {note}");
+            }
+            format!("union view at layer {top} ({} files)", report.files)
+        }
+        None => {
+            let count = tcow.write_union_tar(top, &mut w)?;
+            format!("union view at layer {top} ({count} files)")
+        }
+    };
+    w.finish()?;
+
+    if let Some(out) = output {
+        println!("Exported {what} to {}", out.display());
+    }
+    Ok(())
+}
+
+// ── export-oci ────────────────────────────────────────────────────────────────
+
+fn cmd_export_oci(path: PathBuf, dir: PathBuf, tag: String, os: String, arch: String) -> Result<()> {
+    let tcow = TcowFile::open(&path)?;
+    let opts = OciExportOptions { tag, os, architecture: arch };
+    let manifest = tcow::oci::export_oci(&tcow, &dir, &opts)?;
+
+    println!("Exported {} layer(s) to OCI layout {}", tcow.index.layers.len(), dir.display());
+    println!("  Manifest: {manifest}");
+    println!("  Tag:      {}", opts.tag);
+    Ok(())
+}
+
+// ── import-oci ────────────────────────────────────────────────────────────────
+
+fn cmd_import_oci(
+    path: PathBuf,
+    source: PathBuf,
+    tag: Option<String>,
+    label: Option<String>,
+    dry_run: bool,
+) -> Result<()> {
+    let existed = path.exists();
+    if existed && label.is_some() {
+        bail!("--label is only recorded when creating a new .tcow file, and {:?} already exists", path);
+    }
+    let first = if existed { TcowFile::open(&path)?.index.layers.len() } else { 0 };
+
+    if dry_run {
+        let image = tcow::oci::Image::open(&source, tag.as_deref())?;
+        for i in 0..image.len() {
+            let layer = image.read_layer(i)?;
+            for p in &layer.skipped {
+                eprintln!("warning: skipping /{p} in image layer {i} (cannot be represented in a layer)");
+            }
+            println!(
+                "[DRY RUN] Would import image layer {i} as layer {}  {}  sha256:{}",
+                first + i,
+                format_bytes(layer.size),
+                layer.digest
+            );
+        }
+        return Ok(());
+    }
+
+    let import = tcow::oci::import_oci(&source, &path, tag.as_deref(), label)?;
+    for (i, p) in &import.skipped {
+        eprintln!("warning: skipping /{p} in image layer {i} (cannot be represented in a layer)");
+    }
+    let tcow = import.tcow;
+    for (i, rec) in tcow.index.layers.iter().enumerate().skip(first) {
+        let digest = rec.digest.as_deref().unwrap_or("(none)");
+        println!("  Layer {i:>2}  [{:>5}]  {:>10}  sha256:{digest}", rec.kind, format_bytes(rec.size));
+    }
+    println!(
+        "Imported {} image layer(s) from {} into {}",
+        tcow.index.layers.len() - first,
+        source.display(),
+        path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tcow::{LayerKind, LayerRecord, TcowIndex, FORMAT_VERSION, HEADER_SIZE};
+
+    /// A `.tcow` file whose only layer was written by something other than
+    /// tcow, with entry names stored exactly as given.
+    fn write_hostile_tcow(path: &Path, names: &[&[u8]]) {
+        let mut layer = tar::Builder::new(Vec::new());
+        for name in names {
+            let mut hdr = tar::Header::new_old();
+            hdr.as_old_mut().name[..name.len()].copy_from_slice(name);
+            hdr.set_size(3);
+            hdr.set_mode(0o644);
+            hdr.set_cksum();
+            layer.append(&hdr, &b"pwn"[..]).unwrap();
+        }
+        let layer = layer.into_inner().unwrap();
+
+        let index = TcowIndex {
+            version: FORMAT_VERSION,
+            layers: vec![LayerRecord {
+                offset: HEADER_SIZE,
+                size: layer.len() as u64,
+                kind: LayerKind::Base,
+                digest: None,
+                created_at: "2026-01-01T00:00:00Z".into(),
+                file_digests: Default::default(),
+                extensions: Default::default(),
+            }],
+            last_modified: "2026-01-01T00:00:00Z".into(),
+            label: None,
+            extensions: Default::default(),
+        };
+        let cbor = tcow::encode_cbor(&index).unwrap();
+        let mut f = fs::File::create(path).unwrap();
+        tcow::write_file_header(&mut f, true).unwrap();
+        f.write_all(&layer).unwrap();
+        f.write_all(&cbor).unwrap();
+        tcow::write_trailer_footer(&mut f, HEADER_SIZE + layer.len() as u64, cbor.len() as u32).unwrap();
+    }
+
+    #[test]
+    fn extract_never_writes_outside_the_output_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outdir = tmp.path().join("out");
+        for name in [&b"../escape.sh"[..], b"ok/../../escape.sh", b"/tmp/escape.sh"] {
+            let file = tmp.path().join("hostile.tcow");
+            write_hostile_tcow(&file, &[b"ok.txt", name]);
+
+            let err = cmd_extract(file, None, outdir.clone(), None, None, false).unwrap_err();
+            assert_eq!(exit_code(&err), EXIT_UNSAFE_PATH, "{name:?}: {err:#}");
+            assert!(!tmp.path().join("escape.sh").exists());
+            assert!(!outdir.exists(), "{name:?}: nothing is extracted from a file that fails to open");
+        }
+    }
+
+    #[test]
+    fn exit_codes_keep_errors_apart_from_no_match() {
+        let io_error = || io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        let cases: [(anyhow::Error, i32); 8] = [
+            (ExitError::new(EXIT_NO_MATCH, "").into(), EXIT_NO_MATCH),
+            (io_error().into(), EXIT_IO),
+            (anyhow::Error::from(io_error()).context("cannot open \"a.tcow\""), EXIT_IO),
+            (TcowError::Io { context: "reading layer data".into(), source: io_error() }.into(), EXIT_IO),
+            (anyhow::Error::from(TcowError::Invalid("no".into())).context("inserting"), EXIT_INVALID_INPUT),
+            (anyhow::anyhow!("--layer is only supported for tar exports"), EXIT_INVALID_INPUT),
+            (TcowError::NoSuchLayer { index: 4, count: 2 }.into(), EXIT_NOT_FOUND),
+            (VPath::new("../x").unwrap_err().into(), EXIT_UNSAFE_PATH),
+        ];
+        for (err, code) in cases {
+            assert_eq!(exit_code(&err), code, "{err:#}");
+        }
+    }
+
+    #[test]
+    fn verify_maps_each_outcome_to_its_exit_code_and_json_status() {
+        use tcow::verify::{EntryCheck, EntryKind, EntryReport, EntryStatus, LayerCheck, LayerStatus};
+
+        let layer = |status| LayerCheck {
+            index: 0,
+            kind: LayerKind::Base,
+            size: 1024,
+            status,
+            stored: None,
+            computed: None,
+            error: None,
+        };
+        let damaged = |kind| EntryCheck {
+            layer: 0,
+            path: "/a".into(),
+            kind,
+            status: EntryStatus::Damaged,
+            stored: "00".repeat(32),
+            computed: Some("11".repeat(32)),
+            origin: None,
+        };
+        let report = |failures| EntryReport { references: 1, files: 1, unrecorded: 0, failures };
+        let outcome = |status, entries: Option<&EntryReport>, structural, missing, unrecorded| {
+            verify_outcome(&[layer(LayerStatus::Ok), layer(status)], entries, structural, missing, unrecorded)
+                .map(|(code, status, _)| (code, status))
+        };
+
+        let clean = report(vec![]);
+        assert_eq!(outcome(LayerStatus::Ok, Some(&clean), false, 0, 0), None);
+        assert_eq!(outcome(LayerStatus::Missing, Some(&clean), false, 1, 0), Some((EXIT_MISSING_DIGEST, "missing")));
+        assert_eq!(outcome(LayerStatus::Ok, Some(&clean), false, 0, 3), Some((EXIT_MISSING_DIGEST, "missing")));
+        assert_eq!(outcome(LayerStatus::Mismatch, Some(&clean), false, 0, 0), Some((EXIT_INTEGRITY, "mismatch")));
+        let reference = report(vec![damaged(EntryKind::Reference)]);
+        assert_eq!(outcome(LayerStatus::Missing, Some(&reference), false, 1, 0), Some((EXIT_INTEGRITY, "mismatch")));
+        let file = report(vec![damaged(EntryKind::File)]);
+        assert_eq!(outcome(LayerStatus::Ok, Some(&file), false, 0, 0), Some((EXIT_INTEGRITY, "mismatch")));
+        assert_eq!(outcome(LayerStatus::Unreadable, Some(&file), false, 0, 0), Some((EXIT_INVALID, "structural")));
+        assert_eq!(outcome(LayerStatus::Mismatch, None, true, 1, 0), Some((EXIT_INVALID, "structural")));
+
+        let layers = [layer(LayerStatus::Mismatch)];
+        let failed = verify_outcome(&layers, Some(&file), false, 0, 0);
+        let json = verify_report(Path::new("a.tcow"), failed.as_ref(), &layers, None, Some(&file));
+        let keys: Vec<_> = json.as_object().unwrap().keys().map(String::as_str).collect();
+        assert_eq!(keys, ["entries", "exit_code", "file", "layers", "status", "structure_error"]);
+        assert_eq!((&json["status"], &json["exit_code"]), (&"mismatch".into(), &EXIT_INTEGRITY.into()));
+        assert_eq!(json["file"], "a.tcow");
+        assert_eq!(json["layers"][0]["status"], "mismatch");
+        assert_eq!(json["entries"]["failures"][0]["kind"], "file");
+        assert_eq!(json["entries"]["failures"][0]["status"], "damaged");
+
+        let json = verify_report(Path::new("a.tcow"), None, &[], Some("layer 1: bad"), None);
+        assert_eq!((&json["status"], &json["exit_code"]), (&"ok".into(), &0.into()));
+        assert!(json["entries"].is_null());
+    }
+
+    #[test]
+    fn verify_reads_a_damaged_file_from_disk_and_fails_its_check() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("a.tcow");
+        let files = [(VPath::new("a").unwrap(), b"intact".to_vec())];
+        let tcow = TcowFile::create_in(io::Cursor::new(Vec::new()), &files, &[], None).unwrap();
+        let mut bytes = tcow.into_storage().into_inner();
+        fs::write(&file, &bytes).unwrap();
+        cmd_verify(file.clone(), true, false, true, Some(1)).unwrap();
+
+        let at = bytes.windows(6).position(|w| w == b"intact").unwrap();
+        bytes[at] = b'I';
+        fs::write(&file, &bytes).unwrap();
+        let err = cmd_verify(file, true, false, true, Some(1)).unwrap_err();
+        assert_eq!(exit_code(&err), EXIT_INTEGRITY, "{err:#}");
+        assert!(err.to_string().contains("1 layer(s) and 1 file(s)"), "{err}");
+    }
+
+    fn set_mtime(path: &Path, secs: u64) {
+        let t = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        fs::File::options().write(true).open(path).unwrap().set_modified(t).unwrap();
+    }
+
+    #[test]
+    fn sync_records_host_mtimes_and_hashes_only_when_they_differ() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (dir, file) = (tmp.path().join("dir"), tmp.path().join("sync.tcow"));
+        let host = dir.join("a.txt");
+        fs::create_dir(&dir).unwrap();
+        fs::write(&host, b"hello").unwrap();
+        set_mtime(&host, 1_700_000_000);
+        let sync = || cmd_sync(file.clone(), dir.clone(), None, false, AppendOptions::default(), false).unwrap();
+        let a = VPath::new("a.txt").unwrap();
+
+        sync();
+        let tcow = TcowFile::open(&file).unwrap();
+        assert_eq!(tcow.lookup(&a).unwrap().0.mtime, 1_700_000_000);
+
+        // Touched but unchanged: hashed, and no layer is written
+        set_mtime(&host, 1_700_000_100);
+        sync();
+        assert_eq!(TcowFile::open(&file).unwrap().index.layers.len(), 1);
+
+        // Edited to the same size: the mtime differs, so the hash catches it
+        fs::write(&host, b"jello").unwrap();
+        set_mtime(&host, 1_700_000_200);
+        sync();
+        let tcow = TcowFile::open(&file).unwrap();
+        assert_eq!(tcow.index.layers.len(), 2);
+        assert_eq!(&tcow.lookup(&a).unwrap().0.data[..], b"jello");
+    }
+}
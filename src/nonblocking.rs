@@ -0,0 +1,352 @@
+//! Async wrappers for tokio-based hosts (`async` feature).
+//!
+//! Opening a `.tcow` file parses every layer, and appending or verifying
+//! one hashes and writes whole tar streams; called from an async task, any
+//! of that would stall the executor. `AsyncTcowFile` runs that work on
+//! tokio's blocking pool and shares the parsed file between tasks behind an
+//! async `RwLock`: lookups run concurrently, an append waits for them and
+//! holds the file until its layer is written and read back.
+//!
+//! Appends are cancellation-safe. Dropping an `append` future before it has
+//! the file changes nothing; once it does, the write runs to completion on
+//! the blocking pool even if the future is dropped, and the new layer is
+//! visible to every later call.
+
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::{RwLock, RwLockReadGuard};
+use tokio::task::JoinHandle;
+
+use crate::error::{Result, TcowError};
+use crate::storage::{FileStorage, Storage, StorageMut};
+use crate::verify::{self, EntryReport, LayerCheck};
+use crate::{AppendOptions, AppendReport, Blob, ReadMode, ResolvedEntry, TcowFile, VPath};
+
+/// Bytes copied per blocking read in `AsyncFileReader`.
+const READ_CHUNK: usize = 256 * 1024;
+
+/// A `TcowFile` shared between async tasks. Cloning is cheap and every
+/// clone sees the same file.
+pub struct AsyncTcowFile<S = FileStorage> {
+    inner: Arc<RwLock<TcowFile<S>>>,
+}
+
+impl<S> Clone for AsyncTcowFile<S> {
+    fn clone(&self) -> Self {
+        AsyncTcowFile { inner: Arc::clone(&self.inner) }
+    }
+}
+
+impl<S> From<TcowFile<S>> for AsyncTcowFile<S> {
+    fn from(tcow: TcowFile<S>) -> Self {
+        AsyncTcowFile { inner: Arc::new(RwLock::new(tcow)) }
+    }
+}
+
+impl AsyncTcowFile {
+    /// `TcowFile::open` on the blocking pool.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(path, ReadMode::from_env()).await
+    }
+
+    /// `TcowFile::open_with` on the blocking pool.
+    pub async fn open_with(path: impl AsRef<Path>, mode: ReadMode) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let tcow = blocking(move || TcowFile::open_with(path, mode)).await??;
+        Ok(tcow.into())
+    }
+}
+
+impl<S: Storage + Send + Sync + 'static> AsyncTcowFile<S> {
+    /// `TcowFile::from_storage` on the blocking pool.
+    pub async fn from_storage(storage: S) -> Result<Self> {
+        let tcow = blocking(move || TcowFile::from_storage(storage)).await??;
+        Ok(tcow.into())
+    }
+
+    /// Shared access to the parsed file for anything without an async
+    /// variant. Appends wait until the guard is dropped, so keep it short.
+    pub async fn read(&self) -> RwLockReadGuard<'_, TcowFile<S>> {
+        self.inner.read().await
+    }
+
+    /// `TcowFile::resolve`. The lookup itself is in memory; the returned
+    /// data should be read through `open_file` when it may be large.
+    pub async fn resolve(&self, vpath: &VPath) -> Option<(ResolvedEntry, usize)> {
+        self.inner.read().await.resolve(vpath)
+    }
+
+    /// Open a visible file for streaming. Bytes are copied out on the
+    /// blocking pool, since touching mapped pages or rebuilding a delta
+    /// can block.
+    pub async fn open_file(&self, vpath: &VPath) -> Result<AsyncFileReader> {
+        let tcow = self.inner.read().await;
+        let (entry, _) = tcow
+            .lookup(vpath)
+            .ok_or_else(|| TcowError::NotFound { path: vpath.clone(), layer: None })?;
+        Ok(AsyncFileReader::new(entry.data.clone()))
+    }
+
+    /// Hash every layer and compare it with its recorded digest, as
+    /// `verify::check_loaded_layers` does.
+    pub async fn verify(&self) -> Result<Vec<LayerCheck>> {
+        let tcow = Arc::clone(&self.inner).read_owned().await;
+        blocking(move || verify::check_loaded_layers(&*tcow)).await
+    }
+
+    /// `verify::check_entries` on the blocking pool.
+    pub async fn check_entries(&self, deep: bool) -> Result<EntryReport> {
+        let tcow = Arc::clone(&self.inner).read_owned().await;
+        blocking(move || verify::check_entries(&*tcow, deep)).await
+    }
+
+    /// `TcowFile::refresh` on the blocking pool.
+    pub async fn refresh(&self) -> Result<usize> {
+        let mut tcow = Arc::clone(&self.inner).write_owned().await;
+        blocking(move || tcow.refresh()).await?
+    }
+}
+
+impl<S: StorageMut + Send + Sync + 'static> AsyncTcowFile<S> {
+    /// `TcowFile::append` on the blocking pool. See the module docs for
+    /// what happens when the future is dropped.
+    pub async fn append(&self, entries: Vec<(VPath, Vec<u8>)>, whiteouts: Vec<VPath>) -> Result<()> {
+        let mut tcow = Arc::clone(&self.inner).write_owned().await;
+        blocking(move || tcow.append(&entries, &whiteouts)).await?
+    }
+
+    /// `TcowFile::append_with` on the blocking pool.
+    pub async fn append_with(
+        &self,
+        entries: Vec<(VPath, Vec<u8>)>,
+        whiteouts: Vec<VPath>,
+        opts: AppendOptions,
+    ) -> Result<AppendReport> {
+        let mut tcow = Arc::clone(&self.inner).write_owned().await;
+        blocking(move || tcow.append_with(&entries, &whiteouts, opts)).await?
+    }
+}
+
+/// Run `f` on the blocking pool. The task runs to completion even if the
+/// returned future is dropped; a panic in it is resumed here.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Result<T> {
+    join(tokio::task::spawn_blocking(f).await)
+}
+
+fn join<T>(joined: std::result::Result<T, tokio::task::JoinError>) -> Result<T> {
+    match joined {
+        Ok(v) => Ok(v),
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(TcowError::Io { context: "background task was cancelled".into(), source: io::Error::other(e) }),
+    }
+}
+
+// ── AsyncFileReader ───────────────────────────────────────────────────────────
+
+/// An `AsyncRead + AsyncSeek` view of one file's bytes, the async
+/// counterpart of `FileReader`.
+pub struct AsyncFileReader {
+    data: Blob,
+    pos: u64,
+    /// Bytes starting at `chunk_start`, copied by the last finished read.
+    chunk: Vec<u8>,
+    chunk_start: u64,
+    /// A copy running on the blocking pool, and where it starts.
+    pending: Option<(u64, JoinHandle<Vec<u8>>)>,
+}
+
+impl AsyncFileReader {
+    fn new(data: Blob) -> Self {
+        AsyncFileReader { data, pos: 0, chunk: Vec::new(), chunk_start: 0, pending: None }
+    }
+
+    /// Size of the file's content in bytes.
+    pub fn len(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+impl AsyncRead for AsyncFileReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.pos >= this.len() || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let chunk_end = this.chunk_start + this.chunk.len() as u64;
+            if (this.chunk_start..chunk_end).contains(&this.pos) {
+                let avail = &this.chunk[(this.pos - this.chunk_start) as usize..];
+                let n = avail.len().min(buf.remaining());
+                buf.put_slice(&avail[..n]);
+                this.pos += n as u64;
+                return Poll::Ready(Ok(()));
+            }
+            let (start, handle) = this.pending.get_or_insert_with(|| {
+                let (data, start) = (this.data.clone(), this.pos);
+                let end = (start as usize).saturating_add(READ_CHUNK).min(data.len());
+                (start, tokio::task::spawn_blocking(move || data[start as usize..end].to_vec()))
+            });
+            let joined = ready!(Pin::new(handle).poll(cx));
+            this.chunk_start = *start;
+            this.pending = None;
+            this.chunk = join(joined).map_err(io::Error::other)?;
+        }
+    }
+}
+
+impl AsyncSeek for AsyncFileReader {
+    fn start_seek(mut self: Pin<&mut Self>, pos: SeekFrom) -> io::Result<()> {
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.len().checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file"))?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::task::Waker;
+
+    use super::*;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread().build().unwrap()
+    }
+
+    fn vpath(p: &str) -> VPath {
+        VPath::new(p).unwrap()
+    }
+
+    /// Deterministic bytes spanning a few `READ_CHUNK`s.
+    fn noise(len: usize) -> Vec<u8> {
+        (0..len as u64).map(|i| (i.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 56) as u8).collect()
+    }
+
+    fn create(dir: &Path) -> std::path::PathBuf {
+        let path = dir.join("a.tcow");
+        TcowFile::create(&path, &[(vpath("a"), b"base".to_vec())], &[], None).unwrap();
+        path
+    }
+
+    /// The file on disk opens and holds either just the base layer or the
+    /// base layer plus the whole of the appended one.
+    fn layers_on_disk(path: &Path) -> usize {
+        let tcow = TcowFile::open(path).unwrap();
+        let b = tcow.lookup(&vpath("b")).map(|(e, layer)| (e.data.to_vec(), layer));
+        match tcow.index.layers.len() {
+            1 => assert_eq!(b, None),
+            2 => assert_eq!(b, Some((noise(READ_CHUNK), 1))),
+            n => panic!("{n} layers"),
+        }
+        tcow.index.layers.len()
+    }
+
+    /// Poll `fut` once, then drop it.
+    fn poll_once_and_drop<F: Future>(fut: F) -> bool {
+        let mut fut = Box::pin(fut);
+        fut.as_mut().poll(&mut Context::from_waker(Waker::noop())).is_ready()
+    }
+
+    #[test]
+    fn an_append_dropped_before_it_has_the_file_changes_nothing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = create(tmp.path());
+        runtime().block_on(async {
+            let tcow = AsyncTcowFile::open(&path).await.unwrap();
+            let guard = tcow.read().await;
+            let done = poll_once_and_drop(tcow.append(vec![(vpath("b"), noise(READ_CHUNK))], vec![]));
+            assert!(!done, "the append waits for the read guard");
+            drop(guard);
+            assert_eq!(tcow.read().await.index.layers.len(), 1);
+        });
+        assert_eq!(layers_on_disk(&path), 1);
+    }
+
+    #[test]
+    fn an_append_dropped_once_it_has_the_file_still_completes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = create(tmp.path());
+        runtime().block_on(async {
+            let tcow = AsyncTcowFile::open(&path).await.unwrap();
+            poll_once_and_drop(tcow.append(vec![(vpath("b"), noise(READ_CHUNK))], vec![]));
+            // Waits for the write running on the blocking pool
+            let (entry, layer) = tcow.resolve(&vpath("b")).await.unwrap();
+            assert_eq!((&entry.data[..], layer), (&noise(READ_CHUNK)[..], 1));
+        });
+        assert_eq!(layers_on_disk(&path), 2);
+    }
+
+    async fn read(reader: &mut AsyncFileReader, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        let mut filled = 0;
+        while filled < len {
+            let n = poll_fn(|cx| {
+                let mut out = ReadBuf::new(&mut buf[filled..]);
+                ready!(Pin::new(&mut *reader).poll_read(cx, &mut out))?;
+                Poll::Ready(io::Result::Ok(out.filled().len()))
+            })
+            .await
+            .unwrap();
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        buf.truncate(filled);
+        buf
+    }
+
+    async fn seek(reader: &mut AsyncFileReader, pos: SeekFrom) -> io::Result<u64> {
+        Pin::new(&mut *reader).start_seek(pos)?;
+        poll_fn(|cx| Pin::new(&mut *reader).poll_complete(cx)).await
+    }
+
+    #[test]
+    fn file_reader_seeks_and_reads_across_chunk_boundaries() {
+        let data = noise(READ_CHUNK * 5 / 2);
+        let mut tcow = TcowFile::create_in(io::Cursor::new(Vec::new()), &[], &[], None).unwrap();
+        tcow.append(&[(vpath("big"), data.clone())], &[]).unwrap();
+        runtime().block_on(async {
+            let tcow = AsyncTcowFile::from(tcow);
+            let mut reader = tcow.open_file(&vpath("big")).await.unwrap();
+            assert_eq!(reader.len(), data.len() as u64);
+
+            // One read stops at the end of a chunk; the next carries on
+            assert_eq!(read(&mut reader, data.len()).await, data);
+            assert_eq!(read(&mut reader, 10).await, b"");
+
+            let boundary = READ_CHUNK as u64 - 10;
+            assert_eq!(seek(&mut reader, SeekFrom::Start(boundary)).await.unwrap(), boundary);
+            assert_eq!(read(&mut reader, 20).await, &data[READ_CHUNK - 10..READ_CHUNK + 10]);
+            // Back into the chunk just read, then across the next boundary
+            assert_eq!(seek(&mut reader, SeekFrom::Current(-15)).await.unwrap(), boundary + 5);
+            assert_eq!(read(&mut reader, READ_CHUNK).await, &data[READ_CHUNK - 5..READ_CHUNK * 2 - 5]);
+
+            assert_eq!(seek(&mut reader, SeekFrom::End(-5)).await.unwrap(), data.len() as u64 - 5);
+            assert_eq!(read(&mut reader, 10).await, &data[data.len() - 5..]);
+            assert_eq!(seek(&mut reader, SeekFrom::End(10)).await.unwrap(), data.len() as u64 + 10);
+            assert_eq!(read(&mut reader, 10).await, b"");
+
+            let err = seek(&mut reader, SeekFrom::Current(-(data.len() as i64) - 11)).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        });
+    }
+}
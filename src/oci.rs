@@ -0,0 +1,621 @@
+//! OCI image layout export and import.
+//!
+//! Every tcow layer is already an uncompressed tar stream with Docker-style
+//! whiteouts, which is exactly what an OCI `layer.v1.tar` blob is. Exporting
+//! is therefore a matter of copying each layer's bytes into `blobs/sha256/`
+//! and writing the config, manifest and index documents that reference them;
+//! importing decompresses each image layer to a temporary file and stores it
+//! unchanged.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{sha256_hex, HashingWriter, TcowFile, VPath};
+
+// ── Media types ───────────────────────────────────────────────────────────────
+
+pub const MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const MEDIA_TYPE_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+pub const MEDIA_TYPE_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+// ── Layout documents ──────────────────────────────────────────────────────────
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Descriptor {
+    #[serde(rename = "mediaType")]
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageIndex {
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: u32,
+    #[serde(rename = "mediaType", default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageManifest {
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: u32,
+    #[serde(rename = "mediaType", default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(default)]
+    pub architecture: String,
+    #[serde(default)]
+    pub os: String,
+    pub rootfs: RootFs,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<History>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootFs {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub diff_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct History {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+}
+
+/// Options for `export_oci`.
+#[derive(Debug, Clone)]
+pub struct OciExportOptions {
+    /// Value of the `org.opencontainers.image.ref.name` annotation.
+    pub tag: String,
+    pub os: String,
+    pub architecture: String,
+}
+
+impl Default for OciExportOptions {
+    fn default() -> Self {
+        OciExportOptions { tag: "latest".into(), os: "linux".into(), architecture: "amd64".into() }
+    }
+}
+
+// ── Export ────────────────────────────────────────────────────────────────────
+
+/// Write `tcow` as an OCI image layout in `dir` with one blob per layer.
+/// Layer blobs are copied straight from the `.tcow` file and checked against
+/// the stored `LayerRecord.digest` on the way; their digests double as the
+/// config's `diff_ids` since the blobs are uncompressed. Layers that hold
+/// dedup references or deltas are rebuilt with the content inlined, so their
+/// blob, and with it their `diff_id`, differs from the stored layer; the
+/// stored layer is still hashed against its digest first.
+/// Returns the digest of the manifest.
+pub fn export_oci<S>(tcow: &TcowFile<S>, dir: impl AsRef<Path>, opts: &OciExportOptions) -> Result<String> {
+    let dir = dir.as_ref();
+    let blob_dir = dir.join("blobs").join("sha256");
+    fs::create_dir_all(&blob_dir)
+        .with_context(|| format!("creating blob directory {:?}", blob_dir))?;
+
+    let mut layers = Vec::with_capacity(tcow.index.layers.len());
+    let mut history = Vec::with_capacity(tcow.index.layers.len());
+    for (i, rec) in tcow.index.layers.iter().enumerate() {
+        let tmp = blob_dir.join(format!(".layer-{i}.tmp"));
+        let mut w = HashingWriter::new(BufWriter::new(
+            File::create(&tmp).with_context(|| format!("cannot create {:?}", tmp))?,
+        ));
+        // Layers holding dedup references or deltas are rewritten with the
+        // full content inlined.
+        let materialize = !tcow.layer_is_self_contained(i);
+        let raw_digest = if materialize {
+            tcow.write_layer_tar(i, &mut w)?;
+            let mut raw = HashingWriter::new(io::sink());
+            tcow.copy_raw_layer(i, &mut raw)?;
+            raw.finish().1
+        } else {
+            tcow.copy_raw_layer(i, &mut w)?;
+            String::new()
+        };
+        let (inner, digest) = w.finish();
+        inner.into_inner().map_err(|e| e.into_error())?;
+
+        if let Some(stored) = &rec.digest {
+            let computed = if materialize { &raw_digest } else { &digest };
+            if stored != computed {
+                let _ = fs::remove_file(&tmp);
+                bail!("layer {i} digest mismatch: stored {stored}, computed {computed}");
+            }
+        }
+        let size = fs::metadata(&tmp)?.len();
+        fs::rename(&tmp, blob_dir.join(&digest))?;
+
+        layers.push(Descriptor {
+            media_type: MEDIA_TYPE_LAYER.into(),
+            digest: format!("sha256:{digest}"),
+            size,
+            annotations: BTreeMap::new(),
+        });
+        history.push(History {
+            created: Some(rec.created_at.clone()),
+            created_by: Some(format!("tcow layer {i} ({})", rec.kind)),
+        });
+    }
+
+    let config = ImageConfig {
+        created: Some(tcow.index.last_modified.clone()),
+        architecture: opts.architecture.clone(),
+        os: opts.os.clone(),
+        rootfs: RootFs {
+            kind: "layers".into(),
+            diff_ids: layers.iter().map(|l| l.digest.clone()).collect(),
+        },
+        history,
+    };
+    let config_desc = write_json_blob(&blob_dir, &config, MEDIA_TYPE_CONFIG)?;
+
+    let manifest = ImageManifest {
+        schema_version: 2,
+        media_type: Some(MEDIA_TYPE_MANIFEST.into()),
+        config: config_desc,
+        layers,
+    };
+    let mut manifest_desc = write_json_blob(&blob_dir, &manifest, MEDIA_TYPE_MANIFEST)?;
+    manifest_desc.annotations.insert(ANNOTATION_REF_NAME.into(), opts.tag.clone());
+
+    let index = ImageIndex {
+        schema_version: 2,
+        media_type: Some(MEDIA_TYPE_INDEX.into()),
+        manifests: vec![manifest_desc.clone()],
+    };
+    fs::write(dir.join("index.json"), serde_json::to_vec_pretty(&index)?)?;
+    fs::write(dir.join("oci-layout"), br#"{"imageLayoutVersion":"1.0.0"}"#)?;
+
+    Ok(manifest_desc.digest)
+}
+
+/// Serialise `doc` as JSON into a content-addressed blob and describe it.
+fn write_json_blob<T: Serialize>(blob_dir: &Path, doc: &T, media_type: &str) -> Result<Descriptor> {
+    let bytes = serde_json::to_vec(doc)?;
+    let digest = sha256_hex(&bytes);
+    fs::write(blob_dir.join(&digest), &bytes)?;
+    Ok(Descriptor {
+        media_type: media_type.into(),
+        digest: format!("sha256:{digest}"),
+        size: bytes.len() as u64,
+        annotations: BTreeMap::new(),
+    })
+}
+
+// ── Import ────────────────────────────────────────────────────────────────────
+
+/// One image layer, decompressed and ready to be stored as a tcow layer.
+#[derive(Debug)]
+pub struct ImageLayer {
+    /// Temporary file holding the uncompressed tar stream, exactly as the
+    /// image's `diff_id` describes it, positioned at its start. It is
+    /// deleted when closed.
+    pub tar: File,
+    /// Length of the stream in bytes.
+    pub size: u64,
+    /// Hex SHA-256 of the stream; equal to the layer's `diff_id`.
+    pub digest: String,
+    /// Media type of the blob as referenced by the manifest.
+    pub media_type: String,
+    /// Links, devices and other entries that are stored with the layer but
+    /// cannot be read back through it.
+    pub skipped: Vec<String>,
+}
+
+/// Where image documents and blobs are read from: an OCI layout directory or
+/// a `docker save` tarball, whose entries are located once and then read in
+/// place.
+enum ImageSource {
+    Dir(PathBuf),
+    Archive { file: File, entries: HashMap<VPath, (u64, u64)> },
+}
+
+impl ImageSource {
+    fn open(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            return Ok(ImageSource::Dir(path.to_path_buf()));
+        }
+        let mut file = File::open(path).with_context(|| format!("cannot open {:?}", path))?;
+        let mut magic = [0u8; 4];
+        let n = file.read(&mut magic)?;
+        file.seek(SeekFrom::Start(0))?;
+        if magic[..n].starts_with(&[0x1f, 0x8b]) || magic[..n] == [0x28, 0xb5, 0x2f, 0xfd] {
+            // Entries are read by offset, so a compressed archive is unpacked
+            // to a temporary file first
+            let mut unpacked = tempfile::tempfile().context("creating temporary file")?;
+            io::copy(&mut crate::open_archive_reader(path)?, &mut unpacked)
+                .with_context(|| format!("decompressing {:?}", path))?;
+            unpacked.seek(SeekFrom::Start(0))?;
+            file = unpacked;
+        }
+
+        let mut entries = HashMap::new();
+        let mut archive = tar::Archive::new(&file);
+        for entry_res in archive.entries_with_seek()? {
+            let entry = entry_res.context("reading image archive entry")?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            // Names that could not be referenced safely are never looked up
+            let Ok(name) = VPath::from_entry_name(&entry.path_bytes()) else { continue };
+            entries.insert(name, (entry.raw_file_position(), entry.size()));
+        }
+        Ok(ImageSource::Archive { file, entries })
+    }
+
+    fn exists(&self, rel: &str) -> bool {
+        let Ok(rel) = VPath::from_entry_name(rel.as_bytes()) else { return false };
+        match self {
+            ImageSource::Dir(dir) => dir.join(rel.as_str()).is_file(),
+            ImageSource::Archive { entries, .. } => entries.contains_key(&rel),
+        }
+    }
+
+    /// Open the document or blob at `rel`, a name taken from the image's own
+    /// manifests. Names that are absolute or climb out of the layout with
+    /// `..` are refused, so a crafted manifest cannot read host files.
+    fn open_entry(&self, rel: &str) -> Result<Box<dyn Read>> {
+        let name = VPath::from_entry_name(rel.as_bytes())
+            .with_context(|| format!("image refers to an unsafe path {rel:?}"))?;
+        match self {
+            ImageSource::Dir(dir) => {
+                let p = dir.join(name.as_str());
+                Ok(Box::new(File::open(&p).with_context(|| format!("reading {:?}", p))?))
+            }
+            ImageSource::Archive { file, entries } => {
+                let &(offset, size) = entries
+                    .get(&name)
+                    .ok_or_else(|| anyhow!("{rel} not found in image archive"))?;
+                let mut f = file.try_clone()?;
+                f.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(f.take(size)))
+            }
+        }
+    }
+
+    fn read(&self, rel: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.open_entry(rel)?
+            .read_to_end(&mut data)
+            .with_context(|| format!("reading {rel}"))?;
+        Ok(data)
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, rel: &str) -> Result<T> {
+        serde_json::from_slice(&self.read(rel)?).with_context(|| format!("parsing {rel}"))
+    }
+
+    fn read_blob(&self, digest: &str) -> Result<Vec<u8>> {
+        let (alg, hex) = digest
+            .split_once(':')
+            .ok_or_else(|| anyhow!("malformed digest {digest:?}"))?;
+        self.read(&format!("blobs/{alg}/{hex}"))
+    }
+}
+
+/// `manifest.json` entry written by `docker save`.
+#[derive(Debug, Clone, Deserialize)]
+struct DockerManifestEntry {
+    #[serde(rename = "Config")]
+    config: String,
+    #[serde(rename = "RepoTags", default)]
+    repo_tags: Option<Vec<String>>,
+    #[serde(rename = "Layers")]
+    layers: Vec<String>,
+}
+
+/// An image whose manifest and config have been read. Layers are only read,
+/// one at a time, by `read_layer`.
+pub struct Image {
+    src: ImageSource,
+    /// Location and media type of each layer blob, base first.
+    layers: Vec<(String, String)>,
+    diff_ids: Vec<String>,
+}
+
+impl Image {
+    /// Open the image at `path`, which may be an OCI image layout directory,
+    /// an OCI layout packed into a tar, or a `docker save` tarball. `tag`
+    /// selects an image by reference name when the source holds several;
+    /// otherwise the first one is used.
+    pub fn open(path: impl AsRef<Path>, tag: Option<&str>) -> Result<Self> {
+        let src = ImageSource::open(path.as_ref())?;
+
+        let (layers, diff_ids): (Vec<(String, String)>, Vec<String>) = if src.exists("index.json") {
+            let manifest = select_oci_manifest(&src, tag)?;
+            let config: ImageConfig = serde_json::from_slice(&src.read_blob(&manifest.config.digest)?)
+                .context("parsing image config")?;
+            let refs = manifest
+                .layers
+                .into_iter()
+                .map(|d| (format!("blobs/{}", d.digest.replacen(':', "/", 1)), d.media_type))
+                .collect();
+            (refs, config.rootfs.diff_ids)
+        } else if src.exists("manifest.json") {
+            let entries: Vec<DockerManifestEntry> = src.read_json("manifest.json")?;
+            let entry = match tag {
+                Some(t) => entries
+                    .into_iter()
+                    .find(|e| e.repo_tags.iter().flatten().any(|r| r == t || r.ends_with(&format!(":{t}"))))
+                    .ok_or_else(|| anyhow!("no image tagged {t:?} in manifest.json"))?,
+                None => entries
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow!("manifest.json lists no images"))?,
+            };
+            let config: ImageConfig = src.read_json(&entry.config)?;
+            let refs = entry.layers.into_iter().map(|l| (l, MEDIA_TYPE_LAYER.to_string())).collect();
+            (refs, config.rootfs.diff_ids)
+        } else {
+            bail!("{:?} is neither an OCI image layout nor a docker save archive", path.as_ref());
+        };
+
+        if !diff_ids.is_empty() && diff_ids.len() != layers.len() {
+            bail!(
+                "image config lists {} diff_ids but the manifest has {} layers",
+                diff_ids.len(),
+                layers.len()
+            );
+        }
+        Ok(Image { src, layers, diff_ids })
+    }
+
+    /// Number of layers in the image.
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Decompress layer `i` (0 = base) into a temporary file and check it
+    /// against the `diff_id` recorded in the image config.
+    pub fn read_layer(&self, i: usize) -> Result<ImageLayer> {
+        let (rel, media_type) = self
+            .layers
+            .get(i)
+            .ok_or_else(|| anyhow!("image has no layer {i}"))?;
+        let file = tempfile::tempfile().context("creating temporary file")?;
+        let mut w = HashingWriter::new(BufWriter::new(file));
+        let size = io::copy(&mut crate::decompressing_reader(BufReader::new(self.src.open_entry(rel)?))?, &mut w)
+            .with_context(|| format!("decompressing layer {i} ({rel})"))?;
+        let (w, digest) = w.finish();
+        let mut tar = w.into_inner().map_err(|e| e.into_error())?;
+        if let Some(expected) = self.diff_ids.get(i) {
+            if expected.strip_prefix("sha256:") != Some(digest.as_str()) {
+                bail!("layer {i} diff_id mismatch: expected {expected}, computed sha256:{digest}");
+            }
+        }
+        tar.rewind()?;
+        let skipped = unrepresentable_entries(BufReader::new(&tar))
+            .with_context(|| format!("reading layer {i} ({rel})"))?;
+        tar.rewind()?;
+        Ok(ImageLayer { tar, size, digest, media_type: media_type.clone(), skipped })
+    }
+}
+
+/// Names of the entries in `tar` that the union view ignores: anything other
+/// than regular files and directories.
+fn unrepresentable_entries(tar: impl Read) -> Result<Vec<String>> {
+    let mut skipped = Vec::new();
+    let mut archive = tar::Archive::new(tar);
+    for entry_res in archive.entries()? {
+        let entry = entry_res?;
+        let kind = entry.header().entry_type();
+        if kind.is_file() || kind.is_dir() || kind.is_pax_global_extensions() {
+            continue;
+        }
+        let name = entry.path_bytes();
+        skipped.push(match VPath::from_entry_name(&name) {
+            Ok(p) => p.to_string(),
+            Err(_) => String::from_utf8_lossy(&name).into_owned(),
+        });
+    }
+    Ok(skipped)
+}
+
+/// Pick the image manifest from an OCI layout's `index.json`, following one
+/// level of nested index (multi-platform images use the first entry).
+fn select_oci_manifest(src: &ImageSource, tag: Option<&str>) -> Result<ImageManifest> {
+    let index: ImageIndex = src.read_json("index.json")?;
+    let matches_tag = |d: &Descriptor| {
+        let t = tag.unwrap_or_default();
+        [ANNOTATION_REF_NAME, "io.containerd.image.name"].iter().any(|k| {
+            d.annotations.get(*k).is_some_and(|r| r == t || r.ends_with(&format!(":{t}")))
+        })
+    };
+    let mut desc = match tag {
+        Some(t) => index
+            .manifests
+            .into_iter()
+            .find(matches_tag)
+            .ok_or_else(|| anyhow!("no image tagged {t:?} in index.json"))?,
+        None => index
+            .manifests
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("index.json lists no manifests"))?,
+    };
+    if desc.media_type == MEDIA_TYPE_INDEX || desc.media_type.ends_with("manifest.list.v2+json") {
+        let nested: ImageIndex = serde_json::from_slice(&src.read_blob(&desc.digest)?)
+            .context("parsing nested image index")?;
+        desc = nested
+            .manifests
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("nested image index lists no manifests"))?;
+    }
+    serde_json::from_slice(&src.read_blob(&desc.digest)?).context("parsing image manifest")
+}
+
+/// What `import_oci` stored.
+pub struct OciImport {
+    pub tcow: TcowFile,
+    /// `(image layer, path)` for every entry stored but not visible through
+    /// the union view; see `ImageLayer::skipped`.
+    pub skipped: Vec<(usize, String)>,
+}
+
+/// Store every layer of the image at `src` in the `.tcow` file at `dest`, in
+/// order. Layer tars are kept byte-for-byte, so each tcow layer digest equals
+/// the image layer's `diff_id`. When `dest` does not exist the first image
+/// layer becomes its Base layer, and `label` is recorded; a label for an
+/// existing `dest` is refused. Layers are decompressed to a temporary file
+/// and appended one at a time through a single handle, so no layer is held
+/// in memory; if one fails its `diff_id` check, the layers before it stay
+/// imported.
+pub fn import_oci(
+    src: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    tag: Option<&str>,
+    label: Option<String>,
+) -> Result<OciImport> {
+    let dest = dest.as_ref();
+    if label.is_some() && dest.exists() {
+        bail!("a label is only recorded when creating a new .tcow file, and {:?} already exists", dest);
+    }
+    let image = Image::open(src, tag)?;
+    if image.is_empty() {
+        bail!("image has no layers");
+    }
+
+    let mut skipped = Vec::new();
+    let mut read_layer = |i: usize| -> Result<BufReader<File>> {
+        let layer = image.read_layer(i)?;
+        skipped.extend(layer.skipped.into_iter().map(|p| (i, p)));
+        Ok(BufReader::new(layer.tar))
+    };
+    let (mut tcow, first) = if dest.exists() {
+        (TcowFile::open(dest)?, 0)
+    } else {
+        (TcowFile::create_raw_from(dest, read_layer(0)?, label)?, 1)
+    };
+    for i in first..image.len() {
+        tcow.append_raw_from(read_layer(i)?)?;
+    }
+    Ok(OciImport { tcow, skipped })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_tar_layer, AppendOptions};
+
+    fn vpath(p: &str) -> VPath {
+        VPath::new(p).unwrap()
+    }
+
+    /// Three layers: a base, a delta with a whiteout, and a delta holding a
+    /// dedup reference, which export has to rebuild.
+    fn sample(path: &Path) -> TcowFile {
+        let big = vec![7u8; 4096];
+        let base = [(vpath("a.txt"), b"hello".to_vec()), (vpath("big.bin"), big.clone())];
+        let mut tcow = TcowFile::create(path, &base, &[], Some("sample".into())).unwrap();
+        tcow.append(&[(vpath("b.txt"), b"b".to_vec())], &[vpath("a.txt")]).unwrap();
+        let report = tcow.append_with(&[(vpath("copy.bin"), big)], &[], AppendOptions { dedup: true, delta: false });
+        assert_eq!(report.unwrap().deduped, 1);
+        TcowFile::open(path).unwrap()
+    }
+
+    fn blob<T: DeserializeOwned>(dir: &Path, digest: &str) -> T {
+        let hex = digest.strip_prefix("sha256:").unwrap();
+        serde_json::from_slice(&fs::read(dir.join("blobs/sha256").join(hex)).unwrap()).unwrap()
+    }
+
+    fn contents<S>(tcow: &TcowFile<S>) -> BTreeMap<VPath, Vec<u8>> {
+        tcow.union_view().into_iter().map(|(p, e)| (p, e.data.to_vec())).collect()
+    }
+
+    #[test]
+    fn export_writes_one_blob_per_layer_under_its_digest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tcow = sample(&tmp.path().join("a.tcow"));
+        let dir = tmp.path().join("image");
+        let opts = OciExportOptions { tag: "run-1".into(), ..Default::default() };
+        let manifest_digest = export_oci(&tcow, &dir, &opts).unwrap();
+
+        assert!(dir.join("oci-layout").is_file());
+        let index: ImageIndex = serde_json::from_slice(&fs::read(dir.join("index.json")).unwrap()).unwrap();
+        assert_eq!(index.manifests[0].digest, manifest_digest);
+        assert_eq!(index.manifests[0].annotations[ANNOTATION_REF_NAME], "run-1");
+        let manifest: ImageManifest = blob(&dir, &manifest_digest);
+        let config: ImageConfig = blob(&dir, &manifest.config.digest);
+        assert_eq!((config.os.as_str(), config.architecture.as_str()), ("linux", "amd64"));
+
+        let digests: Vec<_> = manifest.layers.iter().map(|l| l.digest.clone()).collect();
+        assert_eq!(config.rootfs.diff_ids, digests);
+        for (i, layer) in manifest.layers.iter().enumerate() {
+            let bytes = fs::read(dir.join("blobs/sha256").join(&layer.digest[7..])).unwrap();
+            assert_eq!((sha256_hex(&bytes), bytes.len() as u64), (layer.digest[7..].to_string(), layer.size));
+            let stored = tcow.index.layers[i].digest.as_deref().unwrap();
+            // Self-contained layers are copied as stored; the dedup layer is rebuilt
+            assert_eq!(layer.digest[7..] == *stored, i < 2, "layer {i}");
+        }
+        let rebuilt = fs::read(dir.join("blobs/sha256").join(&digests[2][7..])).unwrap();
+        let entry = &parse_tar_layer(&rebuilt).unwrap()[&vpath("copy.bin")];
+        assert!(entry.reference.is_none());
+        assert_eq!(&entry.data[..], &[7u8; 4096][..]);
+        let mut names = fs::read_dir(dir.join("blobs/sha256")).unwrap().map(|e| e.unwrap().file_name());
+        assert!(names.all(|n| !n.to_string_lossy().ends_with(".tmp")), "no temporary blobs are left behind");
+    }
+
+    #[test]
+    fn import_reads_back_an_exported_image() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tcow = sample(&tmp.path().join("a.tcow"));
+        let dir = tmp.path().join("image");
+        let manifest: ImageManifest = blob(&dir, &export_oci(&tcow, &dir, &OciExportOptions::default()).unwrap());
+
+        let dest = tmp.path().join("b.tcow");
+        let imported = import_oci(&dir, &dest, Some("latest"), Some("copy".into())).unwrap();
+        assert!(imported.skipped.is_empty());
+        let copy = imported.tcow;
+        assert_eq!(copy.index.label.as_deref(), Some("copy"));
+        let digests: Vec<_> =
+            copy.index.layers.iter().map(|l| format!("sha256:{}", l.digest.as_deref().unwrap())).collect();
+        let diff_ids: Vec<_> = manifest.layers.iter().map(|l| l.digest.clone()).collect();
+        assert_eq!(digests, diff_ids, "every tcow layer digest is the image layer's diff_id");
+        assert_eq!(contents(&copy), contents(&tcow));
+        assert!(copy.lookup(&vpath("a.txt")).is_none(), "the whiteout still applies");
+
+        // A label cannot be recorded on a file that already exists
+        let before = fs::read(&dest).unwrap();
+        let err = import_oci(&dir, &dest, None, Some("late".into())).err().unwrap();
+        assert!(err.to_string().contains("already exists"), "{err:#}");
+        assert_eq!(fs::read(&dest).unwrap(), before);
+
+        // A blob that no longer matches its diff_id stops the import after the layers before it
+        let last = dir.join("blobs/sha256").join(&diff_ids[2][7..]);
+        let mut bytes = fs::read(&last).unwrap();
+        bytes[600] ^= 0xff;
+        fs::write(&last, bytes).unwrap();
+        let partial = tmp.path().join("c.tcow");
+        let err = import_oci(&dir, &partial, None, None).err().unwrap();
+        assert!(err.to_string().contains("diff_id mismatch"), "{err:#}");
+        assert_eq!(TcowFile::open(&partial).unwrap().index.layers.len(), 2);
+    }
+}
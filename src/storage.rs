@@ -0,0 +1,364 @@
+//! Storage backends.
+//!
+//! `TcowFile` and `LayerWriter` read and write through a `Storage` rather
+//! than a path, so a `.tcow` file can live in a file on disk, a file
+//! descriptor handed over by a caller, an in-memory `Vec<u8>`, or a byte
+//! range inside a bigger container. The path-based constructors use
+//! `FileStorage`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use memmap2::{Mmap, MmapOptions};
+
+use crate::error::{IoContext, Result};
+
+/// Bytes a `.tcow` file can be read from.
+pub trait Storage: Read + Seek {
+    /// Memory-map `len` bytes starting at `offset`, or `None` when the
+    /// backend is not a mappable file.
+    fn map(&self, offset: u64, len: usize) -> Option<io::Result<Mmap>> {
+        let _ = (offset, len);
+        None
+    }
+}
+
+/// Bytes a `.tcow` file can be appended to or created in.
+pub trait StorageMut: Storage + Write {
+    /// Cut or extend the storage to `len` bytes, like `File::set_len`. The
+    /// position is left unchanged.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl Storage for File {
+    fn map(&self, offset: u64, len: usize) -> Option<io::Result<Mmap>> {
+        // SAFETY: the mapping is read-only and covers only the layer region,
+        // which tcow never truncates or rewrites. Another process modifying
+        // the file concurrently is outside what the format supports.
+        Some(unsafe { MmapOptions::new().offset(offset).len(len).map(self) })
+    }
+}
+
+impl StorageMut for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+impl<T: AsRef<[u8]>> Storage for Cursor<T> {}
+
+impl StorageMut for Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(buffer_len(len)?, 0);
+        Ok(())
+    }
+}
+
+impl StorageMut for Cursor<&mut Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(buffer_len(len)?, 0);
+        Ok(())
+    }
+}
+
+fn buffer_len(len: u64) -> io::Result<usize> {
+    usize::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory, "length too large for memory"))
+}
+
+impl<S: Storage + ?Sized> Storage for &mut S {
+    fn map(&self, offset: u64, len: usize) -> Option<io::Result<Mmap>> {
+        (**self).map(offset, len)
+    }
+}
+
+impl<S: StorageMut + ?Sized> StorageMut for &mut S {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+}
+
+// ── FileStorage ───────────────────────────────────────────────────────────────
+
+/// A `.tcow` file on disk, opened read-only and reopened for writing the
+/// first time something is written, so read-only files can still be read.
+#[derive(Debug)]
+pub struct FileStorage {
+    path: PathBuf,
+    file: File,
+    writable: bool,
+}
+
+impl FileStorage {
+    /// Open an existing file for reading.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path).io_context(|| format!("cannot open {:?}", path))?;
+        Ok(FileStorage { path, file, writable: false })
+    }
+
+    /// Create a file, truncating any existing one, for reading and writing.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .io_context(|| format!("cannot create {:?}", path))?;
+        Ok(FileStorage { path, file, writable: true })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn writable(&mut self) -> io::Result<&mut File> {
+        if !self.writable {
+            let pos = self.file.stream_position()?;
+            let mut file = OpenOptions::new().read(true).write(true).open(&self.path).map_err(|e| {
+                io::Error::new(e.kind(), format!("cannot open {:?} for writing: {e}", self.path))
+            })?;
+            // The path may name a different file by now (renamed over or
+            // recreated); writing to that would corrupt it
+            if !same_file(&self.file, &file)? {
+                return Err(io::Error::other(format!(
+                    "{:?} was replaced since it was opened; reopen it before writing",
+                    self.path
+                )));
+            }
+            file.seek(SeekFrom::Start(pos))?;
+            self.file = file;
+            self.writable = true;
+        }
+        Ok(&mut self.file)
+    }
+}
+
+/// True when `a` and `b` are handles to the same file.
+#[cfg(unix)]
+fn same_file(a: &File, b: &File) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (a, b) = (a.metadata()?, b.metadata()?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+/// Without a stable file identity, compare what the metadata offers.
+#[cfg(not(unix))]
+fn same_file(a: &File, b: &File) -> io::Result<bool> {
+    let (a, b) = (a.metadata()?, b.metadata()?);
+    Ok(a.len() == b.len() && a.created().ok() == b.created().ok() && a.modified().ok() == b.modified().ok())
+}
+
+impl Read for FileStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for FileStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writable()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FileStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Storage for FileStorage {
+    fn map(&self, offset: u64, len: usize) -> Option<io::Result<Mmap>> {
+        self.file.map(offset, len)
+    }
+}
+
+impl StorageMut for FileStorage {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.writable()?.set_len(len)
+    }
+}
+
+// ── Region ────────────────────────────────────────────────────────────────────
+
+/// A window of `len` bytes starting at `start` in another storage, for a
+/// `.tcow` file embedded in a bigger container. Offsets are relative to
+/// `start`. Writes past the end grow the window and `set_len` cuts the
+/// container at the window's new end. Both fail unless the window runs to
+/// the end of its container, so data after the window is never overwritten
+/// or cut off; writes inside the window are always allowed.
+#[derive(Debug)]
+pub struct Region<S> {
+    inner: S,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<S> Region<S> {
+    pub fn new(inner: S, start: u64, len: u64) -> Self {
+        Region { inner, start, len, pos: 0 }
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Current length of the window.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Seek> Region<S> {
+    /// Fails unless the window ends where its container does, i.e. it may
+    /// grow or shrink without touching anything after it.
+    fn check_at_tail(&mut self) -> io::Result<()> {
+        if self.inner.seek(SeekFrom::End(0))? != self.start + self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "region is followed by other data in its container and cannot be resized",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Seek> Read for Region<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        self.inner.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = self.inner.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<S: Write + Seek> Write for Region<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pos + buf.len() as u64 > self.len {
+            self.check_at_tail()?;
+        }
+        self.inner.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = self.inner.write(buf)?;
+        self.pos += n as u64;
+        self.len = self.len.max(self.pos);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S> Seek for Region<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(d) => self.len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = target.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative or overflowing position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl<S: Storage> Storage for Region<S> {
+    fn map(&self, offset: u64, len: usize) -> Option<io::Result<Mmap>> {
+        self.inner.map(self.start + offset, len)
+    }
+}
+
+impl<S: StorageMut> StorageMut for Region<S> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.check_at_tail()?;
+        self.inner.set_len(self.start + len)?;
+        self.len = len;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TcowFile, VPath};
+
+    fn files(name: &str) -> Vec<(VPath, Vec<u8>)> {
+        vec![(VPath::new(name).unwrap(), b"content".to_vec())]
+    }
+
+    /// A container holding `prefix`, then a `.tcow` file, then `suffix`.
+    fn container(prefix: &[u8], suffix: &[u8]) -> (Vec<u8>, u64) {
+        let tcow = TcowFile::create_in(Cursor::new(Vec::new()), &files("a"), &[], None).unwrap();
+        let tcow = tcow.into_storage().into_inner();
+        let len = tcow.len() as u64;
+        ([prefix, &tcow, suffix].concat(), len)
+    }
+
+    #[test]
+    fn region_at_the_tail_can_grow() {
+        let (mut buf, len) = container(b"header", b"");
+        let mut tcow = TcowFile::from_storage(Region::new(Cursor::new(&mut buf), 6, len)).unwrap();
+        tcow.append(&files("b"), &[]).unwrap();
+        let grown = tcow.into_storage().len();
+
+        assert!(buf.starts_with(b"header"));
+        assert_eq!(buf.len() as u64, 6 + grown);
+        let reread = TcowFile::from_storage(Region::new(Cursor::new(&buf[..]), 6, grown)).unwrap();
+        assert_eq!(reread.index.layers.len(), 2);
+    }
+
+    #[test]
+    fn region_followed_by_data_refuses_to_resize() {
+        let (mut buf, len) = container(b"header", b"trailing data");
+        let before = buf.clone();
+        let mut tcow = TcowFile::from_storage(Region::new(Cursor::new(&mut buf), 6, len)).unwrap();
+        assert!(tcow.append(&files("b"), &[]).is_err());
+
+        let mut region = tcow.into_storage();
+        assert_eq!(region.set_len(len).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        region.seek(SeekFrom::End(-1)).unwrap();
+        assert!(region.write(b"xy").is_err());
+        // Writes that stay inside the window are fine
+        region.seek(SeekFrom::End(-1)).unwrap();
+        region.write_all(&before[6 + len as usize - 1..6 + len as usize]).unwrap();
+        assert_eq!(buf, before);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn file_storage_refuses_to_write_a_replaced_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.tcow");
+        TcowFile::create(&path, &files("a"), &[], None).unwrap();
+        let mut tcow = TcowFile::open(&path).unwrap();
+
+        // Replace the file behind the open handle, as an editor saving by rename would
+        let replacement = dir.path().join("b.tcow");
+        TcowFile::create(&replacement, &files("other"), &[], None).unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+        let before = std::fs::read(&path).unwrap();
+
+        assert!(tcow.append(&files("b"), &[]).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+    }
+}
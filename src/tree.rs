@@ -0,0 +1,314 @@
+//! Persistent path tree holding the union view.
+//!
+//! Each visible file is a leaf recording which layer it comes from, and
+//! directories stored as entries of their own are marked too. The tree
+//! is built once when a `.tcow` file is opened and then updated one layer at
+//! a time, so lookups cost O(depth) and listings O(results) instead of a full
+//! rebuild per call.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{RawEntry, VPath};
+
+/// The visible files of a stack of layers, keyed by path component.
+#[derive(Debug, Clone, Default)]
+pub struct UnionTree {
+    root: Node,
+    files: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    /// Layer holding the visible file at this path, if there is one. A path
+    /// can be both a file and a directory when layers disagree.
+    layer: Option<usize>,
+    /// Layer of the explicit directory entry at this path, if there is one.
+    /// Directories that only hold files need no entry.
+    dir: Option<usize>,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.layer.is_none() && self.dir.is_none() && self.children.is_empty()
+    }
+
+    fn count(&self) -> usize {
+        self.layer.is_some() as usize + self.children.values().map(Node::count).sum::<usize>()
+    }
+
+    fn collect(&self, path: &mut String, out: &mut Vec<(String, usize)>) {
+        if let Some(layer) = self.layer {
+            out.push((path.clone(), layer));
+        }
+        for (name, child) in &self.children {
+            let len = path.len();
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(name);
+            child.collect(path, out);
+            path.truncate(len);
+        }
+    }
+}
+
+fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|c| !c.is_empty()).collect()
+}
+
+/// Run `f` on the node at `comps`, if it exists, then prune any nodes left
+/// empty on the way back up. Returns whatever `f` returns, or 0.
+fn edit(node: &mut Node, comps: &[&str], f: &mut dyn FnMut(&mut Node) -> usize) -> usize {
+    match comps.split_first() {
+        None => f(node),
+        Some((name, rest)) => {
+            let Some(child) = node.children.get_mut(*name) else { return 0 };
+            let n = edit(child, rest, f);
+            if child.is_empty() {
+                node.children.remove(*name);
+            }
+            n
+        }
+    }
+}
+
+impl UnionTree {
+    /// Build the view of `layers`, base first.
+    pub fn build(layers: &[HashMap<VPath, RawEntry>]) -> Self {
+        let mut tree = UnionTree::default();
+        for (idx, entries) in layers.iter().enumerate() {
+            tree.apply_layer(idx, entries);
+        }
+        tree
+    }
+
+    /// Stack layer `idx` on top of the view. Its whiteouts and opaque
+    /// directories hide what lies below, then its files are added, so a
+    /// layer never hides its own files.
+    pub fn apply_layer(&mut self, idx: usize, entries: &HashMap<VPath, RawEntry>) {
+        for (path, entry) in entries {
+            let comps = components(path);
+            if entry.is_whiteout {
+                if let Some((name, parent)) = comps.split_last() {
+                    self.files -= edit(&mut self.root, parent, &mut |n| {
+                        n.children.remove(*name).map_or(0, |c| c.count())
+                    });
+                }
+            }
+            if entry.is_opaque {
+                self.files -= edit(&mut self.root, &comps, &mut |n| {
+                    std::mem::take(&mut n.children).values().map(Node::count).sum()
+                });
+            }
+        }
+        for (path, entry) in entries {
+            if entry.is_whiteout {
+                continue;
+            }
+            let mut node = &mut self.root;
+            for name in components(path) {
+                node = node.children.entry(name.to_string()).or_default();
+            }
+            if entry.is_dir {
+                node.dir = Some(idx);
+            } else if node.layer.replace(idx).is_none() {
+                self.files += 1;
+            }
+        }
+    }
+
+    fn node(&self, path: &str) -> Option<&Node> {
+        let mut node = &self.root;
+        for name in components(path) {
+            node = node.children.get(name)?;
+        }
+        Some(node)
+    }
+
+    /// Layer of the visible file at `path`, if any.
+    pub fn get(&self, path: &str) -> Option<usize> {
+        self.node(path)?.layer
+    }
+
+    /// True when `path` is a visible file, an explicit directory or a
+    /// directory with visible files beneath it, i.e. when a whiteout for
+    /// `path` would hide something.
+    pub fn contains(&self, path: &str) -> bool {
+        self.node(path).is_some_and(|n| !n.is_empty())
+    }
+
+    /// Layer of the explicit directory entry at `path`, if any.
+    pub fn dir_layer(&self, path: &str) -> Option<usize> {
+        self.node(path)?.dir
+    }
+
+    /// Names directly beneath `path`, sorted, each with the layer of the
+    /// visible file of that name (if any) and whether it is a directory,
+    /// explicit or implied by visible files beneath it. `None` when nothing
+    /// is visible at or beneath `path`.
+    pub fn children(&self, path: &str) -> Option<Vec<(&str, Option<usize>, bool)>> {
+        let node = self.node(path).filter(|n| !n.is_empty())?;
+        Some(
+            node.children
+                .iter()
+                .map(|(name, child)| {
+                    (name.as_str(), child.layer, child.dir.is_some() || !child.children.is_empty())
+                })
+                .collect(),
+        )
+    }
+
+    /// Number of visible files.
+    pub fn len(&self) -> usize {
+        self.files
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files == 0
+    }
+
+    /// Every visible file with its layer.
+    pub fn files(&self) -> Vec<(String, usize)> {
+        let mut out = Vec::with_capacity(self.files);
+        self.root.collect(&mut String::new(), &mut out);
+        out
+    }
+
+    /// Visible files whose path starts with `prefix` (a plain string prefix,
+    /// so `"da"` matches `"data/x"`). Only the subtree of the deepest
+    /// directory named in full by `prefix` is visited.
+    pub fn files_with_prefix(&self, prefix: &str) -> Vec<(String, usize)> {
+        let dir = prefix.rsplit_once('/').map_or("", |(d, _)| d);
+        let mut node = &self.root;
+        for name in components(dir) {
+            match node.children.get(name) {
+                Some(child) => node = child,
+                None => return Vec::new(),
+            }
+        }
+        let mut out = Vec::new();
+        node.collect(&mut dir.trim_matches('/').to_string(), &mut out);
+        out.retain(|(p, _)| p.starts_with(prefix));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{parse_tar_layer, TcowFile};
+
+    /// A layer holding `names` as written: names ending in `/` are
+    /// directories, everything else (whiteout markers included) a file.
+    fn raw_layer(names: &[&str]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for name in names {
+            let mut hdr = tar::Header::new_ustar();
+            hdr.set_path(name).unwrap();
+            hdr.set_entry_type(if name.ends_with('/') { tar::EntryType::Directory } else { tar::EntryType::Regular });
+            hdr.set_size(0);
+            hdr.set_mode(0o644);
+            hdr.set_cksum();
+            builder.append(&hdr, std::io::empty()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// Whiteouts, an opaque directory holding files of its own layer, a
+    /// whiteout of a whole directory and a directory brought back.
+    const STACK: [&[&str]; 4] = [
+        &["a/x", "a/y", "a/sub/z", "b/", "c"],
+        &["a/.wh.y", "c", "d/e"],
+        &["a/.wh..wh..opq", "a/new", ".wh.d"],
+        &["d/", "d/f", "a/sub/", "a/.wh.new", ".wh.c"],
+    ];
+
+    fn layers() -> Vec<HashMap<VPath, RawEntry>> {
+        STACK.iter().map(|names| parse_tar_layer(&raw_layer(names)).unwrap()).collect()
+    }
+
+    /// The union view computed the slow way: each layer's whiteouts remove
+    /// the path and everything beneath it, opaque directories everything
+    /// beneath them, then the layer's files are added.
+    fn naive(layers: &[HashMap<VPath, RawEntry>]) -> BTreeMap<String, usize> {
+        let mut visible = BTreeMap::new();
+        for (idx, layer) in layers.iter().enumerate() {
+            for (path, entry) in layer {
+                let below = format!("{path}/");
+                if entry.is_whiteout {
+                    visible.retain(|p: &String, _| p != path.as_str() && !p.starts_with(&below));
+                }
+                if entry.is_opaque {
+                    visible.retain(|p: &String, _| !p.starts_with(&below));
+                }
+            }
+            for (path, entry) in layer {
+                if !entry.is_whiteout && !entry.is_dir {
+                    visible.insert(path.to_string(), idx);
+                }
+            }
+        }
+        visible
+    }
+
+    /// Everything the tree exposes, walked from the root.
+    fn snapshot(tree: &UnionTree) -> Vec<(String, Option<usize>, Option<usize>, bool)> {
+        fn walk(tree: &UnionTree, dir: &str, out: &mut Vec<(String, Option<usize>, Option<usize>, bool)>) {
+            for (name, layer, is_dir) in tree.children(dir).unwrap_or_default() {
+                let path = if dir.is_empty() { name.to_string() } else { format!("{dir}/{name}") };
+                out.push((path.clone(), layer, tree.dir_layer(&path), is_dir));
+                walk(tree, &path, out);
+            }
+        }
+        let mut out = Vec::new();
+        walk(tree, "", &mut out);
+        out
+    }
+
+    #[test]
+    fn applying_layers_one_at_a_time_matches_building_from_scratch() {
+        let layers = layers();
+        let mut tree = UnionTree::default();
+        for idx in 0..layers.len() {
+            tree.apply_layer(idx, &layers[idx]);
+            let built = UnionTree::build(&layers[..=idx]);
+            assert_eq!(snapshot(&tree), snapshot(&built), "after layer {idx}");
+
+            let files: BTreeMap<_, _> = tree.files().into_iter().collect();
+            assert_eq!(files, naive(&layers[..=idx]), "after layer {idx}");
+            assert_eq!(tree.len(), files.len());
+        }
+    }
+
+    #[test]
+    fn whiteouts_and_opaque_directories_hide_lower_layers_only() {
+        let layers = layers();
+        let tree = UnionTree::build(&layers[..3]);
+        // a/y is whited out, the opaque a/ hides x and sub/z but not a/new
+        // from its own layer, and .wh.d takes d/e with it
+        assert_eq!(tree.files(), [("a/new".to_string(), 2), ("c".to_string(), 1)]);
+        assert_eq!(tree.dir_layer("a"), Some(2));
+        assert!(!tree.contains("a/sub") && !tree.contains("d"));
+        assert_eq!(tree.children("b"), Some(vec![]));
+
+        let tree = UnionTree::build(&layers);
+        assert_eq!(tree.files(), [("d/f".to_string(), 3)]);
+        assert_eq!((tree.dir_layer("d"), tree.dir_layer("a/sub")), (Some(3), Some(3)));
+        assert_eq!(tree.children("a"), Some(vec![("sub", None, true)]));
+        assert_eq!(tree.get("c"), None);
+    }
+
+    #[test]
+    fn an_appended_layer_updates_the_view_as_reopening_would() {
+        let mut tcow = TcowFile::create_raw_in(Cursor::new(Vec::new()), &raw_layer(STACK[0]), true, None).unwrap();
+        for names in &STACK[1..] {
+            tcow.append_raw(&raw_layer(names)).unwrap();
+            assert_eq!(snapshot(&tcow.tree), snapshot(&UnionTree::build(&tcow.layers)));
+        }
+        let reopened = TcowFile::from_storage(tcow.into_storage()).unwrap();
+        assert_eq!(snapshot(&reopened.tree), snapshot(&UnionTree::build(&layers())));
+    }
+}
@@ -0,0 +1,678 @@
+//! Integrity checks behind `tcow verify`.
+//!
+//! Layer digests are checked by streaming each layer's byte range from disk
+//! through SHA-256, several layers at a time, so memory use is one copy
+//! buffer per worker no matter how large the file is. `check_loaded_layers`
+//! hashes an opened file's loaded region instead. Entry checks (references,
+//! deltas and, in deep mode, every file) run one layer per worker, either on
+//! an opened `TcowFile` or, with `check_entries_on_disk`, on the file's tar
+//! headers, streaming each body from disk.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::delta::{self, MAX_DELTA_DEPTH};
+use crate::error::IoContext;
+use crate::{dedup, from_whiteout_tar_path, opaque_whiteout_dir, sha256_hex, writer};
+use crate::{BlobRef, DeltaRef, LayerKind, LayerRecord, Region, TcowError, TcowFile, TcowIndex, VPath};
+
+/// Read size used when streaming a layer through the hasher.
+const HASH_BUF_SIZE: usize = 1 << 20;
+
+/// Outcome of checking one layer's stored digest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LayerStatus {
+    Ok,
+    /// The bytes no longer hash to the stored digest.
+    Mismatch,
+    /// No digest is stored, so the layer could not be checked.
+    Missing,
+    /// The layer's byte range could not be read in full.
+    Unreadable,
+}
+
+/// One layer's digest check.
+#[derive(Debug, Clone, Serialize)]
+pub struct LayerCheck {
+    pub index: usize,
+    pub kind: LayerKind,
+    pub size: u64,
+    pub status: LayerStatus,
+    pub stored: Option<String>,
+    pub computed: Option<String>,
+    /// Why the layer was unreadable.
+    pub error: Option<String>,
+}
+
+/// Hash every layer of the `.tcow` file at `path` straight from disk and
+/// compare the results with the digests in `index`. Layers are hashed in
+/// parallel on the current rayon pool; `progress` is called with the
+/// number of bytes hashed as each chunk completes.
+pub fn check_layers(
+    path: &Path,
+    index: &TcowIndex,
+    trailer_offset: u64,
+    progress: &(dyn Fn(u64) + Sync),
+) -> Vec<LayerCheck> {
+    index
+        .layers
+        .par_iter()
+        .enumerate()
+        .map(|(i, rec)| {
+            let hashed = if rec.offset.saturating_add(rec.size) > trailer_offset {
+                Err(anyhow::anyhow!("layer extends past the trailer at offset {trailer_offset}"))
+            } else {
+                hash_range(path, rec.offset, rec.size, progress)
+            };
+            layer_check(i, rec, hashed)
+        })
+        .collect()
+}
+
+/// `check_layers` for an opened file: each layer is hashed from the
+/// already loaded layer region instead of being read from disk again.
+pub fn check_loaded_layers<S: Sync>(tcow: &TcowFile<S>) -> Vec<LayerCheck> {
+    tcow.index
+        .layers
+        .par_iter()
+        .enumerate()
+        .map(|(i, rec)| {
+            let hashed = tcow.layer_bytes(i).map(|bytes| sha256_hex(&bytes)).map_err(Into::into);
+            layer_check(i, rec, hashed)
+        })
+        .collect()
+}
+
+/// Compare a layer's computed digest, or the error hashing it, with its
+/// record.
+fn layer_check(index: usize, rec: &LayerRecord, hashed: Result<String>) -> LayerCheck {
+    let mut check = LayerCheck {
+        index,
+        kind: rec.kind,
+        size: rec.size,
+        status: LayerStatus::Ok,
+        stored: rec.digest.clone(),
+        computed: None,
+        error: None,
+    };
+    match hashed {
+        Err(e) => {
+            check.status = LayerStatus::Unreadable;
+            check.error = Some(format!("{e:#}"));
+        }
+        Ok(computed) => {
+            check.status = match &rec.digest {
+                None => LayerStatus::Missing,
+                Some(stored) if *stored == computed => LayerStatus::Ok,
+                Some(_) => LayerStatus::Mismatch,
+            };
+            check.computed = Some(computed);
+        }
+    }
+    check
+}
+
+/// SHA-256 of `size` bytes of the file at `path` starting at `offset`.
+fn hash_range(path: &Path, offset: u64, size: u64, progress: &(dyn Fn(u64) + Sync)) -> Result<String> {
+    let mut f = File::open(path)?;
+    f.seek(SeekFrom::Start(offset))?;
+    let mut reader = f.take(size);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; HASH_BUF_SIZE.min(size as usize).max(1)];
+    let mut done = 0u64;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        done += n as u64;
+        progress(n as u64);
+    }
+    if done != size {
+        bail!("layer ends after {done} of {size} bytes");
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// What kind of entry an `EntryCheck` is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Reference,
+    Delta,
+}
+
+/// How an entry failed its check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryStatus {
+    /// The content no longer hashes to its recorded digest.
+    Damaged,
+    /// A digest is recorded for the path but the layer has no such file.
+    Missing,
+}
+
+/// A reference's target or a delta's base.
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryOrigin {
+    pub layer: usize,
+    pub path: String,
+}
+
+/// One entry that failed its check.
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryCheck {
+    pub layer: usize,
+    /// Virtual path, with a leading `/`.
+    pub path: String,
+    pub kind: EntryKind,
+    pub status: EntryStatus,
+    pub stored: String,
+    pub computed: Option<String>,
+    pub origin: Option<EntryOrigin>,
+}
+
+/// Result of `check_entries`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EntryReport {
+    /// Dedup references and deltas checked.
+    pub references: usize,
+    /// Inline files checked (deep mode only).
+    pub files: usize,
+    /// Inline files skipped because no digest is recorded (deep mode only).
+    pub unrecorded: usize,
+    pub failures: Vec<EntryCheck>,
+}
+
+/// Check that every reference and delta in `tcow` still rebuilds the
+/// content whose digest it was written with. With `deep`, also hash every
+/// inline file against the digest recorded in its layer record. Layers are
+/// checked in parallel; failures come back sorted by layer and path.
+pub fn check_entries<S>(tcow: &TcowFile<S>, deep: bool) -> EntryReport {
+    let reports: Vec<EntryReport> = tcow
+        .layers
+        .par_iter()
+        .zip(&tcow.index.layers)
+        .enumerate()
+        .map(|(i, (layer, rec))| {
+            let mut report = EntryReport::default();
+            for (p, entry) in layer {
+                if entry.is_whiteout || entry.is_dir {
+                    continue;
+                }
+                let (kind, stored, origin) = match (&entry.reference, &entry.delta) {
+                    (Some(blob), _) => (EntryKind::Reference, &blob.digest, Some((blob.layer, &blob.path))),
+                    (_, Some(d)) => (EntryKind::Delta, &d.digest, Some((d.base_layer, &d.base_path))),
+                    _ if !deep => continue,
+                    _ => match rec.file_digests.get(p.as_str()) {
+                        Some(stored) => (EntryKind::File, stored, None),
+                        None => {
+                            report.unrecorded += 1;
+                            continue;
+                        }
+                    },
+                };
+                match kind {
+                    EntryKind::File => report.files += 1,
+                    _ => report.references += 1,
+                }
+                let computed = sha256_hex(&entry.data);
+                if computed != *stored {
+                    report.failures.push(EntryCheck {
+                        layer: i,
+                        path: format!("/{p}"),
+                        kind,
+                        status: EntryStatus::Damaged,
+                        stored: stored.clone(),
+                        computed: Some(computed),
+                        origin: origin.map(|(layer, path)| EntryOrigin { layer, path: format!("/{path}") }),
+                    });
+                }
+            }
+            if deep {
+                for (p, stored) in &rec.file_digests {
+                    if layer.get(p.as_str()).is_none_or(|e| e.is_whiteout || e.is_dir) {
+                        report.failures.push(EntryCheck {
+                            layer: i,
+                            path: format!("/{p}"),
+                            kind: EntryKind::File,
+                            status: EntryStatus::Missing,
+                            stored: stored.clone(),
+                            computed: None,
+                            origin: None,
+                        });
+                    }
+                }
+            }
+            report
+        })
+        .collect();
+
+    let mut total = EntryReport::default();
+    for r in reports {
+        total.references += r.references;
+        total.files += r.files;
+        total.unrecorded += r.unrecorded;
+        total.failures.extend(r.failures);
+    }
+    total.failures.sort_by(|a, b| (a.layer, &a.path).cmp(&(b.layer, &b.path)));
+    total
+}
+
+// ── Entry checks from disk ────────────────────────────────────────────────────
+
+/// Where a file in a layer on disk keeps its content.
+enum Body {
+    /// `size` bytes at absolute `offset` in the file.
+    Inline { offset: u64, size: u64 },
+    Reference(BlobRef),
+    /// A patch of `len` bytes at absolute `offset` against the delta's base.
+    Delta { delta: DeltaRef, offset: u64, len: u64 },
+}
+
+impl Body {
+    /// Length of the content the body stands for.
+    fn size(&self) -> u64 {
+        match self {
+            Body::Inline { size, .. } => *size,
+            Body::Reference(blob) => blob.size,
+            Body::Delta { delta, .. } => delta.size,
+        }
+    }
+}
+
+/// The layers of a `.tcow` file on disk, scanned for where each file's
+/// content lives without loading any of it. Whiteouts and directories map
+/// to `None`.
+struct DiskLayers<'a> {
+    path: &'a Path,
+    index: &'a TcowIndex,
+    layers: Vec<HashMap<VPath, Option<Body>>>,
+}
+
+impl<'a> DiskLayers<'a> {
+    /// Scan every layer and check, as opening the file would, that each
+    /// reference and delta names an existing file beneath it.
+    fn scan(path: &'a Path, index: &'a TcowIndex) -> crate::Result<Self> {
+        let mut disk = DiskLayers { path, index, layers: Vec::with_capacity(index.layers.len()) };
+        for (idx, rec) in index.layers.iter().enumerate() {
+            let bodies = scan_bodies(path, rec).map_err(|e| e.in_layer(idx))?;
+            for (p, body) in &bodies {
+                disk.resolve(idx, p, body.as_ref()).map_err(|e| e.in_layer(idx))?;
+            }
+            disk.layers.push(bodies);
+        }
+        Ok(disk)
+    }
+
+    /// The file at `path` in `layer`, if `layer` is below `above`.
+    fn lower(&self, above: usize, layer: usize, path: &VPath) -> Option<&Body> {
+        self.layers[..above].get(layer)?.get(path)?.as_ref()
+    }
+
+    /// The same checks as `dedup::resolve_refs` and `delta::resolve_deltas`.
+    fn resolve(&self, idx: usize, path: &VPath, body: Option<&Body>) -> crate::Result<()> {
+        match body {
+            Some(Body::Reference(blob)) => {
+                let target = self.lower(idx, blob.layer, &blob.path).ok_or_else(|| {
+                    TcowError::corrupt(format!(
+                        "/{path} references /{} in layer {}, which does not exist",
+                        blob.path, blob.layer
+                    ))
+                })?;
+                if target.size() != blob.size {
+                    return Err(TcowError::corrupt(format!(
+                        "/{path} references {} bytes but /{} in layer {} has {}",
+                        blob.size,
+                        blob.path,
+                        blob.layer,
+                        target.size()
+                    )));
+                }
+                if let Some(digest) = self.recorded(blob.layer, &blob.path, target).filter(|d| **d != blob.digest) {
+                    return Err(TcowError::corrupt(format!(
+                        "/{path} references sha256:{} but /{} in layer {} has sha256:{digest}",
+                        blob.digest, blob.path, blob.layer
+                    )));
+                }
+            }
+            Some(Body::Delta { delta, offset, len }) => {
+                let base = self.lower(idx, delta.base_layer, &delta.base_path).ok_or_else(|| {
+                    TcowError::corrupt(format!(
+                        "/{path} is a delta against /{} in layer {}, which does not exist",
+                        delta.base_path, delta.base_layer
+                    ))
+                })?;
+                let depth = match base {
+                    Body::Delta { delta, .. } => delta.depth + 1,
+                    _ => 1,
+                };
+                if depth > MAX_DELTA_DEPTH || depth != delta.depth {
+                    return Err(TcowError::corrupt(format!(
+                        "/{path}: delta chain depth {depth} is invalid (limit {MAX_DELTA_DEPTH})"
+                    )));
+                }
+                let patch = self.read(*offset, *len)?;
+                delta::validate(&patch, base.size(), delta.size)
+                    .map_err(|e| TcowError::corrupt(format!("invalid delta for /{path}: {e}")))?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The digest a loaded entry would carry: the one recorded in its layer
+    /// record, else the one its reference or delta was written with.
+    fn recorded<'b>(&'b self, layer: usize, path: &VPath, body: &'b Body) -> Option<&'b String> {
+        self.index.layers[layer].file_digests.get(path.as_str()).or(match body {
+            Body::Inline { .. } => None,
+            Body::Reference(blob) => Some(&blob.digest),
+            Body::Delta { delta, .. } => Some(&delta.digest),
+        })
+    }
+
+    /// `len` bytes of the file at absolute `offset`.
+    fn read(&self, offset: u64, len: u64) -> crate::Result<Vec<u8>> {
+        let context = || format!("reading {len} bytes at offset {offset}");
+        let mut f = File::open(self.path).io_context(context)?;
+        f.seek(SeekFrom::Start(offset)).io_context(context)?;
+        let mut buf = Vec::new();
+        f.take(len).read_to_end(&mut buf).io_context(context)?;
+        if buf.len() as u64 != len {
+            return Err(TcowError::corrupt(format!("{} of {len} bytes at offset {offset} could be read", buf.len())));
+        }
+        Ok(buf)
+    }
+
+    /// The full content of `body`, rebuilding deltas from their bases.
+    fn content(&self, body: &Body) -> crate::Result<Vec<u8>> {
+        match body {
+            Body::Inline { offset, size } => self.read(*offset, *size),
+            Body::Reference(blob) => self.content(self.resolved(blob.layer, &blob.path)),
+            Body::Delta { delta, offset, len } => {
+                let base = self.content(self.resolved(delta.base_layer, &delta.base_path))?;
+                Ok(delta::apply(&base, &self.read(*offset, *len)?, delta.size))
+            }
+        }
+    }
+
+    /// A target `scan` already found.
+    fn resolved(&self, layer: usize, path: &VPath) -> &Body {
+        self.layers[layer][path].as_ref().expect("targets are checked by scan")
+    }
+
+    /// Hex SHA-256 of the content of `body`. Inline bodies, and references
+    /// to them, are streamed from disk rather than read into memory.
+    fn digest(&self, body: &Body) -> Result<String> {
+        match body {
+            Body::Inline { offset, size } => hash_range(self.path, *offset, *size, &|_| {}),
+            Body::Reference(blob) => self.digest(self.resolved(blob.layer, &blob.path)),
+            Body::Delta { .. } => Ok(sha256_hex(&self.content(body)?)),
+        }
+    }
+}
+
+/// Find every file in the layer `rec` describes and where its content
+/// lives, with the same rules as `parse_tar_layer` but without reading any
+/// file body.
+fn scan_bodies(path: &Path, rec: &LayerRecord) -> crate::Result<HashMap<VPath, Option<Body>>> {
+    let f = File::open(path).io_context(|| format!("cannot open {path:?}"))?;
+    let mut archive = tar::Archive::new(BufReader::new(Region::new(f, rec.offset, rec.size)));
+    let mut bodies = HashMap::new();
+    for entry in archive.entries_with_seek().io_context(|| "reading layer data".into())? {
+        let mut entry = entry.io_context(|| "reading tar entry".into())?;
+        let path = VPath::from_entry_name(&entry.path_bytes())?;
+        let kind = entry.header().entry_type();
+        if path.is_root() {
+            continue;
+        }
+        if let Some(dir) = opaque_whiteout_dir(&path) {
+            bodies.entry(dir).or_insert(None);
+        } else if let Some(real_path) = from_whiteout_tar_path(&path) {
+            bodies.insert(real_path, None);
+        } else if kind.is_dir() {
+            bodies.insert(path, None);
+        } else if kind.is_file() {
+            let (offset, size) = (rec.offset + entry.raw_file_position(), entry.size());
+            let body = if let Some(blob) = dedup::read_ref_entry(&mut entry)? {
+                Body::Reference(blob)
+            } else if let Some(delta) = delta::read_delta_entry(&mut entry)? {
+                Body::Delta { delta, offset, len: size }
+            } else {
+                Body::Inline { offset, size }
+            };
+            if offset + size > rec.offset + rec.size {
+                return Err(TcowError::corrupt(format!("content of /{path} is truncated")));
+            }
+            bodies.insert(path, Some(body));
+        }
+    }
+    Ok(bodies)
+}
+
+/// `check_entries` for the `.tcow` file at `path`, read from disk instead
+/// of from an opened `TcowFile`. Layers are scanned for their tar headers
+/// and file bodies are streamed through SHA-256, so only a delta being
+/// rebuilt, and its base, are ever held in memory. Fails with the error
+/// opening the file would give when a layer cannot be parsed or one of
+/// its references or deltas does not resolve.
+pub fn check_entries_on_disk(path: &Path, index: &TcowIndex, deep: bool) -> Result<EntryReport> {
+    let disk = DiskLayers::scan(path, index)?;
+    let reports: Vec<Result<EntryReport>> = disk
+        .layers
+        .par_iter()
+        .zip(&index.layers)
+        .enumerate()
+        .map(|(i, (layer, rec))| {
+            let mut report = EntryReport::default();
+            for (p, body) in layer {
+                let Some(body) = body else { continue };
+                let (kind, stored, origin) = match body {
+                    Body::Reference(blob) => (EntryKind::Reference, &blob.digest, Some((blob.layer, &blob.path))),
+                    Body::Delta { delta, .. } => {
+                        (EntryKind::Delta, &delta.digest, Some((delta.base_layer, &delta.base_path)))
+                    }
+                    Body::Inline { .. } if !deep => continue,
+                    Body::Inline { .. } => match rec.file_digests.get(p.as_str()) {
+                        Some(stored) => (EntryKind::File, stored, None),
+                        None => {
+                            report.unrecorded += 1;
+                            continue;
+                        }
+                    },
+                };
+                match kind {
+                    EntryKind::File => report.files += 1,
+                    _ => report.references += 1,
+                }
+                let computed = disk.digest(body)?;
+                if computed != *stored {
+                    report.failures.push(EntryCheck {
+                        layer: i,
+                        path: format!("/{p}"),
+                        kind,
+                        status: EntryStatus::Damaged,
+                        stored: stored.clone(),
+                        computed: Some(computed),
+                        origin: origin.map(|(layer, path)| EntryOrigin { layer, path: format!("/{path}") }),
+                    });
+                }
+            }
+            if deep {
+                for (p, stored) in &rec.file_digests {
+                    if layer.get(p.as_str()).is_none_or(Option::is_none) {
+                        report.failures.push(EntryCheck {
+                            layer: i,
+                            path: format!("/{p}"),
+                            kind: EntryKind::File,
+                            status: EntryStatus::Missing,
+                            stored: stored.clone(),
+                            computed: None,
+                            origin: None,
+                        });
+                    }
+                }
+            }
+            Ok(report)
+        })
+        .collect();
+
+    let mut total = EntryReport::default();
+    for r in reports {
+        let r = r?;
+        total.references += r.references;
+        total.files += r.files;
+        total.unrecorded += r.unrecorded;
+        total.failures.extend(r.failures);
+    }
+    total.failures.sort_by(|a, b| (a.layer, &a.path).cmp(&(b.layer, &b.path)));
+    Ok(total)
+}
+
+/// Hex SHA-256 of every regular file in the layer `rec` describes, as
+/// `file_digests` computes it, streamed from the file at `path`.
+pub fn file_digests_on_disk(path: &Path, rec: &LayerRecord) -> Result<BTreeMap<String, String>> {
+    let f = File::open(path)?;
+    let layer = writer::scan_raw_layer(BufReader::new(Region::new(f, rec.offset, rec.size)))?;
+    if layer.size != rec.size {
+        bail!("layer ends after {} of {} bytes", layer.size, rec.size);
+    }
+    Ok(layer.file_digests)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use super::*;
+    use crate::{read_index, AppendOptions};
+
+    fn vpath(p: &str) -> VPath {
+        VPath::new(p).unwrap()
+    }
+
+    /// Deterministic incompressible bytes, large enough to be stored as a
+    /// delta.
+    fn blob(seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..delta::DELTA_MIN_SIZE * 2)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// A base layer with `a.bin`, then a layer with a reference to it and a
+    /// delta against it. Returns the file's bytes.
+    fn stacked() -> Vec<u8> {
+        let base = [(vpath("a.bin"), blob(1))];
+        let mut tcow = TcowFile::create_in(Cursor::new(Vec::new()), &base, &[], None).unwrap();
+        let mut edited = blob(1);
+        edited[1000..1010].copy_from_slice(b"0123456789");
+        let files = [(vpath("copy.bin"), blob(1)), (vpath("a.bin"), edited)];
+        let report = tcow.append_with(&files, &[], AppendOptions { dedup: true, delta: true }).unwrap();
+        assert_eq!((report.deduped, report.deltas), (1, 1));
+        tcow.into_storage().into_inner()
+    }
+
+    fn on_disk(bytes: &[u8]) -> (tempfile::NamedTempFile, TcowIndex, u64) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(bytes).unwrap();
+        let (index, trailer_offset) = read_index(&mut File::open(file.path()).unwrap(), file.path()).unwrap();
+        (file, index, trailer_offset)
+    }
+
+    #[test]
+    fn each_layer_is_ok_mismatched_missing_or_unreadable() {
+        let (file, mut index, trailer_offset) = on_disk(&stacked());
+        let status = |index: &TcowIndex| -> Vec<LayerStatus> {
+            check_layers(file.path(), index, trailer_offset, &|_| {}).iter().map(|c| c.status).collect()
+        };
+        assert_eq!(status(&index), [LayerStatus::Ok, LayerStatus::Ok]);
+
+        index.layers[0].digest = Some("00".repeat(32));
+        index.layers[1].digest = None;
+        assert_eq!(status(&index), [LayerStatus::Mismatch, LayerStatus::Missing]);
+
+        index.layers[1].size = trailer_offset;
+        let checks = check_layers(file.path(), &index, trailer_offset, &|_| {});
+        assert_eq!(checks[1].status, LayerStatus::Unreadable);
+        assert!(checks[1].error.as_ref().unwrap().contains("past the trailer"));
+
+        let json = serde_json::to_value(&checks[0]).unwrap();
+        let keys: Vec<_> = json.as_object().unwrap().keys().map(String::as_str).collect();
+        assert_eq!(keys, ["computed", "error", "index", "kind", "size", "status", "stored"]);
+        assert_eq!(json["status"], "mismatch");
+    }
+
+    #[test]
+    fn entries_checked_on_disk_agree_with_an_opened_file() {
+        let mut bytes = stacked();
+        let (_, index, _) = on_disk(&bytes);
+        // Damage a.bin in the base layer: its reference and delta go with it
+        let layer = index.layers[0].offset as usize..(index.layers[0].offset + index.layers[0].size) as usize;
+        let start = layer.start + bytes[layer].windows(64).position(|w| w == &blob(1)[..64]).unwrap();
+        bytes[start + blob(1).len() - 100] ^= 0xff;
+        let (file, mut index, _) = on_disk(&bytes);
+        index.layers[1].file_digests.insert("gone".into(), "00".repeat(32));
+
+        let report = check_entries_on_disk(file.path(), &index, true).unwrap();
+        let mut tcow = TcowFile::open(file.path()).unwrap();
+        tcow.index = index.clone();
+        let expected = check_entries(&tcow, true);
+        assert_eq!(serde_json::to_value(&report).unwrap(), serde_json::to_value(&expected).unwrap());
+
+        let failures: Vec<_> = report.failures.iter().map(|f| (f.layer, &f.path[..], f.kind, f.status)).collect();
+        assert_eq!(
+            failures,
+            [
+                (0, "/a.bin", EntryKind::File, EntryStatus::Damaged),
+                (1, "/a.bin", EntryKind::Delta, EntryStatus::Damaged),
+                (1, "/copy.bin", EntryKind::Reference, EntryStatus::Damaged),
+                (1, "/gone", EntryKind::File, EntryStatus::Missing),
+            ]
+        );
+        assert_eq!((report.references, report.files, report.unrecorded), (2, 1, 0));
+
+        // Without --deep only references and deltas are checked
+        let shallow = check_entries_on_disk(file.path(), &index, false).unwrap();
+        assert_eq!((shallow.references, shallow.files, shallow.failures.len()), (2, 0, 2));
+    }
+
+    #[test]
+    fn a_dangling_reference_fails_as_opening_the_file_would() {
+        let base = [(vpath("a.bin"), blob(1))];
+        let mut tcow = TcowFile::create_in(Cursor::new(Vec::new()), &base, &[], None).unwrap();
+        let dangling = BlobRef { digest: sha256_hex(&blob(1)), layer: 0, path: vpath("b.bin"), size: 1 };
+        let mut builder = tar::Builder::new(Vec::new());
+        dedup::append_ref_entry(&mut builder, "c.bin", &dangling, 0).unwrap();
+        tcow.append_raw(&builder.into_inner().unwrap()).unwrap_err();
+        let (file, index, _) = on_disk(&tcow.into_storage().into_inner());
+
+        let err = check_entries_on_disk(file.path(), &index, false).unwrap_err();
+        let opened = TcowFile::open(file.path()).err().unwrap();
+        assert_eq!(err.to_string(), opened.to_string());
+        assert!(err.to_string().contains("which does not exist"), "{err}");
+    }
+
+    #[test]
+    fn file_digests_on_disk_match_the_recorded_ones() {
+        let (file, index, _) = on_disk(&stacked());
+        for rec in &index.layers {
+            assert_eq!(file_digests_on_disk(file.path(), rec).unwrap(), rec.file_digests);
+        }
+    }
+}
@@ -0,0 +1,619 @@
+//! A read-only filesystem interface shared by `.tcow` views and host
+//! directories.
+//!
+//! `Vfs` covers what listing, diffing, syncing and extracting need: reading
+//! a file, its metadata, listing a directory and walking a subtree, with
+//! depth limits and path filters. `TcowView`
+//! implements it for the union view of a `.tcow` file, the view as of an
+//! older layer, or the contents of a single layer; `HostDir` implements it
+//! for a directory on disk. `diff` compares any two of them.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use crate::error::{IoContext, Result, TcowError};
+use crate::filter::PathFilter;
+use crate::storage::FileStorage;
+use crate::{sha256_hex, Blob, RawEntry, TcowFile, UnionTree, VPath};
+
+/// What a path names, as reported by `Vfs::metadata`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    /// Content length in bytes; 0 for directories.
+    pub size: u64,
+    /// Modification time in Unix seconds; 0 when the source has none.
+    pub mtime: u64,
+    /// For a file in a `TcowView`, the layer its visible copy comes from;
+    /// for a directory stored as an entry of its own, that entry's layer.
+    pub layer: Option<usize>,
+}
+
+/// One path returned by `read_dir` or `walk`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub path: VPath,
+    pub metadata: Metadata,
+}
+
+/// A tree of files addressed by canonical paths. Only regular files and
+/// directories are visible; a missing path fails with `TcowError::NotFound`.
+pub trait Vfs {
+    /// The full content of the file at `path`.
+    fn read(&self, path: &VPath) -> Result<Blob>;
+
+    fn metadata(&self, path: &VPath) -> Result<Metadata>;
+
+    /// The entries directly inside directory `path`, sorted by path.
+    fn read_dir(&self, path: &VPath) -> Result<Vec<DirEntry>>;
+
+    fn exists(&self, path: &VPath) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    /// Every file at or beneath `path`, sorted by path. Directories are not
+    /// listed themselves.
+    fn walk(&self, path: &VPath) -> Result<Vec<DirEntry>> {
+        self.walk_with(path, &WalkOptions::default())
+    }
+
+    /// `walk` with a depth limit and filter, optionally listing directories.
+    fn walk_with(&self, path: &VPath, opts: &WalkOptions) -> Result<Vec<DirEntry>> {
+        let metadata = self.metadata(path)?;
+        let mut out = Vec::new();
+        if !metadata.is_dir {
+            if opts.admits(path, path) {
+                out.push(DirEntry { path: path.clone(), metadata });
+            }
+            return Ok(out);
+        }
+        let mut dirs = vec![(path.clone(), 0)];
+        while let Some((dir, depth)) = dirs.pop() {
+            for entry in self.read_dir(&dir)? {
+                let is_dir = entry.metadata.is_dir;
+                if (opts.dirs || !is_dir) && opts.admits(path, &entry.path) {
+                    out.push(entry.clone());
+                }
+                if is_dir && opts.descends(depth) {
+                    dirs.push((entry.path, depth + 1));
+                }
+            }
+        }
+        out.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(out)
+    }
+
+    /// Hex SHA-256 of the file at `path`. Sources that record digests
+    /// return the recorded one instead of hashing.
+    fn sha256(&self, path: &VPath) -> Result<String> {
+        Ok(sha256_hex(&self.read(path)?))
+    }
+}
+
+/// Limits and filters for `Vfs::walk_with`.
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    /// Levels to descend below the starting directory: `Some(1)` returns
+    /// only its direct children. Unlimited when `None`.
+    pub max_depth: Option<usize>,
+    /// Return directories as well as files.
+    pub dirs: bool,
+    /// Only return entries whose path matches. Directories are descended
+    /// into whether or not they match.
+    pub filter: Option<PathFilter>,
+}
+
+impl WalkOptions {
+    /// True when `path`, found walking from `root`, is within the depth
+    /// limit and matches the filter. Also usable on paths gathered some
+    /// other way, such as the raw entries of a layer.
+    pub fn admits(&self, root: &VPath, path: &VPath) -> bool {
+        let Some(rel) = path.relative_to(root) else { return false };
+        self.max_depth.is_none_or(|max| rel.components().count() <= max)
+            && self.filter.as_ref().is_none_or(|f| f.matches(path))
+    }
+
+    /// Whether to list the children of a directory `depth` levels down.
+    fn descends(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max| depth + 1 < max)
+    }
+}
+
+fn not_found(path: &VPath, layer: Option<usize>) -> TcowError {
+    TcowError::NotFound { path: path.clone(), layer }
+}
+
+// ── TcowView ──────────────────────────────────────────────────────────────────
+
+/// A `.tcow` file seen through `Vfs`: see `TcowFile::view`, `view_at` and
+/// `layer_view`. A path that one layer holds as a file and a higher one as
+/// a directory reports the file's metadata, but can still be listed.
+pub struct TcowView<'a, S = FileStorage> {
+    tcow: &'a TcowFile<S>,
+    tree: Cow<'a, UnionTree>,
+    /// Set for a single-layer view, so misses name the layer.
+    layer: Option<usize>,
+}
+
+impl<'a, S> TcowView<'a, S> {
+    pub(crate) fn new(tcow: &'a TcowFile<S>, tree: Cow<'a, UnionTree>, layer: Option<usize>) -> Self {
+        TcowView { tcow, tree, layer }
+    }
+
+    fn entry(&self, path: &VPath) -> Option<(&'a RawEntry, usize)> {
+        let layer_idx = self.tree.get(path)?;
+        Some((&self.tcow.layers[layer_idx][path], layer_idx))
+    }
+
+    /// The entry of the file at `path`, failing for directories and misses.
+    fn file(&self, path: &VPath) -> Result<&'a RawEntry> {
+        match self.entry(path) {
+            Some((entry, _)) => Ok(entry),
+            None if self.tree.contains(path) => Err(TcowError::Invalid(format!("/{path} is a directory"))),
+            None => Err(not_found(path, self.layer)),
+        }
+    }
+
+    fn file_metadata(entry: &RawEntry, layer_idx: usize) -> Metadata {
+        Metadata { is_dir: false, size: entry.data.len() as u64, mtime: entry.mtime, layer: Some(layer_idx) }
+    }
+
+    /// Directories stored as entries keep their mtime; implied ones have none.
+    fn dir_metadata(&self, path: &VPath) -> Metadata {
+        match self.tree.dir_layer(path) {
+            Some(idx) => Metadata { mtime: self.tcow.layers[idx][path].mtime, layer: Some(idx), ..DIR_METADATA },
+            None => DIR_METADATA,
+        }
+    }
+}
+
+const DIR_METADATA: Metadata = Metadata { is_dir: true, size: 0, mtime: 0, layer: None };
+
+impl<S> Vfs for TcowView<'_, S> {
+    fn read(&self, path: &VPath) -> Result<Blob> {
+        Ok(self.file(path)?.data.clone())
+    }
+
+    fn metadata(&self, path: &VPath) -> Result<Metadata> {
+        match self.entry(path) {
+            Some((entry, layer_idx)) => Ok(Self::file_metadata(entry, layer_idx)),
+            // The root exists even when the view is empty
+            None if path.is_root() || self.tree.contains(path) => Ok(self.dir_metadata(path)),
+            None => Err(not_found(path, self.layer)),
+        }
+    }
+
+    fn read_dir(&self, path: &VPath) -> Result<Vec<DirEntry>> {
+        let Some(children) = self.tree.children(path) else {
+            return match self.entry(path) {
+                Some(_) => Err(TcowError::Invalid(format!("/{path} is not a directory"))),
+                None if path.is_root() => Ok(Vec::new()),
+                None => Err(not_found(path, self.layer)),
+            };
+        };
+        children
+            .into_iter()
+            .map(|(name, layer, _)| {
+                let child = path.join(name)?;
+                let metadata = match layer {
+                    Some(idx) => Self::file_metadata(&self.tcow.layers[idx][&child], idx),
+                    None => self.dir_metadata(&child),
+                };
+                Ok(DirEntry { path: child, metadata })
+            })
+            .collect()
+    }
+
+    fn exists(&self, path: &VPath) -> bool {
+        path.is_root() || self.tree.contains(path)
+    }
+
+    /// Walks the view's tree directly, so a path that is both a file and a
+    /// directory yields the file and is still descended into.
+    fn walk_with(&self, path: &VPath, opts: &WalkOptions) -> Result<Vec<DirEntry>> {
+        if !self.exists(path) {
+            return Err(not_found(path, self.layer));
+        }
+        let mut out = Vec::new();
+        if let Some((entry, idx)) = self.entry(path) {
+            if opts.admits(path, path) {
+                out.push(DirEntry { path: path.clone(), metadata: Self::file_metadata(entry, idx) });
+            }
+        }
+        let mut dirs = vec![(path.clone(), 0)];
+        while let Some((dir, depth)) = dirs.pop() {
+            for (name, layer, is_dir) in self.tree.children(&dir).unwrap_or_default() {
+                let child = dir.join(name)?;
+                let admitted = opts.admits(path, &child);
+                if let Some(idx) = layer.filter(|_| admitted) {
+                    let metadata = Self::file_metadata(&self.tcow.layers[idx][&child], idx);
+                    out.push(DirEntry { path: child.clone(), metadata });
+                } else if is_dir && layer.is_none() && opts.dirs && admitted {
+                    out.push(DirEntry { metadata: self.dir_metadata(&child), path: child.clone() });
+                }
+                if is_dir && opts.descends(depth) {
+                    dirs.push((child, depth + 1));
+                }
+            }
+        }
+        out.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(out)
+    }
+
+    fn sha256(&self, path: &VPath) -> Result<String> {
+        Ok(self.file(path)?.sha256())
+    }
+}
+
+// ── HostDir ───────────────────────────────────────────────────────────────────
+
+/// Modification time of a host file in Unix seconds, 0 if unavailable.
+pub fn mtime_secs(meta: &fs::Metadata) -> u64 {
+    meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs())
+}
+
+/// A directory on the host, with `VPath`s resolved beneath it. Symlinks and
+/// special files are skipped, as `sync` always has. So are entries whose
+/// names are not UTF-8, which no `VPath` can name; `skipped` lists them so
+/// callers can warn.
+#[derive(Debug, Clone)]
+pub struct HostDir {
+    root: PathBuf,
+    /// Shared by clones, so a walk through a clone is reported too.
+    skipped: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl HostDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        HostDir { root: root.into(), skipped: Arc::default() }
+    }
+
+    /// Host paths passed over so far because their names are not UTF-8,
+    /// sorted and without repeats.
+    pub fn skipped(&self) -> Vec<PathBuf> {
+        let mut skipped = self.skipped.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        skipped.sort();
+        skipped.dedup();
+        skipped
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where `path` lives on the host. Canonical paths have no `..` or
+    /// leading `/`, so this stays inside the root.
+    pub fn host_path(&self, path: &VPath) -> PathBuf {
+        self.root.join(path.as_str())
+    }
+
+    /// `None` for anything but a regular file or directory.
+    fn convert(meta: &fs::Metadata) -> Option<Metadata> {
+        let mtime = mtime_secs(meta);
+        if meta.is_dir() {
+            Some(Metadata { mtime, ..DIR_METADATA })
+        } else if meta.is_file() {
+            Some(Metadata { is_dir: false, size: meta.len(), mtime, layer: None })
+        } else {
+            None
+        }
+    }
+
+    fn io_error(&self, path: &VPath, what: &str, e: io::Error) -> TcowError {
+        if e.kind() == io::ErrorKind::NotFound {
+            return not_found(path, None);
+        }
+        TcowError::Io { context: format!("{what} {:?}", self.host_path(path)), source: e }
+    }
+}
+
+impl Vfs for HostDir {
+    fn read(&self, path: &VPath) -> Result<Blob> {
+        self.metadata(path)?;
+        fs::read(self.host_path(path)).map(Blob::from).map_err(|e| self.io_error(path, "reading", e))
+    }
+
+    fn metadata(&self, path: &VPath) -> Result<Metadata> {
+        let meta = fs::symlink_metadata(self.host_path(path))
+            .map_err(|e| self.io_error(path, "reading metadata of", e))?;
+        Self::convert(&meta).ok_or_else(|| not_found(path, None))
+    }
+
+    fn read_dir(&self, path: &VPath) -> Result<Vec<DirEntry>> {
+        let dir = self.host_path(path);
+        let mut entries = BTreeMap::new();
+        for entry in fs::read_dir(&dir).map_err(|e| self.io_error(path, "reading directory", e))? {
+            let entry = entry.io_context(|| format!("reading directory {:?}", dir))?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                self.skipped.lock().unwrap_or_else(|e| e.into_inner()).push(entry.path());
+                continue;
+            };
+            let child = path.join(name)?;
+            let meta = entry.metadata().io_context(|| format!("reading metadata of {:?}", entry.path()))?;
+            if let Some(metadata) = Self::convert(&meta) {
+                entries.insert(child.clone(), DirEntry { path: child, metadata });
+            }
+        }
+        Ok(entries.into_values().collect())
+    }
+}
+
+// ── Diff ──────────────────────────────────────────────────────────────────────
+
+/// One difference found by `diff`, with the path relative to the roots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added(VPath),
+    Modified(VPath),
+    Deleted(VPath),
+}
+
+impl Change {
+    pub fn path(&self) -> &VPath {
+        match self {
+            Change::Added(p) | Change::Modified(p) | Change::Deleted(p) => p,
+        }
+    }
+
+    /// `A`, `M` or `D`, as `sync` and `diff` print them.
+    pub fn tag(&self) -> char {
+        match self {
+            Change::Added(_) => 'A',
+            Change::Modified(_) => 'M',
+            Change::Deleted(_) => 'D',
+        }
+    }
+}
+
+/// Compare the files beneath `old_root` in `old` with those beneath
+/// `new_root` in `new`, sorted by path. A root that does not exist counts as
+/// empty. A file on both sides is modified when its SHA-256 differs. Without
+/// `checksum`, files whose size differs are modified and files with the same
+/// size and mtime are taken as unchanged without reading them; only the rest
+/// are hashed. `sync` records each captured file's host mtime, so the quick
+/// check holds for files it wrote.
+pub fn diff<A: Vfs + ?Sized, B: Vfs + ?Sized>(
+    old: &A,
+    old_root: &VPath,
+    new: &B,
+    new_root: &VPath,
+    checksum: bool,
+) -> Result<Vec<Change>> {
+    let old_files = files_beneath(old, old_root)?;
+    let new_files = files_beneath(new, new_root)?;
+
+    let mut changes = Vec::new();
+    for (rel, n) in &new_files {
+        let changed = match old_files.get(rel) {
+            None => {
+                changes.push(Change::Added(rel.clone()));
+                continue;
+            }
+            Some(o) if !checksum && n.metadata.size != o.metadata.size => true,
+            Some(o) if !checksum && n.metadata.mtime == o.metadata.mtime => false,
+            Some(o) => old.sha256(&o.path)? != new.sha256(&n.path)?,
+        };
+        if changed {
+            changes.push(Change::Modified(rel.clone()));
+        }
+    }
+    changes.extend(old_files.keys().filter(|rel| !new_files.contains_key(*rel)).cloned().map(Change::Deleted));
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(changes)
+}
+
+/// Files strictly beneath `root`, keyed by path relative to it.
+fn files_beneath<V: Vfs + ?Sized>(vfs: &V, root: &VPath) -> Result<BTreeMap<VPath, DirEntry>> {
+    if !vfs.exists(root) {
+        return Ok(BTreeMap::new());
+    }
+    Ok(vfs
+        .walk(root)?
+        .into_iter()
+        .filter_map(|e| {
+            let rel = e.path.relative_to(root).filter(|rel| !rel.is_root())?;
+            Some((rel, e))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn vpath(p: &str) -> VPath {
+        VPath::new(p).unwrap()
+    }
+
+    const FILES: [(&str, &[u8]); 4] =
+        [("top.txt", b"top"), ("a/x.txt", b"x"), ("a/b/y.json", b"{}"), ("a/b/c/z.txt", b"zzz")];
+
+    /// `FILES` plus an empty directory `e`, in a `.tcow` file and on the
+    /// host.
+    fn sources() -> (TcowFile<Cursor<Vec<u8>>>, tempfile::TempDir) {
+        let files: Vec<_> = FILES.iter().map(|(p, data)| (vpath(p), data.to_vec())).collect();
+        let mut tcow = TcowFile::create_in(Cursor::new(Vec::new()), &files, &[], None).unwrap();
+        let mut dir = tar::Builder::new(Vec::new());
+        let mut hdr = tar::Header::new_ustar();
+        hdr.set_path("e/").unwrap();
+        hdr.set_entry_type(tar::EntryType::Directory);
+        hdr.set_size(0);
+        hdr.set_mode(0o755);
+        hdr.set_cksum();
+        dir.append(&hdr, io::empty()).unwrap();
+        tcow.append_raw(&dir.into_inner().unwrap()).unwrap();
+
+        let host = tempfile::tempdir().unwrap();
+        for (p, data) in FILES {
+            let path = host.path().join(p);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        fs::create_dir(host.path().join("e")).unwrap();
+        (tcow, host)
+    }
+
+    /// Hides a source's own `walk_with`, so the trait's default one runs.
+    struct Plain<'a, V>(&'a V);
+
+    impl<V: Vfs> Vfs for Plain<'_, V> {
+        fn read(&self, path: &VPath) -> Result<Blob> {
+            self.0.read(path)
+        }
+        fn metadata(&self, path: &VPath) -> Result<Metadata> {
+            self.0.metadata(path)
+        }
+        fn read_dir(&self, path: &VPath) -> Result<Vec<DirEntry>> {
+            self.0.read_dir(path)
+        }
+    }
+
+    /// Paths returned by `walk_with`, directories with a trailing `/`.
+    fn walked(vfs: &dyn Vfs, root: &str, opts: &WalkOptions) -> Result<Vec<String>> {
+        let entries = vfs.walk_with(&vpath(root), opts)?;
+        Ok(entries.iter().map(|e| format!("{}{}", e.path, if e.metadata.is_dir { "/" } else { "" })).collect())
+    }
+
+    #[test]
+    fn walks_agree_on_depth_limits_and_filters() {
+        let (tcow, host) = sources();
+        let (view, host) = (tcow.view(), HostDir::new(host.path()));
+        let opts = |max_depth, dirs, glob: Option<&str>| WalkOptions {
+            max_depth,
+            dirs,
+            filter: glob.map(|g| PathFilter::glob(g).unwrap()),
+        };
+        let cases: [(&str, WalkOptions, &[&str]); 9] = [
+            ("", opts(None, false, None), &["a/b/c/z.txt", "a/b/y.json", "a/x.txt", "top.txt"]),
+            ("", opts(Some(1), false, None), &["top.txt"]),
+            ("", opts(Some(1), true, None), &["a/", "e/", "top.txt"]),
+            ("", opts(Some(2), true, None), &["a/", "a/b/", "a/x.txt", "e/", "top.txt"]),
+            ("a", opts(Some(1), false, None), &["a/x.txt"]),
+            // Directories that do not match are still descended into
+            ("", opts(None, false, Some("*.txt")), &["a/b/c/z.txt", "a/x.txt", "top.txt"]),
+            ("", opts(None, true, Some("a/*")), &["a/b/", "a/x.txt"]),
+            ("a/x.txt", opts(Some(1), false, None), &["a/x.txt"]),
+            ("a/x.txt", opts(None, false, Some("*.json")), &[]),
+        ];
+        for (root, opts, expected) in &cases {
+            for (name, vfs) in [("view", &view as &dyn Vfs), ("default", &Plain(&view)), ("host", &host)] {
+                assert_eq!(walked(vfs, root, opts).unwrap(), *expected, "{name} from /{root} with {opts:?}");
+            }
+        }
+        for vfs in [&view as &dyn Vfs, &host] {
+            let err = vfs.walk(&vpath("nope")).unwrap_err();
+            assert!(matches!(err, TcowError::NotFound { .. }), "{err}");
+        }
+    }
+
+    /// A host directory that records which files were hashed.
+    struct Counting {
+        dir: HostDir,
+        hashed: RefCell<Vec<String>>,
+    }
+
+    impl Vfs for Counting {
+        fn read(&self, path: &VPath) -> Result<Blob> {
+            self.dir.read(path)
+        }
+        fn metadata(&self, path: &VPath) -> Result<Metadata> {
+            self.dir.metadata(path)
+        }
+        fn read_dir(&self, path: &VPath) -> Result<Vec<DirEntry>> {
+            self.dir.read_dir(path)
+        }
+        fn sha256(&self, path: &VPath) -> Result<String> {
+            self.hashed.borrow_mut().push(path.to_string());
+            self.dir.sha256(path)
+        }
+    }
+
+    fn host_dir(files: &[(&str, &str, u64)]) -> (tempfile::TempDir, Counting) {
+        let tmp = tempfile::tempdir().unwrap();
+        for (name, data, mtime) in files {
+            let path = tmp.path().join(name);
+            fs::write(&path, data).unwrap();
+            let t = UNIX_EPOCH + std::time::Duration::from_secs(*mtime);
+            fs::File::options().write(true).open(path).unwrap().set_modified(t).unwrap();
+        }
+        let dir = HostDir::new(tmp.path());
+        (tmp, Counting { dir, hashed: RefCell::default() })
+    }
+
+    #[test]
+    fn diff_hashes_only_files_whose_size_matches_but_mtime_does_not() {
+        const T: u64 = 1_700_000_000;
+        let (_old_dir, old) = host_dir(&[
+            ("same", "aaaa", T),
+            ("grown", "aa", T),
+            ("touched", "bbbb", T),
+            ("lied", "cccc", T),
+            ("edited", "yyyy", T),
+            ("gone", "g", T),
+        ]);
+        let (_new_dir, new) = host_dir(&[
+            ("same", "aaaa", T),
+            ("grown", "aaa", T),
+            ("touched", "bbbb", T + 10),
+            // Same size and mtime: taken as unchanged unless checksumming
+            ("lied", "dddd", T),
+            ("edited", "xxxx", T + 10),
+            ("added", "new", T),
+        ]);
+        let root = VPath::root();
+        let tags = |changes: Vec<Change>| -> Vec<String> {
+            changes.iter().map(|c| format!("{} {}", c.tag(), c.path())).collect()
+        };
+
+        let quick = diff(&old, &root, &new, &root, false).unwrap();
+        assert_eq!(tags(quick), ["A added", "M edited", "D gone", "M grown"]);
+        assert_eq!(*new.hashed.borrow(), ["edited", "touched"]);
+        assert_eq!(*old.hashed.borrow(), ["edited", "touched"]);
+
+        new.hashed.borrow_mut().clear();
+        let full = diff(&old, &root, &new, &root, true).unwrap();
+        assert_eq!(tags(full), ["A added", "M edited", "D gone", "M grown", "M lied"]);
+        assert_eq!(*new.hashed.borrow(), ["edited", "grown", "lied", "same", "touched"]);
+
+        // A root missing on one side counts as empty
+        let added = diff(&old, &vpath("nope"), &new, &root, false).unwrap();
+        assert!(added.iter().all(|c| matches!(c, Change::Added(_))) && added.len() == 6);
+    }
+
+    #[test]
+    fn host_dirs_skip_links_and_report_names_that_are_not_utf8() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("file"), b"data").unwrap();
+        fs::create_dir(tmp.path().join("sub")).unwrap();
+        std::os::unix::fs::symlink(tmp.path().join("file"), tmp.path().join("link")).unwrap();
+        let bad = tmp.path().join(<std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(b"bad\xff"));
+        fs::write(&bad, b"x").unwrap();
+
+        let host = HostDir::new(tmp.path());
+        assert_eq!(host.host_path(&vpath("sub/file")), tmp.path().join("sub/file"));
+        let listed: Vec<_> = host.clone().read_dir(&VPath::root()).unwrap().into_iter().map(|e| e.path).collect();
+        assert_eq!(listed, [vpath("file"), vpath("sub")]);
+        // Clones share what they skipped
+        assert_eq!(host.skipped(), [bad]);
+
+        assert_eq!(&host.read(&vpath("file")).unwrap()[..], b"data");
+        assert!(host.metadata(&vpath("sub")).unwrap().is_dir);
+        assert_eq!(host.metadata(&vpath("file")).unwrap().size, 4);
+        for missing in ["link", "nope", "sub/nope"] {
+            let err = host.read(&vpath(missing)).unwrap_err();
+            assert!(matches!(err, TcowError::NotFound { .. }), "{missing}: {err}");
+            assert!(!host.exists(&vpath(missing)));
+        }
+        let err = host.read_dir(&vpath("file")).unwrap_err();
+        assert!(matches!(err, TcowError::Io { .. }), "{err}");
+    }
+}
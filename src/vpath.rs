@@ -0,0 +1,274 @@
+//! Canonical virtual paths.
+//!
+//! Every path inside a `.tcow` file — layer map keys, tar entry names,
+//! reference targets, API arguments — is a `VPath`: UTF-8, relative to the
+//! virtual root, with no empty, `.` or `..` components. Parsing resolves
+//! `.`/`..`, collapses repeated slashes and drops trailing ones, and rejects
+//! anything that would leave the root, so a canonical path can be joined
+//! onto a host directory without escaping it.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+/// Why a path was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    /// `..` components climb above the virtual root.
+    Escapes(String),
+    /// A stored entry name starts with `/`.
+    Absolute(String),
+    /// A stored entry name is not valid UTF-8 (shown lossily).
+    NotUtf8(String),
+    /// The path contains a NUL byte.
+    Nul(String),
+    /// A file operation was given the root directory.
+    Root(String),
+    /// A file name starts with `.wh.`, which marks whiteouts.
+    Reserved(String),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathError::Escapes(p) => write!(f, "path {p:?} escapes the root"),
+            PathError::Absolute(p) => write!(f, "entry name {p:?} is absolute"),
+            PathError::NotUtf8(p) => write!(f, "entry name {p:?} is not valid UTF-8"),
+            PathError::Nul(p) => write!(f, "path {p:?} contains a NUL byte"),
+            PathError::Root(p) => write!(f, "path {p:?} names the root directory, not a file"),
+            PathError::Reserved(p) => write!(f, "path {p:?} uses the reserved whiteout prefix `.wh.`"),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+/// A canonical path inside the virtual filesystem, stored without a leading
+/// `/`. The root is the empty path. Derefs to `str`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VPath(String);
+
+impl VPath {
+    /// The root directory.
+    pub fn root() -> Self {
+        VPath(String::new())
+    }
+
+    /// Parse a path given by a user or caller. Leading slashes are optional
+    /// (`/etc/hosts` and `etc/hosts` are the same path).
+    pub fn new(path: &str) -> Result<Self, PathError> {
+        resolve(path.trim_start_matches('/'), path)
+    }
+
+    /// Parse an entry name stored in a tar layer or imported archive. Unlike
+    /// `new`, absolute names and non-UTF-8 bytes are errors rather than
+    /// being reinterpreted. A leading `./` is allowed.
+    pub fn from_entry_name(raw: &[u8]) -> Result<Self, PathError> {
+        let name = std::str::from_utf8(raw)
+            .map_err(|_| PathError::NotUtf8(String::from_utf8_lossy(raw).into_owned()))?;
+        if name.starts_with('/') {
+            return Err(PathError::Absolute(name.to_string()));
+        }
+        resolve(name, name)
+    }
+
+    /// `Self::new` for an already-canonical string built inside the crate.
+    pub(crate) fn from_canonical(path: String) -> Self {
+        debug_assert!(resolve(&path, &path).is_ok_and(|p| p.0 == path), "{path:?} is not canonical");
+        VPath(path)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Path components from the root down.
+    pub fn components(&self) -> impl DoubleEndedIterator<Item = &str> {
+        self.0.split('/').filter(|c| !c.is_empty())
+    }
+
+    /// Last component, or `None` for the root.
+    pub fn file_name(&self) -> Option<&str> {
+        self.components().next_back()
+    }
+
+    /// Containing directory, or `None` for the root.
+    pub fn parent(&self) -> Option<VPath> {
+        if self.is_root() {
+            return None;
+        }
+        Some(match self.0.rsplit_once('/') {
+            Some((dir, _)) => VPath(dir.to_string()),
+            None => VPath::root(),
+        })
+    }
+
+    /// Resolve `rel` against this directory. `..` in `rel` may climb back
+    /// up, but not above the root.
+    pub fn join(&self, rel: &str) -> Result<VPath, PathError> {
+        let joined = if self.is_root() { rel.to_string() } else { format!("{}/{rel}", self.0) };
+        resolve(&joined, rel)
+    }
+
+    /// True when this path is `dir` or lies beneath it, comparing whole
+    /// components (`data` contains `data/x` but not `database`).
+    pub fn is_within(&self, dir: &VPath) -> bool {
+        dir.is_root()
+            || self.0 == dir.0
+            || self.0.strip_prefix(&dir.0).is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    /// This path relative to `dir`, if it lies within it.
+    pub fn relative_to(&self, dir: &VPath) -> Option<VPath> {
+        if !self.is_within(dir) {
+            return None;
+        }
+        let rest = self.0[dir.0.len()..].trim_start_matches('/');
+        Some(VPath(rest.to_string()))
+    }
+
+    /// Fails unless this path can name a file in a layer: not the root, and
+    /// not named like a whiteout marker.
+    pub fn check_writable(&self) -> Result<(), PathError> {
+        match self.file_name() {
+            None => Err(PathError::Root(format!("/{self}"))),
+            Some(name) if name.starts_with(".wh.") => Err(PathError::Reserved(format!("/{self}"))),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// Canonicalise `path` (already without any leading `/`). `original` is
+/// what errors quote.
+fn resolve(path: &str, original: &str) -> Result<VPath, PathError> {
+    if path.contains('\0') {
+        return Err(PathError::Nul(original.to_string()));
+    }
+    let mut parts: Vec<&str> = Vec::new();
+    for comp in path.split('/') {
+        match comp {
+            "" | "." => {}
+            ".." => {
+                if parts.pop().is_none() {
+                    return Err(PathError::Escapes(original.to_string()));
+                }
+            }
+            name => parts.push(name),
+        }
+    }
+    Ok(VPath(parts.join("/")))
+}
+
+impl Deref for VPath {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for VPath {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Lets maps keyed by `VPath` be queried with a `&str`.
+impl Borrow<str> for VPath {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The canonical form, without a leading `/`.
+impl fmt::Display for VPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for VPath {
+    type Err = PathError;
+
+    fn from_str(s: &str) -> Result<Self, PathError> {
+        VPath::new(s)
+    }
+}
+
+impl From<VPath> for String {
+    fn from(p: VPath) -> String {
+        p.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p(s: &str) -> VPath {
+        VPath::new(s).unwrap()
+    }
+
+    #[test]
+    fn canonicalises_separators_and_dots() {
+        assert_eq!(p("a//b/").as_str(), "a/b");
+        assert_eq!(p("./a/.").as_str(), "a");
+        assert_eq!(p("/etc/hosts"), p("etc/hosts"));
+        assert_eq!(p("a/b/../c").as_str(), "a/c");
+        assert!(p("/").is_root());
+        assert!(p("a/..").is_root());
+    }
+
+    #[test]
+    fn rejects_paths_that_escape_the_root() {
+        assert!(matches!(VPath::new("../x"), Err(PathError::Escapes(_))));
+        assert!(matches!(VPath::new("a/../.."), Err(PathError::Escapes(_))));
+        assert!(matches!(VPath::new("/../etc/passwd"), Err(PathError::Escapes(_))));
+        assert!(matches!(VPath::from_entry_name(b"./../x"), Err(PathError::Escapes(_))));
+    }
+
+    #[test]
+    fn rejects_nul_bytes() {
+        assert!(matches!(VPath::new("a\0b"), Err(PathError::Nul(_))));
+        assert!(matches!(VPath::from_entry_name(b"a\0b"), Err(PathError::Nul(_))));
+    }
+
+    #[test]
+    fn entry_names_must_be_relative_utf8() {
+        assert!(matches!(VPath::from_entry_name(b"/etc/passwd"), Err(PathError::Absolute(_))));
+        assert!(matches!(VPath::from_entry_name(b"a/\xff.txt"), Err(PathError::NotUtf8(_))));
+        assert_eq!(VPath::from_entry_name(b"./usr/bin/").unwrap().as_str(), "usr/bin");
+    }
+
+    #[test]
+    fn is_within_compares_whole_components() {
+        let data = p("data");
+        assert!(p("data").is_within(&data));
+        assert!(p("data/x").is_within(&data));
+        assert!(!p("database").is_within(&data));
+        assert!(!p("dat").is_within(&data));
+        assert!(p("anything").is_within(&VPath::root()));
+        assert_eq!(p("data/x/y").relative_to(&data), Some(p("x/y")));
+        assert_eq!(p("database").relative_to(&data), None);
+    }
+
+    #[test]
+    fn join_stays_under_the_root() {
+        assert_eq!(p("a/b").join("../c").unwrap(), p("a/c"));
+        assert_eq!(p("a").join("..").unwrap(), VPath::root());
+        assert!(matches!(p("a").join("../../x"), Err(PathError::Escapes(_))));
+        assert!(matches!(VPath::root().join(".."), Err(PathError::Escapes(_))));
+    }
+
+    #[test]
+    fn writable_paths_exclude_root_and_whiteout_names() {
+        assert!(p("a/b").check_writable().is_ok());
+        assert!(matches!(VPath::root().check_writable(), Err(PathError::Root(_))));
+        assert!(matches!(p("a/.wh.b").check_writable(), Err(PathError::Reserved(_))));
+    }
+}
@@ -0,0 +1,715 @@
+//! Streaming layer writer.
+//!
+//! `TcowFile::create` and `TcowFile::append` take every file body as an in-memory
+//! `Vec<u8>`. `LayerWriter` instead streams each body from a reader and
+//! hashes each file as it goes, so inserting a multi-GB file needs no more
+//! memory than a copy buffer. An existing `.tcow` file is only written by
+//! `commit`.
+
+use std::collections::BTreeMap;
+use std::fs::{self, Permissions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tempfile::TempPath;
+
+use crate::error::{IoContext, Result, TcowError};
+use crate::storage::{FileStorage, StorageMut};
+use crate::{
+    commit_layer, dedup, delta, encode_cbor, from_whiteout_tar_path, now_rfc3339, now_unix_ts, opaque_whiteout_dir,
+    read_index, to_whiteout_tar_path, write_file_header, write_trailer_footer, BlobRef, DeltaRef, Extensions,
+    LayerKind, LayerRecord, NewLayer, TcowIndex, VPath, FORMAT_VERSION, HEADER_SIZE, OPAQUE_WHITEOUT,
+};
+
+/// Layers appended to an existing file are spooled in memory up to this
+/// size, then on disk.
+const SPOOL_IN_MEMORY: usize = 8 << 20;
+
+/// A layer being written. The `.tcow` file is not touched until `commit`,
+/// so an interrupted writer leaves it exactly as it was:
+///
+/// - a layer appended to an existing file is spooled, and `commit` copies
+///   it in and writes the new trailer. The old trailer is overwritten by
+///   the new layer, so nothing can be written in place before the layer is
+///   complete.
+/// - a file made by `create` is written directly, under a temporary name
+///   next to it that `commit` renames into place.
+///
+/// Dropping the writer without committing discards the spool or the
+/// temporary file.
+pub struct LayerWriter<S: StorageMut = FileStorage> {
+    /// Taken by `commit`.
+    dest: Option<Dest<S>>,
+    builder: Option<tar::Builder<BufWriter<Box<dyn Spool>>>>,
+    /// Offset of the layer's first byte in what `builder` writes to.
+    data_start: u64,
+    index: TcowIndex,
+    /// Offset of the trailer the new layer replaces; unused for a Base layer.
+    trailer_offset: u64,
+    kind: LayerKind,
+    mtime: u64,
+    entries: usize,
+    /// Set when a write failed part-way through an entry, leaving the spool
+    /// with a tar header whose body is incomplete.
+    failed: bool,
+    /// Hex SHA-256 of each file written so far, by canonical path.
+    file_digests: BTreeMap<String, String>,
+}
+
+/// Where `commit` puts the layer.
+enum Dest<S> {
+    /// Copied from the spool into an existing or caller-supplied storage.
+    Storage(S),
+    /// Already written: the builder writes straight into the new file at
+    /// `temp`, which is renamed to `path`.
+    Rename { temp: TempPath, path: PathBuf },
+}
+
+trait Spool: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> Spool for T {}
+
+impl LayerWriter {
+    /// Start a new Delta layer at the end of an existing `.tcow` file. A
+    /// spool that outgrows memory goes next to the file.
+    pub fn append(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut f = FileStorage::open(path)?;
+        let (index, trailer_offset) = read_index(&mut f, path)?;
+        let spool = tempfile::spooled_tempfile_in(SPOOL_IN_MEMORY, parent_dir(path));
+        Ok(Self::start(Dest::Storage(f), Box::new(spool), 0, index, trailer_offset, LayerKind::Delta))
+    }
+
+    /// Create a brand-new `.tcow` file whose Base layer is written through
+    /// this writer. A file already at `path` is replaced by `commit`, and
+    /// keeps its permissions; until then it is left alone.
+    pub fn create(path: impl AsRef<Path>, label: Option<String>) -> Result<Self> {
+        // Replace the file a symlink points at, not the symlink
+        let path = fs::canonicalize(path.as_ref()).unwrap_or_else(|_| path.as_ref().to_path_buf());
+        let dir = parent_dir(&path);
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(".tcow-").suffix(".tmp");
+        if let Some(perms) = default_permissions() {
+            builder.permissions(perms);
+        }
+        let temp = builder
+            .tempfile_in(dir)
+            .io_context(|| format!("cannot create a temporary file in {:?}", dir))?;
+        let (mut file, temp) = temp.into_parts();
+        if let Ok(meta) = fs::metadata(&path) {
+            file.set_permissions(meta.permissions())
+                .io_context(|| format!("cannot copy the permissions of {:?}", path))?;
+        }
+        // Placeholder until `commit` knows whether the layer has entries
+        write_file_header(&mut file, false)?;
+        Ok(Self::start(
+            Dest::Rename { temp, path },
+            Box::new(file),
+            HEADER_SIZE,
+            new_index(label),
+            0,
+            LayerKind::Base,
+        ))
+    }
+}
+
+impl<S: StorageMut> LayerWriter<S> {
+    /// `append` for a `.tcow` file held in `storage`. A spool that outgrows
+    /// memory goes in the system temporary directory.
+    pub fn append_to(mut storage: S) -> Result<Self> {
+        let (index, trailer_offset) = read_index(&mut storage, Path::new(""))?;
+        let spool = tempfile::spooled_tempfile(SPOOL_IN_MEMORY);
+        Ok(Self::start(Dest::Storage(storage), Box::new(spool), 0, index, trailer_offset, LayerKind::Delta))
+    }
+
+    /// `create` in `storage`, replacing anything it held once the layer is
+    /// committed.
+    pub fn create_in(storage: S, label: Option<String>) -> Result<Self> {
+        let spool = tempfile::spooled_tempfile(SPOOL_IN_MEMORY);
+        Ok(Self::start(Dest::Storage(storage), Box::new(spool), 0, new_index(label), 0, LayerKind::Base))
+    }
+
+    fn start(
+        dest: Dest<S>,
+        spool: Box<dyn Spool>,
+        data_start: u64,
+        index: TcowIndex,
+        trailer_offset: u64,
+        kind: LayerKind,
+    ) -> Self {
+        let builder = tar::Builder::new(BufWriter::new(spool));
+        LayerWriter {
+            dest: Some(dest),
+            builder: Some(builder),
+            data_start,
+            index,
+            trailer_offset,
+            kind,
+            mtime: now_unix_ts(),
+            entries: 0,
+            failed: false,
+            file_digests: BTreeMap::new(),
+        }
+    }
+
+    /// Index of the layer being written.
+    pub fn layer_index(&self) -> usize {
+        self.index.layers.len()
+    }
+
+    /// Modification time, in Unix seconds, recorded for entries added from
+    /// now on. Defaults to the time the writer was started.
+    pub fn set_mtime(&mut self, mtime: u64) {
+        self.mtime = mtime;
+    }
+
+    /// Stream `len` bytes from `reader` into the layer as a regular file.
+    /// Fails if the reader ends early or `path` cannot name a file. Once
+    /// the tar header has been written a failure cannot be undone, so it
+    /// also makes `commit` refuse.
+    pub fn add_file(&mut self, path: &VPath, reader: impl Read, len: u64) -> Result<()> {
+        path.check_writable()?;
+        let mut hdr = tar::Header::new_ustar();
+        hdr.set_path(path.as_str())?;
+        hdr.set_size(len);
+        hdr.set_mtime(self.mtime);
+        hdr.set_mode(0o644);
+        hdr.set_cksum();
+
+        let mut counted = CountingReader { inner: reader.take(len), count: 0, hasher: Sha256::new() };
+        let written = self.builder().append(&hdr, &mut counted);
+        let result = match written {
+            Err(e) => Err(TcowError::Io { context: format!("writing /{path}"), source: e }),
+            Ok(()) if counted.count != len => Err(TcowError::Invalid(format!(
+                "/{path}: source ended after {} of {len} bytes",
+                counted.count
+            ))),
+            Ok(()) => Ok(()),
+        };
+        if result.is_err() {
+            self.failed = true;
+            return result;
+        }
+        self.file_digests.insert(path.to_string(), hex::encode(counted.hasher.finalize()));
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Stream `reader` to its end into the layer as a regular file, for
+    /// sources such as stdin whose size is not known up front. The tar
+    /// header is written once the body is in, so nothing is buffered.
+    /// Returns the number of bytes written. A failure makes `commit`
+    /// refuse, as with `add_file`.
+    pub fn add_stream(&mut self, path: &VPath, reader: impl Read) -> Result<u64> {
+        path.check_writable()?;
+        let mut hdr = tar::Header::new_ustar();
+        hdr.set_path(path.as_str())?;
+        hdr.set_mtime(self.mtime);
+        hdr.set_mode(0o644);
+
+        let mut counted = CountingReader { inner: reader, count: 0, hasher: Sha256::new() };
+        let written = write_unsized_entry(self.builder().get_mut(), &mut hdr, &mut counted);
+        self.check(written, path)?;
+        self.file_digests.insert(path.to_string(), hex::encode(counted.hasher.finalize()));
+        self.entries += 1;
+        Ok(counted.count)
+    }
+
+    /// Add a dedup reference: `path` gets the content `blob` points at in
+    /// an earlier layer, without storing it again.
+    pub fn add_reference(&mut self, path: &VPath, blob: &BlobRef) -> Result<()> {
+        if blob.layer >= self.layer_index() {
+            return Err(TcowError::Invalid(format!(
+                "reference to layer {} must point below layer {}",
+                blob.layer,
+                self.layer_index()
+            )));
+        }
+        path.check_writable()?;
+        let mtime = self.mtime;
+        let written = dedup::append_ref_entry(self.builder(), path, blob, mtime);
+        self.check(written, path)?;
+        self.file_digests.insert(path.to_string(), blob.digest.clone());
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Add a delta entry: `path` is rebuilt from `patch` applied to the base
+    /// `delta` names, as produced by `TcowFile::encode_delta`.
+    pub fn add_delta(&mut self, path: &VPath, delta: &DeltaRef, patch: &[u8]) -> Result<()> {
+        if delta.base_layer >= self.layer_index() {
+            return Err(TcowError::Invalid(format!(
+                "delta base layer {} must be below layer {}",
+                delta.base_layer,
+                self.layer_index()
+            )));
+        }
+        path.check_writable()?;
+        let mtime = self.mtime;
+        let written = crate::delta::append_delta_entry(self.builder(), path, delta, patch, mtime);
+        self.check(written, path)?;
+        self.file_digests.insert(path.to_string(), delta.digest.clone());
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Add a whiteout that deletes `path` from the layers below.
+    pub fn add_whiteout(&mut self, path: &VPath) -> Result<()> {
+        path.check_writable()?;
+        let wh_path = to_whiteout_tar_path(path);
+        let mut hdr = tar::Header::new_ustar();
+        hdr.set_path(&wh_path)?;
+        hdr.set_size(0);
+        hdr.set_mtime(self.mtime);
+        hdr.set_mode(0o644);
+        hdr.set_cksum();
+        let written = self.builder().append(&hdr, io::empty());
+        self.check(written, path)?;
+        self.file_digests.remove(path.as_str());
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Add an opaque marker: the contents of directory `dir` in the layers
+    /// below are hidden, while entries this layer adds under it stay.
+    pub fn add_opaque(&mut self, dir: &VPath) -> Result<()> {
+        let marker = dir.join(OPAQUE_WHITEOUT)?;
+        let mut hdr = tar::Header::new_ustar();
+        hdr.set_path(marker.as_str())?;
+        hdr.set_size(0);
+        hdr.set_mtime(self.mtime);
+        hdr.set_mode(0o644);
+        hdr.set_cksum();
+        let written = self.builder().append(&hdr, io::empty());
+        self.check(written, dir)?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Finish the tar stream and write it into the `.tcow` file together
+    /// with the updated CBOR trailer and footer. Returns the new index.
+    /// Fails without touching the file if an earlier write into the layer
+    /// failed part-way.
+    pub fn commit(mut self) -> Result<TcowIndex> {
+        if self.failed {
+            return Err(TcowError::Invalid(
+                "an earlier write into this layer failed; it cannot be committed".into(),
+            ));
+        }
+        let builder = self.builder.take().expect("builder present until commit or drop");
+        let buffered = builder.into_inner().io_context(|| "finishing layer".into())?;
+        let mut spool = buffered.into_inner().map_err(|e| e.into_error())?;
+        let end = spool.stream_position()?;
+        let size = end - self.data_start;
+        spool.seek(SeekFrom::Start(self.data_start))?;
+        let digest = hash_layer(&mut spool, size)?;
+
+        let layer = NewLayer {
+            size,
+            digest,
+            file_digests: std::mem::take(&mut self.file_digests),
+            has_entries: self.entries > 0,
+        };
+        let index = self.index.clone();
+        match self.dest.take().expect("destination present until commit") {
+            Dest::Storage(mut storage) => {
+                spool.seek(SeekFrom::Start(self.data_start))?;
+                match self.kind {
+                    LayerKind::Delta => commit_layer(&mut storage, index, self.trailer_offset, &mut spool, layer),
+                    LayerKind::Base => write_base(&mut storage, index, &mut spool, layer),
+                }
+            }
+            Dest::Rename { temp, path } => {
+                spool.rewind()?;
+                write_file_header(&mut spool, layer.has_entries)?;
+                let index = finish_base(&mut spool, index, layer)?;
+                drop(spool);
+                temp.persist(&path).map_err(|e| TcowError::Io {
+                    context: format!("cannot replace {:?}", path),
+                    source: e.error,
+                })?;
+                Ok(index)
+            }
+        }
+    }
+
+    fn builder(&mut self) -> &mut tar::Builder<BufWriter<Box<dyn Spool>>> {
+        self.builder.as_mut().expect("builder present until commit or drop")
+    }
+
+    /// Poison the writer if writing an entry for `path` failed.
+    fn check(&mut self, written: io::Result<()>, path: &VPath) -> Result<()> {
+        written.map_err(|e| {
+            self.failed = true;
+            TcowError::Io { context: format!("writing /{path}"), source: e }
+        })
+    }
+}
+
+pub(crate) fn new_index(label: Option<String>) -> TcowIndex {
+    TcowIndex {
+        version: FORMAT_VERSION,
+        layers: Vec::new(),
+        last_modified: now_rfc3339(),
+        label,
+        extensions: Extensions::new(),
+    }
+}
+
+/// The directory `path` is in, so temporary files land on the filesystem
+/// the `.tcow` file is on rather than in `/tmp`.
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// What `File::create` would give a new file: read-write for everyone,
+/// less the umask.
+#[cfg(unix)]
+fn default_permissions() -> Option<Permissions> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(Permissions::from_mode(0o666))
+}
+
+#[cfg(not(unix))]
+fn default_permissions() -> Option<Permissions> {
+    None
+}
+
+/// Hex SHA-256 of the next `size` bytes of `spool`.
+fn hash_layer(spool: &mut impl Read, size: u64) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let hashed = io::copy(&mut spool.take(size), &mut hasher).io_context(|| "reading layer data".into())?;
+    if hashed != size {
+        return Err(TcowError::Invalid(format!("layer data ended after {hashed} of {size} bytes")));
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Write a regular-file entry whose body is all of `body`, then go back
+/// and fill in the size in `hdr`. `w` is left at the end of the entry.
+fn write_unsized_entry(w: &mut BufWriter<Box<dyn Spool>>, hdr: &mut tar::Header, body: &mut dyn Read) -> io::Result<()> {
+    let start = w.stream_position()?;
+    w.write_all(&[0u8; 512])?;
+    let len = io::copy(body, w)?;
+    let pad = (512 - len % 512) % 512;
+    w.write_all(&vec![0u8; pad as usize])?;
+    let end = w.stream_position()?;
+
+    hdr.set_size(len);
+    hdr.set_cksum();
+    w.seek(SeekFrom::Start(start))?;
+    w.write_all(hdr.as_bytes())?;
+    w.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// Replace whatever `f` holds with a new file whose Base layer is the
+/// `layer.size` bytes read from `data`.
+pub(crate) fn write_base<S: StorageMut>(
+    f: &mut S,
+    index: TcowIndex,
+    data: &mut dyn Read,
+    layer: NewLayer,
+) -> Result<TcowIndex> {
+    f.set_len(0)?;
+    f.seek(SeekFrom::Start(0))?;
+    write_file_header(f, layer.has_entries)?;
+    let copied = io::copy(&mut data.take(layer.size), f).io_context(|| "writing layer data".into())?;
+    if copied != layer.size {
+        return Err(TcowError::Invalid(format!("layer data ended after {copied} of {} bytes", layer.size)));
+    }
+
+    finish_base(f, index, layer)
+}
+
+/// Record `layer`, already written at `HEADER_SIZE` in `f`, as the Base
+/// layer of `index`, and write the trailer and footer after it.
+fn finish_base<W: Write + Seek>(f: &mut W, mut index: TcowIndex, layer: NewLayer) -> Result<TcowIndex> {
+    let now = now_rfc3339();
+    index.layers.push(LayerRecord {
+        offset: HEADER_SIZE,
+        size: layer.size,
+        kind: LayerKind::Base,
+        digest: Some(layer.digest),
+        created_at: now.clone(),
+        file_digests: layer.file_digests,
+        extensions: Extensions::new(),
+    });
+    index.last_modified = now;
+
+    let end = HEADER_SIZE + layer.size;
+    let cbor_bytes = encode_cbor(&index)?;
+    f.seek(SeekFrom::Start(end))?;
+    f.write_all(&cbor_bytes)?;
+    write_trailer_footer(f, end, cbor_bytes.len() as u32)?;
+    f.flush()?;
+    Ok(index)
+}
+
+/// Describe the already-built layer tar stream read from `layer`, which is
+/// read to its end: its size and digest, the digest of every file as
+/// `parse_layer` would see it, and whether it holds entries. Bodies are
+/// hashed as they stream past, so a layer of any size can be stored without
+/// holding it in memory.
+pub(crate) fn scan_raw_layer(layer: impl Read) -> Result<NewLayer> {
+    let mut counted = CountingReader { inner: layer, count: 0, hasher: Sha256::new() };
+    let mut file_digests = BTreeMap::new();
+    let mut has_entries = false;
+    let mut archive = tar::Archive::new(&mut counted);
+    for entry in archive.entries().io_context(|| "reading layer data".into())? {
+        let mut entry = entry.io_context(|| "reading tar entry".into())?;
+        let path = VPath::from_entry_name(&entry.path_bytes())?;
+        let kind = entry.header().entry_type();
+        if path.is_root() || !(kind.is_file() || kind.is_dir()) {
+            continue;
+        }
+        has_entries = true;
+        // A later entry for the same path replaces the earlier one
+        if let Some(real_path) = from_whiteout_tar_path(&path) {
+            file_digests.remove(real_path.as_str());
+            continue;
+        }
+        file_digests.remove(path.as_str());
+        if kind.is_dir() || opaque_whiteout_dir(&path).is_some() {
+            continue;
+        }
+        let digest = if let Some(blob) = dedup::read_ref_entry(&mut entry)? {
+            blob.digest
+        } else if let Some(delta) = delta::read_delta_entry(&mut entry)? {
+            delta.digest
+        } else {
+            let mut hasher = Sha256::new();
+            io::copy(&mut entry, &mut hasher).io_context(|| format!("reading /{path}"))?;
+            hex::encode(hasher.finalize())
+        };
+        file_digests.insert(path.to_string(), digest);
+    }
+    // Padding after the end-of-archive blocks is part of the layer too
+    io::copy(archive.into_inner(), &mut io::sink()).io_context(|| "reading layer data".into())?;
+    Ok(NewLayer {
+        size: counted.count,
+        digest: hex::encode(counted.hasher.finalize()),
+        file_digests,
+        has_entries,
+    })
+}
+
+/// Counts the bytes read through it so short sources can be detected, and
+/// hashes them for the layer's file digests.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::TcowFile;
+
+    fn vpath(p: &str) -> VPath {
+        VPath::new(p).unwrap()
+    }
+
+    fn base(path: &Path) {
+        TcowFile::create(path, &[(vpath("a.txt"), b"old".to_vec())], &[], None).unwrap();
+    }
+
+    /// Names in `dir` other than the `.tcow` files a test made itself.
+    fn strays(dir: &Path) -> Vec<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|n| !n.ends_with(".tcow"))
+            .collect()
+    }
+
+    #[test]
+    fn commit_writes_a_layer_every_reader_agrees_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("w.tcow");
+        base(&path);
+
+        let mut w = LayerWriter::append(&path).unwrap();
+        assert_eq!(w.layer_index(), 1);
+        w.set_mtime(1_700_000_000);
+        w.add_file(&vpath("b.txt"), &b"bee"[..], 3).unwrap();
+        assert_eq!(w.add_stream(&vpath("s/c.txt"), &b"streamed"[..]).unwrap(), 8);
+        w.add_whiteout(&vpath("a.txt")).unwrap();
+        let index = w.commit().unwrap();
+
+        let tcow = TcowFile::open(&path).unwrap();
+        assert_eq!(tcow.index.layers[1].digest, index.layers[1].digest);
+        assert!(tcow.lookup(&vpath("a.txt")).is_none());
+        let (b, layer) = tcow.lookup(&vpath("b.txt")).unwrap();
+        assert_eq!((&b.data[..], b.mtime, layer), (&b"bee"[..], 1_700_000_000, 1));
+        assert_eq!(&tcow.lookup(&vpath("s/c.txt")).unwrap().0.data[..], b"streamed");
+        assert_eq!(index.layers[1].file_digests["s/c.txt"], crate::sha256_hex(b"streamed"));
+        tcow.verify_layer(1).unwrap();
+        assert!(strays(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn storage_writers_create_and_append_in_place() {
+        let mut buf = Vec::new();
+        let mut w = LayerWriter::create_in(Cursor::new(&mut buf), Some("mem".into())).unwrap();
+        w.add_file(&vpath("a"), &b"1"[..], 1).unwrap();
+        w.commit().unwrap();
+        let mut w = LayerWriter::append_to(Cursor::new(&mut buf)).unwrap();
+        w.add_stream(&vpath("b"), &b"22"[..]).unwrap();
+        w.commit().unwrap();
+
+        let tcow = TcowFile::from_storage(Cursor::new(buf)).unwrap();
+        assert_eq!(tcow.index.label.as_deref(), Some("mem"));
+        assert_eq!(tcow.index.layers.len(), 2);
+        assert!(tcow.header.has_base() && tcow.header.has_entries());
+        assert_eq!(&tcow.lookup(&vpath("b")).unwrap().0.data[..], b"22");
+    }
+
+    #[test]
+    fn a_failed_entry_poisons_the_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("w.tcow");
+        base(&path);
+        let before = fs::read(&path).unwrap();
+
+        let mut w = LayerWriter::append(&path).unwrap();
+        w.add_file(&vpath("ok"), &b"fine"[..], 4).unwrap();
+        let err = w.add_file(&vpath("short"), &b"abc"[..], 10).unwrap_err();
+        assert!(err.to_string().contains("after 3 of 10 bytes"), "{err}");
+        // Later entries are accepted but the layer can no longer be committed
+        w.add_file(&vpath("later"), &b"x"[..], 1).unwrap();
+        assert!(w.commit().is_err());
+        assert_eq!(fs::read(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn rejected_entries_do_not_poison_the_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("w.tcow");
+        base(&path);
+        // A reference may only point below the layer being written
+        let blob = BlobRef { digest: crate::sha256_hex(b"old"), layer: 1, path: vpath("a.txt"), size: 3 };
+
+        let mut w = LayerWriter::append(&path).unwrap();
+        assert!(w.add_file(&vpath(".wh.x"), &b""[..], 0).is_err());
+        assert!(w.add_reference(&vpath("r"), &blob).is_err());
+        w.add_file(&vpath("ok"), &b"fine"[..], 4).unwrap();
+        w.commit().unwrap();
+        assert_eq!(TcowFile::open(&path).unwrap().index.layers.len(), 2);
+    }
+
+    #[test]
+    fn an_interrupted_writer_leaves_the_file_as_it_was() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("w.tcow");
+        base(&path);
+        let before = fs::read(&path).unwrap();
+
+        let mut w = LayerWriter::append(&path).unwrap();
+        w.add_file(&vpath("b"), &b"bee"[..], 3).unwrap();
+        drop(w);
+        assert_eq!(fs::read(&path).unwrap(), before);
+
+        // `create` over an existing file replaces it only on commit
+        let mut w = LayerWriter::create(&path, None).unwrap();
+        w.add_file(&vpath("new"), &b"new"[..], 3).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), before);
+        drop(w);
+        assert_eq!(fs::read(&path).unwrap(), before);
+        assert!(strays(dir.path()).is_empty());
+
+        let fresh = dir.path().join("fresh.tcow");
+        drop(LayerWriter::create(&fresh, None).unwrap());
+        assert!(!fresh.exists());
+        assert!(strays(dir.path()).is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn create_replaces_the_target_of_a_symlink_and_keeps_its_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let (path, link) = (dir.path().join("w.tcow"), dir.path().join("link.tcow"));
+        base(&path);
+        fs::set_permissions(&path, Permissions::from_mode(0o640)).unwrap();
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+
+        let mut w = LayerWriter::create(&link, None).unwrap();
+        w.add_file(&vpath("new"), &b"new"[..], 3).unwrap();
+        w.commit().unwrap();
+
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        let tcow = TcowFile::open(&path).unwrap();
+        assert!(tcow.lookup(&vpath("a.txt")).is_none());
+        assert!(tcow.lookup(&vpath("new")).is_some());
+    }
+
+    #[test]
+    fn a_raw_layer_scan_agrees_with_the_parser() {
+        let big = vec![9u8; 4096];
+        let base = [(vpath("big.bin"), big.clone())];
+        let tcow = TcowFile::create_in(Cursor::new(Vec::new()), &base, &[], None).unwrap();
+        let blob = tcow.content_index().into_values().next().unwrap();
+
+        // A reference, a path written twice, a file then its whiteout, and
+        // padding past the end-of-archive blocks
+        let mut builder = tar::Builder::new(Vec::new());
+        dedup::append_ref_entry(&mut builder, "copy.bin", &blob, 0).unwrap();
+        for (name, body) in [("twice", &b"one"[..]), ("twice", b"two"), ("gone", b"x"), (".wh.gone", b"")] {
+            let mut hdr = tar::Header::new_ustar();
+            hdr.set_path(name).unwrap();
+            hdr.set_size(body.len() as u64);
+            hdr.set_cksum();
+            builder.append(&hdr, body).unwrap();
+        }
+        let mut layer = builder.into_inner().unwrap();
+        layer.extend_from_slice(&[0; 1024]);
+
+        let scanned = scan_raw_layer(&layer[..]).unwrap();
+        assert_eq!((scanned.size, scanned.digest.as_str()), (layer.len() as u64, &*crate::sha256_hex(&layer)));
+        assert!(scanned.has_entries);
+        let parsed = crate::layer_file_digests(&layer).unwrap();
+        assert_eq!(scanned.file_digests, parsed);
+        assert_eq!(parsed.keys().collect::<Vec<_>>(), ["copy.bin", "twice"]);
+
+        let empty = scan_raw_layer(&crate::build_tar_layer(&[], &[]).unwrap()[..]).unwrap();
+        assert!(!empty.has_entries && empty.file_digests.is_empty());
+    }
+
+    #[test]
+    fn raw_layers_from_a_reader_are_stored_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("raw.tcow");
+        let l0 = crate::build_tar_layer(&[(vpath("a.txt"), b"a".to_vec())], &[]).unwrap();
+        let l1 = crate::build_tar_layer(&[(vpath("b.txt"), b"b".to_vec())], &[vpath("a.txt")]).unwrap();
+
+        let mut tcow = TcowFile::create_raw_from(&path, Cursor::new(&l0), Some("raw".into())).unwrap();
+        tcow.append_raw_from(Cursor::new(&l1)).unwrap();
+        assert!(tcow.append_raw_from(Cursor::new(&b"not a tar stream"[..])).is_err());
+
+        let tcow = TcowFile::open(&path).unwrap();
+        assert_eq!(tcow.index.layers.len(), 2);
+        assert_eq!(&tcow.layer_bytes(1).unwrap()[..], &l1[..]);
+        assert_eq!(tcow.index.layers[1].digest.as_deref(), Some(&*crate::sha256_hex(&l1)));
+        assert!(tcow.lookup(&vpath("a.txt")).is_none());
+        assert_eq!(tcow.index.label.as_deref(), Some("raw"));
+        tcow.verify_layer(0).unwrap();
+    }
+}